@@ -0,0 +1,235 @@
+//! Bot accounts: one from the command line, or several listed under
+//! `accounts:` in `config.yaml`, each with its own client, store and plugins.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{self, Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::{Args, BotConfig, appservice::AppserviceConfig};
+
+/// One entry under `accounts:`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccountConfig {
+    /// Shown in logs; defaults to the username.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub homeserver: String,
+    pub username: String,
+    /// Only needed for the first login; the session file is used after that.
+    #[serde(default)]
+    pub password: Option<String>,
+    pub store: PathBuf,
+    /// Defaults to `session.json` inside `store`.
+    #[serde(default)]
+    pub session_file: Option<PathBuf>,
+    /// Defaults to `--device-name`.
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// Plugin IDs this account runs; all of them when omitted.
+    #[serde(default)]
+    pub plugins: Option<Vec<String>>,
+    /// Run as an application service instead of logging in; `username` is
+    /// then the full ID of the appservice's `sender_localpart` user.
+    #[serde(default)]
+    pub appservice: Option<AppserviceConfig>,
+    /// Set up cross-signing and key backup on startup; see `recovery.rs`.
+    #[serde(default)]
+    pub recovery_key_file: Option<PathBuf>,
+}
+
+/// An account ready to log in.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub homeserver: String,
+    pub username: String,
+    pub password: Option<String>,
+    pub store: PathBuf,
+    pub session_file: PathBuf,
+    pub device_name: String,
+    /// `None` runs every configured plugin.
+    pub plugins: Option<HashSet<String>>,
+    pub appservice: Option<AppserviceConfig>,
+    pub recovery_key_file: Option<PathBuf>,
+}
+
+/// The accounts to run: those in `config.yaml` if it lists any, otherwise
+/// the single account described by the command line and environment.
+pub fn resolve(args: &Args, config: &BotConfig) -> Result<Vec<Account>> {
+    if config.accounts.is_empty() {
+        let homeserver = args.homeserver.clone().ok_or_else(|| {
+            anyhow!("set --homeserver or MATRIX_HOMESERVER, or list accounts in the config")
+        })?;
+        let username = args.username.clone().ok_or_else(|| {
+            anyhow!("set --username or MATRIX_USERNAME, or list accounts in the config")
+        })?;
+        return Ok(vec![Account {
+            name: username.clone(),
+            homeserver,
+            username,
+            password: args.password.clone(),
+            store: args.store.clone(),
+            session_file: args.session_file.clone(),
+            device_name: args.device_name.clone(),
+            plugins: None,
+            appservice: None,
+            recovery_key_file: args.recovery_key_file.clone(),
+        }]);
+    }
+
+    let accounts: Vec<Account> = config
+        .accounts
+        .iter()
+        .map(|account| Account {
+            name: account
+                .name
+                .clone()
+                .unwrap_or_else(|| account.username.clone()),
+            homeserver: account.homeserver.clone(),
+            username: account.username.clone(),
+            password: account.password.clone(),
+            store: account.store.clone(),
+            session_file: account
+                .session_file
+                .clone()
+                .unwrap_or_else(|| account.store.join("session.json")),
+            device_name: account
+                .device_name
+                .clone()
+                .unwrap_or_else(|| args.device_name.clone()),
+            plugins: account
+                .plugins
+                .as_ref()
+                .map(|ids| ids.iter().cloned().collect()),
+            appservice: account.appservice.clone(),
+            recovery_key_file: account.recovery_key_file.clone(),
+        })
+        .collect();
+
+    // Two clients sharing a store or session would corrupt each other's
+    // encryption state.
+    let mut names = HashSet::new();
+    let mut paths = HashSet::new();
+    for account in &accounts {
+        if !names.insert(account.name.as_str()) {
+            bail!("account name {:?} is used twice", account.name);
+        }
+        if account.appservice.is_some() && account.recovery_key_file.is_some() {
+            bail!(
+                "account {}: recovery_key_file does not apply in appservice mode",
+                account.name
+            );
+        }
+        let paths_used = [&account.store, &account.session_file]
+            .into_iter()
+            .chain(&account.recovery_key_file);
+        for path in paths_used {
+            if !paths.insert(canonical(path)) {
+                bail!(
+                    "account {}: {} is already used by another account",
+                    account.name,
+                    path.display()
+                );
+            }
+        }
+    }
+    Ok(accounts)
+}
+
+/// `path` made absolute, with symlinks resolved in the part that already
+/// exists, so `./store`, `store/` and a link to it compare equal.
+fn canonical(path: &Path) -> PathBuf {
+    let absolute = path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut existing = absolute.as_path();
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(real) = fs::canonicalize(existing) {
+            return rest.iter().rev().fold(real, |real, name| real.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return absolute,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    fn config(yaml: &str) -> BotConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn resolves_cli_or_configured_accounts() {
+        let args = Args::parse_from([
+            "bot",
+            "--homeserver",
+            "https://hs.example.org",
+            "--username",
+            "bot",
+        ]);
+        let single = resolve(&args, &config("clusters: []")).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].name, "bot");
+        assert!(single[0].plugins.is_none());
+
+        let accounts = resolve(
+            &args,
+            &config(
+                r"
+clusters: []
+accounts:
+  - name: persona
+    homeserver: https://a.example.org
+    username: persona
+    store: ./data/persona
+    plugins: [ai, help]
+  - homeserver: https://b.example.org
+    username: relay
+    store: ./data/relay
+",
+            ),
+        )
+        .unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(
+            accounts[0].session_file,
+            Path::new("./data/persona/session.json")
+        );
+        assert!(accounts[0].plugins.as_ref().unwrap().contains("ai"));
+        assert_eq!(accounts[1].name, "relay");
+        assert_eq!(accounts[1].device_name, "matrix-ping-bot");
+
+        let clash = config(
+            r"
+clusters: []
+accounts:
+  - {homeserver: https://a.example.org, username: one, store: ./data}
+  - {homeserver: https://a.example.org, username: two, store: ./data}
+",
+        );
+        assert!(resolve(&args, &clash).is_err());
+
+        let spelled_differently = config(
+            r"
+clusters: []
+accounts:
+  - {homeserver: https://a.example.org, username: one, store: ./data}
+  - {homeserver: https://a.example.org, username: two, store: data/}
+",
+        );
+        assert!(resolve(&args, &spelled_differently).is_err());
+    }
+}
@@ -0,0 +1,512 @@
+//! Authenticated HTTP/JSON API for operating the running bot. It has its own
+//! listener, separate from `/metrics`, so it can stay on localhost or a Unix
+//! socket. Every request needs `Authorization: Bearer <token>`.
+//!
+//! - `GET /plugins`: registered plugins and whether they are enabled globally
+//! - `POST /plugins/{id}/enable`, `POST /plugins/{id}/disable`: set an
+//!   override; the optional body picks the scope, e.g.
+//!   `{"scope": "room", "target": "!room:example.org"}` (default global)
+//! - `GET /relay`: the resolved relay plan
+//! - `POST /relay/reload`: resolve the relay clusters' aliases again
+//! - `GET /rooms`: joined rooms with aliases and encryption state
+//! - `POST /rooms/{room}/messages`: send `{"body": "...", "markdown": false,
+//!   "notice": false}` to a room ID or alias
+//! - `POST /history/backfill`: refill the AI history files, `{"lines": 50}`
+
+use core::{fmt, net::SocketAddr, str::FromStr};
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context as _, Result};
+use axum::{
+    Json, Router,
+    extract::{Path, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use matrix_sdk::ruma::{
+    OwnedRoomId, RoomAliasId, RoomId, events::room::message::RoomMessageEventContent,
+};
+use plugin_core::{MatrixClient, MatrixRoom, OverrideScope, PluginRegistry};
+use plugin_relay::Relay;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+/// Where the admin API listens: `host:port` or `unix:/path/to/socket`.
+#[derive(Debug, Clone)]
+pub enum AdminListen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for AdminListen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(Self::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(format!("unix:{path}: Unix sockets need a Unix platform"));
+        }
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|e| format!("expected host:port or unix:/path ({e})"))
+    }
+}
+
+impl fmt::Display for AdminListen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdminState {
+    pub registry: Arc<PluginRegistry>,
+    pub client: Arc<dyn MatrixClient>,
+    pub relay: Arc<Relay>,
+    pub history_dir: Arc<PathBuf>,
+    pub token: Arc<str>,
+}
+
+/// An error answered as `{"error": "..."}`.
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn not_found(what: impl Into<String>) -> Self {
+        Self(StatusCode::NOT_FOUND, what.into())
+    }
+
+    fn bad_request(what: impl Into<String>) -> Self {
+        Self(StatusCode::BAD_REQUEST, what.into())
+    }
+
+    fn internal(e: &anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+type ApiResult<T = Json<Value>> = Result<T, ApiError>;
+
+fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/plugins", get(list_plugins))
+        .route("/plugins/{id}/enable", post(enable_plugin))
+        .route("/plugins/{id}/disable", post(disable_plugin))
+        .route("/relay", get(relay_plan))
+        .route("/relay/reload", post(reload_relay))
+        .route("/rooms", get(list_rooms))
+        .route("/rooms/{room}/messages", post(send_message))
+        .route("/history/backfill", post(backfill))
+        .layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .with_state(state)
+}
+
+/// Compare without stopping at the first difference, so response timing
+/// does not reveal how much of a guessed token was right.
+pub fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn authenticate(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        Some(token) if tokens_match(token.as_bytes(), state.token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => {
+            warn!(target: "audit", path = %request.uri().path(), "Admin API request rejected");
+            ApiError(
+                StatusCode::UNAUTHORIZED,
+                "missing or wrong token".to_owned(),
+            )
+            .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PluginInfo {
+    id: String,
+    enabled: bool,
+    dev_only: bool,
+    commands: Vec<String>,
+    mentions: Vec<String>,
+    help: &'static str,
+}
+
+async fn list_plugins(State(state): State<AdminState>) -> Json<Vec<PluginInfo>> {
+    let mut entries = state.registry.entries().await;
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut plugins = Vec::with_capacity(entries.len());
+    for (id, entry) in entries {
+        plugins.push(PluginInfo {
+            enabled: state.registry.is_enabled(&id).await,
+            dev_only: entry
+                .spec
+                .dev_only
+                .unwrap_or_else(|| entry.plugin.dev_only()),
+            commands: entry.spec.triggers.commands.clone(),
+            mentions: entry.spec.triggers.mentions.clone(),
+            help: entry.plugin.help(),
+            id,
+        });
+    }
+    Json(plugins)
+}
+
+async fn enable_plugin(
+    state: State<AdminState>,
+    id: Path<String>,
+    scope: Option<Json<OverrideScope>>,
+) -> ApiResult {
+    set_enabled(state, id, scope, true).await
+}
+
+async fn disable_plugin(
+    state: State<AdminState>,
+    id: Path<String>,
+    scope: Option<Json<OverrideScope>>,
+) -> ApiResult {
+    set_enabled(state, id, scope, false).await
+}
+
+async fn set_enabled(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+    scope: Option<Json<OverrideScope>>,
+    enabled: bool,
+) -> ApiResult {
+    if state.registry.entry(&id).await.is_none() {
+        return Err(ApiError::not_found(format!("unknown plugin: {id}")));
+    }
+    let scope = scope.map_or(OverrideScope::Global, |Json(scope)| scope);
+    info!(target: "audit", plugin = %id, ?scope, enabled, "Admin API changed plugin state");
+    state
+        .registry
+        .set_override(id.clone(), scope.clone(), enabled)
+        .await
+        .map_err(|e| ApiError::internal(&e.context("applied but not saved")))?;
+    Ok(Json(
+        json!({ "id": id, "enabled": enabled, "override": scope }),
+    ))
+}
+
+async fn relay_routes(state: &AdminState) -> ApiResult {
+    let entry = state
+        .registry
+        .entry("relay")
+        .await
+        .ok_or_else(|| ApiError::not_found("relay is not registered"))?;
+    let routes = state
+        .relay
+        .routes(state.client.as_ref(), &entry.spec)
+        .await
+        .map_err(|e| ApiError::internal(&e))?;
+    Ok(Json(json!({ "routes": routes.unwrap_or_default() })))
+}
+
+async fn relay_plan(State(state): State<AdminState>) -> ApiResult {
+    relay_routes(&state).await
+}
+
+async fn reload_relay(State(state): State<AdminState>) -> ApiResult {
+    info!(target: "audit", "Admin API reloaded the relay plan");
+    state.relay.invalidate().await;
+    relay_routes(&state).await
+}
+
+#[derive(Debug, Serialize)]
+struct RoomInfo {
+    room_id: OwnedRoomId,
+    aliases: Vec<String>,
+    encrypted: bool,
+}
+
+async fn list_rooms(State(state): State<AdminState>) -> Json<Vec<RoomInfo>> {
+    let mut rooms = Vec::new();
+    for room in state.client.joined_rooms() {
+        rooms.push(RoomInfo {
+            room_id: room.room_id().to_owned(),
+            aliases: room.aliases().iter().map(ToString::to_string).collect(),
+            encrypted: room.is_encrypted().await,
+        });
+    }
+    rooms.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+    Json(rooms)
+}
+
+/// Find a joined room by ID or alias.
+async fn joined_room(client: &dyn MatrixClient, room: &str) -> ApiResult<Arc<dyn MatrixRoom>> {
+    let room_id = if room.starts_with('#') {
+        let alias = RoomAliasId::parse(room)
+            .map_err(|e| ApiError::bad_request(format!("invalid alias {room}: {e}")))?;
+        client
+            .resolve_alias(&alias)
+            .await
+            .map_err(|e| ApiError::not_found(format!("cannot resolve {room}: {e:#}")))?
+    } else {
+        RoomId::parse(room)
+            .map_err(|e| ApiError::bad_request(format!("invalid room ID {room}: {e}")))?
+    };
+    client
+        .room(&room_id)
+        .ok_or_else(|| ApiError::not_found(format!("not joined to {room_id}")))
+}
+
+#[derive(Debug, Deserialize)]
+struct SendMessage {
+    body: String,
+    #[serde(default)]
+    markdown: bool,
+    #[serde(default)]
+    notice: bool,
+}
+
+async fn send_message(
+    State(state): State<AdminState>,
+    Path(room): Path<String>,
+    Json(message): Json<SendMessage>,
+) -> ApiResult {
+    let target = joined_room(state.client.as_ref(), &room).await?;
+    let content = match (message.markdown, message.notice) {
+        (false, false) => RoomMessageEventContent::text_plain(message.body),
+        (true, false) => RoomMessageEventContent::text_markdown(message.body),
+        (false, true) => RoomMessageEventContent::notice_plain(message.body),
+        (true, true) => RoomMessageEventContent::notice_markdown(message.body),
+    };
+    info!(target: "audit", room_id = %target.room_id(), "Admin API sent a message");
+    let event_id = target
+        .send(content)
+        .await
+        .map_err(|e| ApiError::internal(&e))?;
+    Ok(Json(json!({ "event_id": event_id })))
+}
+
+#[derive(Debug, Deserialize)]
+struct Backfill {
+    #[serde(default = "default_backfill_lines")]
+    lines: u64,
+}
+
+const fn default_backfill_lines() -> u64 {
+    50
+}
+
+async fn backfill(
+    State(state): State<AdminState>,
+    request: Option<Json<Backfill>>,
+) -> (StatusCode, Json<Value>) {
+    let lines = request.map_or_else(default_backfill_lines, |Json(b)| b.lines);
+    info!(target: "audit", lines, "Admin API started a history backfill");
+    let client = Arc::clone(&state.client);
+    let history_dir = state.history_dir.as_ref().clone();
+    tokio::spawn(plugin_ai::backfill_all(client, history_dir, lines));
+    (StatusCode::ACCEPTED, Json(json!({ "lines": lines })))
+}
+
+/// Bind `listen` and serve the admin API in the background.
+pub async fn spawn(listen: &AdminListen, state: AdminState) -> Result<()> {
+    let app = router(state);
+    match listen {
+        AdminListen::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("binding the admin API to {addr}"))?;
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    warn!(error = %e, "Admin API stopped");
+                }
+            });
+        }
+        #[cfg(unix)]
+        AdminListen::Unix(path) => {
+            let listener = bind_unix(path)
+                .with_context(|| format!("binding the admin API to {}", path.display()))?;
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).await {
+                    warn!(error = %e, "Admin API stopped");
+                }
+            });
+        }
+    }
+    info!(listen = %listen, "Serving the admin API");
+    Ok(())
+}
+
+/// Bind a socket at `path` that only the bot's user can connect to. It is
+/// bound and restricted in a private directory, then moved into place, so it
+/// is never reachable with looser permissions. Only an old socket at `path`
+/// is replaced, never another kind of file.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    use std::{
+        fs::{self, DirBuilder, Permissions},
+        os::unix::fs::{DirBuilderExt as _, FileTypeExt as _, PermissionsExt as _},
+    };
+
+    if let Ok(meta) = fs::symlink_metadata(path)
+        && !meta.file_type().is_socket()
+    {
+        anyhow::bail!("{} exists and is not a socket", path.display());
+    }
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} has no file name", path.display()))?;
+    let parent = path.parent().unwrap_or_else(|| std::path::Path::new("."));
+    let private = parent.join(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .with_context(|| format!("creating {}", private.display()))?;
+    let staged = private.join(name);
+    let bound = tokio::net::UnixListener::bind(&staged)
+        .context("binding the socket")
+        .and_then(|listener| {
+            fs::set_permissions(&staged, Permissions::from_mode(0o600))
+                .context("restricting the socket")?;
+            fs::rename(&staged, path).context("moving the socket into place")?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&private);
+    bound
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{Body, to_bytes};
+    use matrix_sdk::ruma::{room_alias_id, room_id, user_id};
+    use plugin_core::{Plugin as _, testing::FakeClient};
+    use tower::ServiceExt as _;
+
+    use super::*;
+
+    async fn call(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        token: &str,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {token}"));
+        if !body.is_null() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        let body = if body.is_null() {
+            Body::empty()
+        } else {
+            Body::from(body.to_string())
+        };
+        let request = request.body(body).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn manages_plugins_and_sends_messages() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        client.add_alias(
+            room_alias_id!("#ops:example.org"),
+            room_id!("!r:example.org"),
+        );
+        let registry = Arc::new(PluginRegistry::new());
+        let ping = plugin_ping::Ping;
+        registry.register(ping.spec(), Arc::new(ping)).await;
+        let app = router(AdminState {
+            registry: Arc::clone(&registry),
+            client: Arc::clone(&client) as Arc<dyn MatrixClient>,
+            relay: Arc::new(Relay::default()),
+            history_dir: Arc::new(std::env::temp_dir()),
+            token: Arc::from("secret"),
+        });
+
+        let (status, _) = call(&app, "GET", "/plugins", "guess", Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) =
+            call(&app, "POST", "/plugins/ping/disable", "secret", Value::Null).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(!registry.is_enabled("ping").await);
+        let (_, plugins) = call(&app, "GET", "/plugins", "secret", Value::Null).await;
+        assert_eq!(plugins[0]["id"], "ping");
+        assert_eq!(plugins[0]["enabled"], false);
+        let (status, _) = call(&app, "POST", "/plugins/nope/enable", "secret", Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = call(
+            &app,
+            "POST",
+            "/rooms/%23ops:example.org/messages",
+            "secret",
+            json!({ "body": "deploy done" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(room.sent_bodies(), ["deploy done"]);
+
+        let (_, rooms) = call(&app, "GET", "/rooms", "secret", Value::Null).await;
+        assert_eq!(rooms[0]["room_id"], "!r:example.org");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_is_private_and_replaces_only_sockets() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = std::env::temp_dir().join(format!("admin-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+
+        let first = bind_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(first);
+        // The socket left behind by an earlier run is replaced.
+        let _second = bind_unix(&path).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let file = dir.join("not-a-socket");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(bind_unix(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
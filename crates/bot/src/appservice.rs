@@ -0,0 +1,485 @@
+//! Appservice mode: instead of logging in and long-polling `/sync`, serve
+//! the appservice API, run the events in the homeserver's transactions
+//! through the usual plugin pipeline, and act as the bot user or one of the
+//! namespaced virtual users. Plugins send as a virtual user through
+//! `PluginContext::acting_as`; events from any namespaced user count as the
+//! bot's own, so plugins do not answer themselves.
+//!
+//! Endpoints, all authenticated with the registration's `hs_token`:
+//!
+//! - `PUT /_matrix/app/v1/transactions/{txn_id}`: events to process
+//! - `GET /_matrix/app/v1/users/{user_id}`: registers namespaced users on demand
+//! - `GET /_matrix/app/v1/rooms/{alias}`: no aliases are provided
+//! - `POST /_matrix/app/v1/ping`
+//!
+//! Encrypted rooms are not supported: their events arrive still encrypted.
+
+use core::net::SocketAddr;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::{Context as _, Result};
+use axum::{
+    Json, Router,
+    extract::{Path, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse as _, Response},
+    routing::{get, post, put},
+};
+use matrix_sdk::ruma::{
+    RoomId, UserId,
+    events::{
+        reaction::OriginalSyncReactionEvent,
+        room::{
+            member::{MembershipState, OriginalSyncRoomMemberEvent},
+            message::OriginalSyncRoomMessageEvent,
+            redaction::OriginalSyncRoomRedactionEvent,
+        },
+    },
+};
+use plugin_core::{MatrixClient, MatrixRoom};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{debug, info, warn};
+
+use crate::{
+    MessagePipeline,
+    admin::tokens_match,
+    appservice_io::AsClient,
+    events::{QueuedRoomEvent, dispatch_room_event},
+    handle_room_message,
+};
+
+/// How many transaction IDs are remembered to skip the homeserver's retries.
+const SEEN_TRANSACTIONS: usize = 256;
+
+/// `appservice:` in an account; the values mirror the registration file.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppserviceConfig {
+    /// Where the homeserver reaches the appservice, the registration's `url`.
+    pub listen: SocketAddr,
+    pub as_token: String,
+    pub hs_token: String,
+    /// Regexes for the virtual users, the registration's `namespaces.users`.
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Appservice {
+    client: AsClient,
+    hs_token: Arc<str>,
+    pipeline: MessagePipeline,
+    autojoin: bool,
+    seen: Arc<Mutex<VecDeque<String>>>,
+}
+
+#[derive(Deserialize)]
+struct Transaction {
+    #[serde(default)]
+    events: Vec<Value>,
+}
+
+fn matrix_error(status: StatusCode, errcode: &str, error: &str) -> Response {
+    (status, Json(json!({ "errcode": errcode, "error": error }))).into_response()
+}
+
+impl Appservice {
+    pub fn new(
+        config: &AppserviceConfig,
+        client: AsClient,
+        pipeline: MessagePipeline,
+        autojoin: bool,
+    ) -> Self {
+        Self {
+            client,
+            hs_token: Arc::from(config.hs_token.as_str()),
+            pipeline,
+            autojoin,
+            seen: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    fn router(self) -> Router {
+        Router::new()
+            .route("/_matrix/app/v1/transactions/{txn_id}", put(transaction))
+            .route("/_matrix/app/v1/users/{user_id}", get(query_user))
+            .route("/_matrix/app/v1/rooms/{alias}", get(query_alias))
+            .route("/_matrix/app/v1/ping", post(ping))
+            .route_layer(middleware::from_fn_with_state(self.clone(), authenticate))
+            .with_state(self)
+    }
+
+    /// Serve the appservice API on `listener` until `stop` flips.
+    pub async fn serve(self, listener: TcpListener, mut stop: watch::Receiver<bool>) -> Result<()> {
+        info!(addr = ?listener.local_addr().ok(), bot = %self.client.bot(), "Serving the appservice API");
+        axum::serve(listener, self.router())
+            .with_graceful_shutdown(async move {
+                let _ = stop.wait_for(|stop| *stop).await;
+            })
+            .await
+            .context("appservice listener failed")
+    }
+
+    async fn handle_event(&self, event: Value) {
+        let Some(room_id) = event["room_id"]
+            .as_str()
+            .and_then(|id| RoomId::parse(id).ok())
+        else {
+            return;
+        };
+        let kind = event["type"].as_str().unwrap_or_default().to_owned();
+        let client: Arc<dyn MatrixClient> = Arc::new(self.client.clone());
+        let room: Arc<dyn MatrixRoom> = Arc::new(self.client.room_handle(&room_id));
+        match kind.as_str() {
+            "m.room.message" => {
+                if let Some(ev) = parse::<OriginalSyncRoomMessageEvent>(event, &kind) {
+                    handle_room_message(&self.pipeline, client, room, ev).await;
+                }
+            }
+            "m.reaction" => {
+                if let Some(ev) = parse::<OriginalSyncReactionEvent>(event, &kind) {
+                    let ctx = self.pipeline.state.context(client, room);
+                    dispatch_room_event(
+                        &ctx,
+                        &self.pipeline.dispatcher,
+                        QueuedRoomEvent::Reaction(Arc::new(ev)),
+                    )
+                    .await;
+                }
+            }
+            "m.room.redaction" => {
+                if let Some(ev) = parse::<OriginalSyncRoomRedactionEvent>(event, &kind) {
+                    let ctx = self.pipeline.state.context(client, room);
+                    dispatch_room_event(
+                        &ctx,
+                        &self.pipeline.dispatcher,
+                        QueuedRoomEvent::Redaction(Arc::new(ev)),
+                    )
+                    .await;
+                }
+            }
+            "m.room.member" => {
+                if let Some(ev) = parse::<OriginalSyncRoomMemberEvent>(event, &kind) {
+                    self.membership(&room_id, &ev).await;
+                    let ctx = self.pipeline.state.context(client, room);
+                    dispatch_room_event(
+                        &ctx,
+                        &self.pipeline.dispatcher,
+                        QueuedRoomEvent::Membership(Arc::new(ev)),
+                    )
+                    .await;
+                }
+            }
+            "m.room.encrypted" => debug!(room_id = %room_id, "Skipping encrypted event"),
+            _ => {}
+        }
+    }
+
+    /// Accept invites for the bot and virtual users, and track the bot's
+    /// rooms.
+    async fn membership(&self, room_id: &RoomId, ev: &OriginalSyncRoomMemberEvent) {
+        let user: &UserId = &ev.state_key;
+        if !self.client.owns(user) {
+            return;
+        }
+        let membership = &ev.content.membership;
+        if *membership == MembershipState::Invite && self.autojoin {
+            info!(room_id = %room_id, user = %user, "Auto-joining invited room");
+            if let Err(e) = self.client.as_user(user.to_owned()).join(room_id).await {
+                warn!(error = %e, "Failed to accept invite");
+            }
+        } else if user == self.client.bot() {
+            if *membership == MembershipState::Join {
+                self.client.set_joined(room_id, true);
+            } else if *membership == MembershipState::Leave || *membership == MembershipState::Ban {
+                self.client.set_joined(room_id, false);
+            }
+        }
+    }
+}
+
+fn parse<T: DeserializeOwned>(event: Value, kind: &str) -> Option<T> {
+    serde_json::from_value(event)
+        .inspect_err(|e| debug!(error = %e, kind, "Skipping event that does not parse"))
+        .ok()
+}
+
+/// Bind the appservice listener early, so a taken port fails startup.
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("binding the appservice listener to {addr}"))
+}
+
+/// The homeserver sends `hs_token` as a bearer token; older ones use the
+/// `access_token` query parameter.
+async fn authenticate(State(app): State<Appservice>, request: Request, next: Next) -> Response {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("access_token="))
+    });
+    match bearer.or(query) {
+        None => matrix_error(StatusCode::UNAUTHORIZED, "M_UNAUTHORIZED", "missing token"),
+        Some(token) if tokens_match(token.as_bytes(), app.hs_token.as_bytes()) => {
+            next.run(request).await
+        }
+        Some(_) => {
+            warn!(target: "audit", path = %request.uri().path(), "Rejected appservice request with a bad token");
+            matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "bad token")
+        }
+    }
+}
+
+async fn transaction(
+    State(app): State<Appservice>,
+    Path(txn_id): Path<String>,
+    Json(transaction): Json<Transaction>,
+) -> Response {
+    {
+        let mut seen = app.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen.contains(&txn_id) {
+            debug!(txn_id, "Skipping repeated transaction");
+            return Json(json!({})).into_response();
+        }
+        if seen.len() == SEEN_TRANSACTIONS {
+            seen.pop_front();
+        }
+        seen.push_back(txn_id.clone());
+    }
+    debug!(
+        txn_id,
+        events = transaction.events.len(),
+        "Received transaction"
+    );
+    for event in transaction.events {
+        app.handle_event(event).await;
+    }
+    Json(json!({})).into_response()
+}
+
+async fn query_user(State(app): State<Appservice>, Path(user_id): Path<String>) -> Response {
+    let Ok(user_id) = UserId::parse(&user_id) else {
+        return matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "not a user ID");
+    };
+    if !app.client.owns(&user_id) {
+        return matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "not in namespace");
+    }
+    match app
+        .client
+        .as_user(user_id.clone())
+        .ensure_registered()
+        .await
+    {
+        Ok(()) => {
+            info!(user = %user_id, "Registered virtual user");
+            Json(json!({})).into_response()
+        }
+        Err(e) => {
+            warn!(user = %user_id, error = %format!("{e:#}"), "Failed to register virtual user");
+            matrix_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "M_UNKNOWN",
+                "registration failed",
+            )
+        }
+    }
+}
+
+async fn query_alias() -> Response {
+    matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no aliases here")
+}
+
+async fn ping() -> Response {
+    Json(json!({})).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use axum::{
+        body::Body,
+        http::{Method, Uri},
+    };
+    use matrix_sdk::ruma::{owned_user_id, user_id};
+    use plugin_core::{KvStore, Plugin as _, PluginRegistry, RateLimiter};
+    use tokio::sync::mpsc;
+    use tower::ServiceExt as _;
+
+    use super::*;
+    use crate::{dispatch::Dispatcher, events::HandlerState};
+
+    /// A homeserver that accepts every request and reports it as
+    /// `(method, path?query, body)`. Room state lookups find nothing.
+    async fn mock_homeserver() -> (String, mpsc::UnboundedReceiver<(Method, String, Value)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new().fallback(async move |method: Method, uri: Uri, body: String| {
+            let body = serde_json::from_str(&body).unwrap_or(Value::Null);
+            let _ = tx.send((method, uri.to_string(), body));
+            if uri.path().contains("/state/") {
+                return matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no state");
+            }
+            Json(json!({ "event_id": "$sent", "room_id": "!r:example.org" })).into_response()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, rx)
+    }
+
+    fn put_transaction(txn_id: &str, token: &str, events: &Value) -> Request {
+        Request::builder()
+            .method(Method::PUT)
+            .uri(format!("/_matrix/app/v1/transactions/{txn_id}"))
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "events": events }).to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn transactions_run_plugins_and_virtual_users_join() {
+        let (homeserver, mut requests) = mock_homeserver().await;
+        let users = vec!["^@bridge_.*:example\\.org$".to_owned()];
+        let client = AsClient::new(
+            &homeserver,
+            "as-secret",
+            owned_user_id!("@bot:example.org"),
+            &users,
+        )
+        .unwrap();
+        let registry = Arc::new(PluginRegistry::new());
+        registry
+            .replace_plugins(
+                vec![(plugin_ping::Ping.spec(), Arc::new(plugin_ping::Ping) as _)],
+                Vec::new(),
+            )
+            .await;
+        let pipeline = MessagePipeline {
+            state: HandlerState {
+                registry,
+                dev_active: false,
+                dev_id: None,
+                history_dir: Arc::new(std::env::temp_dir()),
+                store: KvStore::in_memory().unwrap(),
+            },
+            rate_limiter: Arc::new(RateLimiter::new()),
+            dispatcher: Arc::new(Dispatcher::default()),
+        };
+        let config = AppserviceConfig {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            as_token: "as-secret".to_owned(),
+            hs_token: "hs-secret".to_owned(),
+            users,
+        };
+        let app = Appservice::new(&config, client, pipeline, true).router();
+
+        let events = json!([
+            {
+                "type": "m.room.message",
+                "room_id": "!r:example.org",
+                "sender": "@alice:example.org",
+                "event_id": "$ping",
+                "origin_server_ts": 1,
+                "content": { "msgtype": "m.text", "body": "!ping" },
+            },
+            {
+                // Virtual users are the bot too; answering them could loop.
+                "type": "m.room.message",
+                "room_id": "!r:example.org",
+                "sender": "@bridge_bob:example.org",
+                "event_id": "$echo",
+                "origin_server_ts": 1,
+                "content": { "msgtype": "m.text", "body": "!ping" },
+            },
+            {
+                "type": "m.room.member",
+                "room_id": "!r:example.org",
+                "sender": "@alice:example.org",
+                "state_key": "@bridge_alice:example.org",
+                "event_id": "$invite",
+                "origin_server_ts": 2,
+                "content": { "membership": "invite" },
+            },
+        ]);
+        let rejected = app
+            .clone()
+            .oneshot(put_transaction("1", "wrong", &events))
+            .await
+            .unwrap();
+        assert_eq!(rejected.status(), StatusCode::FORBIDDEN);
+        for _ in 0..2 {
+            // The homeserver retrying a transaction must not run it twice.
+            let accepted = app
+                .clone()
+                .oneshot(put_transaction("1", "hs-secret", &events))
+                .await
+                .unwrap();
+            assert_eq!(accepted.status(), StatusCode::OK);
+        }
+
+        let mut sent = Vec::new();
+        while let Ok(Some(request)) =
+            tokio::time::timeout(Duration::from_millis(500), requests.recv()).await
+        {
+            sent.push(request);
+        }
+        let pongs: Vec<_> = sent
+            .iter()
+            .filter(|(method, uri, _)| {
+                method == Method::PUT && uri.contains("/rooms/!r:example.org/send/m.room.message/")
+            })
+            .collect();
+        assert_eq!(pongs.len(), 1, "{sent:?}");
+        assert_eq!(pongs[0].2["body"], "Pong! 🏓");
+        assert!(pongs[0].1.ends_with("?user_id=%40bot%3Aexample.org"));
+        assert!(sent.iter().any(|(method, uri, _)| method == Method::POST
+            && uri == "/_matrix/client/v3/rooms/!r:example.org/join?user_id=%40bridge_alice%3Aexample.org"));
+    }
+
+    #[tokio::test]
+    async fn plugins_send_as_virtual_users() {
+        let (homeserver, mut requests) = mock_homeserver().await;
+        let client = AsClient::new(
+            &homeserver,
+            "as-secret",
+            owned_user_id!("@bot:example.org"),
+            &["^@bridge_.*:example\\.org$".to_owned()],
+        )
+        .unwrap();
+        let room = client.room_handle(matrix_sdk::ruma::room_id!("!r:example.org"));
+        let ctx = HandlerState {
+            registry: Arc::new(PluginRegistry::new()),
+            dev_active: false,
+            dev_id: None,
+            history_dir: Arc::new(std::env::temp_dir()),
+            store: KvStore::in_memory().unwrap(),
+        }
+        .context(Arc::new(client), Arc::new(room));
+
+        assert!(ctx.client.is_own_user(user_id!("@bridge_bob:example.org")));
+        assert!(!ctx.client.is_own_user(user_id!("@alice:example.org")));
+        assert!(ctx.acting_as(user_id!("@alice:example.org")).is_err());
+
+        let bob = ctx.acting_as(user_id!("@bridge_bob:example.org")).unwrap();
+        plugin_core::send_text(&bob, "hi from bob").await.unwrap();
+        let (method, uri, body) = requests.recv().await.unwrap();
+        assert_eq!(method, Method::PUT);
+        assert!(
+            uri.ends_with("?user_id=%40bridge_bob%3Aexample.org"),
+            "{uri}"
+        );
+        assert_eq!(body["body"], "hi from bob");
+    }
+}
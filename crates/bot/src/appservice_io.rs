@@ -0,0 +1,495 @@
+//! [`MatrixClient`] and [`MatrixRoom`] over the client-server API with an
+//! appservice token, for appservice mode where there is no SDK client or
+//! synced state. Requests assert the identity of the bot user or one of the
+//! appservice's virtual users through the `user_id` query parameter.
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::{Context as _, Result, bail};
+use async_trait::async_trait;
+use matrix_sdk::ruma::{
+    EventId, OwnedDeviceId, OwnedEventId, OwnedMxcUri, OwnedRoomAliasId, OwnedRoomId, OwnedUserId,
+    RoomAliasId, RoomId, TransactionId, UInt, UserId,
+    events::{
+        AnySyncTimelineEvent,
+        reaction::ReactionEventContent,
+        relation::Annotation,
+        room::{
+            ImageInfo, MediaSource,
+            message::{
+                FileInfo, FileMessageEventContent, ImageMessageEventContent, MessageType,
+                RoomMessageEventContent,
+            },
+        },
+    },
+    serde::Raw,
+};
+use mime::Mime;
+use plugin_core::{EncryptionStatus, MatrixClient, MatrixRoom, MemberInfo, MessagesPage};
+use regex::RegexSet;
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+/// Talks to the homeserver as the appservice.
+#[derive(Debug, Clone)]
+pub struct AsClient {
+    inner: Arc<Inner>,
+    /// Whom requests are made as: the bot user or a virtual user.
+    user_id: OwnedUserId,
+}
+
+#[derive(Debug)]
+struct Inner {
+    http: reqwest::Client,
+    homeserver: Url,
+    as_token: String,
+    bot: OwnedUserId,
+    /// The virtual users, the registration's `namespaces.users`.
+    users: RegexSet,
+    /// Rooms the bot user is in: `/joined_rooms` at startup, then kept up
+    /// to date from membership events in transactions.
+    joined: Mutex<BTreeSet<OwnedRoomId>>,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(default)]
+    errcode: String,
+    #[serde(default)]
+    error: String,
+}
+
+/// Turn an error status into an error carrying the Matrix error code.
+async fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().path().to_owned();
+    let body: ErrorBody = response.json().await.unwrap_or(ErrorBody {
+        errcode: String::new(),
+        error: String::new(),
+    });
+    bail!("{url}: {status} {} {}", body.errcode, body.error)
+}
+
+async fn json(request: RequestBuilder) -> Result<Value> {
+    Ok(check(request.send().await?).await?.json().await?)
+}
+
+impl AsClient {
+    /// `bot` is the appservice's `sender_localpart` user, `users` the
+    /// regexes of its user namespace.
+    pub fn new(
+        homeserver: &str,
+        as_token: &str,
+        bot: OwnedUserId,
+        users: &[String],
+    ) -> Result<Self> {
+        let homeserver = Url::parse(homeserver)
+            .with_context(|| format!("invalid homeserver URL {homeserver}"))?;
+        if homeserver.cannot_be_a_base() {
+            bail!("invalid homeserver URL {homeserver}");
+        }
+        let users = RegexSet::new(users).context("invalid appservice user namespace")?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                http: reqwest::Client::new(),
+                homeserver,
+                as_token: as_token.to_owned(),
+                bot: bot.clone(),
+                users,
+                joined: Mutex::new(BTreeSet::new()),
+            }),
+            user_id: bot,
+        })
+    }
+
+    /// The same appservice acting as `user_id`, which must be one it
+    /// [`owns`](Self::owns).
+    pub fn as_user(&self, user_id: OwnedUserId) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            user_id,
+        }
+    }
+
+    pub fn bot(&self) -> &UserId {
+        &self.inner.bot
+    }
+
+    /// Whether the appservice may act as `user`: the bot user or one in its
+    /// namespace.
+    pub fn owns(&self, user: &UserId) -> bool {
+        user == self.inner.bot || self.inner.users.is_match(user.as_str())
+    }
+
+    fn url(&self, path: &[&str]) -> Url {
+        let mut url = self.inner.homeserver.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(path);
+        }
+        url
+    }
+
+    fn request(&self, method: Method, path: &[&str]) -> RequestBuilder {
+        self.inner
+            .http
+            .request(method, self.url(path))
+            .bearer_auth(&self.inner.as_token)
+            .query(&[("user_id", self.user_id.as_str())])
+    }
+
+    /// Register the user this client acts as; a user that already exists
+    /// is fine.
+    pub async fn ensure_registered(&self) -> Result<()> {
+        let response = self
+            .inner
+            .http
+            .post(self.url(&["_matrix", "client", "v3", "register"]))
+            .bearer_auth(&self.inner.as_token)
+            .json(&json!({
+                "type": "m.login.application_service",
+                "username": self.user_id.localpart(),
+                "inhibit_login": true,
+            }))
+            .send()
+            .await?;
+        if response.status() == StatusCode::BAD_REQUEST {
+            let body: ErrorBody = response.json().await?;
+            if body.errcode == "M_USER_IN_USE" {
+                return Ok(());
+            }
+            bail!(
+                "registering {}: {} {}",
+                self.user_id,
+                body.errcode,
+                body.error
+            );
+        }
+        check(response).await?;
+        Ok(())
+    }
+
+    /// Fetch the bot user's rooms from the homeserver.
+    pub async fn load_joined_rooms(&self) -> Result<()> {
+        #[derive(Deserialize)]
+        struct JoinedRooms {
+            joined_rooms: Vec<OwnedRoomId>,
+        }
+        let rooms: JoinedRooms = check(
+            self.as_user(self.inner.bot.clone())
+                .request(Method::GET, &["_matrix", "client", "v3", "joined_rooms"])
+                .send()
+                .await?,
+        )
+        .await?
+        .json()
+        .await?;
+        *self
+            .inner
+            .joined
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = rooms.joined_rooms.into_iter().collect();
+        Ok(())
+    }
+
+    pub async fn join(&self, room_id: &RoomId) -> Result<()> {
+        json(
+            self.request(
+                Method::POST,
+                &["_matrix", "client", "v3", "rooms", room_id.as_str(), "join"],
+            )
+            .json(&json!({})),
+        )
+        .await?;
+        if self.user_id == self.inner.bot {
+            self.set_joined(room_id, true);
+        }
+        Ok(())
+    }
+
+    /// Record that the bot user joined or left `room_id`.
+    pub fn set_joined(&self, room_id: &RoomId, joined: bool) {
+        let mut rooms = self
+            .inner
+            .joined
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if joined {
+            rooms.insert(room_id.to_owned());
+        } else {
+            rooms.remove(room_id);
+        }
+    }
+
+    pub fn room_handle(&self, room_id: &RoomId) -> AsRoom {
+        AsRoom {
+            client: self.clone(),
+            room_id: room_id.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl MatrixClient for AsClient {
+    fn user_id(&self) -> Option<OwnedUserId> {
+        Some(self.user_id.clone())
+    }
+
+    fn device_id(&self) -> Option<OwnedDeviceId> {
+        None
+    }
+
+    fn room(&self, room_id: &RoomId) -> Option<Arc<dyn MatrixRoom>> {
+        Some(Arc::new(self.room_handle(room_id)))
+    }
+
+    fn joined_rooms(&self) -> Vec<Arc<dyn MatrixRoom>> {
+        self.inner
+            .joined
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|room_id| Arc::new(self.room_handle(room_id)) as Arc<dyn MatrixRoom>)
+            .collect()
+    }
+
+    /// Events from virtual users count as the bot's own, so plugins do not
+    /// answer what they sent as one.
+    fn is_own_user(&self, user: &UserId) -> bool {
+        self.owns(user)
+    }
+
+    fn acting_as(&self, user: &UserId) -> Result<Arc<dyn MatrixClient>> {
+        if !self.owns(user) {
+            bail!("{user} is not in the appservice's namespace");
+        }
+        Ok(Arc::new(self.as_user(user.to_owned())))
+    }
+
+    async fn resolve_alias(&self, alias: &RoomAliasId) -> Result<OwnedRoomId> {
+        let response = json(self.request(
+            Method::GET,
+            &[
+                "_matrix",
+                "client",
+                "v3",
+                "directory",
+                "room",
+                alias.as_str(),
+            ],
+        ))
+        .await
+        .with_context(|| format!("resolving {alias}"))?;
+        Ok(serde_json::from_value(response["room_id"].clone())?)
+    }
+
+    async fn download(&self, source: &MediaSource) -> Result<Vec<u8>> {
+        let MediaSource::Plain(uri) = source else {
+            bail!("encrypted media is not supported in appservice mode");
+        };
+        let (server, media_id) = uri.parts()?;
+        let response = self
+            .request(
+                Method::GET,
+                &[
+                    "_matrix",
+                    "client",
+                    "v1",
+                    "media",
+                    "download",
+                    server.as_str(),
+                    media_id,
+                ],
+            )
+            .send()
+            .await?;
+        Ok(check(response).await?.bytes().await?.to_vec())
+    }
+
+    async fn encryption_status(&self) -> EncryptionStatus {
+        EncryptionStatus {
+            own_device_verified: None,
+            cross_signing: None,
+            recovery_state: "not used in appservice mode".to_owned(),
+            backup_state: "not used in appservice mode".to_owned(),
+            backup_upload: None,
+        }
+    }
+}
+
+/// A room as seen by an [`AsClient`]. Nothing is cached: every call is a
+/// request to the homeserver.
+#[derive(Debug, Clone)]
+pub struct AsRoom {
+    client: AsClient,
+    room_id: OwnedRoomId,
+}
+
+impl AsRoom {
+    fn path<'a>(&'a self, rest: &[&'a str]) -> Vec<&'a str> {
+        let mut path = vec!["_matrix", "client", "v3", "rooms", self.room_id.as_str()];
+        path.extend_from_slice(rest);
+        path
+    }
+
+    async fn send_event(&self, event_type: &str, content: Value) -> Result<OwnedEventId> {
+        let txn_id = TransactionId::new();
+        let response = json(
+            self.client
+                .request(
+                    Method::PUT,
+                    &self.path(&["send", event_type, txn_id.as_str()]),
+                )
+                .json(&content),
+        )
+        .await?;
+        Ok(serde_json::from_value(response["event_id"].clone())?)
+    }
+
+    /// A state event's content, or `None` if the room has no such state.
+    async fn state(&self, event_type: &str, state_key: &str) -> Result<Option<Value>> {
+        let response = self
+            .client
+            .request(Method::GET, &self.path(&["state", event_type, state_key]))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(check(response).await?.json().await?))
+    }
+}
+
+#[async_trait]
+impl MatrixRoom for AsRoom {
+    fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    fn aliases(&self) -> Vec<OwnedRoomAliasId> {
+        Vec::new()
+    }
+
+    fn last_prev_batch(&self) -> Option<String> {
+        None
+    }
+
+    async fn send(&self, content: RoomMessageEventContent) -> Result<OwnedEventId> {
+        self.send_event("m.room.message", serde_json::to_value(content)?)
+            .await
+    }
+
+    async fn react(&self, event_id: &EventId, key: &str) -> Result<OwnedEventId> {
+        let content =
+            ReactionEventContent::new(Annotation::new(event_id.to_owned(), key.to_owned()));
+        self.send_event("m.reaction", serde_json::to_value(content)?)
+            .await
+    }
+
+    async fn send_attachment(
+        &self,
+        body: &str,
+        mime: &Mime,
+        data: Vec<u8>,
+    ) -> Result<OwnedEventId> {
+        #[derive(Deserialize)]
+        struct Upload {
+            content_uri: OwnedMxcUri,
+        }
+        let size = UInt::new(data.len() as u64);
+        let upload: Upload = check(
+            self.client
+                .request(Method::POST, &["_matrix", "media", "v3", "upload"])
+                .query(&[("filename", body)])
+                .header(reqwest::header::CONTENT_TYPE, mime.as_ref())
+                .body(data)
+                .send()
+                .await?,
+        )
+        .await?
+        .json()
+        .await?;
+        let msgtype = if mime.type_() == mime::IMAGE {
+            let mut info = ImageInfo::new();
+            info.mimetype = Some(mime.to_string());
+            info.size = size;
+            MessageType::Image(
+                ImageMessageEventContent::plain(body.to_owned(), upload.content_uri)
+                    .info(Box::new(info)),
+            )
+        } else {
+            let mut info = FileInfo::new();
+            info.mimetype = Some(mime.to_string());
+            info.size = size;
+            MessageType::File(
+                FileMessageEventContent::plain(body.to_owned(), upload.content_uri)
+                    .info(Box::new(info)),
+            )
+        };
+        self.send(RoomMessageEventContent::new(msgtype)).await
+    }
+
+    async fn typing(&self, typing: bool) -> Result<()> {
+        let user_id = self.client.user_id.as_str();
+        json(
+            self.client
+                .request(Method::PUT, &self.path(&["typing", user_id]))
+                .json(&json!({ "typing": typing, "timeout": 30_000 })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn member(&self, user: &UserId) -> Result<Option<MemberInfo>> {
+        let Some(member) = self.state("m.room.member", user.as_str()).await? else {
+            return Ok(None);
+        };
+        if member["membership"] != "join" {
+            return Ok(None);
+        }
+        let power_levels = self
+            .state("m.room.power_levels", "")
+            .await?
+            .unwrap_or_default();
+        let power_level = power_levels["users"][user.as_str()]
+            .as_i64()
+            .or_else(|| power_levels["users_default"].as_i64())
+            .unwrap_or(0);
+        Ok(Some(MemberInfo {
+            display_name: member["displayname"].as_str().map(ToOwned::to_owned),
+            power_level,
+        }))
+    }
+
+    async fn is_encrypted(&self) -> bool {
+        self.state("m.room.encryption", "")
+            .await
+            .is_ok_and(|state| state.is_some())
+    }
+
+    async fn messages(&self, from: Option<String>, limit: u32) -> Result<MessagesPage> {
+        #[derive(Deserialize)]
+        struct Messages {
+            chunk: Vec<Raw<AnySyncTimelineEvent>>,
+            end: Option<String>,
+        }
+        let mut request = self
+            .client
+            .request(Method::GET, &self.path(&["messages"]))
+            .query(&[("dir", "b"), ("limit", &limit.to_string())]);
+        if let Some(from) = &from {
+            request = request.query(&[("from", from)]);
+        }
+        let messages: Messages = check(request.send().await?).await?.json().await?;
+        Ok(MessagesPage {
+            events: messages.chunk,
+            end: messages.end,
+        })
+    }
+}
@@ -0,0 +1,432 @@
+//! `--check-config`: load `config.yaml` and the plugin config files the way
+//! startup does, check every plugin's config against the schema it
+//! declares, and report each problem with its file and line. Nothing is
+//! logged in to and no external plugin is started.
+
+use std::{collections::HashMap, fmt, path::PathBuf, sync::Arc};
+
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use plugin_core::{Plugin, PluginContext, PluginSpec, PluginTriggers, check_spec};
+use plugin_relay::Relay;
+use regex::RegexSet;
+use serde::Deserialize as _;
+use serde_path_to_error::Segment;
+use yaml_rust2::parser::{Event, Parser};
+
+use crate::{
+    Args, BotConfig, accounts, interpolate,
+    plugins::{PluginMap, plugin_config_path, plugin_instances, plugins_dir, resolve_plugins},
+};
+
+/// Check the config named by `args` and print what is wrong with it.
+/// Fails if anything is.
+pub fn run(args: &Args) -> Result<()> {
+    let problems = check(args)?;
+    if problems.is_empty() {
+        println!("{}: OK", args.config.display());
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{problem}");
+    }
+    bail!("found {} problem(s) in the config", problems.len());
+}
+
+/// One problem, at a line of a file when that is known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Problem {
+    file: PathBuf,
+    line: Option<usize>,
+    message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{line}: {}", self.file.display(), self.message),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+/// A YAML file and the line each key or sequence item starts on.
+#[derive(Debug)]
+struct Source {
+    path: PathBuf,
+    lines: LineMap,
+}
+
+impl Source {
+    fn problem(&self, path: &[String], message: impl Into<String>) -> Problem {
+        Problem {
+            file: self.path.clone(),
+            line: self.lines.nearest(path),
+            message: message.into(),
+        }
+    }
+}
+
+fn check(args: &Args) -> Result<Vec<Problem>> {
+    let path = &args.config;
+    if !path.exists() {
+        bail!("config file not found at {}", path.display());
+    }
+    let yaml = std::fs::read_to_string(path)?;
+    let source = Source {
+        path: path.clone(),
+        lines: LineMap::parse(&yaml),
+    };
+    let mut problems = Vec::new();
+
+    let mut value: serde_yaml::Value = match serde_yaml::from_str(&yaml) {
+        Ok(value) => value,
+        Err(e) => {
+            problems.push(Problem {
+                file: path.clone(),
+                line: e.location().map(|l| l.line()),
+                message: e.to_string(),
+            });
+            return Ok(problems);
+        }
+    };
+    for error in interpolate::resolve(&mut value) {
+        problems.push(source.problem(&error.path, error.to_string()));
+    }
+
+    let mut ignored = Vec::new();
+    let mut track = serde_path_to_error::Track::new();
+    let parsed = BotConfig::deserialize(serde_ignored::Deserializer::new(
+        serde_path_to_error::Deserializer::new(value, &mut track),
+        &mut |key: serde_ignored::Path<'_>| ignored.push(segments(&key)),
+    ));
+    let config = match parsed {
+        Ok(config) => config,
+        Err(e) => {
+            let at: Vec<String> = track
+                .path()
+                .iter()
+                .filter_map(|segment| match segment {
+                    Segment::Seq { index } => Some(index.to_string()),
+                    Segment::Map { key } => Some(key.clone()),
+                    Segment::Enum { .. } | Segment::Unknown => None,
+                })
+                .collect();
+            let message = if at.is_empty() {
+                e.to_string()
+            } else {
+                format!("{}: {e}", at.join("."))
+            };
+            problems.push(source.problem(&at, message));
+            return Ok(problems);
+        }
+    };
+    for key in ignored {
+        let message = format!("unknown key `{}`", key.join("."));
+        problems.push(source.problem(&key, message));
+    }
+
+    // The same plugins startup registers, with stand-ins for the external
+    // ones so their processes are not started.
+    let mut plugins = plugin_instances(&Arc::new(Relay::default()), &Arc::default());
+    for (idx, external) in config.external_plugins.iter().enumerate() {
+        let at = [
+            "external_plugins".to_owned(),
+            idx.to_string(),
+            "id".to_owned(),
+        ];
+        if plugins.contains_key(external.id.as_str()) {
+            let message = format!("external plugin ID `{}` is already taken", external.id);
+            problems.push(source.problem(&at, message));
+            continue;
+        }
+        let id: &'static str = Box::leak(external.id.clone().into_boxed_str());
+        plugins.insert(id, Arc::new(External(id)));
+    }
+
+    check_accounts(args, &config, &plugins, &source, &mut problems);
+    check_plugins(&config, &plugins, &source, &mut problems);
+    Ok(problems)
+}
+
+fn check_accounts(
+    args: &Args,
+    config: &BotConfig,
+    plugins: &PluginMap,
+    source: &Source,
+    problems: &mut Vec<Problem>,
+) {
+    if let Err(e) = accounts::resolve(args, config) {
+        problems.push(source.problem(&["accounts".to_owned()], format!("{e:#}")));
+    }
+    for (idx, account) in config.accounts.iter().enumerate() {
+        let at = |key: &str| vec!["accounts".to_owned(), idx.to_string(), key.to_owned()];
+        for (item, id) in account.plugins.iter().flatten().enumerate() {
+            if !plugins.contains_key(id.as_str()) {
+                let mut path = at("plugins");
+                path.push(item.to_string());
+                problems.push(source.problem(&path, format!("unknown plugin ID `{id}`")));
+            }
+        }
+        if let Some(appservice) = &account.appservice
+            && let Err(e) = RegexSet::new(&appservice.users)
+        {
+            let mut path = at("appservice");
+            path.push("users".to_owned());
+            problems.push(source.problem(&path, format!("invalid user namespace: {e}")));
+        }
+    }
+}
+
+fn check_plugins(
+    config: &BotConfig,
+    plugins: &PluginMap,
+    source: &Source,
+    problems: &mut Vec<Problem>,
+) {
+    // Specs may sit under `tools:`, the old name for `plugins:`.
+    let list = if source.lines.exact(&["tools".to_owned()]).is_some() {
+        "tools"
+    } else {
+        "plugins"
+    };
+    let specs = config.plugins.as_deref().unwrap_or_default();
+    let spec_path = |id: &str| {
+        specs
+            .iter()
+            .position(|spec| spec.id == id)
+            .map(|idx| vec![list.to_owned(), idx.to_string()])
+    };
+
+    let resolved = resolve_plugins(config, plugins, None);
+    for id in &resolved.unknown_ids {
+        let mut path = spec_path(id).unwrap_or_default();
+        path.push("id".to_owned());
+        problems.push(source.problem(&path, format!("unknown plugin ID `{id}`")));
+    }
+    for problem in &resolved.problems {
+        problems.push(Problem {
+            file: source.path.clone(),
+            line: None,
+            message: problem.clone(),
+        });
+    }
+
+    let root = plugins_dir();
+    for (spec, plugin) in &resolved.entries {
+        let errors = check_spec(spec, plugin.config_schema());
+        if errors.is_empty() {
+            continue;
+        }
+        let file_path = plugin_config_path(&root, &spec.id);
+        let file = std::fs::read_to_string(&file_path).ok().map(|yaml| Source {
+            path: file_path,
+            lines: LineMap::parse(&yaml),
+        });
+        let in_config = spec_path(&spec.id).unwrap_or_default();
+        for error in errors {
+            let message = format!("plugin {}: {error}", spec.id);
+            let config_key: Vec<String> = in_config.iter().chain(&error.path).cloned().collect();
+            // Keys merged from both files are reported where they were written.
+            let in_file = file.as_ref().filter(|file| {
+                file.lines.exact(&error.path).is_some() || source.lines.exact(&config_key).is_none()
+            });
+            let problem = match in_file {
+                Some(file) => file.problem(&error.path, message),
+                None => source.problem(&config_key, message),
+            };
+            problems.push(problem);
+        }
+    }
+}
+
+/// Stand-in for an external plugin, which only declares its ID here.
+#[derive(Debug)]
+struct External(&'static str);
+
+#[async_trait]
+impl Plugin for External {
+    fn id(&self) -> &'static str {
+        self.0
+    }
+    fn help(&self) -> &'static str {
+        ""
+    }
+    fn spec(&self) -> PluginSpec {
+        PluginSpec {
+            id: self.0.to_owned(),
+            enabled: true,
+            dev_only: None,
+            triggers: PluginTriggers::default(),
+            config: serde_yaml::Value::default(),
+        }
+    }
+    async fn run(&self, _ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
+        bail!(
+            "external plugin {} is not started by --check-config",
+            self.0
+        )
+    }
+}
+
+fn segments(path: &serde_ignored::Path<'_>) -> Vec<String> {
+    use serde_ignored::Path;
+    let mut segments = match path {
+        Path::Root => return Vec::new(),
+        Path::Seq { parent, .. }
+        | Path::Map { parent, .. }
+        | Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => segments(parent),
+    };
+    if let Path::Seq { index, .. } = path {
+        segments.push(index.to_string());
+    } else if let Path::Map { key, .. } = path {
+        segments.push(key.clone());
+    }
+    segments
+}
+
+/// The line each mapping key and sequence item starts on, keyed by its path
+/// from the document root, with sequence indices as decimal strings.
+#[derive(Debug, Default)]
+struct LineMap(HashMap<Vec<String>, usize>);
+
+enum Frame {
+    /// The key whose value is being read, if any.
+    Mapping(Option<String>),
+    /// The index of the next item.
+    Sequence(usize),
+}
+
+impl LineMap {
+    /// Parse errors leave the rest of the map empty; serde reports them
+    /// with their own line numbers.
+    fn parse(yaml: &str) -> Self {
+        let mut map = HashMap::new();
+        let mut path: Vec<String> = Vec::new();
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut parser = Parser::new_from_str(yaml);
+        while let Ok((event, mark)) = parser.next_token() {
+            if matches!(event, Event::StreamEnd) {
+                break;
+            }
+            // A scalar where a mapping expects a key is the key itself.
+            if let (Event::Scalar(key, ..), Some(Frame::Mapping(current @ None))) =
+                (&event, frames.last_mut())
+            {
+                path.push(key.clone());
+                map.entry(path.clone()).or_insert_with(|| mark.line());
+                *current = Some(key.clone());
+                continue;
+            }
+            let starts_node = matches!(
+                event,
+                Event::Scalar(..)
+                    | Event::Alias(_)
+                    | Event::MappingStart(..)
+                    | Event::SequenceStart(..)
+            );
+            if starts_node && let Some(Frame::Sequence(index)) = frames.last() {
+                path.push(index.to_string());
+                map.entry(path.clone()).or_insert_with(|| mark.line());
+            }
+            let ends_node = if let Event::MappingStart(..) = event {
+                frames.push(Frame::Mapping(None));
+                false
+            } else if let Event::SequenceStart(..) = event {
+                frames.push(Frame::Sequence(0));
+                false
+            } else if let Event::MappingEnd | Event::SequenceEnd = event {
+                frames.pop();
+                true
+            } else {
+                starts_node
+            };
+            if !ends_node {
+                continue;
+            }
+            match frames.last_mut() {
+                Some(Frame::Mapping(current @ Some(_))) => {
+                    *current = None;
+                    path.pop();
+                }
+                Some(Frame::Sequence(index)) => {
+                    *index += 1;
+                    path.pop();
+                }
+                Some(Frame::Mapping(None)) | None => {}
+            }
+        }
+        Self(map)
+    }
+
+    fn exact(&self, path: &[String]) -> Option<usize> {
+        self.0.get(path).copied()
+    }
+
+    /// The line of `path`, or of its closest ancestor that is in the file.
+    fn nearest(&self, path: &[String]) -> Option<usize> {
+        (1..=path.len())
+            .rev()
+            .find_map(|len| self.exact(&path[..len]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    #[test]
+    fn reports_problems_at_their_lines() {
+        let path = std::env::temp_dir().join(format!("check-config-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            r"
+clusters: []
+dev_mod: true
+plugins:
+  - id: echo
+    prefix: '> '
+    uppercase: yes please
+  - id: ai
+    mcp_servers:
+      time:
+        args: [run, --mcp-server, time]
+        comand: cargo
+  - id: ecko
+",
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "bot",
+            "--config",
+            path.to_str().unwrap(),
+            "--homeserver",
+            "https://hs.example.org",
+            "--username",
+            "bot",
+        ]);
+        let problems = check(&args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let found: Vec<(Option<usize>, &str)> = problems
+            .iter()
+            .map(|p| (p.line, p.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Some(3), "unknown key `dev_mod`"),
+                (Some(13), "unknown plugin ID `ecko`"),
+                (Some(7), "plugin echo: uppercase: expected true or false"),
+                (
+                    Some(12),
+                    "plugin ai: mcp_servers.time.comand: unknown key `comand`; did you mean `command`?"
+                ),
+            ]
+        );
+    }
+}
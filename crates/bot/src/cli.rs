@@ -0,0 +1,314 @@
+//! Subcommands for one-off account and room administration. They use the
+//! same store and session file as the bot, so stop the bot first: two
+//! clients writing one store would corrupt its encryption state.
+//!
+//! ```text
+//! matrix-ping-bot login                 # e.g. to provision a Docker volume
+//! matrix-ping-bot send '#ops:example.org' 'deploy finished'
+//! matrix-ping-bot --account relay rooms
+//! ```
+
+use core::time::Duration;
+use std::{fs, io, path::Path};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use clap::Subcommand;
+use matrix_sdk::{
+    Client,
+    config::SyncSettings,
+    ruma::{
+        OwnedRoomId, RoomAliasId, RoomId, RoomOrAliasId,
+        events::room::message::RoomMessageEventContent,
+    },
+};
+use plugin_core::{MatrixClient as _, SdkClient};
+
+use crate::{
+    Args, BotConfig,
+    accounts::{self, Account},
+    build_client, load_config, login, restore_session,
+};
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Log in, prompting for the password if needed, save the session file
+    /// and exit
+    Login,
+    /// Invalidate the session's access token and remove the session file
+    Logout {
+        /// Also delete the matrix-sdk crypto, state and event cache
+        /// databases; they hold the logged-out device's keys, which a new
+        /// login cannot use. Plugin state, overrides and other files in the
+        /// store directory are kept
+        #[arg(long)]
+        delete_store: bool,
+    },
+    /// Show the user and device of the saved session
+    Whoami,
+    /// List joined rooms with their aliases and encryption state
+    Rooms,
+    /// Send a plain-text message to a joined room
+    Send {
+        /// Room ID or alias
+        room: String,
+        text: String,
+    },
+    /// Join a room
+    Join {
+        /// Room ID or alias
+        room: String,
+    },
+    /// Leave a room
+    Leave {
+        /// Room ID or alias
+        room: String,
+    },
+}
+
+/// Run `command` for the account `args` selects, then return.
+pub async fn run(args: &Args, command: &Command) -> Result<()> {
+    // A volume can be provisioned before there is a config file.
+    let config = if args.config.exists() {
+        load_config(&args.config)?
+    } else {
+        BotConfig::default()
+    };
+    let account = select(args, accounts::resolve(args, &config)?)?;
+    if account.appservice.is_some() {
+        bail!(
+            "account {} runs as an appservice and has no session to use",
+            account.name
+        );
+    }
+    fs::create_dir_all(&account.store)
+        .with_context(|| format!("creating store directory at {}", account.store.display()))?;
+
+    match command {
+        Command::Login => {
+            let client = login(&account).await?;
+            let whoami = client.whoami().await.context("checking the session")?;
+            println!(
+                "{} {} (session in {})",
+                whoami.user_id,
+                whoami.device_id.map(|d| d.to_string()).unwrap_or_default(),
+                account.session_file.display()
+            );
+        }
+        Command::Logout { delete_store } => {
+            let client = session(&account).await?;
+            client.matrix_auth().logout().await.context("logging out")?;
+            fs::remove_file(&account.session_file)
+                .with_context(|| format!("removing {}", account.session_file.display()))?;
+            if *delete_store {
+                drop(client);
+                delete_sdk_store(&account.store)?;
+            }
+            println!("Logged out {}", account.username);
+        }
+        Command::Whoami => {
+            let client = session(&account).await?;
+            let whoami = client.whoami().await.context("checking the session")?;
+            println!(
+                "{} {}",
+                whoami.user_id,
+                whoami.device_id.map(|d| d.to_string()).unwrap_or_default()
+            );
+        }
+        Command::Rooms => {
+            let client = SdkClient(synced(&account).await?);
+            let mut rooms = client.joined_rooms();
+            rooms.sort_by(|a, b| a.room_id().cmp(b.room_id()));
+            for room in rooms {
+                let aliases: Vec<String> = room.aliases().iter().map(ToString::to_string).collect();
+                let encryption = if room.is_encrypted().await {
+                    "encrypted"
+                } else {
+                    "unencrypted"
+                };
+                println!("{}\t{encryption}\t{}", room.room_id(), aliases.join(","));
+            }
+        }
+        Command::Send { room, text } => {
+            let client = synced(&account).await?;
+            let room_id = room_id(&client, room).await?;
+            let room = SdkClient(client)
+                .room(&room_id)
+                .ok_or_else(|| anyhow!("not joined to {room_id}"))?;
+            let event_id = room
+                .send(RoomMessageEventContent::text_plain(text))
+                .await
+                .context("sending the message")?;
+            println!("{event_id}");
+        }
+        Command::Join { room } => {
+            let client = session(&account).await?;
+            let target =
+                RoomOrAliasId::parse(room).with_context(|| format!("invalid room {room}"))?;
+            let joined = client
+                .join_room_by_id_or_alias(&target, &[])
+                .await
+                .with_context(|| format!("joining {room}"))?;
+            println!("{}", joined.room_id());
+        }
+        Command::Leave { room } => {
+            let client = synced(&account).await?;
+            let room_id = room_id(&client, room).await?;
+            client
+                .get_room(&room_id)
+                .ok_or_else(|| anyhow!("not joined to {room_id}"))?
+                .leave()
+                .await
+                .with_context(|| format!("leaving {room_id}"))?;
+            println!("Left {room_id}");
+        }
+    }
+    Ok(())
+}
+
+/// The account named by `--account`, or the only one.
+fn select(args: &Args, accounts: Vec<Account>) -> Result<Account> {
+    let names = || {
+        accounts
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match &args.account {
+        Some(name) => accounts
+            .iter()
+            .find(|a| &a.name == name)
+            .cloned()
+            .ok_or_else(|| anyhow!("no account named {name}; the config has {}", names())),
+        None if accounts.len() == 1 => Ok(accounts.into_iter().next().expect("one account")),
+        None => bail!(
+            "the config lists several accounts; pick one with --account: {}",
+            names()
+        ),
+    }
+}
+
+/// A client logged in with the saved session; never prompts for a password.
+async fn session(account: &Account) -> Result<Client> {
+    let client = build_client(account).await?;
+    if !restore_session(&client, account).await? {
+        bail!(
+            "no session at {}; run the login subcommand first",
+            account.session_file.display()
+        );
+    }
+    Ok(client)
+}
+
+/// A logged-in client whose store has caught up with the server, so it knows
+/// the joined rooms and can encrypt for their members.
+async fn synced(account: &Account) -> Result<Client> {
+    let client = session(account).await?;
+    client
+        .sync_once(SyncSettings::default().timeout(Duration::ZERO))
+        .await
+        .context("syncing")?;
+    Ok(client)
+}
+
+async fn room_id(client: &Client, room: &str) -> Result<OwnedRoomId> {
+    if room.starts_with('#') {
+        let alias = RoomAliasId::parse(room).with_context(|| format!("invalid alias {room}"))?;
+        let response = client
+            .resolve_room_alias(&alias)
+            .await
+            .with_context(|| format!("resolving {room}"))?;
+        Ok(response.room_id)
+    } else {
+        RoomId::parse(room).with_context(|| format!("invalid room ID {room}"))
+    }
+}
+
+/// The databases matrix-sdk keeps in the store directory; the rest of it
+/// belongs to the bot and its plugins.
+const SDK_DATABASES: [&str; 3] = [
+    "matrix-sdk-crypto.sqlite3",
+    "matrix-sdk-state.sqlite3",
+    "matrix-sdk-event-cache.sqlite3",
+];
+
+/// Remove the matrix-sdk databases from `store`, with their journal
+/// files.
+fn delete_sdk_store(store: &Path) -> Result<()> {
+    for database in SDK_DATABASES {
+        for suffix in ["", "-wal", "-shm", "-journal"] {
+            let path = store.join(format!("{database}{suffix}"));
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("removing {}", path.display()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    #[test]
+    fn parses_subcommands_and_picks_the_account() {
+        let args = Args::parse_from([
+            "bot",
+            "--account",
+            "relay",
+            "send",
+            "#ops:example.org",
+            "deploy finished",
+        ]);
+        assert!(matches!(
+            &args.command,
+            Some(Command::Send { room, text }) if room == "#ops:example.org" && text == "deploy finished"
+        ));
+
+        let config: BotConfig = serde_yaml::from_str(
+            r"
+clusters: []
+accounts:
+  - { name: persona, homeserver: https://a.example.org, username: persona, store: ./a }
+  - { name: relay, homeserver: https://a.example.org, username: relay, store: ./b }
+",
+        )
+        .unwrap();
+        let accounts = accounts::resolve(&args, &config).unwrap();
+        assert_eq!(select(&args, accounts.clone()).unwrap().username, "relay");
+
+        let unnamed = Args::parse_from(["bot", "rooms"]);
+        let err = select(&unnamed, accounts).unwrap_err().to_string();
+        assert!(err.contains("persona, relay"), "{err}");
+    }
+
+    #[test]
+    fn delete_store_keeps_bot_files() {
+        let store = std::env::temp_dir().join(format!("cli-store-{}", std::process::id()));
+        fs::create_dir_all(&store).unwrap();
+        let sdk = [
+            "matrix-sdk-crypto.sqlite3",
+            "matrix-sdk-crypto.sqlite3-wal",
+            "matrix-sdk-state.sqlite3",
+        ];
+        let kept = ["plugin-state.sqlite3", "registry.json", "session.json"];
+        for name in sdk.iter().chain(&kept) {
+            fs::write(store.join(name), "").unwrap();
+        }
+
+        delete_sdk_store(&store).unwrap();
+        let mut left: Vec<String> = fs::read_dir(&store)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        fs::remove_dir_all(&store).unwrap();
+        assert_eq!(left, kept);
+    }
+}
@@ -0,0 +1,441 @@
+use core::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::Result;
+use futures_util::future::{BoxFuture, join_all};
+use matrix_sdk::ruma::{
+    OwnedRoomId,
+    events::room::message::{MessageType, OriginalSyncRoomMessageEvent},
+};
+use plugin_core::{
+    MatrixClient, PluginContext, PluginEntry, PluginRegistry, RoomMessageMeta, send_text,
+};
+use tokio::{
+    sync::mpsc,
+    task::{AbortHandle, JoinHandle},
+    time::Instant,
+};
+use tracing::{info, warn};
+
+use crate::events::QueuedRoomEvent;
+
+/// A supervised `on_room_message` or `on_room_event` call waiting its turn.
+type Job = BoxFuture<'static, ()>;
+
+type QueueKey = (OwnedRoomId, String);
+type Queues = Arc<Mutex<HashMap<QueueKey, Queue>>>;
+
+/// Runs `on_room_message` and `on_room_event` hooks off the sync loop, each
+/// in its own task limited by the plugin's `timeout_secs`.
+#[derive(Debug, Default)]
+pub struct Dispatcher {
+    /// One queue per room and passive plugin, so a plugin sees each room's
+    /// messages in order while plugins do not wait for one another. A queue
+    /// is removed once it runs empty.
+    queues: Queues,
+}
+
+#[derive(Debug)]
+struct Queue {
+    tx: mpsc::UnboundedSender<Job>,
+    worker: JoinHandle<()>,
+}
+
+impl Dispatcher {
+    /// Queue `entry`'s `on_room_message` for `event`. Passive work is not
+    /// cancellable, so `!cancel` never drops a relayed message, and problems
+    /// are only logged.
+    pub fn queue_room_message(
+        &self,
+        ctx: PluginContext,
+        entry: PluginEntry,
+        event: Arc<OriginalSyncRoomMessageEvent>,
+        triggered: Arc<HashSet<String>>,
+    ) {
+        let room = ctx.room.room_id().to_owned();
+        let plugin_id = entry.spec.id.clone();
+        let job: Job = Box::pin(async move {
+            let plugin_id = entry.spec.id.clone();
+            let timeout = entry.spec.invocation_timeout();
+            let handle = tokio::spawn(with_timeout(timeout, async move {
+                let meta = RoomMessageMeta {
+                    body: message_body(&event),
+                    triggered_plugins: &triggered,
+                };
+                entry
+                    .plugin
+                    .on_room_message(&ctx, &event, &entry.spec, &meta)
+                    .await
+            }));
+            supervise(handle, timeout, &plugin_id, "on_room_message").await;
+        });
+        self.enqueue((room, plugin_id), job);
+    }
+
+    /// Queue `entry`'s `on_room_event` for `event`, in the same per-room
+    /// queue as the plugin's messages, so an edit never overtakes the message
+    /// it edits.
+    pub fn queue_room_event(&self, ctx: PluginContext, entry: PluginEntry, event: QueuedRoomEvent) {
+        let room = ctx.room.room_id().to_owned();
+        let plugin_id = entry.spec.id.clone();
+        let job: Job = Box::pin(async move {
+            let plugin_id = entry.spec.id.clone();
+            let timeout = entry.spec.invocation_timeout();
+            let handle = tokio::spawn(with_timeout(timeout, async move {
+                entry
+                    .plugin
+                    .on_room_event(&ctx, &event.as_event(), &entry.spec)
+                    .await
+            }));
+            supervise(handle, timeout, &plugin_id, "on_room_event").await;
+        });
+        self.enqueue((room, plugin_id), job);
+    }
+
+    fn enqueue(&self, key: QueueKey, job: Job) {
+        let mut queues = self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+        let queue = queues
+            .entry(key.clone())
+            .or_insert_with(|| spawn_queue_worker(Arc::clone(&self.queues), key.clone()));
+        if let Err(mpsc::error::SendError(job)) = queue.tx.send(job) {
+            // The worker only stops if its task was torn down; start another.
+            *queue = spawn_queue_worker(Arc::clone(&self.queues), key);
+            let _ = queue.tx.send(job);
+        }
+        drop(queues);
+    }
+
+    /// Stop taking passive work and give queued and running work, passive
+    /// or not, up to `grace` to finish before aborting it. Rooms where a
+    /// plugin was cut off get their typing indicator turned off.
+    pub async fn drain(
+        &self,
+        registry: &PluginRegistry,
+        client: &dyn MatrixClient,
+        grace: Duration,
+    ) {
+        // Dropping the senders lets each worker stop once its queue is empty.
+        let workers: Vec<_> = self
+            .queues
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain()
+            .map(|(_, queue)| queue.worker)
+            .collect();
+        let aborts: Vec<_> = workers.iter().map(JoinHandle::abort_handle).collect();
+        let passive = async {
+            if tokio::time::timeout(grace, join_all(workers))
+                .await
+                .is_err()
+            {
+                warn!("Passive plugin work did not finish in time; aborting it");
+                aborts.iter().for_each(AbortHandle::abort);
+            }
+        };
+        let ((), aborted) = tokio::join!(passive, registry.invocations().drain(grace));
+        if !aborted.is_empty() {
+            warn!(
+                rooms = aborted.len(),
+                "Aborted plugin runs that did not finish in time"
+            );
+        }
+        for room_id in aborted {
+            if let Some(room) = client.room(&room_id) {
+                let _ = room.typing(false).await;
+            }
+        }
+    }
+}
+
+/// What made a plugin run.
+#[derive(Debug, Clone, Copy)]
+pub enum Trigger {
+    Command,
+    Mention,
+}
+
+impl Trigger {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Command => "command",
+            Self::Mention => "mention",
+        }
+    }
+}
+
+/// Start `entry`'s `run` for a command or mention, tracked in the
+/// registry's [`Invocations`](plugin_core::Invocations) so `!cancel` can
+/// stop it. Timeouts and panics are reported in the room. The returned
+/// task ends once the run is over and reported.
+pub fn spawn_run(
+    ctx: PluginContext,
+    entry: PluginEntry,
+    args: String,
+    trigger: Trigger,
+) -> JoinHandle<()> {
+    let plugin_id = entry.spec.id.clone();
+    metrics::counter!(
+        "matrix_bot_plugin_invocations_total",
+        "plugin" => plugin_id.clone(),
+        "trigger" => trigger.as_str(),
+    )
+    .increment(1);
+    let timeout = entry.spec.invocation_timeout();
+    let task_ctx = ctx.clone();
+    let handle =
+        ctx.registry
+            .invocations()
+            .spawn(ctx.room.room_id(), &plugin_id, timeout, async move {
+                entry.plugin.run(&task_ctx, &args, &entry.spec).await
+            });
+    tokio::spawn(async move {
+        if let Some(problem) = supervise(handle, timeout, &plugin_id, "run").await {
+            let _ = send_text(&ctx, format!("⚠️ {plugin_id} {problem}")).await;
+        }
+    })
+}
+
+/// Start the worker for `key`'s queue. Once the queue runs empty the worker
+/// removes it from `queues` and stops, so rooms and plugins that go quiet
+/// do not keep a task each.
+fn spawn_queue_worker(queues: Queues, key: QueueKey) -> Queue {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+    let worker = tokio::spawn(async move {
+        while let Some(job) = rx.recv().await {
+            job.await;
+            if !rx.is_empty() {
+                continue;
+            }
+            // Jobs are sent with the lock held, so none can slip in between
+            // this check and the removal.
+            let mut queues = queues.lock().unwrap_or_else(PoisonError::into_inner);
+            let ours = queues
+                .get(&key)
+                .is_some_and(|queue| queue.worker.id() == tokio::task::id());
+            if rx.is_empty() && ours {
+                queues.remove(&key);
+                return;
+            }
+            drop(queues);
+        }
+    });
+    Queue { tx, worker }
+}
+
+async fn with_timeout<F: Future>(timeout: Option<Duration>, fut: F) -> Option<F::Output> {
+    match timeout {
+        Some(limit) => tokio::time::timeout(limit, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+const fn message_body(event: &OriginalSyncRoomMessageEvent) -> Option<&str> {
+    match &event.content.msgtype {
+        MessageType::Text(t) => Some(t.body.as_str()),
+        MessageType::Notice(n) => Some(n.body.as_str()),
+        MessageType::Audio(_)
+        | MessageType::Emote(_)
+        | MessageType::File(_)
+        | MessageType::Image(_)
+        | MessageType::Location(_)
+        | MessageType::ServerNotice(_)
+        | MessageType::Video(_)
+        | MessageType::VerificationRequest(_)
+        | _ => None,
+    }
+}
+
+/// Wait for a plugin task, recording its latency and any failure. Returns
+/// what to tell the room if it timed out or panicked.
+async fn supervise(
+    handle: JoinHandle<Option<Result<()>>>,
+    timeout: Option<Duration>,
+    plugin_id: &str,
+    hook: &'static str,
+) -> Option<String> {
+    let started = Instant::now();
+    let outcome = handle.await;
+    metrics::histogram!(
+        "matrix_bot_plugin_invocation_duration_seconds",
+        "plugin" => plugin_id.to_owned(),
+        "hook" => hook,
+    )
+    .record(started.elapsed());
+    let (kind, problem) = match outcome {
+        Ok(Some(Ok(()))) => return None,
+        Ok(Some(Err(e))) => {
+            warn!(error = %e, plugin = %plugin_id, hook, "Plugin failed");
+            ("error", None)
+        }
+        Ok(None) => {
+            let limit = timeout.unwrap_or_default();
+            warn!(plugin = %plugin_id, hook, timeout_secs = limit.as_secs_f64(), "Plugin timed out");
+            ("timeout", Some(format!("timed out after {limit:?}")))
+        }
+        Err(e) if e.is_cancelled() => {
+            info!(plugin = %plugin_id, hook, "Plugin invocation cancelled");
+            return None;
+        }
+        Err(e) => {
+            warn!(error = %e, plugin = %plugin_id, hook, "Plugin panicked");
+            ("panic", Some("crashed".to_owned()))
+        }
+    };
+    metrics::counter!(
+        "matrix_bot_plugin_errors_total",
+        "plugin" => plugin_id.to_owned(),
+        "hook" => hook,
+        "kind" => kind,
+    )
+    .increment(1);
+    problem
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use matrix_sdk::ruma::{events::room::message::RoomMessageEventContent, room_id, user_id};
+    use plugin_core::{
+        Plugin, PluginSpec, PluginTriggers,
+        testing::{FakeClient, message_event},
+    };
+
+    use super::*;
+
+    /// `run` panics on "panic" and otherwise sleeps for `args` milliseconds.
+    #[derive(Debug)]
+    struct Sleepy;
+
+    #[async_trait]
+    impl Plugin for Sleepy {
+        fn id(&self) -> &'static str {
+            "sleepy"
+        }
+        fn help(&self) -> &'static str {
+            ""
+        }
+        fn spec(&self) -> PluginSpec {
+            PluginSpec {
+                id: "sleepy".to_owned(),
+                enabled: true,
+                dev_only: None,
+                triggers: PluginTriggers::default(),
+                config: serde_yaml::from_str("timeout_secs: 0.05").unwrap(),
+            }
+        }
+        async fn run(&self, ctx: &PluginContext, args: &str, _spec: &PluginSpec) -> Result<()> {
+            assert!(args != "panic", "asked to panic");
+            tokio::time::sleep(Duration::from_millis(args.parse()?)).await;
+            send_text(ctx, "done").await
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_are_isolated_timed_out_and_cancellable() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        let entry = PluginEntry {
+            spec: Sleepy.spec(),
+            plugin: Arc::new(Sleepy),
+        };
+        // Without `timeout_secs` a run gets the default two minutes.
+        let mut patient = entry.clone();
+        patient.spec.config = serde_yaml::Value::default();
+
+        let runs = [
+            spawn_run(
+                ctx.clone(),
+                entry.clone(),
+                "panic".to_owned(),
+                Trigger::Command,
+            ),
+            spawn_run(
+                ctx.clone(),
+                entry.clone(),
+                "3600000".to_owned(),
+                Trigger::Command,
+            ),
+            spawn_run(
+                ctx.clone(),
+                patient.clone(),
+                "0".to_owned(),
+                Trigger::Mention,
+            ),
+        ];
+        for run in runs {
+            run.await.unwrap();
+        }
+        let mut sent = room.sent_bodies();
+        sent.sort();
+        assert_eq!(
+            sent,
+            [
+                "done",
+                "⚠️ sleepy crashed",
+                "⚠️ sleepy timed out after 50ms"
+            ]
+        );
+
+        // Tracked as soon as it is spawned, so it can be cancelled at once.
+        let slow = spawn_run(ctx.clone(), patient, "3600000".to_owned(), Trigger::Command);
+        let stopped = ctx.registry.invocations().cancel(ctx.room.room_id(), None);
+        assert_eq!(stopped, ["sleepy"]);
+        slow.await.unwrap();
+        assert!(ctx.registry.invocations().is_empty());
+        assert_eq!(room.sent_bodies().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn idle_queues_are_removed() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        let entry = PluginEntry {
+            spec: Sleepy.spec(),
+            plugin: Arc::new(Sleepy),
+        };
+        let event = Arc::new(message_event(
+            user_id!("@alice:example.org"),
+            &RoomMessageEventContent::text_plain("hi"),
+        ));
+        let dispatcher = Dispatcher::default();
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+
+        dispatcher.queue_room_message(
+            ctx.clone(),
+            entry.clone(),
+            Arc::clone(&event),
+            Arc::default(),
+        );
+        assert_eq!(dispatcher.queues.lock().unwrap().len(), 1);
+        // Queued behind the message above; once it has run, the queue is
+        // empty and its worker removes it.
+        let worker = {
+            let queues = dispatcher.queues.lock().unwrap();
+            let queue = queues.values().next().unwrap();
+            let _ = queue.tx.send(Box::pin(async move {
+                let _ = done_tx.send(());
+            }));
+            let worker = queue.worker.abort_handle();
+            drop(queues);
+            worker
+        };
+        done_rx.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !worker.is_finished() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("queue worker did not stop");
+        assert!(dispatcher.queues.lock().unwrap().is_empty());
+
+        // A later message gets a fresh queue.
+        dispatcher.queue_room_message(ctx, entry, event, Arc::default());
+        assert_eq!(dispatcher.queues.lock().unwrap().len(), 1);
+    }
+}
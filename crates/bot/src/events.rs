@@ -0,0 +1,312 @@
+use std::{path::PathBuf, sync::Arc};
+
+use matrix_sdk::{
+    Client,
+    room::Room,
+    ruma::events::{
+        reaction::OriginalSyncReactionEvent,
+        room::{
+            member::OriginalSyncRoomMemberEvent,
+            message::{OriginalSyncRoomMessageEvent, Relation},
+            redaction::OriginalSyncRoomRedactionEvent,
+        },
+    },
+};
+use plugin_core::{
+    KvStore, MatrixClient, MatrixRoom, PluginContext, PluginRegistry, RoomEvent, RoomRef,
+    SdkClient, SdkRoom,
+};
+
+use crate::dispatch::Dispatcher;
+
+/// What the room event handlers need to build a [`PluginContext`].
+#[derive(Debug, Clone)]
+pub struct HandlerState {
+    pub registry: Arc<PluginRegistry>,
+    pub dev_active: bool,
+    pub dev_id: Option<Arc<str>>,
+    pub history_dir: Arc<PathBuf>,
+    pub store: KvStore,
+}
+
+impl HandlerState {
+    pub fn context(
+        &self,
+        client: Arc<dyn MatrixClient>,
+        room: Arc<dyn MatrixRoom>,
+    ) -> PluginContext {
+        PluginContext {
+            client,
+            room,
+            event: None,
+            dev_active: self.dev_active,
+            dev_id: self.dev_id.clone(),
+            registry: Arc::clone(&self.registry),
+            history_dir: Arc::clone(&self.history_dir),
+            store: self.store.clone(),
+        }
+    }
+}
+
+/// A [`RoomEvent`] that owns its event, so it can wait in a [`Dispatcher`]
+/// queue.
+#[derive(Debug, Clone)]
+pub enum QueuedRoomEvent {
+    Reaction(Arc<OriginalSyncReactionEvent>),
+    Membership(Arc<OriginalSyncRoomMemberEvent>),
+    Redaction(Arc<OriginalSyncRoomRedactionEvent>),
+    /// Only built by [`Self::edit`], so the message is known to be an edit.
+    Edit(Arc<OriginalSyncRoomMessageEvent>),
+}
+
+impl QueuedRoomEvent {
+    /// The message as an edit, if it replaces an earlier message.
+    pub fn edit(event: Arc<OriginalSyncRoomMessageEvent>) -> Option<Self> {
+        RoomEvent::edit(&event)
+            .is_some()
+            .then_some(Self::Edit(event))
+    }
+
+    pub fn as_event(&self) -> RoomEvent<'_> {
+        match self {
+            Self::Reaction(ev) => RoomEvent::Reaction(ev),
+            Self::Membership(ev) => RoomEvent::Membership(ev),
+            Self::Redaction(ev) => RoomEvent::Redaction(ev),
+            Self::Edit(event) => match &event.content.relates_to {
+                Some(Relation::Replacement(replacement)) => RoomEvent::Edit {
+                    event,
+                    replaces: &replacement.event_id,
+                    new_content: &replacement.new_content,
+                },
+                Some(Relation::Reply { .. } | Relation::Thread(_) | _) | None => {
+                    unreachable!("QueuedRoomEvent::edit only wraps replacements")
+                }
+            },
+        }
+    }
+}
+
+/// Forward reactions, membership changes and redactions to plugins. Edits
+/// arrive as room messages and are dispatched from the message handler.
+pub fn add_room_event_handlers(
+    client: &Client,
+    state: &HandlerState,
+    dispatcher: &Arc<Dispatcher>,
+) {
+    let (s, d) = (state.clone(), Arc::clone(dispatcher));
+    client.add_event_handler(
+        async move |ev: OriginalSyncReactionEvent, room: Room, client: Client| {
+            dispatch_room_event(
+                &s.context(Arc::new(SdkClient(client)), Arc::new(SdkRoom(room))),
+                &d,
+                QueuedRoomEvent::Reaction(Arc::new(ev)),
+            )
+            .await;
+        },
+    );
+    let (s, d) = (state.clone(), Arc::clone(dispatcher));
+    client.add_event_handler(
+        async move |ev: OriginalSyncRoomMemberEvent, room: Room, client: Client| {
+            dispatch_room_event(
+                &s.context(Arc::new(SdkClient(client)), Arc::new(SdkRoom(room))),
+                &d,
+                QueuedRoomEvent::Membership(Arc::new(ev)),
+            )
+            .await;
+        },
+    );
+    let (s, d) = (state.clone(), Arc::clone(dispatcher));
+    client.add_event_handler(
+        async move |ev: OriginalSyncRoomRedactionEvent, room: Room, client: Client| {
+            dispatch_room_event(
+                &s.context(Arc::new(SdkClient(client)), Arc::new(SdkRoom(room))),
+                &d,
+                QueuedRoomEvent::Redaction(Arc::new(ev)),
+            )
+            .await;
+        },
+    );
+}
+
+/// Queue `event` for every plugin that opted in to its kind, gated like room
+/// messages: dev-only plugins only in dev mode, only where the plugin is
+/// enabled, and the bot's own events only to plugins that want them.
+pub async fn dispatch_room_event(
+    ctx: &PluginContext,
+    dispatcher: &Dispatcher,
+    event: QueuedRoomEvent,
+) {
+    let kind = event.as_event().kind();
+    let is_self = ctx.client.is_own_user(event.as_event().sender());
+    let room_ref = RoomRef::from_room(ctx.room.as_ref());
+    for (plugin_id, entry) in ctx.registry.entries().await {
+        if !entry.plugin.room_event_kinds().contains(&kind) {
+            continue;
+        }
+        if is_self && !entry.plugin.wants_own_messages() {
+            continue;
+        }
+        if entry
+            .spec
+            .dev_only
+            .unwrap_or_else(|| entry.plugin.dev_only())
+            && !ctx.dev_active
+        {
+            continue;
+        }
+        if !ctx.registry.is_enabled_in(&plugin_id, &room_ref).await {
+            continue;
+        }
+        dispatcher.queue_room_event(ctx.clone(), entry, event.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+    use std::sync::Mutex;
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use matrix_sdk::ruma::{
+        UserId,
+        events::room::message::{
+            OriginalSyncRoomMessageEvent, ReplacementMetadata, RoomMessageEventContent,
+        },
+        owned_event_id, room_id, user_id,
+    };
+    use plugin_core::{
+        MatrixRoom as _, OverrideScope, Plugin, PluginSpec, PluginTriggers, RoomEventKind,
+        testing::{FakeClient, message_event},
+    };
+
+    use super::*;
+
+    /// Records the bodies of the edits it sees; an edit to "hang" never
+    /// returns.
+    #[derive(Debug, Default)]
+    struct EditLog {
+        seen: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Plugin for EditLog {
+        fn id(&self) -> &'static str {
+            "editlog"
+        }
+        fn help(&self) -> &'static str {
+            ""
+        }
+        fn spec(&self) -> PluginSpec {
+            PluginSpec {
+                id: "editlog".to_owned(),
+                enabled: true,
+                dev_only: None,
+                triggers: PluginTriggers::default(),
+                config: serde_yaml::Value::default(),
+            }
+        }
+        async fn run(&self, _ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
+            Ok(())
+        }
+        fn room_event_kinds(&self) -> &'static [RoomEventKind] {
+            &[RoomEventKind::Edit]
+        }
+        async fn on_room_event(
+            &self,
+            _ctx: &PluginContext,
+            event: &RoomEvent<'_>,
+            _spec: &PluginSpec,
+        ) -> Result<()> {
+            if let RoomEvent::Edit { new_content, .. } = event {
+                if new_content.msgtype.body() == "hang" {
+                    core::future::pending::<()>().await;
+                }
+                self.seen
+                    .lock()
+                    .unwrap()
+                    .push(new_content.msgtype.body().to_owned());
+            }
+            Ok(())
+        }
+    }
+
+    fn edit(sender: &UserId, body: &str) -> OriginalSyncRoomMessageEvent {
+        let original = owned_event_id!("$original:example.org");
+        let content = RoomMessageEventContent::text_plain(body)
+            .make_replacement(ReplacementMetadata::new(original, None));
+        message_event(sender, &content)
+    }
+
+    #[tokio::test]
+    async fn edits_are_gated_like_messages() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        let plugin = Arc::new(EditLog::default());
+        ctx.registry
+            .register(
+                plugin.spec(),
+                Arc::clone(&plugin) as Arc<dyn Plugin + Send + Sync>,
+            )
+            .await;
+
+        let dispatcher = Dispatcher::default();
+        let alice = user_id!("@alice:example.org");
+        let theirs = QueuedRoomEvent::edit(Arc::new(edit(alice, "fixed typo"))).unwrap();
+        dispatch_room_event(&ctx, &dispatcher, theirs.clone()).await;
+        // The bot's own edits are skipped unless the plugin asks for them.
+        let own = edit(user_id!("@bot:example.org"), "own edit");
+        dispatch_room_event(
+            &ctx,
+            &dispatcher,
+            QueuedRoomEvent::edit(Arc::new(own)).unwrap(),
+        )
+        .await;
+        ctx.registry
+            .set_override(
+                "editlog",
+                OverrideScope::Room(room.room_id().to_string()),
+                false,
+            )
+            .await
+            .unwrap();
+        dispatch_room_event(&ctx, &dispatcher, theirs).await;
+        dispatcher
+            .drain(&ctx.registry, ctx.client.as_ref(), Duration::from_secs(5))
+            .await;
+
+        assert_eq!(*plugin.seen.lock().unwrap(), ["fixed typo"]);
+    }
+
+    #[tokio::test]
+    async fn stuck_handlers_time_out_without_holding_up_dispatch() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        let plugin = Arc::new(EditLog::default());
+        let mut spec = plugin.spec();
+        spec.config = serde_yaml::from_str("timeout_secs: 1").unwrap();
+        ctx.registry
+            .register(spec, Arc::clone(&plugin) as Arc<dyn Plugin + Send + Sync>)
+            .await;
+
+        let dispatcher = Dispatcher::default();
+        let alice = user_id!("@alice:example.org");
+        for body in ["hang", "after"] {
+            let event = QueuedRoomEvent::edit(Arc::new(edit(alice, body))).unwrap();
+            // Returns once queued, not once handled.
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                dispatch_room_event(&ctx, &dispatcher, event),
+            )
+            .await
+            .unwrap();
+        }
+        dispatcher
+            .drain(&ctx.registry, ctx.client.as_ref(), Duration::from_secs(5))
+            .await;
+
+        assert_eq!(*plugin.seen.lock().unwrap(), ["after"]);
+    }
+}
@@ -0,0 +1,254 @@
+//! What `/healthz` and `/readyz` report: startup progress and sync recency,
+//! per account.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+use std::{
+    sync::{Arc, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::future::join_all;
+use plugin_core::MatrixClient;
+use serde::Serialize;
+
+/// Shared between the sync loop, which updates it, and the HTTP listener.
+#[derive(Debug)]
+pub struct Health {
+    account: String,
+    started: SystemTime,
+    /// How old the last successful sync may be before the bot is unhealthy.
+    max_sync_age: Duration,
+    session_restored: AtomicBool,
+    registry_built: AtomicBool,
+    first_sync_done: AtomicBool,
+    /// Appservice mode has no sync loop whose age could be checked.
+    appservice: AtomicBool,
+    /// Unix time in milliseconds; 0 until the first successful sync.
+    last_sync_ms: AtomicU64,
+    client: OnceLock<Arc<dyn MatrixClient>>,
+}
+
+#[derive(Debug, Serialize)]
+#[allow(clippy::struct_excessive_bools, reason = "JSON response body")]
+pub struct Readiness {
+    pub account: String,
+    pub ready: bool,
+    pub session_restored: bool,
+    pub first_sync_completed: bool,
+    pub registry_built: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Liveness {
+    pub account: String,
+    pub healthy: bool,
+    /// Seconds since the last successful sync, or since startup before it.
+    pub last_sync_age_secs: f64,
+    pub max_sync_age_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub own_device_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_state: Option<String>,
+}
+
+/// `/readyz` for all accounts: ready only when every account is.
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub accounts: Vec<Readiness>,
+}
+
+/// `/healthz` for all accounts: healthy only when every account is.
+#[derive(Debug, Serialize)]
+pub struct LivenessReport {
+    pub healthy: bool,
+    pub accounts: Vec<Liveness>,
+}
+
+pub fn readiness(healths: &[Arc<Health>]) -> ReadinessReport {
+    let accounts: Vec<_> = healths.iter().map(|h| h.readiness()).collect();
+    ReadinessReport {
+        ready: accounts.iter().all(|r| r.ready),
+        accounts,
+    }
+}
+
+pub async fn liveness(healths: &[Arc<Health>]) -> LivenessReport {
+    let accounts = join_all(healths.iter().map(|h| h.liveness())).await;
+    LivenessReport {
+        healthy: accounts.iter().all(|l| l.healthy),
+        accounts,
+    }
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
+
+impl Health {
+    /// `sync_timeout` is the long-poll timeout; a sync may legitimately
+    /// take that long, so the allowed age is a minute on top of it.
+    pub fn new(account: &str, sync_timeout: Duration) -> Self {
+        Self {
+            account: account.to_owned(),
+            started: SystemTime::now(),
+            max_sync_age: sync_timeout + Duration::from_secs(60),
+            session_restored: AtomicBool::new(false),
+            registry_built: AtomicBool::new(false),
+            first_sync_done: AtomicBool::new(false),
+            appservice: AtomicBool::new(false),
+            last_sync_ms: AtomicU64::new(0),
+            client: OnceLock::new(),
+        }
+    }
+
+    /// The client is logged in; its device and backup state are reported
+    /// from now on.
+    pub fn session_restored(&self, client: Arc<dyn MatrixClient>) {
+        let _ = self.client.set(client);
+        self.session_restored.store(true, Ordering::Relaxed);
+    }
+
+    pub fn registry_built(&self) {
+        self.registry_built.store(true, Ordering::Relaxed);
+    }
+
+    /// The appservice listener is up. Events are pushed to it rather than
+    /// synced, so it counts as the first sync and liveness no longer
+    /// depends on sync age.
+    pub fn appservice_started(&self) {
+        self.appservice.store(true, Ordering::Relaxed);
+        self.first_sync_done.store(true, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of a sync, also for the metrics endpoint.
+    pub fn record_sync(&self, ok: bool) {
+        let outcome = if ok { "ok" } else { "error" };
+        metrics::counter!("matrix_bot_syncs_total", "outcome" => outcome).increment(1);
+        if !ok {
+            return;
+        }
+        let now = SystemTime::now();
+        self.last_sync_ms.store(unix_ms(now), Ordering::Relaxed);
+        self.first_sync_done.store(true, Ordering::Relaxed);
+        metrics::gauge!("matrix_bot_last_sync_timestamp_seconds").set(
+            now.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        );
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let session_restored = self.session_restored.load(Ordering::Relaxed);
+        let first_sync_completed = self.first_sync_done.load(Ordering::Relaxed);
+        let registry_built = self.registry_built.load(Ordering::Relaxed);
+        Readiness {
+            account: self.account.clone(),
+            ready: session_restored && first_sync_completed && registry_built,
+            session_restored,
+            first_sync_completed,
+            registry_built,
+        }
+    }
+
+    /// Healthy while the last successful sync is recent. Before the first
+    /// one, the time since startup counts instead, so a slow initial sync
+    /// is not mistaken for a hang.
+    pub async fn liveness(&self) -> Liveness {
+        let last = match self.last_sync_ms.load(Ordering::Relaxed) {
+            0 => unix_ms(self.started),
+            ms => ms,
+        };
+        let age = Duration::from_millis(unix_ms(SystemTime::now()).saturating_sub(last));
+        let mut liveness = Liveness {
+            account: self.account.clone(),
+            healthy: self.appservice.load(Ordering::Relaxed) || age <= self.max_sync_age,
+            last_sync_age_secs: age.as_secs_f64(),
+            max_sync_age_secs: self.max_sync_age.as_secs_f64(),
+            user_id: None,
+            device_id: None,
+            own_device_verified: None,
+            backup_state: None,
+        };
+        if let Some(client) = self.client.get() {
+            let encryption = client.encryption_status().await;
+            liveness.user_id = client.user_id().map(|id| id.to_string());
+            liveness.device_id = client.device_id().map(|id| id.to_string());
+            liveness.own_device_verified = encryption.own_device_verified;
+            liveness.backup_state = Some(encryption.backup_state);
+        }
+        liveness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::user_id;
+    use plugin_core::testing::FakeClient;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn ready_after_startup_steps_and_unhealthy_when_sync_stalls() {
+        let health = Health::new("main", Duration::ZERO);
+        assert!(!health.readiness().ready);
+
+        health.session_restored(FakeClient::new(user_id!("@bot:example.org")));
+        health.registry_built();
+        health.record_sync(false);
+        assert!(!health.readiness().first_sync_completed);
+        health.record_sync(true);
+        assert!(health.readiness().ready);
+
+        let live = health.liveness().await;
+        assert!(live.healthy);
+        assert_eq!(live.user_id.as_deref(), Some("@bot:example.org"));
+
+        // Pretend the last sync was two minutes ago.
+        health
+            .last_sync_ms
+            .store(unix_ms(SystemTime::now()) - 120_000, Ordering::Relaxed);
+        assert!(!health.liveness().await.healthy);
+    }
+
+    #[tokio::test]
+    async fn reports_need_every_account() {
+        let healths = [
+            Arc::new(Health::new("one", Duration::from_secs(30))),
+            Arc::new(Health::new("two", Duration::from_secs(30))),
+        ];
+        for health in &healths {
+            health.registry_built();
+            health.appservice_started();
+        }
+        healths[0].session_restored(FakeClient::new(user_id!("@one:example.org")));
+        let report = readiness(&healths);
+        assert!(!report.ready);
+        assert!(report.accounts[0].ready);
+        assert_eq!(report.accounts[1].account, "two");
+        assert!(!report.accounts[1].session_restored);
+
+        healths[1].session_restored(FakeClient::new(user_id!("@two:example.org")));
+        assert!(readiness(&healths).ready);
+
+        healths[1].appservice.store(false, Ordering::Relaxed);
+        healths[1]
+            .last_sync_ms
+            .store(unix_ms(SystemTime::now()) - 120_000, Ordering::Relaxed);
+        let report = liveness(&healths).await;
+        assert!(!report.healthy);
+        assert!(report.accounts[0].healthy);
+        assert_eq!(
+            report.accounts[1].user_id.as_deref(),
+            Some("@two:example.org")
+        );
+    }
+}
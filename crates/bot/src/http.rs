@@ -0,0 +1,200 @@
+//! Optional HTTP listener for operators: Prometheus metrics at `/metrics`,
+//! liveness at `/healthz` and readiness at `/readyz`.
+
+use core::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{StatusCode, header},
+    response::IntoResponse,
+    routing::get,
+};
+use metrics::{Unit, describe_counter, describe_gauge, describe_histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tracing::{info, warn};
+
+use crate::health::{self, Health};
+
+/// Latency buckets in seconds, from a quick command to a long AI tool loop.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+/// Install the global Prometheus recorder. Until this runs, the `metrics`
+/// macros used across the bot and its plugins record nothing.
+pub fn install_metrics() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), LATENCY_BUCKETS)?
+        .install_recorder()
+        .context("installing the metrics recorder")?;
+    describe_metrics();
+    Ok(handle)
+}
+
+fn describe_metrics() {
+    describe_counter!(
+        "matrix_bot_plugin_invocations_total",
+        "Plugin runs started by a command or mention"
+    );
+    describe_counter!(
+        "matrix_bot_plugin_errors_total",
+        "Plugin hooks that failed, timed out or panicked"
+    );
+    describe_histogram!(
+        "matrix_bot_plugin_invocation_duration_seconds",
+        Unit::Seconds,
+        "How long plugin hooks took"
+    );
+    describe_counter!(
+        "matrix_bot_syncs_total",
+        "Sync responses from the homeserver"
+    );
+    describe_gauge!(
+        "matrix_bot_last_sync_timestamp_seconds",
+        Unit::Seconds,
+        "Unix time of the last successful sync"
+    );
+    describe_counter!(
+        "matrix_bot_relay_messages_total",
+        "Messages the relay forwarded or failed to forward, per cluster"
+    );
+    describe_counter!(
+        "matrix_bot_ai_requests_total",
+        "Requests to the AI provider"
+    );
+    describe_counter!(
+        "matrix_bot_ai_tokens_total",
+        Unit::Count,
+        "Tokens the AI provider reported using"
+    );
+    describe_histogram!(
+        "matrix_bot_ai_request_duration_seconds",
+        Unit::Seconds,
+        "How long AI provider requests took"
+    );
+    describe_counter!(
+        "matrix_bot_mcp_tool_calls_total",
+        "Tool calls the AI made through MCP servers"
+    );
+}
+
+#[derive(Debug, Clone)]
+struct HttpState {
+    metrics: PrometheusHandle,
+    healths: Arc<[Arc<Health>]>,
+}
+
+fn router(state: HttpState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(state)
+}
+
+async fn metrics(State(state): State<HttpState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+const fn status(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+async fn healthz(State(state): State<HttpState>) -> impl IntoResponse {
+    let liveness = health::liveness(&state.healths).await;
+    (status(liveness.healthy), Json(liveness))
+}
+
+async fn readyz(State(state): State<HttpState>) -> impl IntoResponse {
+    let readiness = health::readiness(&state.healths);
+    (status(readiness.ready), Json(readiness))
+}
+
+/// Bind `addr` and serve the endpoints in the background.
+pub async fn spawn(
+    addr: SocketAddr,
+    metrics: PrometheusHandle,
+    healths: Arc<[Arc<Health>]>,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("binding the HTTP listener to {addr}"))?;
+    info!(%addr, "Serving metrics and health checks");
+    let app = router(HttpState { metrics, healths });
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!(error = %e, "HTTP listener stopped");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use axum::body::{Body, to_bytes};
+    use axum::http::Request;
+    use metrics_exporter_prometheus::PrometheusRecorder;
+    use tower::ServiceExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_metrics_and_probes() {
+        let recorder: PrometheusRecorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), LATENCY_BUCKETS)
+            .unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("matrix_bot_plugin_invocations_total", "plugin" => "ping", "trigger" => "command")
+                .increment(2);
+            metrics::histogram!("matrix_bot_plugin_invocation_duration_seconds", "plugin" => "ping", "hook" => "run")
+                .record(0.2);
+        });
+
+        let app = router(HttpState {
+            metrics: handle,
+            healths: Arc::new([Arc::new(Health::new("main", Duration::from_secs(30)))]),
+        });
+        let response = app
+            .clone()
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            text.contains(
+                r#"matrix_bot_plugin_invocations_total{plugin="ping",trigger="command"} 2"#
+            )
+        );
+        assert!(text.contains(
+            r#"matrix_bot_plugin_invocation_duration_seconds_bucket{plugin="ping",hook="run",le="0.25"} 1"#
+        ));
+
+        let get = |path: &'static str| {
+            app.clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+        assert_eq!(get("/healthz").await.unwrap().status(), StatusCode::OK);
+        let ready = get("/readyz").await.unwrap();
+        assert_eq!(ready.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = to_bytes(ready.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["ready"], false);
+        assert_eq!(json["accounts"][0]["account"], "main");
+        assert_eq!(json["accounts"][0]["first_sync_completed"], false);
+    }
+}
@@ -0,0 +1,205 @@
+//! References in config values, resolved when a config file is loaded:
+//!
+//! - `${VAR}`: the environment variable, which must be set
+//! - `${VAR:-default}`: `default` when the variable is unset or empty
+//! - `$${`: a literal `${`
+//! - `file:/run/secrets/name`, as the whole value: the file's contents
+//!   without the trailing newline
+//! - `secret:` before any of the above, or before a literal value: mark the
+//!   value as a secret
+//!
+//! What comes from the environment or a file under a secret-bearing key
+//! (see [`is_secret_key`]), and every value marked `secret:`, is masked in
+//! logs and in what plugins show, see [`register_secret`]. Other resolved
+//! values, such as a homeserver URL or a store path, are shown as they are.
+
+use anyhow::{Result, bail};
+use plugin_core::{ConfigError, register_secret};
+use serde_yaml::Value;
+
+/// Parse YAML and resolve the references in it.
+pub fn parse(yaml: &str) -> Result<Value> {
+    let mut value: Value = serde_yaml::from_str(yaml)?;
+    let errors = resolve(&mut value);
+    if !errors.is_empty() {
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        bail!("{}", errors.join("\n"));
+    }
+    Ok(value)
+}
+
+/// Resolve the references in every string value below `value`. Values that
+/// fail are left as they were.
+pub fn resolve(value: &mut Value) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    resolve_at(value, &mut Vec::new(), &mut errors);
+    errors
+}
+
+/// Whether values under `key` hold credentials: passwords, tokens, secrets
+/// and keys such as `api_key`.
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    ["password", "token", "secret"]
+        .iter()
+        .any(|word| key.contains(word))
+        || key.ends_with("key")
+        || key.ends_with("keys")
+}
+
+fn resolve_at(value: &mut Value, path: &mut Vec<String>, errors: &mut Vec<ConfigError>) {
+    if let Value::String(s) = value {
+        // Sequence items belong to the key of their list.
+        let under_secret_key = path
+            .iter()
+            .rev()
+            .find(|segment| segment.parse::<usize>().is_err())
+            .is_some_and(|key| is_secret_key(key));
+        let (raw, marked) = s
+            .strip_prefix("secret:")
+            .map_or((s.as_str(), false), |raw| (raw, true));
+        match resolve_str(raw, under_secret_key || marked) {
+            Ok(Some(resolved)) => *s = resolved,
+            Ok(None) if marked => {
+                let raw = raw.to_owned();
+                register_secret(&raw);
+                *s = raw;
+            }
+            Ok(None) => {}
+            Err(message) => errors.push(ConfigError {
+                path: path.clone(),
+                message,
+            }),
+        }
+    } else if let Value::Sequence(items) = value {
+        for (idx, item) in items.iter_mut().enumerate() {
+            path.push(idx.to_string());
+            resolve_at(item, path, errors);
+            path.pop();
+        }
+    } else if let Value::Mapping(map) = value {
+        for (key, item) in map.iter_mut() {
+            path.push(
+                key.as_str()
+                    .map_or_else(|| format!("{key:?}"), ToOwned::to_owned),
+            );
+            resolve_at(item, path, errors);
+            path.pop();
+        }
+    } else if let Value::Tagged(tagged) = value {
+        resolve_at(&mut tagged.value, path, errors);
+    }
+}
+
+/// The resolved string, or `None` if `s` holds no references. With
+/// `secret`, what the references pull in is registered as a secret.
+fn resolve_str(s: &str, secret: bool) -> Result<Option<String>, String> {
+    if let Some(file) = s.strip_prefix("file:") {
+        let contents = std::fs::read_to_string(file).map_err(|e| format!("reading {file}: {e}"))?;
+        let contents = contents.trim_end_matches(['\r', '\n']).to_owned();
+        if secret {
+            register_secret(&contents);
+        }
+        return Ok(Some(contents));
+    }
+    if !s.contains("${") {
+        return Ok(None);
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(escaped) = after.strip_prefix("${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some(body) = after.strip_prefix('{') else {
+            out.push('$');
+            rest = after;
+            continue;
+        };
+        let end = body.find('}').ok_or("`${` without a closing `}`")?;
+        let (name, default) = match body[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&body[..end], None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("`${{{name}}}` is not a valid variable name"));
+        }
+        match (std::env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => out.push_str(default),
+            (Ok(value), _) => {
+                if secret {
+                    register_secret(&value);
+                }
+                out.push_str(&value);
+            }
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(_), None) => return Err(format!("environment variable {name} is not set")),
+        }
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(Some(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_env_defaults_and_files() {
+        let secret = std::env::temp_dir().join(format!("interpolate-{}", std::process::id()));
+        std::fs::write(&secret, "s3cr3t-from-a-file\n").unwrap();
+        let marked =
+            std::env::temp_dir().join(format!("interpolate-marked-{}", std::process::id()));
+        std::fs::write(&marked, "marked-as-secret\n").unwrap();
+        let plain = std::env::temp_dir().join(format!("interpolate-plain-{}", std::process::id()));
+        std::fs::write(&plain, "https://hs.example.org\n").unwrap();
+        let yaml = format!(
+            r"
+dir: ${{CARGO_MANIFEST_DIR}}/data
+model: ${{INTERPOLATE_TEST_UNSET:-gpt-4o-mini}}
+prompt: costs $5, not $${{PRICE}}
+keys: [file:{}]
+webhook: secret:file:{}
+homeserver: file:{}
+",
+            secret.display(),
+            marked.display(),
+            plain.display()
+        );
+        let value = parse(&yaml).unwrap();
+        for file in [&secret, &marked, &plain] {
+            std::fs::remove_file(file).unwrap();
+        }
+
+        assert_eq!(
+            value["dir"].as_str(),
+            Some(format!("{}/data", env!("CARGO_MANIFEST_DIR")).as_str())
+        );
+        assert_eq!(value["model"].as_str(), Some("gpt-4o-mini"));
+        assert_eq!(value["prompt"].as_str(), Some("costs $5, not ${PRICE}"));
+        assert_eq!(value["keys"][0].as_str(), Some("s3cr3t-from-a-file"));
+        assert_eq!(value["webhook"].as_str(), Some("marked-as-secret"));
+        assert_eq!(value["homeserver"].as_str(), Some("https://hs.example.org"));
+        assert_eq!(
+            plugin_core::redact_secrets("key: s3cr3t-from-a-file, hook: marked-as-secret"),
+            "key: [redacted], hook: [redacted]"
+        );
+        // Values under other keys are not secrets.
+        let shown = format!("{} https://hs.example.org", env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(plugin_core::redact_secrets(&shown), shown);
+
+        let mut missing =
+            serde_yaml::from_str("a: { b: [x, '${INTERPOLATE_TEST_UNSET}'] }").unwrap();
+        let errors = resolve(&mut missing);
+        assert_eq!(
+            errors[0].to_string(),
+            "a.b.1: environment variable INTERPOLATE_TEST_UNSET is not set"
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use core::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use plugin_core::{LifecycleContext, Plugin, PluginEntry, PluginSpec};
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use tracing::{info, warn};
+
+/// How long a single plugin may take in `on_shutdown` before it is skipped.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Drives the plugin lifecycle hooks: `on_start` once, `on_tick` on each
+/// plugin's interval and `on_shutdown` when the bot stops.
+#[derive(Debug)]
+pub struct Lifecycle {
+    ctx: LifecycleContext,
+    tickers: Arc<Tickers>,
+}
+
+impl Lifecycle {
+    /// Run `on_start` for every active plugin and spawn their tickers.
+    pub async fn start(ctx: LifecycleContext) -> Self {
+        let tickers = Arc::new(Tickers {
+            ctx: ctx.clone(),
+            started: Mutex::default(),
+            running: Mutex::default(),
+        });
+        tickers.sync().await;
+        Self { ctx, tickers }
+    }
+
+    /// The tickers, for the config reloader to resync after a reload.
+    pub fn tickers(&self) -> Arc<Tickers> {
+        Arc::clone(&self.tickers)
+    }
+
+    /// Stop the tickers and give each active plugin a chance to flush state.
+    pub async fn shutdown(self) {
+        self.tickers.stop().await;
+        for (id, entry) in active_entries(&self.ctx).await {
+            if tokio::time::timeout(SHUTDOWN_GRACE, entry.plugin.on_shutdown())
+                .await
+                .is_err()
+            {
+                warn!(plugin = %id, "Plugin on_shutdown timed out");
+            }
+        }
+    }
+}
+
+/// The plugins that have been started and their running `on_tick` loops,
+/// by plugin ID, with the interval each was started with.
+#[derive(Debug)]
+pub struct Tickers {
+    ctx: LifecycleContext,
+    /// Plugins whose `on_start` has run, so a reload starts only new ones.
+    started: Mutex<HashSet<String>>,
+    running: Mutex<HashMap<String, (Duration, JoinHandle<()>)>>,
+}
+
+impl Tickers {
+    /// Bring the tickers in line with the registry: run `on_start` for
+    /// newly added plugins and start their tickers, stop those of removed
+    /// ones and restart any whose interval changed.
+    pub async fn sync(&self) {
+        let active = active_entries(&self.ctx).await;
+        self.start_new(&active).await;
+        let wanted: HashMap<String, Duration> = active
+            .into_iter()
+            .filter_map(|(id, entry)| {
+                tick_interval(entry.plugin.as_ref(), &entry.spec).map(|interval| (id, interval))
+            })
+            .collect();
+        let mut running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        running.retain(|id, (interval, handle)| {
+            let keep = wanted.get(id) == Some(interval) && !handle.is_finished();
+            if !keep {
+                info!(plugin = %id, "Stopping plugin ticker");
+                handle.abort();
+            }
+            keep
+        });
+        for (id, interval) in wanted {
+            if running.contains_key(&id) {
+                continue;
+            }
+            info!(plugin = %id, interval_secs = interval.as_secs_f64(), "Starting plugin ticker");
+            let handle = tokio::spawn(run_ticker(self.ctx.clone(), id.clone(), interval));
+            running.insert(id, (interval, handle));
+        }
+    }
+
+    /// Run `on_start` for plugins that have not had it yet.
+    async fn start_new(&self, active: &[(String, PluginEntry)]) {
+        for (id, entry) in self.claim_unstarted(active) {
+            if let Err(e) = entry.plugin.on_start(&self.ctx, &entry.spec).await {
+                warn!(error = %e, plugin = %id, "Plugin on_start failed");
+            }
+        }
+    }
+
+    /// Mark the plugins in `active` as started, returning those that were
+    /// not yet. Removed plugins are forgotten, so they start again if a
+    /// later reload brings them back.
+    fn claim_unstarted<'a>(
+        &self,
+        active: &'a [(String, PluginEntry)],
+    ) -> Vec<&'a (String, PluginEntry)> {
+        let mut started = self.started.lock().unwrap_or_else(PoisonError::into_inner);
+        started.retain(|id| active.iter().any(|(active, _)| active == id));
+        let unstarted = active
+            .iter()
+            .filter(|(id, _)| started.insert(id.clone()))
+            .collect();
+        drop(started);
+        unstarted
+    }
+
+    /// Abort every ticker and wait until none is running.
+    async fn stop(&self) {
+        let handles: Vec<_> = self
+            .running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain()
+            .map(|(_, (_, handle))| handle)
+            .collect();
+        for handle in handles {
+            handle.abort();
+            let _ = handle.await;
+        }
+    }
+}
+
+/// Registered plugins that may run on this instance. Room overrides are not
+/// considered: a plugin disabled globally can still be enabled in some room.
+async fn active_entries(ctx: &LifecycleContext) -> Vec<(String, PluginEntry)> {
+    let mut entries: Vec<_> = ctx
+        .registry
+        .entries()
+        .await
+        .into_iter()
+        .filter(|(_, entry)| {
+            ctx.dev_active
+                || !entry
+                    .spec
+                    .dev_only
+                    .unwrap_or_else(|| entry.plugin.dev_only())
+        })
+        .collect();
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    entries
+}
+
+/// `tick_interval_secs` from the plugin's config, else the plugin's default.
+fn tick_interval(plugin: &dyn Plugin, spec: &PluginSpec) -> Option<Duration> {
+    let Some(value) = spec.config.get("tick_interval_secs") else {
+        return plugin.tick_interval();
+    };
+    match value.as_f64() {
+        Some(secs) if secs > 0.0 => Duration::try_from_secs_f64(secs).ok(),
+        Some(_) => None,
+        None => {
+            warn!(plugin = %spec.id, "tick_interval_secs is not a number; using the default");
+            plugin.tick_interval()
+        }
+    }
+}
+
+/// Call `on_tick` with the plugin's current spec until the plugin is removed.
+async fn run_ticker(ctx: LifecycleContext, id: String, interval: Duration) {
+    let mut timer = tokio::time::interval(interval);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick fires immediately; on_start has just run.
+    timer.tick().await;
+    loop {
+        timer.tick().await;
+        let Some(entry) = ctx.registry.entry(&id).await else {
+            info!(plugin = %id, "Plugin unregistered; stopping ticker");
+            return;
+        };
+        if let Err(e) = entry.plugin.on_tick(&ctx, &entry.spec).await {
+            warn!(error = %e, plugin = %id, "Plugin on_tick failed");
+        }
+    }
+}
+
+/// Resolve on Ctrl+C or, on Unix, SIGTERM.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => info!("Received Ctrl+C"),
+                    _ = terminate.recv() => info!("Received SIGTERM"),
+                }
+                return;
+            }
+            Err(e) => warn!(error = %e, "Failed to install SIGTERM handler"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Received Ctrl+C");
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use matrix_sdk::ruma::user_id;
+    use plugin_core::{
+        MatrixClient, PluginContext, PluginRegistry, PluginTriggers, testing::FakeClient,
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Counter {
+        starts: AtomicUsize,
+        ticks: AtomicUsize,
+        shutdowns: AtomicUsize,
+        /// Told about every tick, so tests wait for ticks instead of sleeping.
+        ticked: Option<mpsc::UnboundedSender<()>>,
+    }
+
+    impl Counter {
+        fn with_channel() -> (Arc<Self>, mpsc::UnboundedReceiver<()>) {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let plugin = Self {
+                ticked: Some(tx),
+                ..Self::default()
+            };
+            (Arc::new(plugin), rx)
+        }
+    }
+
+    #[async_trait]
+    impl Plugin for Counter {
+        fn id(&self) -> &'static str {
+            "counter"
+        }
+        fn help(&self) -> &'static str {
+            ""
+        }
+        fn spec(&self) -> PluginSpec {
+            PluginSpec {
+                id: "counter".to_owned(),
+                enabled: true,
+                dev_only: None,
+                triggers: PluginTriggers::default(),
+                config: serde_yaml::Value::default(),
+            }
+        }
+        async fn run(&self, _ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
+            Ok(())
+        }
+        async fn on_start(&self, _ctx: &LifecycleContext, _spec: &PluginSpec) -> Result<()> {
+            self.starts.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        async fn on_shutdown(&self) {
+            self.shutdowns.fetch_add(1, Ordering::SeqCst);
+        }
+        fn tick_interval(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+        async fn on_tick(&self, _ctx: &LifecycleContext, _spec: &PluginSpec) -> Result<()> {
+            self.ticks.fetch_add(1, Ordering::SeqCst);
+            if let Some(ticked) = &self.ticked {
+                let _ = ticked.send(());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn config_overrides_the_tick_interval() {
+        let plugin = Counter::default();
+        let mut spec = plugin.spec();
+        assert_eq!(
+            tick_interval(&plugin, &spec),
+            Some(Duration::from_millis(10))
+        );
+        spec.config = serde_yaml::from_str("tick_interval_secs: 90").unwrap();
+        assert_eq!(tick_interval(&plugin, &spec), Some(Duration::from_secs(90)));
+        spec.config = serde_yaml::from_str("tick_interval_secs: 0").unwrap();
+        assert_eq!(tick_interval(&plugin, &spec), None);
+    }
+
+    fn context(registry: &Arc<PluginRegistry>) -> LifecycleContext {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        LifecycleContext {
+            store: client.store(),
+            client: client as Arc<dyn MatrixClient>,
+            dev_active: false,
+            registry: Arc::clone(registry),
+            history_dir: Arc::new(std::env::temp_dir()),
+        }
+    }
+
+    async fn register(registry: &PluginRegistry, plugin: &Arc<Counter>) {
+        registry
+            .register(
+                plugin.spec(),
+                Arc::clone(plugin) as Arc<dyn Plugin + Send + Sync>,
+            )
+            .await;
+    }
+
+    async fn next_tick(ticked: &mut mpsc::UnboundedReceiver<()>) {
+        tokio::time::timeout(Duration::from_secs(5), ticked.recv())
+            .await
+            .expect("no tick within 5s")
+            .expect("tick channel closed");
+    }
+
+    #[tokio::test]
+    async fn hooks_run_in_order() {
+        let (plugin, mut ticked) = Counter::with_channel();
+        let registry = Arc::new(PluginRegistry::new());
+        register(&registry, &plugin).await;
+
+        let lifecycle = Lifecycle::start(context(&registry)).await;
+        assert_eq!(plugin.starts.load(Ordering::SeqCst), 1);
+        next_tick(&mut ticked).await;
+        next_tick(&mut ticked).await;
+        lifecycle.shutdown().await;
+        assert_eq!(plugin.shutdowns.load(Ordering::SeqCst), 1);
+
+        // shutdown waits for the ticker to end, so no tick can follow.
+        let ticks = plugin.ticks.load(Ordering::SeqCst);
+        tokio::task::yield_now().await;
+        assert_eq!(plugin.ticks.load(Ordering::SeqCst), ticks);
+    }
+
+    #[tokio::test]
+    async fn sync_follows_registry_changes() {
+        let (plugin, mut ticked) = Counter::with_channel();
+        let registry = Arc::new(PluginRegistry::new());
+        let lifecycle = Lifecycle::start(context(&registry)).await;
+        let tickers = lifecycle.tickers();
+        assert!(tickers.running.lock().unwrap().is_empty());
+
+        // Added by a reload: started, then ticking.
+        register(&registry, &plugin).await;
+        tickers.sync().await;
+        assert_eq!(plugin.starts.load(Ordering::SeqCst), 1);
+        next_tick(&mut ticked).await;
+
+        // Retimed by a reload: the ticker is replaced.
+        let mut spec = plugin.spec();
+        spec.config = serde_yaml::from_str("tick_interval_secs: 3600").unwrap();
+        registry
+            .register(spec, Arc::clone(&plugin) as Arc<dyn Plugin + Send + Sync>)
+            .await;
+        tickers.sync().await;
+        assert_eq!(
+            tickers.running.lock().unwrap()["counter"].0,
+            Duration::from_secs(3600)
+        );
+        assert_eq!(plugin.starts.load(Ordering::SeqCst), 1);
+
+        // Removed by a reload, then brought back: it starts again.
+        registry.unregister("counter").await;
+        tickers.sync().await;
+        assert!(tickers.running.lock().unwrap().is_empty());
+        register(&registry, &plugin).await;
+        tickers.sync().await;
+        assert_eq!(plugin.starts.load(Ordering::SeqCst), 2);
+        lifecycle.shutdown().await;
+    }
+}
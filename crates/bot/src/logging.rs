@@ -1,6 +1,10 @@
+use std::io::{self, Write};
+
+use plugin_core::redact_secrets;
 use tracing::{Subscriber, level_filters::LevelFilter};
 use tracing_subscriber::{
-    EnvFilter, Layer, layer::SubscriberExt as _, registry::LookupSpan, util::SubscriberInitExt as _,
+    EnvFilter, Layer, fmt::MakeWriter, layer::SubscriberExt as _, registry::LookupSpan,
+    util::SubscriberInitExt as _,
 };
 
 pub enum LogFormat {
@@ -14,7 +18,9 @@ impl LogFormat {
         for<'a> S: Subscriber + LookupSpan<'a>,
     {
         // Shared configuration regardless of where logs are output to.
-        let fmt = tracing_subscriber::fmt::layer().with_thread_names(true);
+        let fmt = tracing_subscriber::fmt::layer()
+            .with_thread_names(true)
+            .with_writer(Redacting);
 
         // Configure the writer based on the desired log target:
         match self {
@@ -29,8 +35,36 @@ impl LogFormat {
     }
 }
 
+/// Stdout with registered secrets masked. Each event reaches the writer in
+/// a single write, so a secret is never split across two.
+#[derive(Debug)]
+struct Redacting;
+
+impl<'a> MakeWriter<'a> for Redacting {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Self
+    }
+}
+
+impl Write for Redacting {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stdout = io::stdout().lock();
+        match core::str::from_utf8(buf) {
+            Ok(text) => stdout.write_all(redact_secrets(text).as_bytes())?,
+            Err(_) => stdout.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
 pub fn init_tracing() {
-    let log_mode = std::env::var("RUST_LOG_MODE").unwrap_or_else(|_ | "pretty".into());
+    let log_mode = std::env::var("RUST_LOG_MODE").unwrap_or_else(|_| "pretty".into());
 
     // Build filter with quieter matrix-sdk crates to reduce spam
     let filter = EnvFilter::builder()
@@ -1,35 +1,67 @@
+mod accounts;
+mod admin;
+mod appservice;
+mod appservice_io;
+mod check;
+mod cli;
+mod dispatch;
+mod events;
+mod health;
+mod http;
+mod interpolate;
+mod lifecycle;
 mod logging;
 mod plugins;
+mod recovery;
+mod reload;
+mod verification;
 
-use core::time::Duration;
+use core::{net::SocketAddr, pin::pin, time::Duration};
 use std::{collections::HashSet, fs, io::IsTerminal as _, path::PathBuf, sync::Arc};
 
 use anyhow::{Context as _, Result, anyhow};
 use clap::Parser;
-use futures_util::StreamExt as _;
+use futures_util::future::join_all;
 use matrix_sdk::{
-    Client, SessionMeta,
+    Client, LoopCtrl, SessionMeta,
     authentication::{SessionTokens, matrix::MatrixSession},
     config::SyncSettings,
-    encryption::verification::{
-        SasState, SasVerification, Verification, VerificationRequest, VerificationRequestState,
-    },
+    encryption::{BackupDownloadStrategy, EncryptionSettings, verification::Verification},
     room::Room,
-    ruma::events::{
-        key::verification::{
-            request::ToDeviceKeyVerificationRequestEvent, start::ToDeviceKeyVerificationStartEvent,
-        },
-        room::{
-            member::{MembershipState, StrippedRoomMemberEvent},
-            message::{MessageType, OriginalSyncRoomMessageEvent},
+    ruma::{
+        UserId,
+        events::{
+            key::verification::{
+                request::ToDeviceKeyVerificationRequestEvent,
+                start::ToDeviceKeyVerificationStartEvent,
+            },
+            room::{
+                member::{MembershipState, StrippedRoomMemberEvent},
+                message::{MessageType, OriginalSyncRoomMessageEvent},
+            },
         },
     },
 };
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info, warn};
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{Instrument as _, debug, info, info_span, warn};
 
-use crate::logging::init_tracing;
-use plugin_core::{PluginContext, PluginSpec, RoomMessageMeta, truncate};
+use crate::{
+    accounts::Account,
+    appservice_io::AsClient,
+    dispatch::Trigger,
+    lifecycle::Lifecycle,
+    logging::init_tracing,
+    verification::{Verifications, VerifyMode},
+};
+use plugin_core::{
+    KvStore, LifecycleContext, MatrixClient, MatrixRoom, PermissionRules, PluginContext,
+    PluginEntry, PluginSpec, RateLimitRules, RateLimiter, Reloader, RoomRef, SdkClient, SdkRoom,
+    ThrottleNotice, member_power_level, react, send_text, track_backup_uploads, truncate,
+};
+use plugin_external::ExternalPluginConfig;
+use plugin_relay::Relay;
+use plugin_verify::Verifier;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -37,14 +69,16 @@ use plugin_core::{PluginContext, PluginSpec, RoomMessageMeta, truncate};
     version,
     about = "Simple Matrix ping bot with E2EE"
 )]
+#[allow(clippy::struct_excessive_bools, reason = "independent CLI flags")]
 struct Args {
     /// Homeserver base URL, e.g. `https://matrix-client.matrix.org`.
+    /// Not needed when the config lists `accounts`.
     #[arg(long, env = "MATRIX_HOMESERVER")]
-    homeserver: String,
+    homeserver: Option<String>,
 
     /// Username (localpart or full user ID)
     #[arg(long, env = "MATRIX_USERNAME")]
-    username: String,
+    username: Option<String>,
 
     /// Password (if omitted, will prompt if needed)
     #[arg(long, env = "MATRIX_PASSWORD")]
@@ -70,10 +104,27 @@ struct Args {
     #[arg(long)]
     no_autojoin: bool,
 
-    /// Auto-accept and confirm SAS verifications (insecure for production)
+    /// Do not reload when the config files change (SIGHUP and `!tools reload` still work)
+    #[arg(long, env = "MATRIX_NO_CONFIG_WATCH")]
+    no_config_watch: bool,
+
+    /// Set up cross-signing and server-side key backup on startup, writing
+    /// the new recovery key to this file, or restore both with the key
+    /// already in it
+    #[arg(long, env = "MATRIX_RECOVERY_KEY_FILE")]
+    recovery_key_file: Option<PathBuf>,
+
+    /// Auto-accept and confirm SAS verifications (insecure for production);
+    /// ignored when `--verify-mode` is set
     #[arg(long, env = "MATRIX_AUTO_VERIFY", default_value_t = true)]
     auto_verify: bool,
 
+    /// How SAS verifications are confirmed: `auto`, `operator` (through
+    /// `!verify` in the verify plugin's admin room) or `log`; see
+    /// `verification.rs`. Defaults to `auto`, or `log` if `MATRIX_AUTO_VERIFY=false`
+    #[arg(long, env = "MATRIX_VERIFY_MODE", value_enum)]
+    verify_mode: Option<VerifyMode>,
+
     /// Sync timeout in milliseconds
     #[arg(long, env = "MATRIX_SYNC_TIMEOUT_MS", default_value_t = 30000)]
     sync_timeout_ms: u64,
@@ -86,9 +137,40 @@ struct Args {
     #[arg(long, env = "MATRIX_MODE")]
     mode: Option<String>,
 
+    /// Serve Prometheus metrics at `/metrics` and health checks at `/healthz`
+    /// and `/readyz` on this address, e.g. `127.0.0.1:9184`
+    #[arg(long, env = "MATRIX_HTTP_LISTEN")]
+    http_listen: Option<SocketAddr>,
+
+    /// Serve the admin API on `host:port` or `unix:/path/to/socket`; see `admin.rs`
+    #[arg(long, env = "MATRIX_ADMIN_LISTEN")]
+    admin_listen: Option<admin::AdminListen>,
+
+    /// Bearer token the admin API requires
+    #[arg(long, env = "MATRIX_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// On SIGINT/SIGTERM, how long running plugins get to finish before
+    /// they are aborted
+    #[arg(long, env = "MATRIX_SHUTDOWN_TIMEOUT_SECS", default_value_t = 20)]
+    shutdown_timeout_secs: u64,
+
+    /// Check the config and plugin config files, print any problems and exit;
+    /// nothing is logged in to or started
+    #[arg(long)]
+    check_config: bool,
+
     /// Run as an internal MCP server (e.g. "time") instead of the bot
     #[arg(long)]
     mcp_server: Option<String>,
+
+    /// The entry under `accounts` that subcommands act on; needed when the
+    /// config lists more than one
+    #[arg(long, env = "MATRIX_ACCOUNT")]
+    account: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<cli::Command>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -100,7 +182,7 @@ struct SavedSession {
     device_id: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Default, Deserialize, Clone)]
 pub(crate) struct BotConfig {
     pub(crate) clusters: Vec<RoomCluster>,
     #[serde(default)]
@@ -113,10 +195,19 @@ pub(crate) struct BotConfig {
     pub(crate) dev_id: Option<String>,
     #[serde(default, alias = "tools")]
     pub(crate) plugins: Option<Vec<PluginSpec>>,
+    /// Plugins run as separate processes; changes need a restart.
+    #[serde(default)]
+    pub(crate) external_plugins: Vec<ExternalPluginConfig>,
+    /// Accounts to run in this process; without any, the account comes from
+    /// the command line. Changes need a restart.
+    #[serde(default)]
+    pub(crate) accounts: Vec<accounts::AccountConfig>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) struct RoomCluster {
+    #[serde(default)]
+    pub(crate) name: Option<String>,
     pub(crate) rooms: Vec<String>,
     #[serde(default)]
     pub(crate) reupload_media: Option<bool>,
@@ -126,49 +217,407 @@ pub(crate) struct RoomCluster {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_tracing();
-
     // Load .env if present so clap can pick up env vars.
     let _ = dotenvy::dotenv();
     let args = Args::parse();
 
-    if let Some(tool_name) = args.mcp_server {
-        plugin_ai::run_mcp_server(&tool_name);
+    // These print their results to stdout, without logs around them.
+    if args.check_config {
+        return check::run(&args);
+    }
+    if let Some(command) = &args.command {
+        return cli::run(&args, command).await;
+    }
+    init_tracing();
+
+    if let Some(tool_name) = &args.mcp_server {
+        plugin_ai::run_mcp_server(tool_name);
         return Ok(());
     }
 
-    fs::create_dir_all(&args.store)
-        .with_context(|| format!("creating store directory at {}", args.store.display()))?;
+    let config = load_config(&args.config)?;
+    let accounts = accounts::resolve(&args, &config)?;
+    let env_dev = matches!(args.mode.as_deref(), Some(m) if m.eq_ignore_ascii_case("dev"));
+    let dev_active = (args.dev || env_dev) && config.dev_mode.unwrap_or(false);
+    let dev_id = config.dev_id.as_ref().map(|s| Arc::<str>::from(s.as_str()));
+    if dev_active && dev_id.is_none() {
+        return Err(anyhow!(
+            "Dev mode requested but no dev_id provided in config.yaml"
+        ));
+    }
+    // Loud banner so mode is obvious at startup
+    print_mode_banner(dev_active, dev_id.as_deref());
+    let admin_token = match &args.admin_listen {
+        Some(_) => Some(
+            args.admin_token
+                .as_deref()
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(Arc::<str>::from)
+                .ok_or_else(|| {
+                    anyhow!("--admin-listen needs --admin-token or MATRIX_ADMIN_TOKEN")
+                })?,
+        ),
+        None => None,
+    };
+
+    // `/healthz` and `/readyz` cover every account; the admin API follows
+    // the first.
+    let sync_timeout = Duration::from_millis(args.sync_timeout_ms);
+    let healths: Arc<[_]> = accounts
+        .iter()
+        .map(|account| Arc::new(health::Health::new(&account.name, sync_timeout)))
+        .collect();
+    if let Some(addr) = args.http_listen {
+        http::spawn(addr, http::install_metrics()?, Arc::clone(&healths)).await?;
+    }
 
-    // Build client with SQLite store to persist E2EE state
-    let client = Client::builder()
-        .homeserver_url(&args.homeserver)
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let shared = Shared {
+        args: &args,
+        config: &config,
+        dev_active,
+        dev_id,
+        admin_token,
+        stop: stop_rx,
+    };
+    let runs = join_all(
+        accounts
+            .into_iter()
+            .zip(healths.iter().map(Arc::clone))
+            .enumerate()
+            .map(|(idx, (account, health))| {
+                let span = info_span!("account", name = %account.name);
+                let (shared, stop_tx) = (&shared, &stop_tx);
+                async move {
+                    let result = run_account(shared, account, health, idx == 0).await;
+                    if result.is_err() {
+                        // Take the other accounts down too, so the process exits
+                        // with an error and its supervisor restarts it.
+                        stop_tx.send_replace(true);
+                    }
+                    result
+                }
+                .instrument(span)
+            }),
+    );
+    let mut runs = pin!(runs);
+    let results = tokio::select! {
+        results = &mut runs => results,
+        () = lifecycle::shutdown_signal() => {
+            stop_tx.send_replace(true);
+            tokio::select! {
+                results = runs => results,
+                () = lifecycle::shutdown_signal() => {
+                    warn!("Second shutdown signal; exiting without waiting");
+                    return Ok(());
+                }
+            }
+        }
+    };
+    results.into_iter().collect()
+}
+
+/// What every account's run shares.
+#[derive(Debug)]
+struct Shared<'a> {
+    args: &'a Args,
+    config: &'a BotConfig,
+    dev_active: bool,
+    dev_id: Option<Arc<str>>,
+    /// Set when the admin API is enabled.
+    admin_token: Option<Arc<str>>,
+    /// Flips to `true` when the bot should stop.
+    stop: watch::Receiver<bool>,
+}
+
+/// Log `account` in and run its plugins and sync loop until the bot stops.
+/// Only the `primary` account serves the admin API.
+async fn run_account(
+    shared: &Shared<'_>,
+    account: Account,
+    health: Arc<health::Health>,
+    primary: bool,
+) -> Result<()> {
+    let Shared {
+        args,
+        config,
+        dev_active,
+        ..
+    } = *shared;
+    let dev_id = shared.dev_id.clone();
+    let mut stop = shared.stop.clone();
+
+    fs::create_dir_all(&account.store)
+        .with_context(|| format!("creating store directory at {}", account.store.display()))?;
+    let connection = match &account.appservice {
+        Some(appservice) => {
+            let bot = UserId::parse(&account.username).with_context(|| {
+                format!(
+                    "account {}: appservice mode needs a full user ID",
+                    account.name
+                )
+            })?;
+            let client = AsClient::new(
+                &account.homeserver,
+                &appservice.as_token,
+                bot,
+                &appservice.users,
+            )?;
+            client
+                .load_joined_rooms()
+                .await
+                .context("fetching the appservice bot's rooms")?;
+            Connection::Appservice(client, appservice::bind(appservice.listen).await?)
+        }
+        None => Connection::Sync(connect(&account).await?),
+    };
+    let client_io: Arc<dyn MatrixClient> = match &connection {
+        Connection::Sync(client) => Arc::new(SdkClient(client.clone())),
+        Connection::Appservice(client, _) => Arc::new(client.clone()),
+    };
+    health.session_restored(Arc::clone(&client_io));
+
+    // Build plugin registry
+    let relay = Arc::new(Relay::default());
+    let verifier = Arc::new(Verifier::default());
+    let mut plugin_map = plugins::plugin_instances(&relay, &verifier);
+    // Each account starts its own external plugin processes, so stopping
+    // one account does not shut them down under the others.
+    plugins::add_external_plugins(config, &mut plugin_map).await;
+    if let Some(unknown) = account
+        .plugins
+        .iter()
+        .flatten()
+        .find(|id| !plugin_map.contains_key(id.as_str()))
+    {
+        return Err(anyhow!(
+            "account {}: unknown plugin {unknown}",
+            account.name
+        ));
+    }
+    let registry = plugins::build_registry(
+        config,
+        &account.store,
+        &plugin_map,
+        account.plugins.as_ref(),
+    )
+    .await;
+    let reloader = Arc::new(reload::ConfigReloader::new(
+        args.config.clone(),
+        plugin_map,
+        account.plugins.clone(),
+        &registry,
+        (config.dev_mode, config.dev_id.clone()),
+    ));
+    registry
+        .set_reloader(Arc::clone(&reloader) as Arc<dyn Reloader>)
+        .await;
+    if !args.no_config_watch {
+        reload::spawn_config_watcher(Arc::clone(&reloader));
+    }
+    reload::spawn_sighup_handler(Arc::clone(&reloader));
+    health.registry_built();
+    let history_dir = Arc::new(account.store.join("history"));
+    let lifecycle_registry = Arc::clone(&registry);
+    let lifecycle_history_dir = Arc::clone(&history_dir);
+    if primary && let (Some(listen), Some(token)) = (&args.admin_listen, &shared.admin_token) {
+        admin::spawn(
+            listen,
+            admin::AdminState {
+                registry: Arc::clone(&registry),
+                client: Arc::clone(&client_io),
+                relay,
+                history_dir: Arc::clone(&history_dir),
+                token: Arc::clone(token),
+            },
+        )
+        .await?;
+    }
+    let store = KvStore::in_dir(&account.store)?;
+    let lifecycle_store = store.clone();
+    let shutdown_store = store.clone();
+    let pipeline = MessagePipeline {
+        state: events::HandlerState {
+            registry: Arc::clone(&registry),
+            dev_active,
+            dev_id,
+            history_dir: Arc::clone(&history_dir),
+            store: store.clone(),
+        },
+        rate_limiter: Arc::new(RateLimiter::new()),
+        dispatcher: Arc::new(dispatch::Dispatcher::default()),
+    };
+    // Log registered plugin commands/mentions for visibility
+    let entries_for_log = registry.entries().await;
+    let mut mention_set = std::collections::BTreeSet::new();
+    let mut command_set = std::collections::BTreeSet::new();
+    for (_, entry) in &entries_for_log {
+        for cmd in &entry.spec.triggers.commands {
+            let normalized = if cmd.starts_with('!') {
+                cmd.clone()
+            } else {
+                format!("!{cmd}")
+            };
+            command_set.insert(normalized);
+        }
+        for mention in &entry.spec.triggers.mentions {
+            let raw = if mention.starts_with('@') {
+                mention.clone()
+            } else {
+                format!("@{mention}")
+            };
+            mention_set.insert(raw.to_lowercase());
+        }
+    }
+    let mention_keys: Vec<String> = mention_set.into_iter().collect();
+    let command_keys: Vec<String> = command_set.into_iter().collect();
+    info!(mentions = ?mention_keys, commands = ?command_keys, "Registered plugin triggers");
+
+    if *stop.borrow() {
+        return Ok(());
+    }
+    let events = match connection {
+        Connection::Sync(client) => {
+            let verify_mode = args.verify_mode.unwrap_or(if args.auto_verify {
+                VerifyMode::Auto
+            } else {
+                VerifyMode::Log
+            });
+            if verify_mode == VerifyMode::Operator {
+                let entry = registry
+                    .entry("verify")
+                    .await
+                    .context("--verify-mode operator needs the verify plugin")?;
+                plugin_verify::check_operator_mode(&entry.spec)?;
+            }
+            let verifications = Verifications::new(verify_mode, verifier, Arc::clone(&registry));
+            add_sync_handlers(&client, &pipeline, &verifications, args);
+            // Start syncing with configured timeout
+            info!(
+                timeout_ms = args.sync_timeout_ms,
+                "Starting sync… Press Ctrl+C to stop."
+            );
+            let settings = SyncSettings::new().timeout(Duration::from_millis(args.sync_timeout_ms));
+            let first = client.sync_once(settings.clone()).await;
+            health.record_sync(first.is_ok());
+            let first = first.map_err(|e| anyhow!("initial sync failed: {e}"))?;
+            EventSource::Sync(client, settings.token(first.next_batch))
+        }
+        Connection::Appservice(client, listener) => {
+            let config = account
+                .appservice
+                .as_ref()
+                .context("appservice settings vanished")?;
+            let app =
+                appservice::Appservice::new(config, client, pipeline.clone(), !args.no_autojoin);
+            health.appservice_started();
+            EventSource::Appservice(app, listener)
+        }
+    };
+    let lifecycle = Lifecycle::start(LifecycleContext {
+        client: Arc::clone(&client_io),
+        dev_active,
+        registry: Arc::clone(&lifecycle_registry),
+        history_dir: lifecycle_history_dir,
+        store: lifecycle_store,
+    })
+    .await;
+    reloader.set_tickers(&lifecycle.tickers());
+    let result = match events {
+        EventSource::Sync(client, settings) => tokio::select! {
+            res = client.sync_with_result_callback(settings, async |res| {
+                health.record_sync(res.is_ok());
+                res.map(|_| LoopCtrl::Continue)
+            }) => {
+                res.map_err(|e| anyhow!("sync terminated: {e}"))
+            }
+            _ = stop.wait_for(|stop| *stop) => Ok(()),
+        },
+        EventSource::Appservice(app, listener) => app.serve(listener, stop).await,
+    };
+    // Leaving the sync loop or listener stops new events; now let in-flight
+    // work finish.
+    let grace = Duration::from_secs(args.shutdown_timeout_secs);
+    info!(
+        grace_secs = args.shutdown_timeout_secs,
+        "Waiting for running plugins"
+    );
+    pipeline
+        .dispatcher
+        .drain(&lifecycle_registry, client_io.as_ref(), grace)
+        .await;
+    info!("Shutting down plugins");
+    lifecycle.shutdown().await;
+    if let Err(e) = shutdown_store.checkpoint().await {
+        warn!(error = %e, "Failed to checkpoint the plugin state store");
+    }
+    info!("Shutdown complete");
+    result
+}
+
+/// How an account reaches its homeserver.
+enum Connection {
+    Sync(Client),
+    Appservice(AsClient, TcpListener),
+}
+
+/// Where an account's events come from once its plugins are running.
+enum EventSource {
+    Sync(Client, SyncSettings),
+    Appservice(appservice::Appservice, TcpListener),
+}
+
+/// A client for `account` with its store, which persists E2EE state.
+/// It is not logged in yet.
+async fn build_client(account: &Account) -> Result<Client> {
+    Client::builder()
+        .homeserver_url(&account.homeserver)
         .handle_refresh_tokens()
-        .sqlite_store(&args.store, None)
+        .sqlite_store(&account.store, None)
+        // Fetch room keys from the backup when a message cannot be decrypted,
+        // e.g. after restoring with a recovery key on a new device.
+        .with_encryption_settings(EncryptionSettings {
+            backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
+            ..EncryptionSettings::default()
+        })
         .build()
         .await
-        .context("building matrix client")?;
+        .context("building matrix client")
+}
+
+/// Log `client` in with the account's session file, if there is one.
+async fn restore_session(client: &Client, account: &Account) -> Result<bool> {
+    let Some(session) = load_session(&account.session_file)? else {
+        return Ok(false);
+    };
+    info!("Restoring session for {}", session.user_id);
+    let matrix_session = MatrixSession {
+        meta: SessionMeta {
+            user_id: session.user_id.parse().context("invalid stored user_id")?,
+            device_id: session.device_id.into(),
+        },
+        tokens: SessionTokens {
+            access_token: session.access_token,
+            refresh_token: session.refresh_token,
+        },
+    };
+    client
+        .restore_session(matrix_session)
+        .await
+        .context("restoring session")?;
+    Ok(true)
+}
+
+/// Restore `account`'s saved session, or log in with its password and save
+/// a new one.
+async fn login(account: &Account) -> Result<Client> {
+    let client = build_client(account).await?;
 
     // Restore session if available; otherwise login
-    if let Some(session) = load_session(&args.session_file)? {
-        info!("Restoring session for {}", session.user_id);
-        let matrix_session = MatrixSession {
-            meta: SessionMeta {
-                user_id: session.user_id.parse().context("invalid stored user_id")?,
-                device_id: session.device_id.into(),
-            },
-            tokens: SessionTokens {
-                access_token: session.access_token,
-                refresh_token: session.refresh_token,
-            },
-        };
-        client
-            .restore_session(matrix_session)
-            .await
-            .context("restoring session")?;
-    } else {
+    if !restore_session(&client, account).await? {
         // Treat empty env/arg as missing; avoid prompting in non-interactive (Docker) mode.
-        let password = if let Some(p) = args
+        let password = if let Some(p) = account
             .password
             .as_deref()
             .map(str::trim)
@@ -178,8 +627,9 @@ async fn main() -> Result<()> {
         } else {
             if !std::io::stdin().is_terminal() {
                 return Err(anyhow!(
-                    "No MATRIX_PASSWORD provided and no stored session. In Docker/non-interactive mode, set MATRIX_PASSWORD env or mount an existing session at {}",
-                    args.session_file.display()
+                    "No password provided for account {} and no stored session. In Docker/non-interactive mode, set MATRIX_PASSWORD env or mount an existing session at {}",
+                    account.name,
+                    account.session_file.display()
                 ));
             }
             warn!("No password provided via --password or MATRIX_PASSWORD. Prompting...");
@@ -196,11 +646,11 @@ async fn main() -> Result<()> {
             }
         };
 
-        info!("Logging in as {}", args.username);
+        info!("Logging in as {}", account.username);
         let response = client
             .matrix_auth()
-            .login_username(&args.username, &password)
-            .initial_device_display_name(&args.device_name)
+            .login_username(&account.username, &password)
+            .initial_device_display_name(&account.device_name)
             .request_refresh_token()
             .send()
             .await
@@ -213,53 +663,35 @@ async fn main() -> Result<()> {
             user_id: response.user_id.to_string(),
             device_id: response.device_id.to_string(),
         };
-        save_session(&args.session_file, &session)?;
+        save_session(&account.session_file, &session)?;
         info!(
             "Logged in: user={} device={}",
             session.user_id, session.device_id
         );
     }
 
-    let config = load_config(&args.config)?;
-    let env_dev = matches!(args.mode.as_deref(), Some(m) if m.eq_ignore_ascii_case("dev"));
-    let dev_active = (args.dev || env_dev) && config.dev_mode.unwrap_or(false);
-    let dev_id = config.dev_id.as_ref().map(|s| Arc::<str>::from(s.as_str()));
-    if dev_active && dev_id.is_none() {
-        return Err(anyhow!(
-            "Dev mode requested but no dev_id provided in config.yaml"
-        ));
-    }
-    // Loud banner so mode is obvious at startup
-    print_mode_banner(dev_active, dev_id.as_deref());
-    // Build plugin registry
-    let registry = plugins::build_registry(&config).await;
-    let history_dir = Arc::new(args.store.join("history"));
-    // Log registered plugin commands/mentions for visibility
-    let entries_for_log = registry.entries().await;
-    let mut mention_set = std::collections::BTreeSet::new();
-    let mut command_set = std::collections::BTreeSet::new();
-    for (_, entry) in &entries_for_log {
-        for cmd in &entry.spec.triggers.commands {
-            let normalized = if cmd.starts_with('!') {
-                cmd.clone()
-            } else {
-                format!("!{cmd}")
-            };
-            command_set.insert(normalized);
-        }
-        for mention in &entry.spec.triggers.mentions {
-            let raw = if mention.starts_with('@') {
-                mention.clone()
-            } else {
-                format!("@{mention}")
-            };
-            mention_set.insert(raw.to_lowercase());
-        }
+    Ok(client)
+}
+
+/// Log `account` in and restore or set up its cross-signing and key backup
+/// if it has a recovery key file.
+async fn connect(account: &Account) -> Result<Client> {
+    let client = login(account).await?;
+    track_backup_uploads(&client);
+    if let Some(key_file) = &account.recovery_key_file {
+        recovery::set_up(&client, account, key_file).await?;
     }
-    let mention_keys: Vec<String> = mention_set.into_iter().collect();
-    let command_keys: Vec<String> = command_set.into_iter().collect();
-    info!(mentions = ?mention_keys, commands = ?command_keys, "Registered plugin triggers");
+    Ok(client)
+}
 
+/// Register the SDK event handlers that feed synced events to plugins, plus
+/// auto-join and SAS verification.
+fn add_sync_handlers(
+    client: &Client,
+    pipeline: &MessagePipeline,
+    verifications: &Verifications,
+    args: &Args,
+) {
     // Auto-join handler for invites
     if !args.no_autojoin {
         client.add_event_handler(
@@ -281,288 +713,482 @@ async fn main() -> Result<()> {
         );
     }
 
+    events::add_room_event_handlers(client, &pipeline.state, &pipeline.dispatcher);
+
     // Message handler: plugins + relay
-    client.add_event_handler(async move |ev: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
-        // Identify own user; do not early-return yet so we can record history even for own messages
-        let Some(own_id) = client.user_id() else { return; };
-        // Log incoming message details for diagnostics
-        let msg_kind = match &ev.content.msgtype {
-            MessageType::Text(_) => "text",
-            MessageType::Notice(_) => "notice",
-            MessageType::Emote(_) => "emote",
-            MessageType::Image(_) => "image",
-            MessageType::File(_) => "file",
-            MessageType::Audio(_) => "audio",
-            MessageType::Video(_) => "video",
-            MessageType::Location(_) | MessageType::ServerNotice(_) | MessageType::VerificationRequest(_) | _ => "other",
-        };
-        let body_snippet: Option<String> = match &ev.content.msgtype {
-            MessageType::Text(t) => Some(truncate(&t.body, 200)),
-            MessageType::Notice(n) => Some(truncate(&n.body, 200)),
-            MessageType::Emote(e) => Some(truncate(&e.body, 200)),
-            MessageType::Audio(_) | MessageType::File(_) | MessageType::Image(_) | MessageType::Location(_) | MessageType::ServerNotice(_) | MessageType::Video(_) | MessageType::VerificationRequest(_) | _ => None,
-        };
-        info!(room_id = %room.room_id(), sender = %ev.sender, kind = %msg_kind, body = ?body_snippet, "Incoming message");
+    let message_pipeline = pipeline.clone();
+    client.add_event_handler(
+        async move |ev: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+            handle_room_message(
+                &message_pipeline,
+                Arc::new(SdkClient(client)),
+                Arc::new(SdkRoom(room)),
+                ev,
+            )
+            .await;
+        },
+    );
 
-        // Plain text/notice messages; plugins by !command or @mention
-        let body_opt = match &ev.content.msgtype {
-            MessageType::Text(t) => Some(t.body.as_str()),
-            MessageType::Notice(n) => Some(n.body.as_str()),
-            MessageType::Audio(_) | MessageType::Emote(_) | MessageType::File(_) | MessageType::Image(_) | MessageType::Location(_) | MessageType::ServerNotice(_) | MessageType::Video(_) | MessageType::VerificationRequest(_) | _ => None,
-        };
-        let is_self = ev.sender == own_id;
-        let mut triggered_plugins: HashSet<String> = HashSet::new();
-
-        if !is_self && let Some(body) = body_opt.map(str::trim) {
-            let dev_id_opt = dev_id.as_deref();
-            // !command
-            if body.starts_with('!') {
-                let mut parts = body.splitn(2, ' ');
-                let cmd = parts.next().unwrap_or("");
-                let args_raw = parts.next().unwrap_or("").trim();
-                let (normalized_cmd, routing) = classify_command_token(cmd, dev_id_opt);
-                info!(cmd = %cmd, normalized_cmd = %normalized_cmd, route = ?routing, args = %args_raw, dev_active = dev_active, "Parsed command token");
-                if let Some(entry) = registry
-                    .entry_by_command(&normalized_cmd)
-                    .await
-                {
-                    let plugin_id = entry.spec.id.clone();
-                    let args_clean = args_raw.to_owned();
-                    match routing {
-                        DevRouting::OtherDev => {
-                            info!(plugin = %plugin_id, "Ignoring command targeted at different dev id");
-                        }
-                        DevRouting::Dev if !dev_active => {
-                            info!(plugin = %plugin_id, "Ignoring dev command in prod mode");
-                        }
-                        DevRouting::Prod if dev_active => {
-                            info!(plugin = %plugin_id, "Ignoring prod command in dev mode");
-                        }
-                        _ if entry
-                            .spec
-                            .dev_only
-                            .unwrap_or_else(|| entry.plugin.dev_only())
-                            && !dev_active =>
-                        {
-                            info!(plugin = %plugin_id, "Ignoring dev-only plugin in prod mode");
-                        }
-                        _ if !registry.is_enabled(&plugin_id).await => {
-                            info!(plugin = %plugin_id, "Plugin disabled");
-                        }
-                        DevRouting::Prod | DevRouting::Dev => {
-                            let ctx = PluginContext {
-                                client: client.clone(),
-                                room: room.clone(),
-                                dev_active,
-                                dev_id: dev_id.clone(),
-                                registry: Arc::clone(&registry),
-                                history_dir: Arc::clone(&history_dir),
-                            };
-                            if let Err(e) = entry.plugin.run(&ctx, &args_clean, &entry.spec).await {
-                                warn!(error = %e, plugin = %plugin_id, "Plugin failed");
-                            } else {
-                                triggered_plugins.insert(plugin_id.clone());
-                            }
-                        }
-                    }
-                }
+    // Emoji SAS verification handlers; `--verify-mode` decides who confirms.
+    if verifications.mode == VerifyMode::Auto {
+        warn!(
+            "SAS verifications are confirmed automatically; use --verify-mode operator in production"
+        );
+    }
+    let request_verifications = verifications.clone();
+    client.add_event_handler(async move |ev: ToDeviceKeyVerificationRequestEvent, client: Client| {
+            info!(user = %ev.sender, flow = %ev.content.transaction_id, "Received verification request");
+            if let Some(req) = client.encryption().get_verification_request(&ev.sender, &ev.content.transaction_id).await {
+                tokio::spawn(verification::handle_request(req, client, request_verifications.clone()));
+            } else {
+                warn!(user = %ev.sender, flow = %ev.content.transaction_id, "No verification request found");
             }
-            // @mention anywhere in the message (case-insensitive; tolerant of punctuation)
+    });
+
+    let room_verifications = verifications.clone();
+    client.add_event_handler(async move |ev: OriginalSyncRoomMessageEvent, client: Client| {
+        if let MessageType::VerificationRequest(_) = &ev.content.msgtype {
+            info!(user = %ev.sender, event = %ev.event_id, "Received in-room verification request");
+            if let Some(req) = client
+                .encryption()
+                .get_verification_request(&ev.sender, &ev.event_id)
+                .await
             {
-                let mut executed_mention = false;
-                for (token_idx, token_raw) in body.split_whitespace().enumerate() {
-                    debug!(token_idx, token_raw = token_raw);
-                    // Fast skip: tokens without '@' cannot be mentions
-                    if !token_raw.contains('@') {
-                        debug!(token_idx, token_raw = token_raw, "Skip: no @ in token");
-                        continue;
-                    }
+                tokio::spawn(verification::handle_request(req, client, room_verifications.clone()));
+            }
+        }
+    });
 
-                    // Trim leading and trailing punctuation that commonly wraps mentions
-                    let token_leading = token_raw
-                        .trim_start_matches(['(', '[', '{', '<', '"', '\'']);
-                    let mut token = token_leading
-                        .trim_end_matches([':', ',', '.', ';', '!', '?', '…', '—', '–', ')', ']', '}', '>', '"', '\'']);
-                    // Strip possessive suffixes like @ai's or @ai’s
-                    if let Some(t) = token.strip_suffix("'s").or_else(|| token.strip_suffix("’s")) {
-                        token = t;
-                    }
+    let start_verifications = verifications.clone();
+    client.add_event_handler(async move |ev: ToDeviceKeyVerificationStartEvent, client: Client| {
+        info!(user = %ev.sender, flow = %ev.content.transaction_id, "Received verification start");
+        if let Some(Verification::SasV1(sas)) = client
+            .encryption()
+            .get_verification(&ev.sender, ev.content.transaction_id.as_str())
+            .await
+        {
+            let flow_id = ev.content.transaction_id.to_string();
+            tokio::spawn(verification::handle_sas(sas, flow_id, client, start_verifications.clone()));
+        }
 
-                    // Only consider tokens that now begin with '@'
-                    if !token.starts_with('@') {
-                        debug!(token_idx, token = token, token_raw = token_raw, "Skip: token not starting with @ after trim");
-                        continue;
-                    }
+    });
+    // End emoji SAS handlers
+}
 
-                    let (normalized_mention, routing) = classify_mention_token(token, dev_id_opt);
-                    let key = normalized_mention.to_lowercase();
-                    debug!(token = token, dev_id_opt = dev_id_opt, key = key);
-                    info!(token_idx, token_raw = %token_raw, token = %token, normalized = %normalized_mention, key = %key, route = ?routing, "Checking mention token");
-                    let var_name =  registry
-                        .entry_by_mention(&key)
-                        .await;
-                    debug!(pass = var_name.is_some(), "Mention lookup (reg: {:#?})", registry);
+/// What the message pipeline needs besides the event; shared by the sync
+/// loop and appservice mode.
+#[derive(Debug, Clone)]
+pub(crate) struct MessagePipeline {
+    pub(crate) state: events::HandlerState,
+    pub(crate) rate_limiter: Arc<RateLimiter>,
+    pub(crate) dispatcher: Arc<dispatch::Dispatcher>,
+}
 
-                    if let Some(entry) = var_name
-                    {
-                        let plugin_id = entry.spec.id.clone();
-                        info!(token_idx, plugin = %plugin_id, "Mention matched");
-                        // Use the FULL body as the prompt so earlier words are preserved
-                        // (the AI can see the initiator and routing prefix as part of the message)
-                        let args_source = body;
-
-                        // Evaluate gating; continue scanning if not allowed
-                        let blocked = match routing {
-                            DevRouting::OtherDev => {
-                                info!(token_idx, plugin = %plugin_id, reason = "other-dev", "Ignoring mention");
-                                true
-                            }
-                            DevRouting::Dev if !dev_active => {
-                                info!(token_idx, plugin = %plugin_id, reason = "dev-in-prod", "Ignoring mention");
-                                true
-                            }
-                            DevRouting::Prod if dev_active => {
-                                info!(token_idx, plugin = %plugin_id, reason = "prod-in-dev", "Ignoring mention");
-                                true
-                            }
-                            _ if entry
-                                .spec
-                                .dev_only
-                                .unwrap_or_else(|| entry.plugin.dev_only())
-                                && !dev_active =>
-                            {
-                                info!(token_idx, plugin = %plugin_id, reason = "dev-only-in-prod", "Ignoring mention");
-                                true
-                            }
-                            _ if !registry.is_enabled(&plugin_id).await => {
-                                info!(token_idx, plugin = %plugin_id, reason = "disabled", "Ignoring mention");
-                                true
-                            }
-                            DevRouting::Prod | DevRouting::Dev => false,
-                        };
+/// Run plugins for a room message: `!commands`, `@mentions`, then passive
+/// plugins such as the relay.
+pub(crate) async fn handle_room_message(
+    pipeline: &MessagePipeline,
+    client_io: Arc<dyn MatrixClient>,
+    room_io: Arc<dyn MatrixRoom>,
+    ev: OriginalSyncRoomMessageEvent,
+) {
+    // Identify own user; do not early-return yet so we can record history even for own messages
+    if client_io.user_id().is_none() {
+        return;
+    }
+    let events::HandlerState {
+        registry,
+        dev_id,
+        history_dir,
+        store,
+        ..
+    } = &pipeline.state;
+    let dev_active = pipeline.state.dev_active;
+    let MessagePipeline {
+        rate_limiter,
+        dispatcher,
+        ..
+    } = pipeline;
+    // Log incoming message details for diagnostics
+    let msg_kind = match &ev.content.msgtype {
+        MessageType::Text(_) => "text",
+        MessageType::Notice(_) => "notice",
+        MessageType::Emote(_) => "emote",
+        MessageType::Image(_) => "image",
+        MessageType::File(_) => "file",
+        MessageType::Audio(_) => "audio",
+        MessageType::Video(_) => "video",
+        MessageType::Location(_)
+        | MessageType::ServerNotice(_)
+        | MessageType::VerificationRequest(_)
+        | _ => "other",
+    };
+    let body_snippet: Option<String> = match &ev.content.msgtype {
+        MessageType::Text(t) => Some(truncate(&t.body, 200)),
+        MessageType::Notice(n) => Some(truncate(&n.body, 200)),
+        MessageType::Emote(e) => Some(truncate(&e.body, 200)),
+        MessageType::Audio(_)
+        | MessageType::File(_)
+        | MessageType::Image(_)
+        | MessageType::Location(_)
+        | MessageType::ServerNotice(_)
+        | MessageType::Video(_)
+        | MessageType::VerificationRequest(_)
+        | _ => None,
+    };
+    info!(room_id = %room_io.room_id(), sender = %ev.sender, kind = %msg_kind, body = ?body_snippet, "Incoming message");
 
-                        if blocked {
-                            continue; // keep scanning for a later valid mention
-                        }
+    let ev = Arc::new(ev);
+
+    // Plain text/notice messages; plugins by !command or @mention
+    let body_opt = match &ev.content.msgtype {
+        MessageType::Text(t) => Some(t.body.as_str()),
+        MessageType::Notice(n) => Some(n.body.as_str()),
+        MessageType::Audio(_)
+        | MessageType::Emote(_)
+        | MessageType::File(_)
+        | MessageType::Image(_)
+        | MessageType::Location(_)
+        | MessageType::ServerNotice(_)
+        | MessageType::Video(_)
+        | MessageType::VerificationRequest(_)
+        | _ => None,
+    };
+    let is_self = client_io.is_own_user(&ev.sender);
+    let room_ref = RoomRef::from_room(room_io.as_ref());
+    let mut triggered_plugins: HashSet<String> = HashSet::new();
 
+    if !is_self && let Some(body) = body_opt.map(str::trim) {
+        let dev_id_opt = dev_id.as_deref();
+        // !command
+        if body.starts_with('!') {
+            let mut parts = body.splitn(2, ' ');
+            let cmd = parts.next().unwrap_or("");
+            let args_raw = parts.next().unwrap_or("").trim();
+            let (normalized_cmd, routing) = classify_command_token(cmd, dev_id_opt);
+            info!(cmd = %cmd, normalized_cmd = %normalized_cmd, route = ?routing, args = %args_raw, dev_active = dev_active, "Parsed command token");
+            if let Some(entry) = registry.entry_by_command(&normalized_cmd).await {
+                let plugin_id = entry.spec.id.clone();
+                let args_clean = args_raw.to_owned();
+                match routing {
+                    DevRouting::OtherDev => {
+                        info!(plugin = %plugin_id, "Ignoring command targeted at different dev id");
+                    }
+                    DevRouting::Dev if !dev_active => {
+                        info!(plugin = %plugin_id, "Ignoring dev command in prod mode");
+                    }
+                    DevRouting::Prod if dev_active => {
+                        info!(plugin = %plugin_id, "Ignoring prod command in dev mode");
+                    }
+                    _ if entry
+                        .spec
+                        .dev_only
+                        .unwrap_or_else(|| entry.plugin.dev_only())
+                        && !dev_active =>
+                    {
+                        info!(plugin = %plugin_id, "Ignoring dev-only plugin in prod mode");
+                    }
+                    _ if !registry.is_enabled_in(&plugin_id, &room_ref).await => {
+                        info!(plugin = %plugin_id, "Plugin disabled");
+                    }
+                    DevRouting::Prod | DevRouting::Dev => {
                         let ctx = PluginContext {
-                            client: client.clone(),
-                            room: room.clone(),
+                            client: Arc::clone(&client_io),
+                            room: Arc::clone(&room_io),
+                            event: Some(Arc::clone(&ev)),
                             dev_active,
                             dev_id: dev_id.clone(),
-                            registry: Arc::clone(&registry),
-                            history_dir: Arc::clone(&history_dir),
+                            registry: Arc::clone(registry),
+                            history_dir: Arc::clone(history_dir),
+                            store: store.clone(),
                         };
-                        if let Err(e) = entry.plugin.run(&ctx, args_source, &entry.spec).await {
-                            warn!(error = %e, plugin = %plugin_id, "Plugin failed");
+                        let subcommand = args_clean.split_whitespace().next();
+                        if !authorize(&ctx, &entry, &ev.sender, subcommand).await {
+                            // Denial already replied to and audited.
+                        } else if !admit(&ctx, &entry, rate_limiter, &ev).await {
+                            // Throttled; the sender has been told if configured.
                         } else {
+                            dispatch::spawn_run(ctx, entry, args_clean, Trigger::Command);
                             triggered_plugins.insert(plugin_id.clone());
-                            executed_mention = true;
                         }
-                        // Handle only the first mention that actually targets this instance
-                        break;
                     }
                 }
-                if !executed_mention {
-                    debug!("No actionable mention found in message");
-                }
             }
         }
-
-        let meta = RoomMessageMeta {
-            body: body_opt,
-            triggered_plugins: &triggered_plugins,
-        };
-
-        // Passive plugins (e.g., relay)
-        let passive_entries = registry.entries().await;
-        if !passive_entries.is_empty() {
-            let base_ctx = PluginContext {
-                client: client.clone(),
-                room: room.clone(),
-                dev_active,
-                dev_id: dev_id.clone(),
-                registry: Arc::clone(&registry),
-                history_dir: Arc::clone(&history_dir),
-            };
-
-            for (plugin_id, entry) in passive_entries {
-                if !entry.plugin.handles_room_messages() {
-                    continue;
-                }
-                if is_self && !entry.plugin.wants_own_messages() {
+        // @mention anywhere in the message (case-insensitive; tolerant of punctuation)
+        {
+            let mut executed_mention = false;
+            for (token_idx, token_raw) in body.split_whitespace().enumerate() {
+                debug!(token_idx, token_raw = token_raw);
+                // Fast skip: tokens without '@' cannot be mentions
+                if !token_raw.contains('@') {
+                    debug!(token_idx, token_raw = token_raw, "Skip: no @ in token");
                     continue;
                 }
-                if entry
-                    .spec
-                    .dev_only
-                    .unwrap_or_else(|| entry.plugin.dev_only())
-                    && !dev_active
+
+                // Trim leading and trailing punctuation that commonly wraps mentions
+                let token_leading = token_raw.trim_start_matches(['(', '[', '{', '<', '"', '\'']);
+                let mut token = token_leading.trim_end_matches([
+                    ':', ',', '.', ';', '!', '?', '…', '—', '–', ')', ']', '}', '>', '"', '\'',
+                ]);
+                // Strip possessive suffixes like @ai's or @ai’s
+                if let Some(t) = token
+                    .strip_suffix("'s")
+                    .or_else(|| token.strip_suffix("’s"))
                 {
-                    continue;
+                    token = t;
                 }
-                if !registry.is_enabled(&plugin_id).await {
+
+                // Only consider tokens that now begin with '@'
+                if !token.starts_with('@') {
+                    debug!(
+                        token_idx,
+                        token = token,
+                        token_raw = token_raw,
+                        "Skip: token not starting with @ after trim"
+                    );
                     continue;
                 }
-                if let Err(e) = entry
-                    .plugin
-                    .on_room_message(&base_ctx, &ev, &entry.spec, &meta)
-                    .await
-                {
-                    warn!(error = %e, plugin = %plugin_id, "Plugin on_room_message failed");
+
+                let (normalized_mention, routing) = classify_mention_token(token, dev_id_opt);
+                let key = normalized_mention.to_lowercase();
+                debug!(token = token, dev_id_opt = dev_id_opt, key = key);
+                info!(token_idx, token_raw = %token_raw, token = %token, normalized = %normalized_mention, key = %key, route = ?routing, "Checking mention token");
+                let var_name = registry.entry_by_mention(&key).await;
+                debug!(
+                    pass = var_name.is_some(),
+                    "Mention lookup (reg: {:#?})", registry
+                );
+
+                if let Some(entry) = var_name {
+                    let plugin_id = entry.spec.id.clone();
+                    info!(token_idx, plugin = %plugin_id, "Mention matched");
+                    // Use the FULL body as the prompt so earlier words are preserved
+                    // (the AI can see the initiator and routing prefix as part of the message)
+                    let args_source = body;
+
+                    // Evaluate gating; continue scanning if not allowed
+                    let blocked = match routing {
+                        DevRouting::OtherDev => {
+                            info!(token_idx, plugin = %plugin_id, reason = "other-dev", "Ignoring mention");
+                            true
+                        }
+                        DevRouting::Dev if !dev_active => {
+                            info!(token_idx, plugin = %plugin_id, reason = "dev-in-prod", "Ignoring mention");
+                            true
+                        }
+                        DevRouting::Prod if dev_active => {
+                            info!(token_idx, plugin = %plugin_id, reason = "prod-in-dev", "Ignoring mention");
+                            true
+                        }
+                        _ if entry
+                            .spec
+                            .dev_only
+                            .unwrap_or_else(|| entry.plugin.dev_only())
+                            && !dev_active =>
+                        {
+                            info!(token_idx, plugin = %plugin_id, reason = "dev-only-in-prod", "Ignoring mention");
+                            true
+                        }
+                        _ if !registry.is_enabled_in(&plugin_id, &room_ref).await => {
+                            info!(token_idx, plugin = %plugin_id, reason = "disabled", "Ignoring mention");
+                            true
+                        }
+                        DevRouting::Prod | DevRouting::Dev => false,
+                    };
+
+                    if blocked {
+                        continue; // keep scanning for a later valid mention
+                    }
+
+                    let ctx = PluginContext {
+                        client: Arc::clone(&client_io),
+                        room: Arc::clone(&room_io),
+                        event: Some(Arc::clone(&ev)),
+                        dev_active,
+                        dev_id: dev_id.clone(),
+                        registry: Arc::clone(registry),
+                        history_dir: Arc::clone(history_dir),
+                        store: store.clone(),
+                    };
+                    if !authorize(&ctx, &entry, &ev.sender, None).await
+                        || !admit(&ctx, &entry, rate_limiter, &ev).await
+                    {
+                        break;
+                    }
+                    dispatch::spawn_run(ctx, entry, args_source.to_owned(), Trigger::Mention);
+                    triggered_plugins.insert(plugin_id.clone());
+                    executed_mention = true;
+                    // Handle only the first mention that actually targets this instance
+                    break;
                 }
             }
+            if !executed_mention {
+                debug!("No actionable mention found in message");
+            }
         }
-    });
+    }
 
-    // Emoji SAS verification handlers (print emojis to console). If auto_verify is true,
-    // we will auto-confirm once emojis are shown.
-    let auto_confirm = args.auto_verify;
-    client.add_event_handler(async move |ev: ToDeviceKeyVerificationRequestEvent, client: Client| {
-            info!(user = %ev.sender, flow = %ev.content.transaction_id, "Received verification request");
-            if let Some(req) = client.encryption().get_verification_request(&ev.sender, &ev.content.transaction_id).await {
-                tokio::spawn(handle_verification_request(req, auto_confirm));
-            } else {
-                warn!(user = %ev.sender, flow = %ev.content.transaction_id, "No verification request found");
-            }
-    });
+    let triggered_plugins = Arc::new(triggered_plugins);
 
-    client.add_event_handler(async move |ev: OriginalSyncRoomMessageEvent, client: Client| {
-        if let MessageType::VerificationRequest(_) = &ev.content.msgtype {
-            info!(user = %ev.sender, event = %ev.event_id, "Received in-room verification request");
-            if let Some(req) = client
-                .encryption()
-                .get_verification_request(&ev.sender, &ev.event_id)
-                .await
+    // Passive plugins (e.g., relay)
+    let passive_entries = registry.entries().await;
+    if !passive_entries.is_empty() {
+        let base_ctx = PluginContext {
+            client: Arc::clone(&client_io),
+            room: Arc::clone(&room_io),
+            event: Some(Arc::clone(&ev)),
+            dev_active,
+            dev_id: dev_id.clone(),
+            registry: Arc::clone(registry),
+            history_dir: Arc::clone(history_dir),
+            store: store.clone(),
+        };
+
+        for (plugin_id, entry) in passive_entries {
+            if !entry.plugin.handles_room_messages() {
+                continue;
+            }
+            if is_self && !entry.plugin.wants_own_messages() {
+                continue;
+            }
+            if entry
+                .spec
+                .dev_only
+                .unwrap_or_else(|| entry.plugin.dev_only())
+                && !dev_active
             {
-                tokio::spawn(handle_verification_request(req, auto_confirm));
+                continue;
+            }
+            if !registry.is_enabled_in(&plugin_id, &room_ref).await {
+                continue;
             }
+            dispatcher.queue_room_message(
+                base_ctx.clone(),
+                entry,
+                Arc::clone(&ev),
+                Arc::clone(&triggered_plugins),
+            );
         }
-    });
-
-    client.add_event_handler(async move |ev: ToDeviceKeyVerificationStartEvent, client: Client| {
-        info!(user = %ev.sender, flow = %ev.content.transaction_id, "Received verification start");
-        if let Some(Verification::SasV1(sas)) = client
-            .encryption()
-            .get_verification(&ev.sender, ev.content.transaction_id.as_str())
-            .await
-        {
-            tokio::spawn(handle_sas(sas, auto_confirm));
+        if let Some(edit) = events::QueuedRoomEvent::edit(Arc::clone(&ev)) {
+            events::dispatch_room_event(&base_ctx, dispatcher, edit).await;
         }
+    }
+}
 
-    });
-    // End emoji SAS handlers
+/// Evaluate the plugin's permission rules for `sender`.
+///
+/// Denied attempts are answered in the room and recorded under the `audit` log
+/// target. Returns whether the invocation may proceed.
+async fn authorize(
+    ctx: &PluginContext,
+    entry: &PluginEntry,
+    sender: &UserId,
+    subcommand: Option<&str>,
+) -> bool {
+    let plugin_id = entry.spec.id.as_str();
+    let rules = match PermissionRules::from_spec(&entry.spec) {
+        Ok(Some(rules)) => rules,
+        Ok(None) => return true,
+        Err(e) => {
+            // Fail closed: a broken rule set must not silently grant access.
+            warn!(error = %e, plugin = %plugin_id, "Invalid permission rules; denying");
+            let _ = send_text(
+                ctx,
+                format!("⛔ {plugin_id}: permission rules are misconfigured"),
+            )
+            .await;
+            return false;
+        }
+    };
+    let power_level = if rules.needs_power_level(subcommand) {
+        member_power_level(ctx.room.as_ref(), sender).await
+    } else {
+        0
+    };
+    let Err(denial) = rules.check_invocation(sender, power_level, subcommand) else {
+        return true;
+    };
+    let target = denial
+        .subcommand
+        .as_deref()
+        .map_or_else(|| plugin_id.to_owned(), |sub| format!("{plugin_id} {sub}"));
+    warn!(
+        target: "audit",
+        plugin = %plugin_id,
+        subcommand = ?denial.subcommand,
+        sender = %sender,
+        room_id = %ctx.room.room_id(),
+        reason = %denial.reason,
+        "Permission denied"
+    );
+    let _ = send_text(
+        ctx,
+        format!(
+            "⛔ {sender} is not allowed to use {target}: {}",
+            denial.reason
+        ),
+    )
+    .await;
+    false
+}
 
-    // Start syncing with configured timeout
+/// Apply the plugin's rate limits to this invocation.
+///
+/// Only the first throttled attempt in a row is answered, so spamming a
+/// limited command does not make the bot spam back. Returns whether the
+/// invocation may proceed.
+async fn admit(
+    ctx: &PluginContext,
+    entry: &PluginEntry,
+    limiter: &RateLimiter,
+    ev: &OriginalSyncRoomMessageEvent,
+) -> bool {
+    let plugin_id = entry.spec.id.as_str();
+    let rules = match RateLimitRules::from_spec(&entry.spec) {
+        Ok(Some(rules)) => rules,
+        Ok(None) => return true,
+        Err(e) => {
+            // Fail closed like the permission rules. Loading the config
+            // already rejects these, so this should not happen.
+            warn!(error = %e, plugin = %plugin_id, "Invalid rate_limit config; denying");
+            let _ = send_text(ctx, format!("⛔ {plugin_id}: rate_limit is misconfigured")).await;
+            return false;
+        }
+    };
+    let Err(throttled) = limiter.check(plugin_id, &rules, &ev.sender, ctx.room.room_id()) else {
+        return true;
+    };
     info!(
-        timeout_ms = args.sync_timeout_ms,
-        "Starting sync… Press Ctrl+C to stop."
+        plugin = %plugin_id,
+        sender = %ev.sender,
+        room_id = %ctx.room.room_id(),
+        scope = ?throttled.scope,
+        retry_after_secs = throttled.retry_after.as_secs(),
+        "Rate limited"
     );
-    let settings = SyncSettings::new().timeout(Duration::from_millis(args.sync_timeout_ms));
-    client
-        .sync(settings)
-        .await
-        .map_err(|e| anyhow!("sync terminated: {e}"))
+    if !throttled.first {
+        return false;
+    }
+    let result = match rules.notify {
+        ThrottleNotice::Reply => {
+            send_text(
+                ctx,
+                format!(
+                    "⏳ {plugin_id} is rate limited {}; try again in {}s",
+                    throttled.scope,
+                    throttled.retry_after.as_secs().max(1)
+                ),
+            )
+            .await
+        }
+        ThrottleNotice::React => react(ctx, "⏳").await.map(|_| ()),
+        ThrottleNotice::Silent => Ok(()),
+    };
+    if let Err(e) = result {
+        warn!(error = %e, plugin = %plugin_id, "Failed to send throttle notice");
+    }
+    false
 }
 
 fn load_config(path: &PathBuf) -> Result<BotConfig> {
@@ -574,7 +1200,8 @@ fn load_config(path: &PathBuf) -> Result<BotConfig> {
     }
     let yaml = fs::read_to_string(path)
         .with_context(|| format!("reading config file at {}", path.display()))?;
-    let cfg: BotConfig = serde_yaml::from_str(&yaml).context("parsing YAML config")?;
+    let value = interpolate::parse(&yaml).context("parsing YAML config")?;
+    let cfg: BotConfig = serde_path_to_error::deserialize(value).context("parsing YAML config")?;
     Ok(cfg)
 }
 
@@ -618,83 +1245,6 @@ fn print_mode_banner(dev_active: bool, dev_id: Option<&str>) {
     }
 }
 
-async fn handle_verification_request(request: VerificationRequest, auto_confirm: bool) {
-    info!(user = %request.other_user_id(), "Accepting verification request");
-    if let Err(e) = request.accept().await {
-        warn!(error = %e, "Failed to accept verification request");
-        return;
-    }
-    let mut stream = request.changes();
-    while let Some(state) = stream.next().await {
-        match state {
-            VerificationRequestState::Transitioned { verification } => {
-                if let Some(sas) = verification.sas() {
-                    tokio::spawn(handle_sas(sas, auto_confirm));
-                }
-                break;
-            }
-            VerificationRequestState::Cancelled(info) => {
-                warn!(reason = %info.reason(), "Verification cancelled (request stage)");
-                break;
-            }
-            VerificationRequestState::Done => {
-                info!("Verification already done at request stage");
-                break;
-            }
-            VerificationRequestState::Created { .. }
-            | VerificationRequestState::Requested { .. }
-            | VerificationRequestState::Ready { .. } => {}
-        }
-    }
-}
-
-async fn handle_sas(sas: SasVerification, auto_confirm: bool) {
-    info!(user = %sas.other_device().user_id(), device = %sas.other_device().device_id(), "Starting SAS verification");
-    if let Err(e) = sas.accept().await {
-        warn!(error = %e, "Failed to accept SAS");
-        return;
-    }
-
-    let mut stream = sas.changes();
-    while let Some(state) = stream.next().await {
-        match state.clone() {
-            SasState::KeysExchanged {
-                emojis: Some(e), ..
-            } => {
-                let emoji_string = e
-                    .emojis
-                    .iter()
-                    .map(|em| em.symbol)
-                    .collect::<Vec<_>>()
-                    .join(" ");
-                let descriptions = e
-                    .emojis
-                    .iter()
-                    .map(|em| em.description)
-                    .collect::<Vec<_>>()
-                    .join(" ");
-                debug!("SAS emojis: {emoji_string}\nSAS names:  {descriptions}");
-                if auto_confirm && let Err(e) = sas.confirm().await {
-                    warn!(error = %e, "Failed to confirm SAS");
-                }
-            }
-            SasState::Done { .. } => {
-                info!("Verification completed");
-                break;
-            }
-            SasState::Cancelled(info) => {
-                warn!(reason = %info.reason(), "Verification cancelled (SAS stage)");
-                break;
-            }
-            SasState::Created { .. }
-            | SasState::Started { .. }
-            | SasState::Accepted { .. }
-            | SasState::KeysExchanged { .. }
-            | SasState::Confirmed => {}
-        }
-    }
-}
-
 fn load_session(path: &PathBuf) -> Result<Option<SavedSession>> {
     if !path.exists() {
         return Ok(None);
@@ -1,34 +1,115 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::{BotConfig, RoomCluster};
-use plugin_core::{Plugin, PluginRegistry, PluginSpec, PluginTriggers};
+use anyhow::{Context as _, Result};
+
+use crate::{BotConfig, RoomCluster, interpolate};
+use plugin_core::{
+    Plugin, PluginRegistry, PluginSpec, PluginTriggers, RateLimitRules, SettingsStore,
+};
+use plugin_external::ExternalPlugin;
 use plugin_relay::{Relay, RelayConfig};
+use plugin_verify::Verifier;
 use tracing::{info, warn};
 
-pub async fn build_registry(config: &BotConfig) -> Arc<PluginRegistry> {
-    // Build a map of plugin id -> instance. Plugins are stateless; one instance is fine.
+pub type PluginMap = HashMap<&'static str, Arc<dyn Plugin + Send + Sync>>;
+
+/// Build a map of plugin id -> instance. Instances are kept for the life of the
+/// process so config reloads can invalidate their caches instead of losing them.
+/// The relay is passed in so the admin API can inspect its plan, the verifier
+/// so the SAS handlers can wait for `!verify`.
+pub fn plugin_instances(relay: &Arc<Relay>, verifier: &Arc<Verifier>) -> PluginMap {
     #[rustfmt::skip]
-    let plugins: HashMap<&'static str, Arc<dyn Plugin + Send + Sync>> = HashMap::from([
+    let plugins: PluginMap = HashMap::from([
         ("ping", Arc::new(plugin_ping::Ping) as Arc<dyn Plugin + Send + Sync>),
         ("mode", Arc::new(plugin_mode::ModeTool) as Arc<dyn Plugin + Send + Sync>),
         ("diag", Arc::new(plugin_diagnostics::DiagTool) as Arc<dyn Plugin + Send + Sync>),
         ("tools", Arc::new(plugin_tools_manager::ToolsManager) as Arc<dyn Plugin + Send + Sync>),
         ("ai", Arc::new(plugin_ai::AiTool) as Arc<dyn Plugin + Send + Sync>),
+        ("cancel", Arc::new(plugin_cancel::CancelTool) as Arc<dyn Plugin + Send + Sync>),
         ("echo", Arc::new(plugin_echo::EchoTool) as Arc<dyn Plugin + Send + Sync>),
-        ("relay", Arc::new(Relay::default()) as Arc<dyn Plugin + Send + Sync>),
+        ("help", Arc::new(plugin_help::HelpTool) as Arc<dyn Plugin + Send + Sync>),
+        ("relay", Arc::clone(relay) as Arc<dyn Plugin + Send + Sync>),
+        ("verify", Arc::clone(verifier) as Arc<dyn Plugin + Send + Sync>),
     ]);
+    plugins
+}
+
+/// Start the processes listed under `external_plugins` and add them to
+/// `plugins`. A plugin that fails to start is logged and left out.
+pub async fn add_external_plugins(config: &BotConfig, plugins: &mut PluginMap) {
+    for external in &config.external_plugins {
+        if plugins.contains_key(external.id.as_str()) {
+            warn!(plugin = %external.id, "External plugin ID is already taken; skipping it");
+            continue;
+        }
+        match ExternalPlugin::spawn(external).await {
+            Ok(plugin) => {
+                info!(plugin = %external.id, command = %external.command, "Started external plugin");
+                plugins.insert(plugin.id(), Arc::new(plugin));
+            }
+            Err(e) => {
+                warn!(plugin = %external.id, error = %format!("{e:#}"), "Failed to start external plugin");
+            }
+        }
+    }
+}
+
+/// Plugin specs resolved from `config.yaml` and `plugins/<id>/config.yaml`.
+#[derive(Debug)]
+pub struct ResolvedPlugins {
+    pub entries: Vec<(PluginSpec, Arc<dyn Plugin + Send + Sync>)>,
+    pub clusters: Vec<(String, Vec<String>)>,
+    /// Specs whose ID matches no plugin; they were skipped.
+    pub unknown_ids: Vec<String>,
+    /// Plugin config files that were skipped, e.g. for YAML errors.
+    pub problems: Vec<String>,
+}
+
+impl ResolvedPlugins {
+    /// Drop the plugins whose `rate_limit` is invalid and describe each, so a
+    /// broken limit never runs a plugin unthrottled.
+    pub fn reject_invalid_rate_limits(&mut self) -> Vec<String> {
+        let mut rejected = Vec::new();
+        self.entries
+            .retain(|(spec, _)| match RateLimitRules::from_spec(spec) {
+                Ok(_) => true,
+                Err(e) => {
+                    rejected.push(format!("{e:#}"));
+                    false
+                }
+            });
+        rejected
+    }
+}
 
+/// Resolve the specs for every plugin in `plugins`, or only for the IDs in
+/// `only` when an account limits its plugin set.
+pub fn resolve_plugins(
+    config: &BotConfig,
+    plugins: &PluginMap,
+    only: Option<&HashSet<String>>,
+) -> ResolvedPlugins {
     let mut specs = config.plugins.clone().unwrap_or_default();
 
     // Inject relay plugin configuration if clusters are defined and no explicit spec exists.
-    info!(clusters_count = config.clusters.len(), "Checking relay config");
+    info!(
+        clusters_count = config.clusters.len(),
+        "Checking relay config"
+    );
     if !specs.iter().any(|s| s.id == "relay") && !config.clusters.is_empty() {
         let relay_config = RelayConfig {
             clusters: config.clusters.iter().map(cluster_from_bot).collect(),
             reupload_media: config.reupload_media,
             caption_media: config.caption_media,
         };
-        info!(relay_clusters = relay_config.clusters.len(), "Creating relay spec");
+        info!(
+            relay_clusters = relay_config.clusters.len(),
+            "Creating relay spec"
+        );
         let config_value = serde_yaml::to_value(relay_config).unwrap_or_default();
         let mut relay_spec = PluginSpec {
             id: "relay".to_owned(),
@@ -52,32 +133,103 @@ pub async fn build_registry(config: &BotConfig) -> Arc<PluginRegistry> {
         merge_default_spec(&mut specs, p.spec());
     }
 
-    let registry = Arc::new(PluginRegistry::new());
-    let default_dir = if std::path::Path::new("./plugins").exists() {
-        "./plugins".to_owned()
-    } else {
-        "./tools".to_owned()
-    };
-    let plugins_dir = std::env::var("PLUGINS_DIR")
-        .or_else(|_| std::env::var("TOOLS_DIR"))
-        .unwrap_or(default_dir);
+    if let Some(only) = only {
+        specs.retain(|spec| only.contains(&spec.id));
+    }
 
+    let plugins_dir = plugins_dir();
+    let mut entries = Vec::with_capacity(specs.len());
+    let mut unknown_ids = Vec::new();
+    let mut problems = Vec::new();
     for mut spec in specs {
         let Some(plugin) = plugins.get(spec.id.as_str()) else {
-            warn!("Unknown plugin ID: {}", spec.id);
+            unknown_ids.push(spec.id);
             continue;
         };
-        if let Some(file_cfg) = load_plugin_config(&plugins_dir, spec.id.as_str()) {
-            spec.config = merge_yaml(file_cfg, spec.config);
+        match load_plugin_config(&plugins_dir, spec.id.as_str()) {
+            Ok(Some(file_cfg)) => spec.config = merge_yaml(file_cfg, spec.config),
+            Ok(None) => {}
+            Err(e) => problems.push(format!("{e:#}")),
         }
-        registry.register(spec, Arc::clone(plugin)).await;
+        entries.push((spec, Arc::clone(plugin)));
+    }
+
+    ResolvedPlugins {
+        entries,
+        clusters: cluster_names(config),
+        unknown_ids,
+        problems,
+    }
+}
+
+pub async fn build_registry(
+    config: &BotConfig,
+    store_dir: &Path,
+    plugins: &PluginMap,
+    only: Option<&HashSet<String>>,
+) -> Arc<PluginRegistry> {
+    let mut resolved = resolve_plugins(config, plugins, only);
+    for id in &resolved.unknown_ids {
+        warn!("Unknown plugin ID: {id}");
+    }
+    for problem in resolved.reject_invalid_rate_limits() {
+        warn!("{problem}; not loading the plugin");
+    }
+    for problem in &resolved.problems {
+        warn!("{problem}");
+    }
+
+    let registry = Arc::new(PluginRegistry::new());
+    registry
+        .replace_plugins(resolved.entries, resolved.clusters)
+        .await;
+
+    // Overrides set at runtime (e.g. `!tools disable`) outlive restarts.
+    let settings = SettingsStore::in_dir(store_dir);
+    match registry.attach_store(settings.clone()).await {
+        Ok(()) => info!(file = %settings.path().display(), "Loaded persisted plugin overrides"),
+        Err(e) => warn!(
+            error = %e,
+            file = %settings.path().display(),
+            "Failed to load persisted plugin overrides; runtime changes will not be saved"
+        ),
     }
 
     registry
 }
 
+/// Directory holding per-plugin `<id>/config.yaml` files.
+pub fn plugins_dir() -> String {
+    let default_dir = if std::path::Path::new("./plugins").exists() {
+        "./plugins".to_owned()
+    } else {
+        "./tools".to_owned()
+    };
+    std::env::var("PLUGINS_DIR")
+        .or_else(|_| std::env::var("TOOLS_DIR"))
+        .unwrap_or(default_dir)
+}
+
+/// Name each configured cluster for scoped overrides; unnamed clusters are
+/// numbered from 1 in config order.
+fn cluster_names(config: &BotConfig) -> Vec<(String, Vec<String>)> {
+    config
+        .clusters
+        .iter()
+        .enumerate()
+        .map(|(idx, cluster)| {
+            let name = cluster
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("cluster-{}", idx + 1));
+            (name, cluster.rooms.clone())
+        })
+        .collect()
+}
+
 fn cluster_from_bot(cluster: &RoomCluster) -> plugin_relay::RelayCluster {
     plugin_relay::RelayCluster {
+        name: cluster.name.clone(),
         rooms: cluster.rooms.clone(),
         reupload_media: cluster.reupload_media,
         caption_media: cluster.caption_media,
@@ -109,24 +261,21 @@ fn merge_yaml(file_cfg: serde_yaml::Value, spec_cfg: serde_yaml::Value) -> serde
     }
 }
 
-fn load_plugin_config(root: &str, id: &str) -> Option<serde_yaml::Value> {
+pub fn plugin_config_path(root: &str, id: &str) -> PathBuf {
     let root = root.trim_end_matches('/');
-    let path = format!("{root}/{id}/config.yaml");
-    match std::fs::read_to_string(&path) {
-        Ok(s) => match serde_yaml::from_str::<serde_yaml::Value>(&s) {
-            Ok(v) => Some(v),
-            Err(e) => {
-                tracing::warn!(plugin = %id, file = %path, error = %e, "Failed to parse plugin config YAML");
-                None
-            }
-        },
-        Err(e) => {
-            if std::path::Path::new(&path).exists() {
-                tracing::warn!(plugin = %id, file = %path, error = %e, "Failed to read plugin config file");
-            }
-            None
-        }
+    PathBuf::from(format!("{root}/{id}/config.yaml"))
+}
+
+fn load_plugin_config(root: &str, id: &str) -> Result<Option<serde_yaml::Value>> {
+    let path = plugin_config_path(root, id);
+    if !path.exists() {
+        return Ok(None);
     }
+    let s = std::fs::read_to_string(&path)
+        .with_context(|| format!("reading plugin config {}", path.display()))?;
+    let value = interpolate::parse(&s)
+        .with_context(|| format!("parsing plugin config {}", path.display()))?;
+    Ok(Some(value))
 }
 
 fn merge_default_spec(specs: &mut Vec<PluginSpec>, default: PluginSpec) {
@@ -152,9 +301,81 @@ fn merge_default_spec(specs: &mut Vec<PluginSpec>, default: PluginSpec) {
                 existing.triggers.mentions.push(mention);
             }
         }
-        // Do not override existing.config here; file config will merge later.
+        // The plugin's defaults (e.g. permission rules) stay in force unless
+        // the entry overrides them key by key; file config merges later.
         // Respect existing.enabled/dev_only as user-provided or file-provided.
+        let config = core::mem::take(&mut existing.config);
+        existing.config = merge_defaults(config, default.config);
     } else {
         specs.push(default);
     }
 }
+
+/// Deep-merge `default` under `user`: mappings merge key by key, any other
+/// value set by the user replaces the default outright.
+fn merge_defaults(user: serde_yaml::Value, default: serde_yaml::Value) -> serde_yaml::Value {
+    use serde_yaml::Value::{Mapping, Null};
+    match (user, default) {
+        (Mapping(mut user), Mapping(default)) => {
+            for (k, v_default) in default {
+                let merged = match user.remove(&k) {
+                    Some(v_user) => merge_defaults(v_user, v_default),
+                    None => v_default,
+                };
+                user.insert(k, merged);
+            }
+            Mapping(user)
+        }
+        (Null, default) => default,
+        (user, _) => user,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plugin_core::Plugin as _;
+
+    use super::*;
+
+    fn min_power_level(spec: &PluginSpec, subcommand: &str) -> Option<u64> {
+        spec.config["permissions"]["subcommands"][subcommand]["min_power_level"].as_u64()
+    }
+
+    #[test]
+    fn bare_entry_keeps_default_permissions() {
+        let mut specs: Vec<PluginSpec> = serde_yaml::from_str("- id: tools").unwrap();
+        merge_default_spec(&mut specs, plugin_tools_manager::ToolsManager.spec());
+
+        let [tools] = specs.as_slice() else {
+            panic!("expected one spec, got {specs:?}");
+        };
+        for subcommand in ["enable", "disable", "reset", "reload"] {
+            assert_eq!(min_power_level(tools, subcommand), Some(50), "{subcommand}");
+        }
+        assert!(tools.triggers.commands.iter().any(|c| c == "!tools"));
+    }
+
+    #[test]
+    fn entry_overrides_defaults_key_by_key() {
+        let mut specs: Vec<PluginSpec> = serde_yaml::from_str(
+            "
+- id: tools
+  permissions:
+    allow_servers: [example.org]
+    subcommands:
+      reload:
+        min_power_level: 100
+",
+        )
+        .unwrap();
+        merge_default_spec(&mut specs, plugin_tools_manager::ToolsManager.spec());
+
+        let tools = &specs[0];
+        assert_eq!(min_power_level(tools, "reload"), Some(100));
+        assert_eq!(min_power_level(tools, "enable"), Some(50));
+        assert_eq!(
+            tools.config["permissions"]["allow_servers"][0].as_str(),
+            Some("example.org")
+        );
+    }
+}
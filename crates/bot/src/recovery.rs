@@ -0,0 +1,189 @@
+//! Cross-signing and server-side key backup for accounts with a
+//! `recovery_key_file`.
+//!
+//! On the first start the bot creates its cross-signing identity, turns on
+//! key backup and secret storage, and writes the recovery key to the file,
+//! readable only by its owner. Later starts, including ones with a new
+//! device or an emptied store, restore the cross-signing keys and the backup
+//! with that key; this also verifies the device, so people who verified the
+//! bot once need not verify each new device.
+//!
+//! Keep a copy of the key elsewhere: nothing else can read the backup, and
+//! the file usually lives on the same volume as the store.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write as _,
+    path::Path,
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use matrix_sdk::{
+    Client,
+    encryption::recovery::RecoveryState,
+    ruma::api::client::uiaa::{AuthData, Password, UserIdentifier},
+};
+use plugin_core::register_secret;
+use tracing::info;
+
+use crate::accounts::Account;
+
+/// Restore cross-signing and key backup with the key in `key_file`, or set
+/// them up and write a new key there if the file does not exist.
+pub async fn set_up(client: &Client, account: &Account, key_file: &Path) -> Result<()> {
+    let encryption = client.encryption();
+    // Recovery learns the server's secret storage state in the background
+    // after login.
+    encryption.wait_for_e2ee_initialization_tasks().await;
+    let recovery = encryption.recovery();
+
+    if key_file.exists() {
+        let key = read_key(key_file)?;
+        let cross_signing_complete = encryption
+            .cross_signing_status()
+            .await
+            .is_some_and(|status| status.is_complete());
+        match recovery.state() {
+            RecoveryState::Enabled if cross_signing_complete => {}
+            RecoveryState::Disabled => bail!(
+                "{} holds a recovery key, but account {} has no secret storage on the server; \
+                 move the file away to set up a new one",
+                key_file.display(),
+                account.name
+            ),
+            RecoveryState::Enabled | RecoveryState::Incomplete | RecoveryState::Unknown => {
+                recovery
+                    .recover(&key)
+                    .await
+                    .with_context(|| format!("restoring secrets with {}", key_file.display()))?;
+                info!("Restored cross-signing keys and key backup with the recovery key");
+            }
+        }
+        return Ok(());
+    }
+
+    bootstrap_cross_signing(client, account).await?;
+    match recovery.state() {
+        RecoveryState::Disabled => {}
+        RecoveryState::Enabled | RecoveryState::Incomplete => bail!(
+            "account {} already has secret storage; write its recovery key to {} to restore it",
+            account.name,
+            key_file.display()
+        ),
+        RecoveryState::Unknown => bail!(
+            "could not find out whether account {} has secret storage",
+            account.name
+        ),
+    }
+    // Create the file first, so a key the server accepted is never lost to
+    // a file that cannot be written.
+    let mut file = create_key_file(key_file)?;
+    let key = match recovery.enable().await {
+        Ok(key) => key,
+        Err(e) => {
+            drop(file);
+            let _ = fs::remove_file(key_file);
+            return Err(e).context("enabling key backup and secret storage");
+        }
+    };
+    register_secret(&key);
+    writeln!(file, "{key}")
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("writing the recovery key to {}", key_file.display()))?;
+    info!(
+        file = %key_file.display(),
+        "Enabled cross-signing and key backup; keep a copy of the recovery key"
+    );
+    Ok(())
+}
+
+fn read_key(key_file: &Path) -> Result<String> {
+    let key = fs::read_to_string(key_file)
+        .with_context(|| format!("reading the recovery key from {}", key_file.display()))?;
+    let key = key.trim().to_owned();
+    if key.is_empty() {
+        bail!("{} is empty", key_file.display());
+    }
+    register_secret(&key);
+    Ok(key)
+}
+
+/// A new file at `path` that only its owner can read on Unix; an existing
+/// file is never overwritten.
+fn create_key_file(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .with_context(|| format!("creating {}", path.display()))
+}
+
+/// Create the account's cross-signing identity unless it has one. The
+/// homeserver may ask for the password first.
+async fn bootstrap_cross_signing(client: &Client, account: &Account) -> Result<()> {
+    let encryption = client.encryption();
+    let Err(e) = encryption.bootstrap_cross_signing_if_needed(None).await else {
+        return Ok(());
+    };
+    let Some(uiaa) = e.as_uiaa_response() else {
+        return Err(e).context("setting up cross-signing");
+    };
+    let password = account
+        .password
+        .as_deref()
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .ok_or_else(|| {
+            anyhow!(
+                "the homeserver wants account {}'s password to set up cross-signing; \
+                 provide it for this start",
+                account.name
+            )
+        })?;
+    let mut auth = Password::new(
+        UserIdentifier::UserIdOrLocalpart(account.username.clone()),
+        password.to_owned(),
+    );
+    auth.session.clone_from(&uiaa.session);
+    encryption
+        .bootstrap_cross_signing(Some(AuthData::Password(auth)))
+        .await
+        .context("setting up cross-signing")?;
+    info!("Created the cross-signing identity");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_file_is_private_and_never_overwritten() {
+        let dir = std::env::temp_dir().join(format!("recovery-{}", std::process::id()));
+        let path = dir.join("keys/recovery-key");
+        let mut file = create_key_file(&path).unwrap();
+        writeln!(file, "EsTc 1234 abcd").unwrap();
+        drop(file);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(create_key_file(&path).is_err());
+        assert_eq!(read_key(&path).unwrap(), "EsTc 1234 abcd");
+
+        fs::write(&path, "\n").unwrap();
+        assert!(read_key(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
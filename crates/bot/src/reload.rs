@@ -0,0 +1,277 @@
+use core::{fmt::Write as _, time::Duration};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, OnceLock, Weak},
+    time::SystemTime,
+};
+
+use anyhow::{Result, anyhow, bail};
+use async_trait::async_trait;
+use plugin_core::{PluginRegistry, Reloader};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    lifecycle::Tickers,
+    load_config,
+    plugins::{PluginMap, plugin_config_path, plugins_dir, resolve_plugins},
+};
+
+/// How often the config files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Rebuilds plugin specs from `--config` and the per-plugin config files and
+/// swaps them into the running registry.
+#[derive(Debug)]
+pub struct ConfigReloader {
+    config_path: PathBuf,
+    plugins: PluginMap,
+    /// The account's plugin set, if it does not run every plugin.
+    only: Option<HashSet<String>>,
+    registry: Weak<PluginRegistry>,
+    /// Dev settings only apply at startup; remembered to flag changes.
+    dev_settings: (Option<bool>, Option<String>),
+    /// Set once the lifecycle hooks have started; resynced after each reload
+    /// so added plugins are started and tickers follow added, removed or
+    /// retimed plugins.
+    tickers: OnceLock<Weak<Tickers>>,
+    /// Serialises concurrent reloads (watcher, SIGHUP and `!tools reload`).
+    lock: Mutex<()>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        config_path: PathBuf,
+        plugins: PluginMap,
+        only: Option<HashSet<String>>,
+        registry: &Arc<PluginRegistry>,
+        dev_settings: (Option<bool>, Option<String>),
+    ) -> Self {
+        Self {
+            config_path,
+            plugins,
+            only,
+            registry: Arc::downgrade(registry),
+            dev_settings,
+            tickers: OnceLock::new(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn set_tickers(&self, tickers: &Arc<Tickers>) {
+        let _ = self.tickers.set(Arc::downgrade(tickers));
+    }
+
+    /// Every file whose change should trigger a reload.
+    fn watched_files(&self) -> Vec<PathBuf> {
+        let root = plugins_dir();
+        let mut files = vec![self.config_path.clone()];
+        let mut ids: Vec<&str> = self.plugins.keys().copied().collect();
+        ids.sort_unstable();
+        files.extend(ids.into_iter().map(|id| plugin_config_path(&root, id)));
+        files
+    }
+
+    fn fingerprint(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        self.watched_files()
+            .into_iter()
+            .map(|path| {
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                (path, modified)
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Reloader for ConfigReloader {
+    async fn reload(&self) -> Result<String> {
+        let _guard = self.lock.lock().await;
+        let registry = self
+            .registry
+            .upgrade()
+            .ok_or_else(|| anyhow!("plugin registry is gone"))?;
+
+        let config = load_config(&self.config_path)?;
+        let mut resolved = resolve_plugins(&config, &self.plugins, self.only.as_ref());
+        let rejected = resolved.reject_invalid_rate_limits();
+        let problems: Vec<String> = resolved.problems.into_iter().chain(rejected).collect();
+        if !problems.is_empty() {
+            bail!("config not applied:\n{}", problems.join("\n"));
+        }
+        // Like at startup, a spec for a plugin that does not exist is skipped
+        // rather than holding back the rest of the config.
+        for id in &resolved.unknown_ids {
+            warn!("Unknown plugin ID: {id}");
+        }
+
+        let plugin_count = resolved.entries.len();
+        let cluster_count = resolved.clusters.len();
+        let specs: Vec<_> = resolved
+            .entries
+            .iter()
+            .map(|(spec, plugin)| (spec.clone(), Arc::clone(plugin)))
+            .collect();
+        registry
+            .replace_plugins(resolved.entries, resolved.clusters)
+            .await;
+        for (spec, plugin) in &specs {
+            plugin.on_reload(spec).await;
+        }
+        if let Some(tickers) = self.tickers.get().and_then(Weak::upgrade) {
+            tickers.sync().await;
+        }
+
+        let mut summary =
+            format!("config reloaded: {plugin_count} plugins, {cluster_count} clusters");
+        for id in &resolved.unknown_ids {
+            let _ = write!(summary, "\nskipped unknown plugin ID: {id}");
+        }
+        if (config.dev_mode, config.dev_id) != self.dev_settings {
+            summary.push_str("\nnote: dev_mode/dev_id changes take effect after a restart");
+        }
+        info!(
+            plugins = plugin_count,
+            clusters = cluster_count,
+            "Config reloaded"
+        );
+        Ok(summary)
+    }
+}
+
+async fn reload_and_log(reloader: &ConfigReloader, trigger: &str) {
+    match reloader.reload().await {
+        Ok(summary) => info!(trigger, "{summary}"),
+        Err(e) => {
+            warn!(trigger, error = %format!("{e:#}"), "Config reload failed; keeping previous config");
+        }
+    }
+}
+
+/// Poll the config files and reload when any of them changes.
+pub fn spawn_config_watcher(reloader: Arc<ConfigReloader>) {
+    tokio::spawn(async move {
+        let mut last = reloader.fingerprint();
+        let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let current = reloader.fingerprint();
+            if current != last {
+                last = current;
+                reload_and_log(&reloader, "file-watch").await;
+            }
+        }
+    });
+}
+
+/// Reload on SIGHUP, the conventional "re-read your config" signal.
+#[cfg(unix)]
+pub fn spawn_sighup_handler(reloader: Arc<ConfigReloader>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(error = %e, "Failed to install SIGHUP handler");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            reload_and_log(&reloader, "SIGHUP").await;
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_sighup_handler(_reloader: Arc<ConfigReloader>) {}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use plugin_relay::Relay;
+    use plugin_verify::Verifier;
+
+    use super::*;
+    use crate::plugins::{build_registry, plugin_instances};
+
+    /// A registry built from `yaml` like at startup, and its reloader.
+    async fn start(name: &str, yaml: &str) -> (PathBuf, Arc<PluginRegistry>, ConfigReloader) {
+        let dir = std::env::temp_dir().join(format!("reload-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        fs::write(&path, yaml).unwrap();
+        let plugins = plugin_instances(&Arc::new(Relay::default()), &Arc::new(Verifier::default()));
+        let config = load_config(&path).unwrap();
+        let registry = build_registry(&config, &dir, &plugins, None).await;
+        let reloader = ConfigReloader::new(path.clone(), plugins, None, &registry, (None, None));
+        (path, registry, reloader)
+    }
+
+    #[tokio::test]
+    async fn reload_applies_changed_specs() {
+        let (path, registry, reloader) = start(
+            "changed",
+            "clusters: []\nplugins:\n  - id: echo\n    triggers: {commands: ['!one']}\n",
+        )
+        .await;
+        assert!(registry.entry_by_command("!one").await.is_some());
+        assert!(registry.is_enabled("ping").await);
+
+        fs::write(
+            &path,
+            "clusters: []\nplugins:\n  - id: echo\n    triggers: {commands: ['!two']}\n  - id: ping\n    enabled: false\n",
+        )
+        .unwrap();
+        reloader.reload().await.unwrap();
+        assert!(registry.entry_by_command("!one").await.is_none());
+        assert!(registry.entry_by_command("!two").await.is_some());
+        assert!(!registry.is_enabled("ping").await);
+
+        // Dropping the entries brings back the plugins' own defaults.
+        fs::write(&path, "clusters: []\n").unwrap();
+        reloader.reload().await.unwrap();
+        assert!(registry.entry_by_command("!two").await.is_none());
+        assert!(registry.is_enabled("ping").await);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_skips_unknown_ids_like_startup() {
+        let (path, registry, reloader) =
+            start("unknown", "clusters: []\nplugins:\n  - id: nope\n").await;
+        assert!(registry.entry("nope").await.is_none());
+        assert!(registry.entry("echo").await.is_some());
+
+        fs::write(
+            &path,
+            "clusters: []\nplugins:\n  - id: nope\n  - id: echo\n    enabled: false\n",
+        )
+        .unwrap();
+        let summary = reloader.reload().await.unwrap();
+        assert!(
+            summary.contains("skipped unknown plugin ID: nope"),
+            "{summary}"
+        );
+        assert!(!registry.is_enabled("echo").await);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_previous_config_on_invalid_rate_limit() {
+        let (path, registry, reloader) = start("ratelimit", "clusters: []\n").await;
+
+        fs::write(
+            &path,
+            "clusters: []\nplugins:\n  - id: echo\n    enabled: false\n    rate_limit:\n      per_user: {burst: 1, per_minute: 0}\n",
+        )
+        .unwrap();
+        let err = reloader.reload().await.unwrap_err();
+        assert!(format!("{err:#}").contains("config not applied"), "{err:#}");
+        assert!(registry.is_enabled("echo").await);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
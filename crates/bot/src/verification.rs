@@ -0,0 +1,228 @@
+//! SAS verification of the bot's device by other users. `--verify-mode`
+//! decides what happens once both sides show their emojis:
+//!
+//! - `auto` confirms at once, so anyone can verify the bot; insecure
+//! - `operator` posts them and waits for `!verify confirm|cancel`, see the
+//!   verify plugin
+//! - `log` only logs them; nothing is confirmed
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use clap::ValueEnum;
+use futures_util::StreamExt as _;
+use matrix_sdk::{
+    Client,
+    encryption::verification::{
+        SasState, SasVerification, VerificationRequest, VerificationRequestState,
+    },
+};
+use plugin_core::{MatrixRoom, PluginRegistry, RoomRef, SdkClient, SdkRoom};
+use plugin_verify::{Decision, SasPrompt, Verifier, room_to_ask};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VerifyMode {
+    Auto,
+    Operator,
+    Log,
+}
+
+/// What the SAS handlers of one account share.
+#[derive(Debug, Clone)]
+pub struct Verifications {
+    pub mode: VerifyMode,
+    verifier: Arc<Verifier>,
+    registry: Arc<PluginRegistry>,
+    /// Flows being handled; a flow shows up both as a request and as a start
+    /// event.
+    active: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Verifications {
+    pub fn new(mode: VerifyMode, verifier: Arc<Verifier>, registry: Arc<PluginRegistry>) -> Self {
+        Self {
+            mode,
+            verifier,
+            registry,
+            active: Arc::default(),
+        }
+    }
+
+    /// Whether `flow_id` is new; it counts as active until [`Self::end`].
+    fn begin(&self, flow_id: &str) -> bool {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        active.insert(flow_id.to_owned())
+    }
+
+    fn end(&self, flow_id: &str) {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        active.remove(flow_id);
+    }
+
+    /// Ask the operators about `sas`'s emojis. Cancels the flow unless they
+    /// confirm it.
+    async fn ask(&self, client: &Client, sas: &SasVerification, prompt: SasPrompt) -> bool {
+        let Some(entry) = self.registry.entry("verify").await else {
+            warn!("--verify-mode operator needs the verify plugin; cancelling");
+            cancel(sas).await;
+            return false;
+        };
+        // In-room flows run in a room with the user; otherwise look for a DM.
+        let user_room = sas
+            .room_id()
+            .and_then(|room_id| client.get_room(room_id))
+            .or_else(|| client.get_dm_room(sas.other_user_id()))
+            .map(|room| Arc::new(SdkRoom(room)) as Arc<dyn MatrixRoom>);
+        let client_io = SdkClient(client.clone());
+        let room = match room_to_ask(&client_io, &entry.spec, &prompt, user_room).await {
+            Ok(room) => room,
+            Err(e) => {
+                warn!(error = %format!("{e:#}"), "Could not ask about the verification; cancelling");
+                cancel(sas).await;
+                return false;
+            }
+        };
+        let room_ref = RoomRef::from_room(room.as_ref());
+        if !self.registry.is_enabled_in("verify", &room_ref).await {
+            warn!(room = %room.room_id(), "The verify plugin is disabled in the room to ask in; cancelling");
+            cancel(sas).await;
+            return false;
+        }
+        let asking = self.verifier.ask(&entry.spec, &prompt, room);
+        let decision = tokio::select! {
+            decision = asking => decision,
+            () = finished(sas) => return false,
+        };
+        match decision {
+            Ok(Decision::Confirm) => true,
+            Ok(Decision::Cancel | Decision::TimedOut) => {
+                cancel(sas).await;
+                false
+            }
+            Err(e) => {
+                warn!(error = %format!("{e:#}"), "Could not ask about the verification; cancelling");
+                cancel(sas).await;
+                false
+            }
+        }
+    }
+}
+
+pub async fn handle_request(
+    request: VerificationRequest,
+    client: Client,
+    verifications: Verifications,
+) {
+    info!(user = %request.other_user_id(), "Accepting verification request");
+    if let Err(e) = request.accept().await {
+        warn!(error = %e, "Failed to accept verification request");
+        return;
+    }
+    let mut stream = request.changes();
+    while let Some(state) = stream.next().await {
+        match state {
+            VerificationRequestState::Transitioned { verification } => {
+                if let Some(sas) = verification.sas() {
+                    let flow_id = request.flow_id().to_owned();
+                    tokio::spawn(handle_sas(sas, flow_id, client, verifications));
+                }
+                break;
+            }
+            VerificationRequestState::Cancelled(info) => {
+                warn!(reason = %info.reason(), "Verification cancelled (request stage)");
+                break;
+            }
+            VerificationRequestState::Done => {
+                info!("Verification already done at request stage");
+                break;
+            }
+            VerificationRequestState::Created { .. }
+            | VerificationRequestState::Requested { .. }
+            | VerificationRequestState::Ready { .. } => {}
+        }
+    }
+}
+
+pub async fn handle_sas(
+    sas: SasVerification,
+    flow_id: String,
+    client: Client,
+    verifications: Verifications,
+) {
+    if !verifications.begin(&flow_id) {
+        return;
+    }
+    info!(user = %sas.other_device().user_id(), device = %sas.other_device().device_id(), flow = %flow_id, "Starting SAS verification");
+    if let Err(e) = sas.accept().await {
+        warn!(error = %e, "Failed to accept SAS");
+        verifications.end(&flow_id);
+        return;
+    }
+
+    let mut stream = sas.changes();
+    while let Some(state) = stream.next().await {
+        match state.clone() {
+            SasState::KeysExchanged {
+                emojis: Some(e), ..
+            } => {
+                let emojis: Vec<(String, String)> = e
+                    .emojis
+                    .iter()
+                    .map(|em| (em.symbol.to_owned(), em.description.to_owned()))
+                    .collect();
+                let symbols: Vec<&str> = emojis.iter().map(|(s, _)| s.as_str()).collect();
+                let names: Vec<&str> = emojis.iter().map(|(_, n)| n.as_str()).collect();
+                info!(flow = %flow_id, "SAS emojis: {}\nSAS names:  {}", symbols.join(" "), names.join(" "));
+                let confirm = match verifications.mode {
+                    VerifyMode::Auto => true,
+                    VerifyMode::Log => false,
+                    VerifyMode::Operator => {
+                        let prompt = SasPrompt {
+                            flow_id: flow_id.clone(),
+                            user: sas.other_user_id().to_owned(),
+                            device: sas.other_device().device_id().to_owned(),
+                            emojis,
+                        };
+                        verifications.ask(&client, &sas, prompt).await
+                    }
+                };
+                if confirm && let Err(e) = sas.confirm().await {
+                    warn!(error = %e, "Failed to confirm SAS");
+                }
+            }
+            SasState::Done { .. } => {
+                info!("Verification completed");
+                break;
+            }
+            SasState::Cancelled(info) => {
+                warn!(reason = %info.reason(), "Verification cancelled (SAS stage)");
+                break;
+            }
+            SasState::Created { .. }
+            | SasState::Started { .. }
+            | SasState::Accepted { .. }
+            | SasState::KeysExchanged { .. }
+            | SasState::Confirmed => {}
+        }
+    }
+    verifications.end(&flow_id);
+}
+
+/// Resolve once `sas` is done or cancelled.
+async fn finished(sas: &SasVerification) {
+    let mut changes = sas.changes();
+    while let Some(state) = changes.next().await {
+        if matches!(state, SasState::Done { .. } | SasState::Cancelled(_)) {
+            return;
+        }
+    }
+}
+
+async fn cancel(sas: &SasVerification) {
+    if let Err(e) = sas.cancel().await {
+        warn!(error = %e, "Failed to cancel SAS");
+    }
+}
@@ -19,7 +19,9 @@ pub struct Content {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum Part {
-    Text { text: String },
+    Text {
+        text: String,
+    },
     FunctionCall {
         #[serde(rename = "functionCall")]
         function_call: FunctionCall,
@@ -61,6 +63,14 @@ pub struct FunctionDeclaration {
 #[serde(rename_all = "camelCase")]
 pub struct GeminiResponse {
     pub candidates: Option<Vec<Candidate>>,
+    pub usage_metadata: Option<UsageMetadata>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UsageMetadata {
+    pub prompt_token_count: u64,
+    pub candidates_token_count: u64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -77,33 +87,27 @@ pub fn sanitize_schema(v: Value) -> Value {
             if let Some(t) = map.remove("type") {
                 let new_type = match t {
                     Value::String(s) => Value::String(s.to_uppercase()),
-                    Value::Array(arr) => {
-                        if let Some(first) = arr.first() {
-                            if let Some(s) = first.as_str() {
-                                Value::String(s.to_uppercase())
-                            } else {
-                                Value::String("STRING".to_string())
-                            }
-                        } else {
-                            Value::String("STRING".to_string())
-                        }
+                    Value::Array(arr) => Value::String(
+                        arr.first()
+                            .and_then(Value::as_str)
+                            .map_or_else(|| "STRING".to_owned(), str::to_uppercase),
+                    ),
+                    Value::Null | Value::Bool(_) | Value::Number(_) | Value::Object(_) => {
+                        Value::String("OBJECT".to_owned())
                     }
-                    _ => Value::String("OBJECT".to_string()),
                 };
-                map.insert("type".to_string(), new_type);
+                map.insert("type".to_owned(), new_type);
             }
-            
+
             // Recurse
-            for (_, v) in map.iter_mut() {
+            for (_, v) in &mut map {
                 *v = sanitize_schema(v.clone());
             }
 
             Value::Object(map)
         }
-        Value::Array(arr) => {
-            Value::Array(arr.into_iter().map(sanitize_schema).collect())
-        }
-        _ => v,
+        Value::Array(arr) => Value::Array(arr.into_iter().map(sanitize_schema).collect()),
+        Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_) => v,
     }
 }
 
@@ -122,26 +126,40 @@ mod tests {
                 "query": {
                     "type": ["string", "null"],
                     "description": "Search query"
-                },
-                "limit": {
-                    "type": "number"
                 }
             }
         });
 
         let sanitized = sanitize_schema(schema);
-        println!("Sanitized: {}", serde_json::to_string_pretty(&sanitized).unwrap());
+        println!(
+            "Sanitized: {}",
+            serde_json::to_string_pretty(&sanitized).unwrap()
+        );
 
         let obj = sanitized.as_object().unwrap();
         assert!(!obj.contains_key("$schema"));
         assert!(!obj.contains_key("additionalProperties"));
-        
+
         let props = obj.get("properties").unwrap().as_object().unwrap();
         let query = props.get("query").unwrap().as_object().unwrap();
         assert_eq!(query.get("type").unwrap(), &json!("STRING"));
-        
-        let limit = props.get("limit").unwrap().as_object().unwrap();
-         assert_eq!(limit.get("type").unwrap(), &json!("STRING")); // number -> STRING? no, number -> NUMBER?
-         // My code uppercases strings. "number" -> "NUMBER".
+    }
+
+    #[test]
+    fn test_sanitize_schema_uppercases_scalar_types() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "limit": { "type": "number" },
+                "count": { "type": "integer" },
+                "exact": { "type": "boolean" }
+            }
+        });
+
+        let sanitized = sanitize_schema(schema);
+        let props = &sanitized["properties"];
+        assert_eq!(props["limit"]["type"], json!("NUMBER"));
+        assert_eq!(props["count"]["type"], json!("INTEGER"));
+        assert_eq!(props["exact"]["type"], json!("BOOLEAN"));
     }
 }
@@ -2,46 +2,37 @@ use std::{
     borrow::ToOwned,
     collections::{BTreeSet, HashMap},
     path::{Path, PathBuf},
-    sync::Once,
+    sync::Arc,
 };
 
 use anyhow::Result;
 use async_trait::async_trait;
-use matrix_sdk::{
-    Client,
-    room::{MessagesOptions, Room},
-    ruma::{
-        MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId,
-        events::{
-            AnySyncMessageLikeEvent, AnySyncTimelineEvent,
-            room::message::{
-                MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
-                SyncRoomMessageEvent,
-            },
-        },
-        serde::Raw,
+use matrix_sdk::ruma::{
+    MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId,
+    events::{
+        AnySyncMessageLikeEvent, AnySyncTimelineEvent,
+        room::message::{MessageType, OriginalSyncRoomMessageEvent, SyncRoomMessageEvent},
     },
+    serde::Raw,
 };
-use tracing::{debug, info, warn, error};
+use tracing::{debug, error, info, warn};
 
-mod mcp;
 mod gemini;
-mod pii;
+mod mcp;
 pub mod mcp_server;
+mod pii;
 
 pub use mcp_server::run_mcp_server;
 
-
 use plugin_core::{
-    Plugin, PluginContext, PluginSpec, PluginTriggers, RoomMessageMeta, sanitize_line, send_text,
-    str_config, truncate,
+    Arg, CommandSpec, ConfigSchema, ConfigType, LifecycleContext, MatrixClient, MatrixRoom, Opt,
+    OutgoingMessage, Plugin, PluginContext, PluginSpec, PluginTriggers, RoomMessageMeta,
+    escape_html, redact_secrets, register_secret, sanitize_line, send_text, str_config,
 };
 
 #[derive(Debug)]
 pub struct AiPlugin;
 
-static HISTORY_BACKFILL_ONCE: Once = Once::new();
-
 impl AiPlugin {
     fn base_triggers() -> PluginTriggers {
         let mut triggers = PluginTriggers {
@@ -70,7 +61,7 @@ impl Plugin for AiTool {
         "ai"
     }
     fn help(&self) -> &'static str {
-        "Ask the AI: !ai <prompt>"
+        "Ask the AI: !ai [--log] <prompt>, or stop its answers here: !ai stop"
     }
     fn spec(&self) -> PluginSpec {
         PluginSpec {
@@ -87,7 +78,14 @@ impl Plugin for AiTool {
     fn handles_room_messages(&self) -> bool {
         true
     }
+    fn command(&self) -> Option<CommandSpec> {
+        Some(command())
+    }
 
+    async fn on_start(&self, ctx: &LifecycleContext, spec: &PluginSpec) -> Result<()> {
+        start_backfill(ctx, spec);
+        Ok(())
+    }
 
     async fn on_room_message(
         &self,
@@ -96,8 +94,6 @@ impl Plugin for AiTool {
         spec: &PluginSpec,
         meta: &RoomMessageMeta<'_>,
     ) -> Result<()> {
-        trigger_backfill(ctx, spec);
-
         let Some(body) = message_body(&event.content.msgtype) else {
             return Ok(());
         };
@@ -108,10 +104,7 @@ impl Plugin for AiTool {
             return Ok(());
         }
 
-        let Some(own_id) = ctx.client.user_id() else {
-            return Ok(());
-        };
-        if event.sender == own_id {
+        if ctx.client.is_own_user(&event.sender) {
             return Ok(());
         }
 
@@ -123,15 +116,36 @@ impl Plugin for AiTool {
         for handle in fallback_handles(ctx, spec) {
             if body_lc.contains(&handle) {
                 info!(plugin = %self.id(), handle, "Fallback mention matched; delegating to run()");
-                if let Err(err) = self.run(ctx, body, spec).await {
-                    warn!(error = %err, plugin = %self.id(), "AI fallback run failed");
-                }
+                // Track the answer like a command run so `!ai stop` reaches it.
+                let (task_ctx, body, task_spec) = (ctx.clone(), body.to_owned(), spec.clone());
+                let answer = ctx.registry.invocations().spawn(
+                    ctx.room.room_id(),
+                    self.id(),
+                    spec.invocation_timeout(),
+                    async move { Self.run(&task_ctx, &body, &task_spec).await },
+                );
+                tokio::spawn(async move {
+                    match answer.await {
+                        Ok(Some(Ok(()))) => {}
+                        Ok(Some(Err(err))) => {
+                            warn!(error = %err, plugin = "ai", "AI fallback run failed");
+                        }
+                        Ok(None) => warn!(plugin = "ai", "AI fallback run timed out"),
+                        Err(err) if err.is_cancelled() => {
+                            info!(plugin = "ai", "AI fallback run stopped");
+                        }
+                        Err(err) => warn!(error = %err, plugin = "ai", "AI fallback run panicked"),
+                    }
+                });
                 break;
             }
         }
 
         Ok(())
     }
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(config_schema())
+    }
     async fn run(&self, ctx: &PluginContext, args: &str, spec: &PluginSpec) -> Result<()> {
         use serde_json::Value;
 
@@ -156,9 +170,17 @@ impl Plugin for AiTool {
         struct Choice {
             message: ChoiceMsg,
         }
+        #[derive(serde::Deserialize, Default)]
+        #[serde(default)]
+        struct Usage {
+            prompt_tokens: u64,
+            completion_tokens: u64,
+        }
         #[derive(serde::Deserialize)]
         struct ChatResp {
             choices: Vec<Choice>,
+            #[serde(default)]
+            usage: Option<Usage>,
         }
         #[derive(serde::Serialize, Clone, Debug)]
         struct Msg {
@@ -191,24 +213,46 @@ impl Plugin for AiTool {
             tools: Vec<ToolDef>,
         }
 
-        let (args_no_log, log_to_room) = extract_log_flag(args);
-        let prompt_raw = args_no_log.trim();
-        if prompt_raw.is_empty() {
-            return send_text(ctx, "Usage: !ai <prompt>").await;
+        if args.trim().eq_ignore_ascii_case("stop") {
+            let stopped = ctx
+                .registry
+                .invocations()
+                .cancel(ctx.room.room_id(), Some(self.id()));
+            let _ = ctx.room.typing(false).await;
+            let reply = match stopped.len() {
+                0 => "nothing to stop".to_owned(),
+                1 => "stopped 1 answer".to_owned(),
+                n => format!("stopped {n} answers"),
+            };
+            return send_text(ctx, reply).await;
         }
 
-        let pii_enabled = spec.config.get("pii_redaction").and_then(|v| v.as_bool()).unwrap_or(false);
+        let Some(m) = command().parse_or_reply(ctx, args).await? else {
+            return Ok(());
+        };
+        let log_to_room = m.flag("log");
+        let prompt_raw = m.get_str("prompt").unwrap_or_default();
+
+        let pii_enabled = spec
+            .config
+            .get("pii_redaction")
+            .and_then(serde_yaml::Value::as_bool)
+            .unwrap_or(false);
         // Start typing indicator
-        let _ = ctx.room.typing_notice(true).await;
+        let _ = ctx.room.typing(true).await;
+
+        let ner_enabled = spec
+            .config
+            .get("pii_ner")
+            .and_then(serde_yaml::Value::as_bool)
+            .unwrap_or(false);
 
-        let ner_enabled = spec.config.get("pii_ner").and_then(|v| v.as_bool()).unwrap_or(false);
-        
         let mut redactor = if ner_enabled {
             pii::PiiRedactor::with_ner()
         } else {
             pii::PiiRedactor::new()
         };
-        
+
         let prompt = if pii_enabled {
             redactor.redact(prompt_raw)
         } else {
@@ -233,21 +277,26 @@ impl Plugin for AiTool {
                         }
                     } else {
                         // Fallback: split command string
-                        let parts: Vec<String> = cmd_str.split_whitespace().map(ToOwned::to_owned).collect();
+                        let parts: Vec<String> =
+                            cmd_str.split_whitespace().map(ToOwned::to_owned).collect();
                         if !parts.is_empty() {
-                            cmd = parts[0].clone();
+                            cmd.clone_from(&parts[0]);
                             args_vec = parts[1..].to_vec();
                         }
                     }
-                    
-                    info!("Connecting to MCP server: {} ({} {:?})", name, cmd, args_vec);
+
+                    info!(
+                        "Connecting to MCP server: {} ({} {:?})",
+                        name, cmd, args_vec
+                    );
                     match mcp::McpClient::new(&cmd, &args_vec).await {
                         Ok(client) => {
                             mcp_clients.push(client);
                         }
                         Err(e) => {
                             warn!("Failed to connect to MCP server {}: {}", name, e);
-                            send_text(ctx, format!("MCP connection failed for {}: {}", name, e)).await?;
+                            send_text(ctx, format!("MCP connection failed for {name}: {e}"))
+                                .await?;
                         }
                     }
                 }
@@ -279,12 +328,10 @@ impl Plugin for AiTool {
         }
 
         let provider = str_config(spec, "provider").unwrap_or_else(|| "openai".to_owned());
-        
-        let api_base = str_config(spec, "api_base")
-            .or_else(|| std::env::var("AI_API_BASE").ok());
-        
-        let api_path = str_config(spec, "api_path")
-            .or_else(|| std::env::var("AI_API_PATH").ok());
+
+        let api_base = str_config(spec, "api_base").or_else(|| std::env::var("AI_API_BASE").ok());
+
+        let api_path = str_config(spec, "api_path").or_else(|| std::env::var("AI_API_PATH").ok());
 
         let model = str_config(spec, "model")
             .or_else(|| std::env::var("AI_MODEL").ok())
@@ -309,8 +356,19 @@ impl Plugin for AiTool {
         } else if let Ok(k) = std::env::var("AI_API_KEY") {
             key_source = "env.AI_API_KEY".into();
             Some(k)
-        } else if let Ok(k) = std::env::var(if provider == "gemini" { "GOOGLE_API_KEY" } else { "OPENAI_API_KEY" }) {
-            key_source = format!("env.{}", if provider == "gemini" { "GOOGLE_API_KEY" } else { "OPENAI_API_KEY" });
+        } else if let Ok(k) = std::env::var(if provider == "gemini" {
+            "GOOGLE_API_KEY"
+        } else {
+            "OPENAI_API_KEY"
+        }) {
+            key_source = format!(
+                "env.{}",
+                if provider == "gemini" {
+                    "GOOGLE_API_KEY"
+                } else {
+                    "OPENAI_API_KEY"
+                }
+            );
             Some(k)
         } else {
             None
@@ -324,20 +382,34 @@ impl Plugin for AiTool {
             let openai_key = std::env::var("OPENAI_API_KEY").ok();
             warn!(
                 "AI request blocked: no API key set. Debug: provider={}, config.api_key={}, AI_API_KEY={:?}, GOOGLE_API_KEY={:?}, OPENAI_API_KEY={:?}",
-                provider, config_key, ai_api_key.as_ref().map(|_| "[SET]"), google_key.as_ref().map(|_| "[SET]"), openai_key.as_ref().map(|_| "[SET]")
+                provider,
+                config_key,
+                ai_api_key.as_ref().map(|_| "[SET]"),
+                google_key.as_ref().map(|_| "[SET]"),
+                openai_key.as_ref().map(|_| "[SET]")
             );
             return send_text(ctx, "AI key missing: set config.api_key etc").await;
         }
         let api_key = api_key.unwrap();
+        // Gemini takes the key in the URL, which is logged below.
+        register_secret(&api_key);
 
         let url = if let Some(base) = api_base {
-            format!("{}{}", base.trim_end_matches('/'), api_path.unwrap_or_else(|| "/v1/chat/completions".to_owned()))
+            format!(
+                "{}{}",
+                base.trim_end_matches('/'),
+                api_path.unwrap_or_else(|| "/v1/chat/completions".to_owned())
+            )
         } else if provider == "gemini" {
             let base = "https://generativelanguage.googleapis.com/v1beta/models";
-            format!("{}/{}:generateContent?key={}", base, model, api_key)
+            format!("{base}/{model}:generateContent?key={api_key}")
         } else {
             let base = "https://api.openai.com";
-            format!("{}{}", base, api_path.unwrap_or_else(|| "/v1/chat/completions".to_owned()))
+            format!(
+                "{}{}",
+                base,
+                api_path.unwrap_or_else(|| "/v1/chat/completions".to_owned())
+            )
         };
 
         let name = ai_name(spec);
@@ -355,24 +427,27 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
         );
         let ctx_lines = read_last_history(&ctx.history_dir, &ctx.room.room_id().to_owned(), 11);
         let context_lines = ctx_lines.join("\n");
-        
+
         let history_status = if context_lines.is_empty() {
-            let hist_path = history_path(ctx.history_dir.as_ref().as_path(), &ctx.room.room_id().to_owned());
+            let hist_path = history_path(
+                ctx.history_dir.as_ref().as_path(),
+                &ctx.room.room_id().to_owned(),
+            );
             format!("No history found at: {}", hist_path.display())
         } else {
             format!("Loaded {} messages", ctx_lines.len())
         };
-        
+
         if !context_lines.is_empty() {
             system_prompt = system_prompt
                 .replace("(handle)", format!("@{name}").as_str())
                 .replacen("(context grabbed from the chat)", &context_lines, 1);
         }
-        
+
         if pii_enabled {
             system_prompt = redactor.redact(&system_prompt);
         }
-        
+
         system_prompt.push_str("\n\nIMPORTANT: Do not use markdown formatting. Do not use bold (**text**), italics (*text*), or lists. Write in plain text paragraphs only. Keep your response very concise and short (under 100 words).");
 
         info!(
@@ -385,7 +460,6 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
             "AI request prepared"
         );
 
-
         if log_to_room {
             let debug_info = format!(
                 "🔧 DEBUG INFO\n\
@@ -400,12 +474,17 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
                 \n\
                 💬 USER PROMPT:\n\
                 {}",
-                provider, model, tools.len(), pii_enabled, history_status, system_prompt, prompt
+                provider,
+                model,
+                tools.len(),
+                pii_enabled,
+                history_status,
+                system_prompt,
+                prompt
             );
-            let _ = send_text(ctx, debug_info).await;
+            let _ = send_text(ctx, redact_secrets(&debug_info).into_owned()).await;
         }
 
-
         let mut messages = vec![
             Msg {
                 role: "system".into(),
@@ -432,7 +511,7 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
             }
 
             if log_to_room {
-                 let _ = send_text(ctx, format!("AI turn {turn} calling API...")).await;
+                let _ = send_text(ctx, format!("AI turn {turn} calling API...")).await;
             }
 
             let client = reqwest::Client::new();
@@ -443,8 +522,8 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
 
             if provider == "gemini" {
                 // Convert messages to Gemini format
-                use gemini::{GeminiBody, Content, Part, Tools, FunctionDeclaration};
-                
+                use gemini::{Content, FunctionDeclaration, GeminiBody, Part, Tools};
+
                 let mut gemini_contents = Vec::new();
                 let mut system_inst = None;
 
@@ -464,60 +543,62 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
                             });
                         }
                     } else if msg.role == "assistant" {
-                         let mut parts = Vec::new();
-                         if let Some(c) = &msg.content {
-                             parts.push(Part::Text { text: c.clone() });
-                         }
-                         if let Some(tcs) = &msg.tool_calls {
+                        let mut parts = Vec::new();
+                        if let Some(c) = &msg.content {
+                            parts.push(Part::Text { text: c.clone() });
+                        }
+                        if let Some(tcs) = &msg.tool_calls {
                             for tc_val in tcs {
-                                if let Ok(tc) = serde_json::from_value::<ToolCall>(tc_val.clone()) {
-                                    if let Ok(args_val) = serde_json::from_str::<Value>(&tc.function.arguments) {
-                                        parts.push(Part::FunctionCall {
-                                            function_call: gemini::FunctionCall {
-                                                name: tc.function.name,
-                                                args: args_val,
-                                            }
-                                        });
-                                    }
+                                if let Ok(tc) = serde_json::from_value::<ToolCall>(tc_val.clone())
+                                    && let Ok(args_val) =
+                                        serde_json::from_str::<Value>(&tc.function.arguments)
+                                {
+                                    parts.push(Part::FunctionCall {
+                                        function_call: gemini::FunctionCall {
+                                            name: tc.function.name,
+                                            args: args_val,
+                                        },
+                                    });
                                 }
                             }
-                         }
-                         if !parts.is_empty() {
-                             gemini_contents.push(Content {
-                                 role: "model".into(),
-                                 parts,
-                             });
-                         }
+                        }
+                        if !parts.is_empty() {
+                            gemini_contents.push(Content {
+                                role: "model".into(),
+                                parts,
+                            });
+                        }
                     } else if msg.role == "tool" {
                         // Tool response
                         let response_content = msg.content.clone().unwrap_or_default();
-                         
-                         // Reconstruct logic for finding function name
-                         let mut fn_name = "unknown".to_owned();
-                         'scan: for m in messages.iter().rev() {
-                             if m.role == "assistant" {
-                                 if let Some(tcs) = &m.tool_calls {
-                                     for tc_val in tcs {
-                                          if let Ok(tc) = serde_json::from_value::<ToolCall>(tc_val.clone()) {
-                                              if Some(&tc.id) == msg.tool_call_id.as_ref() {
-                                                  fn_name = tc.function.name.clone();
-                                                  break 'scan;
-                                              }
-                                          }
-                                     }
-                                 }
-                             }
-                         }
-                         
-                         gemini_contents.push(Content {
-                             role: "function".into(),
-                             parts: vec![Part::FunctionResponse {
-                                 function_response: gemini::FunctionResponse {
-                                     name: fn_name,
-                                     response: serde_json::json!({ "content": response_content }), 
-                                 }
-                             }],
-                         });
+
+                        // Reconstruct logic for finding function name
+                        let mut fn_name = "unknown".to_owned();
+                        'scan: for m in messages.iter().rev() {
+                            if m.role == "assistant"
+                                && let Some(tcs) = &m.tool_calls
+                            {
+                                for tc_val in tcs {
+                                    if let Ok(tc) =
+                                        serde_json::from_value::<ToolCall>(tc_val.clone())
+                                        && Some(&tc.id) == msg.tool_call_id.as_ref()
+                                    {
+                                        fn_name.clone_from(&tc.function.name);
+                                        break 'scan;
+                                    }
+                                }
+                            }
+                        }
+
+                        gemini_contents.push(Content {
+                            role: "function".into(),
+                            parts: vec![Part::FunctionResponse {
+                                function_response: gemini::FunctionResponse {
+                                    name: fn_name,
+                                    response: serde_json::json!({ "content": response_content }),
+                                },
+                            }],
+                        });
                     }
                 }
 
@@ -525,15 +606,22 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
                     None
                 } else {
                     Some(vec![Tools {
-                        function_declarations: tools.iter().map(|t| FunctionDeclaration {
-                            name: t.function.name.clone(),
-                            description: t.function.description.clone(),
-                            parameters: {
-                                let sanitized = gemini::sanitize_schema(t.function.parameters.clone());
-                                debug!("Sanitized schema for {}: {}", t.function.name, sanitized);
-                                sanitized
-                            },
-                        }).collect(),
+                        function_declarations: tools
+                            .iter()
+                            .map(|t| FunctionDeclaration {
+                                name: t.function.name.clone(),
+                                description: t.function.description.clone(),
+                                parameters: {
+                                    let sanitized =
+                                        gemini::sanitize_schema(t.function.parameters.clone());
+                                    debug!(
+                                        "Sanitized schema for {}: {}",
+                                        t.function.name, sanitized
+                                    );
+                                    sanitized
+                                },
+                            })
+                            .collect(),
                     }])
                 };
 
@@ -544,56 +632,68 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
                 };
 
                 let resp = client.post(&url).json(&body).send().await;
-                
+
                 match resp {
                     Ok(r) => {
                         let status = r.status();
                         if !status.is_success() {
-                             let text = r.text().await.unwrap_or_default();
-                             warn!("Gemini API error: {} {}", status, text);
-                             send_text(ctx, format!("Gemini error: {}", text)).await?;
-                             return Ok(());
+                            record_ai_request(&provider, started, None);
+                            let text = r.text().await.unwrap_or_default();
+                            warn!("Gemini API error: {} {}", status, text);
+                            send_text(ctx, format!("Gemini error: {text}")).await?;
+                            return Ok(());
                         }
                         let text = r.text().await.unwrap_or_default();
                         error!("Gemini Raw Response: {}", text);
                         match serde_json::from_str::<gemini::GeminiResponse>(&text) {
                             Ok(g_resp) => {
-                                if let Some(candidates) = g_resp.candidates {
-                                    if let Some(cand) = candidates.first() {
-                                        let mut text_parts = Vec::new();
-                                        for part in &cand.content.parts {
-                                            match part {
-                                                Part::Text { text } => text_parts.push(text.clone()),
-                                                Part::FunctionCall { function_call } => {
-                                                     final_tool_calls.push(ToolCall {
-                                                         id: format!("call_{}", uuid::Uuid::new_v4()), 
-                                                         kind: "function".into(),
-                                                         function: ToolCallFunction {
-                                                             name: function_call.name.clone(),
-                                                             arguments: serde_json::to_string(&function_call.args).unwrap_or_default(),
-                                                         }
-                                                     });
-                                                }
-                                                _ => {}
+                                let usage = g_resp.usage_metadata.unwrap_or_default();
+                                record_ai_request(
+                                    &provider,
+                                    started,
+                                    Some((usage.prompt_token_count, usage.candidates_token_count)),
+                                );
+                                if let Some(candidates) = g_resp.candidates
+                                    && let Some(cand) = candidates.first()
+                                {
+                                    let mut text_parts = Vec::new();
+                                    for part in &cand.content.parts {
+                                        match part {
+                                            Part::Text { text } => text_parts.push(text.clone()),
+                                            Part::FunctionCall { function_call } => {
+                                                final_tool_calls.push(ToolCall {
+                                                    id: format!("call_{}", uuid::Uuid::new_v4()),
+                                                    kind: "function".into(),
+                                                    function: ToolCallFunction {
+                                                        name: function_call.name.clone(),
+                                                        arguments: serde_json::to_string(
+                                                            &function_call.args,
+                                                        )
+                                                        .unwrap_or_default(),
+                                                    },
+                                                });
                                             }
-                                        }
-                                        if !text_parts.is_empty() {
-                                            final_content = Some(text_parts.join("\n"));
+                                            Part::FunctionResponse { .. } => {}
                                         }
                                     }
+                                    if !text_parts.is_empty() {
+                                        final_content = Some(text_parts.join("\n"));
+                                    }
                                 }
                             }
                             Err(e) => {
+                                record_ai_request(&provider, started, None);
                                 warn!("Failed to parse Gemini JSON: {}", e);
-                                send_text(ctx, format!("Gemini JSON error: {}", e)).await?;
+                                send_text(ctx, format!("Gemini JSON error: {e}")).await?;
                                 break;
                             }
                         }
                     }
                     Err(e) => {
-                         warn!("HTTP error: {}", e);
-                         send_text(ctx, format!("HTTP error: {}", e)).await?;
-                         break;
+                        record_ai_request(&provider, started, None);
+                        warn!("HTTP error: {}", e);
+                        send_text(ctx, format!("HTTP error: {e}")).await?;
+                        break;
                     }
                 }
             } else {
@@ -605,82 +705,115 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
                     tools: tools.clone(),
                 };
 
-                let resp = client.post(&url).bearer_auth(&api_key).json(&body).send().await;
+                let resp = client
+                    .post(&url)
+                    .bearer_auth(&api_key)
+                    .json(&body)
+                    .send()
+                    .await;
 
-                 match resp {
+                match resp {
                     Ok(r) => {
                         let status = r.status();
                         if !status.is_success() {
-                             let text = r.text().await.unwrap_or_default();
-                             warn!("AI API error: {} {}", status, text);
-                             send_text(ctx, format!("AI error: {}", text)).await?;
-                             return Ok(());
+                            record_ai_request(&provider, started, None);
+                            let text = r.text().await.unwrap_or_default();
+                            warn!("AI API error: {} {}", status, text);
+                            send_text(ctx, format!("AI error: {text}")).await?;
+                            return Ok(());
                         }
                         match r.json::<ChatResp>().await {
                             Ok(p) => {
+                                let usage = p.usage.unwrap_or_default();
+                                record_ai_request(
+                                    &provider,
+                                    started,
+                                    Some((usage.prompt_tokens, usage.completion_tokens)),
+                                );
                                 if let Some(choice) = p.choices.first() {
-                                    final_content = choice.message.content.clone();
+                                    final_content.clone_from(&choice.message.content);
                                     if let Some(tcs) = &choice.message.tool_calls {
-                                        final_tool_calls = tcs.clone();
+                                        final_tool_calls.clone_from(tcs);
                                     }
                                 }
                             }
                             Err(e) => {
+                                record_ai_request(&provider, started, None);
                                 warn!("Failed to parse JSON: {}", e);
-                                send_text(ctx, format!("JSON parse error: {}", e)).await?;
+                                send_text(ctx, format!("JSON parse error: {e}")).await?;
                                 break;
                             }
                         }
                     }
                     Err(e) => {
+                        record_ai_request(&provider, started, None);
                         warn!("HTTP error: {}", e);
-                        send_text(ctx, format!("HTTP error: {}", e)).await?;
+                        send_text(ctx, format!("HTTP error: {e}")).await?;
                         break;
                     }
                 }
             }
-            
+
             // Handle results (common)
-            let tool_calls_json = if !final_tool_calls.is_empty() {
-                Some(serde_json::to_value(&final_tool_calls).unwrap().as_array().unwrap().clone())
-            } else {
+            let tool_calls_json = if final_tool_calls.is_empty() {
                 None
+            } else {
+                Some(
+                    serde_json::to_value(&final_tool_calls)
+                        .unwrap()
+                        .as_array()
+                        .unwrap()
+                        .clone(),
+                )
             };
-            
+
             if final_content.is_some() || tool_calls_json.is_some() {
-                 messages.push(Msg {
+                messages.push(Msg {
                     role: "assistant".into(),
                     content: final_content.clone(),
                     tool_calls: tool_calls_json,
                     tool_call_id: None,
-                 });
+                });
             }
 
-            if let Some(text) = &final_content {
-                if !text.trim().is_empty() {
-                    // Output to room - RESTORE PII
-                    let restored_text = if pii_enabled {
-                        redactor.restore(text)
-                    } else {
-                        text.clone()
-                    };
-                    
-                    let header = if ctx.dev_active { "=======DEV MODE=======\n" } else { "" };
-                    let prefix = format!("@{name}:");
-                    let bold_prefix = to_bold(&prefix);
-                    let out_text = format!("{header}{bold_prefix} {restored_text}");
-                    let content = RoomMessageEventContent::text_plain(out_text);
-                    ctx.room.send(content).await?;
-                }
+            if let Some(text) = &final_content
+                && !text.trim().is_empty()
+            {
+                // Output to room - RESTORE PII
+                let restored_text = if pii_enabled {
+                    redactor.restore(text)
+                } else {
+                    text.clone()
+                };
+
+                // Model output is untrusted, so only the prefix is formatted.
+                OutgoingMessage::html(
+                    format!("@{name}: {restored_text}"),
+                    format!(
+                        "<strong>@{}:</strong> {}",
+                        escape_html(&name),
+                        escape_html(&restored_text).replace('\n', "<br>")
+                    ),
+                )
+                .reply()
+                .send(ctx)
+                .await?;
             }
 
-            if !final_tool_calls.is_empty() {
-                if log_to_room {
-                    send_text(ctx, format!("Executing {} tool calls...", final_tool_calls.len())).await?;
-                }
-                
-                for call in final_tool_calls {
-                    let mut result_content = "Tool execution failed".to_owned();
+            if final_tool_calls.is_empty() {
+                // Done
+                break;
+            }
+            if log_to_room {
+                send_text(
+                    ctx,
+                    format!("Executing {} tool calls...", final_tool_calls.len()),
+                )
+                .await?;
+            }
+
+            for call in final_tool_calls {
+                let mut result_content =
                     if let Some(&client_idx) = tool_map.get(&call.function.name) {
                         // RESTORE PII in Args
                         let args_str = if pii_enabled {
@@ -691,45 +824,56 @@ Routing prefixes like !dev.command or @dev.name are delivery hints; ignore them
 
                         if let Ok(args) = serde_json::from_str::<Value>(&args_str) {
                             info!("Calling tool {} with {:?}", call.function.name, args);
-                            match mcp_clients[client_idx].call_tool(&call.function.name, args).await {
-                                Ok(res) => {
-                                    result_content = res.to_string();
-                                }
-                                Err(e) => {
-                                    result_content = format!("Error: {}", e);
-                                }
+                            match mcp_clients[client_idx]
+                                .call_tool(&call.function.name, args)
+                                .await
+                            {
+                                Ok(res) => res.to_string(),
+                                Err(e) => format!("Error: {e}"),
                             }
                         } else {
-                            result_content = "Invalid JSON arguments".to_owned();
+                            "Invalid JSON arguments".to_owned()
                         }
                     } else {
-                        result_content = format!("Unknown tool: {}", call.function.name);
-                    }
-                    
-                    // REDACT PII in Result
-                    if pii_enabled {
-                        result_content = redactor.redact(&result_content);
-                    }
-                    
-                    messages.push(Msg {
-                        role: "tool".into(),
-                        content: Some(result_content),
-                        tool_calls: None,
-                        tool_call_id: Some(call.id.clone()),
-                    });
+                        format!("Unknown tool: {}", call.function.name)
+                    };
+
+                // REDACT PII in Result
+                if pii_enabled {
+                    result_content = redactor.redact(&result_content);
                 }
-            } else {
-                // Done
-                break;
+
+                messages.push(Msg {
+                    role: "tool".into(),
+                    content: Some(result_content),
+                    tool_calls: None,
+                    tool_call_id: Some(call.id.clone()),
+                });
             }
         }
 
         // Stop typing indicator
-        let _ = ctx.room.typing_notice(false).await;
+        let _ = ctx.room.typing(false).await;
         Ok(())
     }
 }
 
+/// Record a provider request for the metrics endpoint. `tokens` is the
+/// (prompt, completion) usage of a successful request; `None` marks a failure.
+fn record_ai_request(provider: &str, started: std::time::Instant, tokens: Option<(u64, u64)>) {
+    let outcome = if tokens.is_some() { "ok" } else { "error" };
+    metrics::counter!("matrix_bot_ai_requests_total", "provider" => provider.to_owned(), "outcome" => outcome)
+        .increment(1);
+    metrics::histogram!("matrix_bot_ai_request_duration_seconds", "provider" => provider.to_owned())
+        .record(started.elapsed());
+    if let Some((prompt, completion)) = tokens {
+        metrics::counter!("matrix_bot_ai_tokens_total", "provider" => provider.to_owned(), "kind" => "prompt")
+            .increment(prompt);
+        metrics::counter!("matrix_bot_ai_tokens_total", "provider" => provider.to_owned(), "kind" => "completion")
+            .increment(completion);
+    }
+}
+
 fn ai_env_handle() -> Option<String> {
     std::env::var("AI_HANDLE").ok().map(|raw| {
         if raw.starts_with('@') {
@@ -771,10 +915,10 @@ async fn record_history(ctx: &PluginContext, event: &OriginalSyncRoomMessageEven
         return;
     }
 
-    let sender_name = match ctx.room.get_member(&event.sender).await {
+    let sender_name = match ctx.room.member(&event.sender).await {
         Ok(Some(member)) => member
-            .display_name()
-            .map_or_else(|| event.sender.localpart().to_owned(), ToOwned::to_owned),
+            .display_name
+            .unwrap_or_else(|| event.sender.localpart().to_owned()),
         _ => event.sender.localpart().to_owned(),
     };
     let timestamp = time::OffsetDateTime::now_utc()
@@ -806,7 +950,8 @@ fn fallback_handles(ctx: &PluginContext, spec: &PluginSpec) -> BTreeSet<String>
     handles
 }
 
-fn trigger_backfill(ctx: &PluginContext, spec: &PluginSpec) {
+/// Seed the history files from the server in the background, if configured.
+fn start_backfill(ctx: &LifecycleContext, spec: &PluginSpec) {
     let enable = spec
         .config
         .get("history_backfill_on_start")
@@ -820,12 +965,10 @@ fn trigger_backfill(ctx: &PluginContext, spec: &PluginSpec) {
         .get("history_backfill_lines")
         .and_then(serde_yaml::Value::as_u64)
         .unwrap_or(50);
-    let client = ctx.client.clone();
+    let client = Arc::clone(&ctx.client);
     let history_dir = ctx.history_dir.as_ref().clone();
-    HISTORY_BACKFILL_ONCE.call_once(|| {
-        tokio::spawn(async move {
-            backfill_all(client, history_dir, limit).await;
-        });
+    tokio::spawn(async move {
+        backfill_all(client, history_dir, limit).await;
     });
 }
 
@@ -861,7 +1004,7 @@ fn read_last_history(history_dir: &Path, room_id: &OwnedRoomId, n: usize) -> Vec
 }
 
 async fn history_line_from_raw(
-    room: &Room,
+    room: &dyn MatrixRoom,
     raw_event: Raw<AnySyncTimelineEvent>,
     name_cache: &mut HashMap<OwnedUserId, String>,
 ) -> Option<String> {
@@ -907,17 +1050,17 @@ async fn history_line_from_raw(
 }
 
 async fn resolve_display_name(
-    room: &Room,
+    room: &dyn MatrixRoom,
     cache: &mut HashMap<OwnedUserId, String>,
     user_id: &OwnedUserId,
 ) -> String {
     if let Some(name) = cache.get(user_id) {
         return name.clone();
     }
-    let display = match room.get_member(user_id).await {
+    let display = match room.member(user_id).await {
         Ok(Some(member)) => member
-            .display_name()
-            .map_or_else(|| user_id.localpart().to_owned(), ToOwned::to_owned),
+            .display_name
+            .unwrap_or_else(|| user_id.localpart().to_owned()),
         _ => user_id.localpart().to_owned(),
     };
     cache.insert(user_id.clone(), display.clone());
@@ -943,7 +1086,7 @@ fn timestamp_to_rfc3339(ts: MilliSecondsSinceUnixEpoch) -> Option<String> {
         .ok()
 }
 
-pub async fn backfill_all(client: Client, history_dir: PathBuf, limit: u64) {
+pub async fn backfill_all(client: Arc<dyn MatrixClient>, history_dir: PathBuf, limit: u64) {
     if limit == 0 {
         info!(dir = %history_dir.display(), "AI backfill skipped because limit is zero");
         return;
@@ -967,11 +1110,7 @@ pub async fn backfill_all(client: Client, history_dir: PathBuf, limit: u64) {
         while remaining > 0 {
             page_counter += 1;
             let batch = remaining.min(50);
-            let mut options = MessagesOptions::backward();
-            options.from.clone_from(&from_token);
-            options.limit = (batch as u32).into();
-
-            let response = match room.messages(options).await {
+            let response = match room.messages(from_token.clone(), batch as u32).await {
                 Ok(res) => res,
                 Err(err) => {
                     warn!(room = %room_id, error = %err, "AI backfill: room/messages request failed");
@@ -980,18 +1119,18 @@ pub async fn backfill_all(client: Client, history_dir: PathBuf, limit: u64) {
             };
 
             let next_token = response.end.clone();
-            if response.chunk.is_empty() {
+            if response.events.is_empty() {
                 info!(room = %room_id, pages = page_counter, fetched = total_appended, "AI backfill: empty chunk returned");
                 break;
             }
 
             let mut appended_this_page = 0usize;
-            for timeline_event in response.chunk.into_iter().rev() {
+            for raw_event in response.events.into_iter().rev() {
                 if remaining == 0 {
                     break;
                 }
                 if let Some(line) =
-                    history_line_from_raw(&room, timeline_event.into_raw(), &mut name_cache).await
+                    history_line_from_raw(room.as_ref(), raw_event, &mut name_cache).await
                 {
                     append_history_line(&history_dir, &room_id, &line);
                     appended_this_page += 1;
@@ -1028,26 +1167,140 @@ pub async fn backfill_all(client: Client, history_dir: PathBuf, limit: u64) {
 
 // context cleansing helper removed; we now use exact history lines
 
-fn extract_log_flag(args: &str) -> (String, bool) {
-    let mut out: Vec<&str> = Vec::new();
-    let mut flag = false;
-    for t in args.split_whitespace() {
-        if t == "-log" || t == "--log" {
-            flag = true;
-        } else {
-            out.push(t);
+fn command() -> CommandSpec {
+    CommandSpec::new("!ai")
+        .about("Ask the AI assistant, or stop its answers here with !ai stop")
+        .opt(
+            Opt::flag("log")
+                .alias("-log")
+                .anywhere()
+                .help("also post tool calls to the room"),
+        )
+        .arg(Arg::rest("prompt").required())
+}
+
+fn config_schema() -> ConfigSchema {
+    let mcp_server = ConfigSchema::new()
+        .field("command", ConfigType::String)
+        .field("args", ConfigType::list(ConfigType::String));
+    ConfigSchema::new()
+        .field("name", ConfigType::String)
+        .field("provider", ConfigType::OneOf(&["openai", "gemini"]))
+        .field("api_base", ConfigType::String)
+        .field("api_path", ConfigType::String)
+        .field("api_key", ConfigType::String)
+        .field("api_key_env", ConfigType::String)
+        .field("model", ConfigType::String)
+        .field("system_prompt", ConfigType::String)
+        .field("pii_redaction", ConfigType::Bool)
+        .field("pii_ner", ConfigType::Bool)
+        .field(
+            "mcp_servers",
+            ConfigType::map(ConfigType::Object(mcp_server)),
+        )
+        .field("history_backfill_on_start", ConfigType::Bool)
+        .field("history_backfill_lines", ConfigType::Integer)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use matrix_sdk::ruma::{room_id, user_id};
+    use plugin_core::testing::FakeClient;
+
+    use super::*;
+
+    fn parse(args: &str) -> (String, bool) {
+        let m = command().parse(args).unwrap();
+        (m.get_str("prompt").unwrap().to_owned(), m.flag("log"))
+    }
+
+    #[test]
+    fn log_flag_is_found_anywhere() {
+        assert_eq!(
+            parse("--log what time is it"),
+            ("what time is it".to_owned(), true)
+        );
+        assert_eq!(
+            parse("what time is it --log"),
+            ("what time is it".to_owned(), true)
+        );
+        assert_eq!(
+            parse("what -log time\nis it"),
+            ("what time\nis it".to_owned(), true)
+        );
+        assert_eq!(
+            parse("what is a --logfile"),
+            ("what is a --logfile".to_owned(), false)
+        );
+        assert_eq!(
+            parse("\"what does --log do\""),
+            ("\"what does --log do\"".to_owned(), false)
+        );
+        assert_eq!(
+            parse("what is -- --log"),
+            ("what is -- --log".to_owned(), false)
+        );
+    }
+
+    fn spec(config: &str) -> PluginSpec {
+        PluginSpec {
+            config: serde_yaml::from_str(config).unwrap(),
+            ..AiTool.spec()
         }
     }
-    (out.join(" "), flag)
-}
 
-fn to_bold(s: &str) -> String {
-    s.chars()
-        .map(|c| match c {
-            'A'..='Z' => char::from_u32('𝐀' as u32 + (c as u32 - 'A' as u32)).unwrap_or(c),
-            'a'..='z' => char::from_u32('𝐚' as u32 + (c as u32 - 'a' as u32)).unwrap_or(c),
-            '0'..='9' => char::from_u32('𝟎' as u32 + (c as u32 - '0' as u32)).unwrap_or(c),
-            _ => c,
-        })
-        .collect()
+    #[tokio::test]
+    async fn empty_prompt_gets_the_usage() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        AiTool
+            .run(&client.context(&room), "  ", &AiTool.spec())
+            .await
+            .unwrap();
+        assert_eq!(
+            room.sent_bodies(),
+            [command().parse("").unwrap_err().to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_api_key_is_reported() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let spec = spec("api_key_env: PLUGIN_AI_TEST_KEY_THAT_IS_NEVER_SET");
+        AiTool
+            .run(&client.context(&room), "hello", &spec)
+            .await
+            .unwrap();
+        assert_eq!(
+            room.sent_bodies(),
+            ["AI key missing: set config.api_key etc"]
+        );
+    }
+
+    #[tokio::test]
+    async fn stop_cancels_answers_in_the_room() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        let forever = || tokio::time::sleep(Duration::from_secs(3600));
+        let answer = ctx
+            .registry
+            .invocations()
+            .spawn(ctx.room.room_id(), "ai", None, forever());
+        let other = ctx
+            .registry
+            .invocations()
+            .spawn(ctx.room.room_id(), "relay", None, forever());
+
+        AiTool.run(&ctx, "stop", &AiTool.spec()).await.unwrap();
+        AiTool.run(&ctx, "STOP", &AiTool.spec()).await.unwrap();
+        assert!(answer.await.unwrap_err().is_cancelled());
+        assert!(!other.is_finished());
+        assert_eq!(room.sent_bodies(), ["stopped 1 answer", "nothing to stop"]);
+        assert_eq!(room.typing_calls(), [false, false]);
+        other.abort();
+    }
 }
@@ -1,25 +1,23 @@
-use std::{
-    collections::HashMap,
-    process::Stdio,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
-};
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::{collections::HashMap, process::Stdio, sync::Arc};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context as _, Result, anyhow};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
     process::{Child, Command},
-    sync::{mpsc, Mutex},
+    sync::{Mutex, mpsc},
 };
 use tracing::{debug, error, info, warn};
 
 #[derive(Debug)]
 pub struct McpClient {
     name: String,
+    #[allow(
+        dead_code,
+        reason = "held so `kill_on_drop` stops the server with the client"
+    )]
     process: Mutex<Option<Child>>,
     tx: mpsc::Sender<JsonRpcMessage>,
     requests: Arc<Mutex<HashMap<u64, tokio::sync::oneshot::Sender<Result<Value>>>>>,
@@ -73,6 +71,9 @@ impl McpClient {
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            // The server lives as long as the AI run that started it, also
+            // when that run is aborted by `!ai stop` or shutdown.
+            .kill_on_drop(true)
             .spawn()
             .context("Failed to spawn MCP server process")?;
 
@@ -81,17 +82,18 @@ impl McpClient {
         let stderr = child.stderr.take().context("Failed to open stderr")?;
 
         let (tx, mut rx) = mpsc::channel::<JsonRpcMessage>(32);
-        let requests = Arc::new(Mutex::new(
-            HashMap::<u64, tokio::sync::oneshot::Sender<Result<Value>>>::new(),
-        ));
-        let requests_clone = requests.clone();
+        let requests = Arc::new(Mutex::new(HashMap::<
+            u64,
+            tokio::sync::oneshot::Sender<Result<Value>>,
+        >::new()));
+        let requests_clone = Arc::clone(&requests);
 
         // Writer task
         tokio::spawn(async move {
             let mut writer = stdin;
             while let Some(msg) = rx.recv().await {
                 let json = serde_json::to_string(&msg).unwrap();
-                eprintln!("Sending to MCP stdin: {}", json);
+                eprintln!("Sending to MCP stdin: {json}");
                 if let Err(e) = writer.write_all(json.as_bytes()).await {
                     error!("Failed to write to MCP stdin: {}", e);
                     break;
@@ -173,7 +175,7 @@ impl McpClient {
     async fn request(&self, method: &str, params: Option<Value>) -> Result<Value> {
         let id = self.next_id.fetch_add(1, Ordering::Relaxed);
         let (tx, rx) = tokio::sync::oneshot::channel();
-        
+
         {
             let mut map = self.requests.lock().await;
             map.insert(id, tx);
@@ -196,8 +198,8 @@ impl McpClient {
 
     async fn notify(&self, method: &str, params: Option<Value>) -> Result<()> {
         let notif = JsonRpcNotification {
-            jsonrpc: "2.0".to_string(),
-            method: method.to_string(),
+            jsonrpc: "2.0".to_owned(),
+            method: method.to_owned(),
             params,
         };
 
@@ -225,21 +227,18 @@ impl McpClient {
 
         let res = self.request("initialize", Some(params)).await?;
         debug!("MCP Initialize response: {:?}", res);
-        
+
         // After initialize, we must send notification "notifications/initialized"
-        let notif = JsonRpcNotification {
-            jsonrpc: "2.0".into(),
-            method: "notifications/initialized".into(),
-            params: None,
-        };
-        self.tx.send(JsonRpcMessage::Notification(notif)).await?;
+        self.notify("notifications/initialized", None).await?;
 
         Ok(())
     }
 
     pub async fn list_tools(&self) -> Result<Vec<McpTool>> {
         let res = self.request("tools/list", None).await?;
-        let tools_val = res.get("tools").ok_or_else(|| anyhow!("No 'tools' in response"))?;
+        let tools_val = res
+            .get("tools")
+            .ok_or_else(|| anyhow!("No 'tools' in response"))?;
         let tools: Vec<McpTool> = serde_json::from_value(tools_val.clone())?;
         Ok(tools)
     }
@@ -249,7 +248,15 @@ impl McpClient {
             "name": name,
             "arguments": args
         });
-        self.request("tools/call", Some(params)).await
+        let result = self.request("tools/call", Some(params)).await;
+        metrics::counter!(
+            "matrix_bot_mcp_tool_calls_total",
+            "server" => self.name.clone(),
+            "tool" => name.to_owned(),
+            "outcome" => if result.is_ok() { "ok" } else { "error" },
+        )
+        .increment(1);
+        result
     }
 }
 
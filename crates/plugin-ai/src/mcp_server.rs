@@ -1,10 +1,11 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead as _, Write as _};
 use time::OffsetDateTime;
 
 #[derive(Deserialize, Debug)]
 struct JsonRpcRequest {
+    #[allow(dead_code, reason = "part of the wire format")]
     jsonrpc: String,
     method: String,
     #[serde(default)]
@@ -28,15 +29,20 @@ struct JsonRpcError {
     message: String,
 }
 
+/// Runs the built-in MCP server named `server_name` on stdin/stdout.
+///
+/// # Panics
+///
+/// Panics if a response cannot be serialised or written to stdout.
 pub fn run_mcp_server(server_name: &str) {
     if server_name != "time" {
-        eprintln!("Unknown internal server: {}", server_name);
+        eprintln!("Unknown internal server: {server_name}");
         std::process::exit(1);
     }
 
     let stdin = io::stdin();
     let mut stdout = io::stdout();
-    
+
     // Tools definition
     let tools = serde_json::json!({
         "tools": [
@@ -53,14 +59,13 @@ pub fn run_mcp_server(server_name: &str) {
     });
 
     for line in stdin.lock().lines() {
-        let line = match line {
-            Ok(l) => l,
-            Err(_) => break,
+        let Ok(line) = line else {
+            break;
         };
 
         if let Ok(req) = serde_json::from_str::<JsonRpcRequest>(&line) {
             let mut response = JsonRpcResponse {
-                jsonrpc: "2.0".to_string(),
+                jsonrpc: "2.0".to_owned(),
                 result: None,
                 error: None,
                 id: req.id.clone(),
@@ -87,7 +92,9 @@ pub fn run_mcp_server(server_name: &str) {
                         if let Some(name) = params.get("name").and_then(|v| v.as_str()) {
                             if name == "get_current_time" {
                                 let now = OffsetDateTime::now_utc();
-                                let time_str = now.format(&time::format_description::well_known::Rfc3339).unwrap();
+                                let time_str = now
+                                    .format(&time::format_description::well_known::Rfc3339)
+                                    .unwrap();
                                 response.result = Some(serde_json::json!({
                                     "content": [
                                         {
@@ -97,38 +104,38 @@ pub fn run_mcp_server(server_name: &str) {
                                     ]
                                 }));
                             } else {
-                                 response.error = Some(JsonRpcError {
+                                response.error = Some(JsonRpcError {
                                     code: -32601,
-                                    message: format!("Tool not found: {}", name),
+                                    message: format!("Tool not found: {name}"),
                                 });
                             }
                         } else {
                             response.error = Some(JsonRpcError {
                                 code: -32602,
-                                message: "Missing 'name' parameter".to_string(),
+                                message: "Missing 'name' parameter".to_owned(),
                             });
                         }
                     } else {
-                         response.error = Some(JsonRpcError {
+                        response.error = Some(JsonRpcError {
                             code: -32602,
-                            message: "Invalid params".to_string(),
+                            message: "Invalid params".to_owned(),
                         });
                     }
                 }
                 "notificiations/initialized" => {
-                     // ignore
-                     continue; 
+                    // ignore
+                    continue;
                 }
                 _ => {
                     // Ignore other methods or return error?
                     // MCP has ping etc.
                 }
             }
-            
+
             if response.result.is_some() || response.error.is_some() {
-                 let out = serde_json::to_string(&response).unwrap();
-                 let _ = writeln!(stdout, "{}", out);
-                 let _ = stdout.flush();
+                let out = serde_json::to_string(&response).unwrap();
+                let _ = writeln!(stdout, "{out}");
+                let _ = stdout.flush();
             }
         }
     }
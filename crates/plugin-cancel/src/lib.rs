@@ -0,0 +1,139 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use plugin_core::{
+    Arg, CommandSpec, ConfigSchema, Plugin, PluginContext, PluginSpec, PluginTriggers, send_text,
+};
+
+/// `!cancel` stops everyone's work in the room, so it is for moderators
+/// unless the config says otherwise.
+const DEFAULT_CONFIG: &str = "
+permissions:
+  min_power_level: 50
+";
+
+#[derive(Debug)]
+pub struct CancelTool;
+
+#[async_trait]
+impl Plugin for CancelTool {
+    fn id(&self) -> &'static str {
+        "cancel"
+    }
+    fn help(&self) -> &'static str {
+        "Stop plugin work still running in this room: !cancel [plugin]"
+    }
+    fn spec(&self) -> PluginSpec {
+        PluginSpec {
+            id: "cancel".to_owned(),
+            enabled: true,
+            dev_only: None,
+            triggers: PluginTriggers {
+                commands: vec!["!cancel".to_owned()],
+                mentions: vec![],
+            },
+            config: serde_yaml::from_str(DEFAULT_CONFIG).unwrap_or_default(),
+        }
+    }
+    fn command(&self) -> Option<CommandSpec> {
+        Some(command())
+    }
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(ConfigSchema::new())
+    }
+    async fn run(&self, ctx: &PluginContext, args: &str, _spec: &PluginSpec) -> Result<()> {
+        let Some(m) = command().parse_or_reply(ctx, args).await? else {
+            return Ok(());
+        };
+        let plugin = m.get_str("plugin");
+        let stopped = ctx
+            .registry
+            .invocations()
+            .cancel(ctx.room.room_id(), plugin);
+        send_text(ctx, summary(&stopped, plugin)).await
+    }
+}
+
+fn command() -> CommandSpec {
+    CommandSpec::new("!cancel")
+        .about("Stop plugin work still running in this room")
+        .arg(Arg::new("plugin").help("only stop this plugin, e.g. ai"))
+}
+
+/// Describe what [`Invocations::cancel`](plugin_core::Invocations::cancel)
+/// stopped, e.g. "stopped 2 tasks (ai ×2)".
+fn summary(stopped: &[String], plugin: Option<&str>) -> String {
+    if stopped.is_empty() {
+        return plugin.map_or_else(
+            || "nothing is running here".to_owned(),
+            |id| format!("nothing of {id} is running here"),
+        );
+    }
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for id in stopped {
+        match counts.iter_mut().find(|(seen, _)| seen == id) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((id, 1)),
+        }
+    }
+    let parts: Vec<_> = counts
+        .into_iter()
+        .map(|(id, n)| {
+            if n == 1 {
+                id.to_owned()
+            } else {
+                format!("{id} ×{n}")
+            }
+        })
+        .collect();
+    let tasks = if stopped.len() == 1 { "task" } else { "tasks" };
+    format!("stopped {} {tasks} ({})", stopped.len(), parts.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use matrix_sdk::ruma::{room_id, user_id};
+    use plugin_core::{PermissionRules, testing::FakeClient};
+
+    use super::*;
+
+    #[test]
+    fn needs_moderator_power_level_by_default() {
+        let rules = PermissionRules::from_spec(&CancelTool.spec())
+            .unwrap()
+            .unwrap();
+        let user = user_id!("@user:example.org");
+        assert!(rules.check(user, 0).is_err());
+        assert!(rules.check(user, 50).is_ok());
+    }
+
+    #[tokio::test]
+    async fn stops_running_work_in_the_room() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        let invocations = ctx.registry.invocations();
+        let forever = || tokio::time::sleep(Duration::from_secs(3600));
+        let ai = invocations.spawn(ctx.room.room_id(), "ai", None, forever());
+        invocations.spawn(ctx.room.room_id(), "ai", None, forever());
+        invocations.spawn(ctx.room.room_id(), "relay", None, forever());
+
+        CancelTool
+            .run(&ctx, "ai", &CancelTool.spec())
+            .await
+            .unwrap();
+        assert!(ai.await.unwrap_err().is_cancelled());
+        CancelTool.run(&ctx, "", &CancelTool.spec()).await.unwrap();
+        CancelTool.run(&ctx, "", &CancelTool.spec()).await.unwrap();
+        assert_eq!(
+            room.sent_bodies(),
+            [
+                "stopped 2 tasks (ai ×2)",
+                "stopped 1 task (relay)",
+                "nothing is running here"
+            ]
+        );
+    }
+}
@@ -0,0 +1,945 @@
+use core::fmt::{self, Write as _};
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{PluginContext, escape_html, send_text};
+
+/// Declarative description of a command line: subcommands, positional
+/// arguments, options and flags.
+///
+/// ```
+/// use plugin_core::{Arg, CommandSpec, Opt};
+///
+/// let cmd = CommandSpec::new("!greet")
+///     .about("Say hello")
+///     .opt(Opt::flag("loud").short('l').help("Shout"))
+///     .arg(Arg::rest("name").required());
+/// let m = cmd.parse("-l Ada  Lovelace").unwrap();
+/// assert!(m.flag("loud"));
+/// assert_eq!(m.get_str("name"), Some("Ada  Lovelace"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct CommandSpec {
+    name: String,
+    about: String,
+    args: Vec<Arg>,
+    opts: Vec<Opt>,
+    subcommands: Vec<Self>,
+    default_subcommand: Option<String>,
+}
+
+/// A positional argument.
+#[derive(Debug, Clone)]
+pub struct Arg {
+    name: String,
+    help: String,
+    kind: ValueKind,
+    required: bool,
+    rest: bool,
+}
+
+/// An option (`--name value`) or, without a value kind, a flag (`--name`).
+#[derive(Debug, Clone)]
+pub struct Opt {
+    name: String,
+    short: Option<char>,
+    aliases: Vec<String>,
+    help: String,
+    value: Option<ValueKind>,
+    anywhere: bool,
+}
+
+/// The type a value is checked against while parsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueKind {
+    Text,
+    Integer,
+    Number,
+    Choice(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Integer(i64),
+    Number(f64),
+    Flag,
+}
+
+/// The result of a successful parse.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArgMatches {
+    path: Vec<String>,
+    values: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// `--help` or `-h` was given; not really an error.
+    Help,
+    UnterminatedQuote,
+    UnknownOption(String),
+    UnknownSubcommand(String),
+    MissingSubcommand,
+    MissingValue(String),
+    InvalidValue {
+        name: String,
+        value: String,
+        expected: String,
+    },
+    MissingArgument(String),
+    UnexpectedArgument(String),
+}
+
+/// A parse failure together with the usage (or full help) of the command
+/// it happened in. Its `Display` output is meant to be sent to the room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub usage: String,
+}
+
+impl CommandSpec {
+    #[must_use]
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            about: String::new(),
+            args: Vec::new(),
+            opts: Vec::new(),
+            subcommands: Vec::new(),
+            default_subcommand: None,
+        }
+    }
+
+    #[must_use]
+    pub fn about(mut self, about: impl Into<String>) -> Self {
+        self.about = about.into();
+        self
+    }
+
+    #[must_use]
+    pub fn arg(mut self, arg: Arg) -> Self {
+        self.args.push(arg);
+        self
+    }
+
+    #[must_use]
+    pub fn opt(mut self, opt: Opt) -> Self {
+        self.opts.push(opt);
+        self
+    }
+
+    #[must_use]
+    pub fn subcommand(mut self, sub: Self) -> Self {
+        self.subcommands.push(sub);
+        self
+    }
+
+    /// Subcommand to run when none is given.
+    #[must_use]
+    pub fn default_subcommand(mut self, name: impl Into<String>) -> Self {
+        self.default_subcommand = Some(name.into());
+        self
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[must_use]
+    pub fn about_text(&self) -> &str {
+        &self.about
+    }
+
+    #[must_use]
+    pub fn subcommands(&self) -> &[Self] {
+        &self.subcommands
+    }
+
+    fn find_subcommand(&self, name: &str) -> Option<&Self> {
+        self.subcommands
+            .iter()
+            .find(|sub| sub.name.eq_ignore_ascii_case(name))
+    }
+
+    /// One-line synopsis, e.g. `!tools enable <id> [--room]`.
+    #[must_use]
+    pub fn usage(&self) -> String {
+        Self::usage_for(&[self])
+    }
+
+    fn usage_for(chain: &[&Self]) -> String {
+        let mut out = chain
+            .iter()
+            .map(|cmd| cmd.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let Some(cmd) = chain.last() else {
+            return out;
+        };
+        for opt in &cmd.opts {
+            write!(out, " [{}]", opt.synopsis()).ok();
+        }
+        if !cmd.subcommands.is_empty() {
+            let names = cmd
+                .subcommands
+                .iter()
+                .map(|sub| sub.name.as_str())
+                .collect::<Vec<_>>()
+                .join("|");
+            if cmd.default_subcommand.is_some() {
+                write!(out, " [{names}]").ok();
+            } else {
+                write!(out, " <{names}>").ok();
+            }
+        }
+        for arg in &cmd.args {
+            write!(out, " {}", arg.synopsis()).ok();
+        }
+        out
+    }
+
+    /// Multi-line help: description, usage, subcommands, arguments and options.
+    #[must_use]
+    pub fn help(&self) -> String {
+        Self::help_for(&[self])
+    }
+
+    fn help_for(chain: &[&Self]) -> String {
+        let mut out = String::new();
+        let Some(cmd) = chain.last() else {
+            return out;
+        };
+        if !cmd.about.is_empty() {
+            writeln!(out, "{}", cmd.about).ok();
+        }
+        write!(out, "Usage: {}", Self::usage_for(chain)).ok();
+        for (title, rows) in cmd.help_sections() {
+            write!(out, "\n{title}:").ok();
+            for (synopsis, about) in rows {
+                write!(out, "\n  {synopsis}").ok();
+                if !about.is_empty() {
+                    write!(out, " — {about}").ok();
+                }
+            }
+        }
+        out
+    }
+
+    /// The same content as [`Self::help`], formatted as Matrix HTML.
+    #[must_use]
+    pub fn help_html(&self) -> String {
+        let mut out = String::new();
+        if !self.about.is_empty() {
+            write!(out, "<p>{}</p>", escape_html(&self.about)).ok();
+        }
+        write!(
+            out,
+            "<p>Usage: <code>{}</code></p>",
+            escape_html(&self.usage())
+        )
+        .ok();
+        for (title, rows) in self.help_sections() {
+            write!(out, "<p><b>{title}:</b></p><ul>").ok();
+            for (synopsis, about) in rows {
+                write!(out, "<li><code>{}</code>", escape_html(&synopsis)).ok();
+                if !about.is_empty() {
+                    write!(out, " — {}", escape_html(about)).ok();
+                }
+                out.push_str("</li>");
+            }
+            out.push_str("</ul>");
+        }
+        out
+    }
+
+    /// Subcommands, arguments and options as `(title, [(synopsis, about)])`.
+    fn help_sections(&self) -> Vec<(&'static str, Vec<(String, &str)>)> {
+        let subcommands = self
+            .subcommands
+            .iter()
+            .map(|sub| (Self::usage_for(&[sub]), sub.about.as_str()))
+            .collect();
+        let args = self
+            .args
+            .iter()
+            .filter(|arg| !arg.help.is_empty())
+            .map(|arg| (format!("<{}>", arg.name), arg.help.as_str()))
+            .collect();
+        let opts = self
+            .opts
+            .iter()
+            .map(|opt| (opt.synopsis(), opt.help.as_str()))
+            .collect();
+        [
+            ("Subcommands", subcommands),
+            ("Arguments", args),
+            ("Options", opts),
+        ]
+        .into_iter()
+        .filter(|(_, rows): &(_, Vec<_>)| !rows.is_empty())
+        .collect()
+    }
+
+    /// Parse the argument string a plugin receives in [`crate::Plugin::run`].
+    ///
+    /// Arguments are split on whitespace; single or double quotes at the start
+    /// of a word group it with the following words until the closing quote.
+    /// Options may appear anywhere before a [`Arg::rest`] argument, which takes
+    /// the remaining input verbatim; an unknown option where a rest argument is
+    /// expected starts that text instead of failing. `--` ends option parsing.
+    /// Flags marked [`Opt::anywhere`] are also taken out of a rest argument.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ParseError`] carrying usage text if the input does not fit
+    /// the declaration or `--help` was requested.
+    pub fn parse(&self, input: &str) -> Result<ArgMatches, ParseError> {
+        Parser {
+            input,
+            tokens: Tokenizer::new(input),
+            chain: vec![self],
+            matches: ArgMatches::default(),
+            positional: 0,
+            options_done: false,
+        }
+        .run()
+    }
+
+    /// Parse `input`, replying with the error or help text if that fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error only if sending the reply fails.
+    pub async fn parse_or_reply(
+        &self,
+        ctx: &PluginContext,
+        input: &str,
+    ) -> Result<Option<ArgMatches>> {
+        match self.parse(input) {
+            Ok(matches) => Ok(Some(matches)),
+            Err(e) => send_text(ctx, e.to_string()).await.map(|()| None),
+        }
+    }
+}
+
+impl Arg {
+    /// A single word (or quoted phrase).
+    #[must_use]
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            help: String::new(),
+            kind: ValueKind::Text,
+            required: false,
+            rest: false,
+        }
+    }
+
+    /// Everything from this argument to the end of the input, verbatim.
+    #[must_use]
+    pub fn rest(name: impl Into<String>) -> Self {
+        Self {
+            rest: true,
+            ..Self::new(name)
+        }
+    }
+
+    #[must_use]
+    pub const fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    #[must_use]
+    pub fn kind(mut self, kind: ValueKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    #[must_use]
+    pub fn help(mut self, help: impl Into<String>) -> Self {
+        self.help = help.into();
+        self
+    }
+
+    fn synopsis(&self) -> String {
+        let dots = if self.rest { "..." } else { "" };
+        if self.required {
+            format!("<{}{dots}>", self.name)
+        } else {
+            format!("[{}{dots}]", self.name)
+        }
+    }
+}
+
+impl Opt {
+    /// A boolean switch such as `--all`.
+    #[must_use]
+    pub fn flag(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            short: None,
+            aliases: Vec::new(),
+            help: String::new(),
+            value: None,
+            anywhere: false,
+        }
+    }
+
+    /// An option taking a value: `--name value` or `--name=value`.
+    #[must_use]
+    pub fn value(name: impl Into<String>, kind: ValueKind) -> Self {
+        Self {
+            value: Some(kind),
+            ..Self::flag(name)
+        }
+    }
+
+    #[must_use]
+    pub const fn short(mut self, short: char) -> Self {
+        self.short = Some(short);
+        self
+    }
+
+    /// Another literal spelling, e.g. `-log` for `--log`.
+    #[must_use]
+    pub fn alias(mut self, alias: impl Into<String>) -> Self {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    /// Also recognise this flag inside a [`Arg::rest`] argument, where it is
+    /// cut out of the text. Quoted words and words after `--` stay text.
+    #[must_use]
+    pub const fn anywhere(mut self) -> Self {
+        self.anywhere = true;
+        self
+    }
+
+    #[must_use]
+    pub fn help(mut self, help: impl Into<String>) -> Self {
+        self.help = help.into();
+        self
+    }
+
+    fn matches(&self, token: &str) -> bool {
+        token
+            .strip_prefix("--")
+            .is_some_and(|long| long == self.name)
+            || self.short.is_some_and(|short| {
+                token
+                    .strip_prefix('-')
+                    .is_some_and(|rest| rest.len() == short.len_utf8() && rest.starts_with(short))
+            })
+            || self.aliases.iter().any(|alias| alias == token)
+    }
+
+    fn synopsis(&self) -> String {
+        let mut out = self
+            .short
+            .map_or_else(String::new, |short| format!("-{short}|"));
+        write!(out, "--{}", self.name).ok();
+        if let Some(kind) = &self.value {
+            write!(out, " <{}>", kind.placeholder()).ok();
+        }
+        out
+    }
+}
+
+impl ValueKind {
+    fn placeholder(&self) -> String {
+        match self {
+            Self::Text => "text".to_owned(),
+            Self::Integer => "integer".to_owned(),
+            Self::Number => "number".to_owned(),
+            Self::Choice(choices) => choices.join("|"),
+        }
+    }
+
+    fn parse(&self, name: &str, raw: String) -> Result<Value, ParseErrorKind> {
+        let invalid = |raw: String| ParseErrorKind::InvalidValue {
+            name: name.to_owned(),
+            value: raw,
+            expected: self.placeholder(),
+        };
+        match self {
+            Self::Text => Ok(Value::Text(raw)),
+            Self::Integer => raw.parse().map(Value::Integer).map_err(|_| invalid(raw)),
+            Self::Number => raw.parse().map(Value::Number).map_err(|_| invalid(raw)),
+            Self::Choice(choices) => choices
+                .iter()
+                .find(|choice| choice.eq_ignore_ascii_case(&raw))
+                .map(|choice| Value::Text(choice.clone()))
+                .ok_or_else(|| invalid(raw)),
+        }
+    }
+}
+
+impl ArgMatches {
+    /// The first-level subcommand, if the command has any.
+    #[must_use]
+    pub fn subcommand(&self) -> Option<&str> {
+        self.path.first().map(String::as_str)
+    }
+
+    /// All selected subcommands, outermost first.
+    #[must_use]
+    pub fn subcommand_path(&self) -> &[String] {
+        &self.path
+    }
+
+    #[must_use]
+    pub fn flag(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+
+    #[must_use]
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match self.values.get(name) {
+            Some(Value::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn get_int(&self, name: &str) -> Option<i64> {
+        match self.values.get(name) {
+            Some(Value::Integer(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn get_number(&self, name: &str) -> Option<f64> {
+        match self.values.get(name) {
+            Some(Value::Number(n)) => Some(*n),
+            #[allow(clippy::cast_precision_loss, reason = "integers are valid numbers")]
+            Some(Value::Integer(n)) => Some(*n as f64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Help => Ok(()),
+            Self::UnterminatedQuote => f.write_str("unterminated quote"),
+            Self::UnknownOption(opt) => write!(f, "unknown option {opt}"),
+            Self::UnknownSubcommand(sub) => write!(f, "unknown subcommand {sub}"),
+            Self::MissingSubcommand => f.write_str("missing subcommand"),
+            Self::MissingValue(opt) => write!(f, "--{opt} needs a value"),
+            Self::InvalidValue {
+                name,
+                value,
+                expected,
+            } => write!(f, "invalid value {value:?} for {name}; expected {expected}"),
+            Self::MissingArgument(arg) => write!(f, "missing <{arg}>"),
+            Self::UnexpectedArgument(arg) => write!(f, "unexpected argument {arg:?}"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::Help => f.write_str(&self.usage),
+            ParseErrorKind::UnterminatedQuote
+            | ParseErrorKind::UnknownOption(_)
+            | ParseErrorKind::UnknownSubcommand(_)
+            | ParseErrorKind::MissingSubcommand
+            | ParseErrorKind::MissingValue(_)
+            | ParseErrorKind::InvalidValue { .. }
+            | ParseErrorKind::MissingArgument(_)
+            | ParseErrorKind::UnexpectedArgument(_) => {
+                write!(f, "{}\nUsage: {}", self.kind, self.usage)
+            }
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+struct Token {
+    text: String,
+    start: usize,
+    quoted: bool,
+    /// An opening quote without a closing one; only a rest argument accepts it.
+    unterminated: bool,
+}
+
+/// Splits input into words lazily, so text taken verbatim by a rest
+/// argument is never tokenized.
+struct Tokenizer<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    const fn new(input: &'a str) -> Self {
+        Self { input, pos: 0 }
+    }
+}
+
+impl Iterator for Tokenizer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.input[self.pos..];
+        let trimmed = rest.trim_start();
+        if trimmed.is_empty() {
+            self.pos = self.input.len();
+            return None;
+        }
+        let start = self.pos + (rest.len() - trimmed.len());
+        let mut chars = trimmed.char_indices();
+        let quote = trimmed.chars().next().filter(|c| matches!(c, '"' | '\''));
+        let Some(quote) = quote else {
+            let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+            self.pos = start + end;
+            return Some(Token {
+                text: trimmed[..end].to_owned(),
+                start,
+                quoted: false,
+                unterminated: false,
+            });
+        };
+        chars.next();
+        let Some((close, _)) = chars.find(|&(_, c)| c == quote) else {
+            self.pos = self.input.len();
+            return Some(Token {
+                text: trimmed.to_owned(),
+                start,
+                quoted: true,
+                unterminated: true,
+            });
+        };
+        self.pos = start + close + quote.len_utf8();
+        Some(Token {
+            text: trimmed[quote.len_utf8()..close].to_owned(),
+            start,
+            quoted: true,
+            unterminated: false,
+        })
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Tokenizer<'a>,
+    chain: Vec<&'a CommandSpec>,
+    matches: ArgMatches,
+    positional: usize,
+    options_done: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn cmd(&self) -> &'a CommandSpec {
+        self.chain[self.chain.len() - 1]
+    }
+
+    fn fail(&self, kind: ParseErrorKind) -> ParseError {
+        let usage = if kind == ParseErrorKind::Help {
+            CommandSpec::help_for(&self.chain)
+        } else {
+            CommandSpec::usage_for(&self.chain)
+        };
+        ParseError { kind, usage }
+    }
+
+    /// The next word; an unterminated quote is an error unless `allow_unterminated`.
+    fn next_token(&mut self, allow_unterminated: bool) -> Result<Option<Token>, ParseError> {
+        match self.tokens.next() {
+            Some(token) if token.unterminated && !allow_unterminated => {
+                Err(self.fail(ParseErrorKind::UnterminatedQuote))
+            }
+            token => Ok(token),
+        }
+    }
+
+    fn run(mut self) -> Result<ArgMatches, ParseError> {
+        while let Some(token) = self.next_token(self.expects_rest())? {
+            if !self.options_done && !token.quoted && is_option_like(&token.text) {
+                match token.text.as_str() {
+                    "--" => {
+                        self.options_done = true;
+                        continue;
+                    }
+                    "--help" | "-h" => return Err(self.fail(ParseErrorKind::Help)),
+                    // Unknown options where free text is expected are part of it.
+                    text if self.expects_rest() && self.find_opt(text).is_none() => {}
+                    text => {
+                        self.option(text)?;
+                        continue;
+                    }
+                }
+            }
+            if self.positional_arg(token)? {
+                break;
+            }
+        }
+        self.finish()
+    }
+
+    /// Look up an option by spelling (ignoring any `=value`) in the current
+    /// command and its parents.
+    fn find_opt(&self, text: &str) -> Option<&'a Opt> {
+        let flag = text.split_once('=').map_or(text, |(flag, _)| flag);
+        self.chain
+            .iter()
+            .rev()
+            .find_map(|cmd| cmd.opts.iter().find(|opt| opt.matches(flag)))
+    }
+
+    fn option(&mut self, text: &str) -> Result<(), ParseError> {
+        let (flag, inline) = match text.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_owned())),
+            _ => (text, None),
+        };
+        let opt = self
+            .find_opt(flag)
+            .ok_or_else(|| self.fail(ParseErrorKind::UnknownOption(flag.to_owned())))?;
+        let value = match &opt.value {
+            None if inline.is_some() => {
+                return Err(self.fail(ParseErrorKind::UnexpectedArgument(text.to_owned())));
+            }
+            None => Value::Flag,
+            Some(kind) => {
+                let raw = match inline {
+                    Some(raw) => raw,
+                    None => self
+                        .next_token(false)?
+                        .map(|token| token.text)
+                        .ok_or_else(|| self.fail(ParseErrorKind::MissingValue(opt.name.clone())))?,
+                };
+                kind.parse(&opt.name, raw).map_err(|kind| self.fail(kind))?
+            }
+        };
+        self.matches.values.insert(opt.name.clone(), value);
+        Ok(())
+    }
+
+    /// Handle a non-option word. Returns `true` once a rest argument has
+    /// consumed the remaining input.
+    fn positional_arg(&mut self, token: Token) -> Result<bool, ParseError> {
+        let cmd = self.cmd();
+        if !cmd.subcommands.is_empty() {
+            let sub = cmd
+                .find_subcommand(&token.text)
+                .ok_or_else(|| self.fail(ParseErrorKind::UnknownSubcommand(token.text)))?;
+            self.enter(sub);
+            return Ok(false);
+        }
+        let Some(arg) = cmd.args.get(self.positional) else {
+            return Err(self.fail(ParseErrorKind::UnexpectedArgument(token.text)));
+        };
+        self.positional += 1;
+        let raw = if arg.rest {
+            self.rest_text(token.start)
+        } else {
+            token.text
+        };
+        let value = arg
+            .kind
+            .parse(&arg.name, raw)
+            .map_err(|kind| self.fail(kind))?;
+        self.matches.values.insert(arg.name.clone(), value);
+        Ok(arg.rest)
+    }
+
+    /// The input from `start` on, less any [`Opt::anywhere`] flags in it,
+    /// which are recorded as matched.
+    fn rest_text(&mut self, start: usize) -> String {
+        let mut text = String::new();
+        let mut kept_from = start;
+        while !self.options_done
+            && let Some(token) = self.tokens.next()
+        {
+            if token.unterminated || (!token.quoted && token.text == "--") {
+                break;
+            }
+            let Some(opt) = self.find_anywhere(&token) else {
+                continue;
+            };
+            self.matches.values.insert(opt.name.clone(), Value::Flag);
+            // Drop the flag together with the whitespace before it.
+            text.push_str(self.input[kept_from..token.start].trim_end());
+            kept_from = token.start + token.text.len();
+        }
+        text.push_str(&self.input[kept_from..]);
+        text.trim_end().to_owned()
+    }
+
+    fn find_anywhere(&self, token: &Token) -> Option<&'a Opt> {
+        if token.quoted {
+            return None;
+        }
+        self.chain.iter().rev().find_map(|cmd| {
+            cmd.opts
+                .iter()
+                .find(|opt| opt.anywhere && opt.value.is_none() && opt.matches(&token.text))
+        })
+    }
+
+    fn expects_rest(&self) -> bool {
+        let cmd = self.cmd();
+        cmd.subcommands.is_empty() && cmd.args.get(self.positional).is_some_and(|arg| arg.rest)
+    }
+
+    fn enter(&mut self, sub: &'a CommandSpec) {
+        self.matches.path.push(sub.name.clone());
+        self.chain.push(sub);
+        self.positional = 0;
+    }
+
+    fn finish(mut self) -> Result<ArgMatches, ParseError> {
+        loop {
+            let cmd = self.cmd();
+            if cmd.subcommands.is_empty() {
+                break;
+            }
+            let Some(sub) = cmd
+                .default_subcommand
+                .as_deref()
+                .and_then(|name| cmd.find_subcommand(name))
+            else {
+                return Err(self.fail(ParseErrorKind::MissingSubcommand));
+            };
+            self.enter(sub);
+        }
+        let missing = self
+            .cmd()
+            .args
+            .iter()
+            .find(|arg| arg.required && !self.matches.values.contains_key(&arg.name));
+        if let Some(arg) = missing {
+            return Err(self.fail(ParseErrorKind::MissingArgument(arg.name.clone())));
+        }
+        Ok(self.matches)
+    }
+}
+
+/// Whether a bare word should be treated as an option; negative numbers are not.
+fn is_option_like(text: &str) -> bool {
+    text.strip_prefix('-')
+        .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_digit() || c == '.'))
+        && text.len() > 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tools() -> CommandSpec {
+        CommandSpec::new("!tools")
+            .default_subcommand("list")
+            .subcommand(CommandSpec::new("list"))
+            .subcommand(
+                CommandSpec::new("enable")
+                    .arg(Arg::new("id").required())
+                    .opt(Opt::flag("global")),
+            )
+            .subcommand(
+                CommandSpec::new("limit")
+                    .arg(Arg::new("count").kind(ValueKind::Integer).required())
+                    .opt(Opt::value(
+                        "mode",
+                        ValueKind::Choice(vec!["soft".into(), "hard".into()]),
+                    )),
+            )
+    }
+
+    #[test]
+    fn subcommands_args_and_flags() {
+        let cmd = tools();
+        assert_eq!(cmd.parse("").unwrap().subcommand(), Some("list"));
+        let m = cmd.parse("ENABLE --global 'my plugin'").unwrap();
+        assert_eq!(m.subcommand(), Some("enable"));
+        assert!(m.flag("global"));
+        assert_eq!(m.get_str("id"), Some("my plugin"));
+        let m = cmd.parse("limit -3 --mode=Hard").unwrap();
+        assert_eq!(m.get_int("count"), Some(-3));
+        assert_eq!(m.get_str("mode"), Some("hard"));
+    }
+
+    #[test]
+    fn errors_carry_usage() {
+        let cmd = tools();
+        let err = cmd.parse("enable").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::MissingArgument("id".into()));
+        assert_eq!(
+            err.to_string(),
+            "missing <id>\nUsage: !tools enable [--global] <id>"
+        );
+        let err = cmd.parse("limit ten").unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidValue { .. }));
+        assert!(matches!(
+            cmd.parse("enable x --bogus").unwrap_err().kind,
+            ParseErrorKind::UnknownOption(_)
+        ));
+        assert_eq!(
+            cmd.parse("enable \"x").unwrap_err().kind,
+            ParseErrorKind::UnterminatedQuote
+        );
+        let help = cmd.parse("--help").unwrap_err().to_string();
+        assert!(help.contains("Subcommands:\n  list\n  enable [--global] <id>"));
+    }
+
+    #[test]
+    fn rest_takes_remaining_input_verbatim() {
+        let cmd = CommandSpec::new("!ai")
+            .opt(Opt::flag("log").alias("-log"))
+            .arg(Arg::rest("prompt").required());
+        let m = cmd.parse(" -log what's  \"up\" --log ").unwrap();
+        assert!(m.flag("log"));
+        assert_eq!(m.get_str("prompt"), Some("what's  \"up\" --log"));
+        let m = cmd.parse("'twas -- brillig").unwrap();
+        assert_eq!(m.get_str("prompt"), Some("'twas -- brillig"));
+        let m = cmd.parse("-_- --log").unwrap();
+        assert_eq!(m.get_str("prompt"), Some("-_- --log"));
+        let m = cmd.parse("-- --log").unwrap();
+        assert!(!m.flag("log"));
+        assert_eq!(m.get_str("prompt"), Some("--log"));
+    }
+
+    #[test]
+    fn anywhere_flags_are_cut_out_of_rest() {
+        let cmd = CommandSpec::new("!ai")
+            .opt(Opt::flag("log").alias("-log").anywhere())
+            .arg(Arg::rest("prompt").required());
+        let prompt = |input: &str| {
+            let m = cmd.parse(input).unwrap();
+            (m.get_str("prompt").unwrap().to_owned(), m.flag("log"))
+        };
+        assert_eq!(
+            prompt("--log what time is it"),
+            ("what time is it".into(), true)
+        );
+        assert_eq!(
+            prompt("what time is it --log"),
+            ("what time is it".into(), true)
+        );
+        assert_eq!(
+            prompt("what -log time\nis it"),
+            ("what time\nis it".into(), true)
+        );
+        assert_eq!(
+            prompt("what is a --logfile"),
+            ("what is a --logfile".into(), false)
+        );
+        assert_eq!(
+            prompt("explain \"what does --log do\""),
+            ("explain \"what does --log do\"".into(), false)
+        );
+        assert_eq!(prompt("why -- --log"), ("why -- --log".into(), false));
+    }
+}
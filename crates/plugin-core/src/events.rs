@@ -0,0 +1,76 @@
+use matrix_sdk::ruma::{
+    EventId, UserId,
+    events::{
+        reaction::OriginalSyncReactionEvent,
+        room::{
+            member::OriginalSyncRoomMemberEvent,
+            message::{
+                OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContentWithoutRelation,
+            },
+            redaction::OriginalSyncRoomRedactionEvent,
+        },
+    },
+};
+
+/// The kinds of [`RoomEvent`] a plugin can opt in to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoomEventKind {
+    Reaction,
+    Membership,
+    Redaction,
+    Edit,
+}
+
+/// A room event other than a new message, passed to
+/// [`Plugin::on_room_event`](crate::Plugin::on_room_event).
+#[derive(Debug, Clone, Copy)]
+pub enum RoomEvent<'a> {
+    /// An `m.annotation` such as an emoji reaction.
+    Reaction(&'a OriginalSyncReactionEvent),
+    /// Joins, leaves, invites, kicks and bans, plus profile changes.
+    Membership(&'a OriginalSyncRoomMemberEvent),
+    Redaction(&'a OriginalSyncRoomRedactionEvent),
+    /// An `m.replace` message; `new_content` is what the edited message now says.
+    Edit {
+        event: &'a OriginalSyncRoomMessageEvent,
+        replaces: &'a EventId,
+        new_content: &'a RoomMessageEventContentWithoutRelation,
+    },
+}
+
+impl<'a> RoomEvent<'a> {
+    /// View a message event as an edit, if it replaces an earlier message.
+    #[must_use]
+    pub fn edit(event: &'a OriginalSyncRoomMessageEvent) -> Option<Self> {
+        match &event.content.relates_to {
+            Some(Relation::Replacement(replacement)) => Some(Self::Edit {
+                event,
+                replaces: &replacement.event_id,
+                new_content: &replacement.new_content,
+            }),
+            Some(Relation::Reply { .. } | Relation::Thread(_) | _) | None => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn kind(&self) -> RoomEventKind {
+        match self {
+            Self::Reaction(_) => RoomEventKind::Reaction,
+            Self::Membership(_) => RoomEventKind::Membership,
+            Self::Redaction(_) => RoomEventKind::Redaction,
+            Self::Edit { .. } => RoomEventKind::Edit,
+        }
+    }
+
+    /// Who sent the event; for membership changes this is who made the
+    /// change, not necessarily the member it is about.
+    #[must_use]
+    pub fn sender(&self) -> &UserId {
+        match self {
+            Self::Reaction(ev) => &ev.sender,
+            Self::Membership(ev) => &ev.sender,
+            Self::Redaction(ev) => &ev.sender,
+            Self::Edit { event, .. } => &event.sender,
+        }
+    }
+}
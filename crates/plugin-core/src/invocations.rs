@@ -0,0 +1,216 @@
+use core::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use tokio::task::{AbortHandle, JoinHandle};
+
+type Running = Arc<Mutex<HashMap<u64, Invocation>>>;
+
+#[derive(Debug)]
+struct Invocation {
+    room: OwnedRoomId,
+    plugin: String,
+    started: Instant,
+    task: tokio::task::Id,
+    abort: AbortHandle,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Plugin invocations running in their own task, tracked per room so they
+/// can be cancelled, e.g. by `!cancel`.
+#[derive(Debug, Default)]
+pub struct Invocations {
+    next_id: AtomicU64,
+    running: Running,
+}
+
+/// Removes an invocation from the tracker when its future is dropped,
+/// whether it finished, panicked or was aborted.
+struct Untrack {
+    running: Running,
+    id: u64,
+}
+
+impl Drop for Untrack {
+    fn drop(&mut self) {
+        lock(&self.running).remove(&self.id);
+    }
+}
+
+impl Invocations {
+    /// Spawn `fut` as `plugin`'s work in `room`, aborting it after
+    /// `timeout`. The task yields `None` if it timed out.
+    pub fn spawn<F>(
+        &self,
+        room: &RoomId,
+        plugin: &str,
+        timeout: Option<Duration>,
+        fut: F,
+    ) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let untrack = Untrack {
+            running: Arc::clone(&self.running),
+            id,
+        };
+        // Hold the lock until the task is recorded, so a task that finishes
+        // at once cannot untrack itself before it was tracked.
+        let mut running = lock(&self.running);
+        let handle = tokio::spawn(async move {
+            let _untrack = untrack;
+            match timeout {
+                Some(limit) => tokio::time::timeout(limit, fut).await.ok(),
+                None => Some(fut.await),
+            }
+        });
+        running.insert(
+            id,
+            Invocation {
+                room: room.to_owned(),
+                plugin: plugin.to_owned(),
+                started: Instant::now(),
+                task: handle.id(),
+                abort: handle.abort_handle(),
+            },
+        );
+        handle
+    }
+
+    /// Abort the work in flight in `room`, only `plugin`'s if given. The
+    /// calling task is spared, so a plugin can stop its own earlier work.
+    /// Returns the IDs of the plugins that were stopped, oldest first.
+    pub fn cancel(&self, room: &RoomId, plugin: Option<&str>) -> Vec<String> {
+        let current = tokio::task::try_id();
+        let mut running = lock(&self.running);
+        let mut ids: Vec<_> = running
+            .iter()
+            .filter(|(_, inv)| {
+                inv.room == room
+                    && plugin.is_none_or(|p| inv.plugin == p)
+                    && Some(inv.task) != current
+            })
+            .map(|(&id, inv)| (inv.started, id))
+            .collect();
+        ids.sort_unstable();
+        let stopped: Vec<_> = ids
+            .into_iter()
+            .filter_map(|(_, id)| running.remove(&id))
+            .collect();
+        drop(running);
+        stopped
+            .into_iter()
+            .map(|inv| {
+                inv.abort.abort();
+                inv.plugin
+            })
+            .collect()
+    }
+
+    /// Wait up to `grace` for the work in flight to finish, then abort the
+    /// rest. Returns the rooms where work was aborted, each once.
+    pub async fn drain(&self, grace: Duration) -> Vec<OwnedRoomId> {
+        let deadline = tokio::time::Instant::now() + grace;
+        while !self.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let mut rooms: Vec<_> = lock(&self.running)
+            .values()
+            .map(|inv| {
+                inv.abort.abort();
+                inv.room.clone()
+            })
+            .collect();
+        rooms.sort_unstable();
+        rooms.dedup();
+        // Aborted tasks drop their futures, and with them any child
+        // processes, the next time the runtime gets to them.
+        let settle = tokio::time::Instant::now() + Duration::from_secs(1);
+        while !self.is_empty() && tokio::time::Instant::now() < settle {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        rooms
+    }
+
+    /// How many invocations are in flight.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        lock(&self.running).len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::room_id;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cancel_spares_other_rooms_plugins_and_the_caller() {
+        let invocations = Arc::new(Invocations::default());
+        let room = room_id!("!a:example.org");
+        let forever = || tokio::time::sleep(Duration::from_secs(3600));
+        let ai = invocations.spawn(room, "ai", None, forever());
+        let relay = invocations.spawn(room, "relay", None, forever());
+        let elsewhere = invocations.spawn(room_id!("!b:example.org"), "ai", None, forever());
+        let stopper = {
+            let tracker = Arc::clone(&invocations);
+            invocations.spawn(
+                room,
+                "ai",
+                None,
+                async move { tracker.cancel(room, Some("ai")) },
+            )
+        };
+
+        assert_eq!(stopper.await.unwrap().unwrap(), ["ai"]);
+        assert!(ai.await.unwrap_err().is_cancelled());
+        assert!(!relay.is_finished());
+        assert_eq!(invocations.len(), 2);
+
+        assert_eq!(invocations.cancel(room, None), ["relay"]);
+        assert!(relay.await.unwrap_err().is_cancelled());
+        elsewhere.abort();
+        let _ = elsewhere.await;
+        assert!(invocations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_quick_work_and_aborts_the_rest() {
+        let invocations = Invocations::default();
+        let room = room_id!("!a:example.org");
+        let quick = invocations.spawn(room, "ping", None, async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        });
+        let stuck = invocations.spawn(
+            room_id!("!b:example.org"),
+            "ai",
+            None,
+            tokio::time::sleep(Duration::from_secs(3600)),
+        );
+
+        let aborted = invocations.drain(Duration::from_millis(200)).await;
+        assert_eq!(aborted, [room_id!("!b:example.org").to_owned()]);
+        assert!(invocations.is_empty());
+        assert!(quick.await.unwrap().is_some());
+        assert!(stuck.await.unwrap_err().is_cancelled());
+    }
+}
@@ -0,0 +1,308 @@
+use core::fmt::Debug;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{Context as _, Result, bail};
+use async_trait::async_trait;
+use futures_util::StreamExt as _;
+use matrix_sdk::{
+    Client,
+    attachment::AttachmentConfig,
+    encryption::backups::UploadState,
+    media::{MediaFormat, MediaRequestParameters},
+    room::{MessagesOptions, Room},
+    ruma::{
+        EventId, OwnedDeviceId, OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId,
+        RoomAliasId, RoomId, UserId,
+        events::room::power_levels::UserPowerLevel,
+        events::{
+            AnySyncTimelineEvent,
+            reaction::ReactionEventContent,
+            relation::Annotation,
+            room::{MediaSource, message::RoomMessageEventContent},
+        },
+        serde::Raw,
+    },
+};
+use mime::Mime;
+
+/// What plugins need to know about a room member.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberInfo {
+    pub display_name: Option<String>,
+    /// The member's power level; users with infinite power report `i64::MAX`.
+    pub power_level: i64,
+}
+
+/// One page of `/messages`, oldest-last as returned by the server.
+#[derive(Debug, Clone, Default)]
+pub struct MessagesPage {
+    pub events: Vec<Raw<AnySyncTimelineEvent>>,
+    /// Token to continue paginating from, if there are more events.
+    pub end: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncryptionStatus {
+    pub own_device_verified: Option<bool>,
+    /// Which private cross-signing keys this device holds, or `None` if
+    /// that is unknown.
+    pub cross_signing: Option<String>,
+    /// Whether secret storage is set up and its secrets are on this device.
+    pub recovery_state: String,
+    pub backup_state: String,
+    /// How far uploading room keys to the backup got, if it has started.
+    pub backup_upload: Option<String>,
+}
+
+/// The room operations plugins use, so they can run against a fake in tests.
+#[async_trait]
+pub trait MatrixRoom: Send + Sync + Debug {
+    fn room_id(&self) -> &RoomId;
+    /// Canonical alias first, then alternative aliases.
+    fn aliases(&self) -> Vec<OwnedRoomAliasId>;
+    /// Pagination token for the oldest event from the last sync, if any.
+    fn last_prev_batch(&self) -> Option<String>;
+
+    async fn send(&self, content: RoomMessageEventContent) -> Result<OwnedEventId>;
+    async fn react(&self, event_id: &EventId, key: &str) -> Result<OwnedEventId>;
+    async fn send_attachment(&self, body: &str, mime: &Mime, data: Vec<u8>)
+    -> Result<OwnedEventId>;
+    async fn typing(&self, typing: bool) -> Result<()>;
+    async fn member(&self, user: &UserId) -> Result<Option<MemberInfo>>;
+    async fn is_encrypted(&self) -> bool;
+    /// Fetch up to `limit` events backwards from `from` (or the timeline end).
+    async fn messages(&self, from: Option<String>, limit: u32) -> Result<MessagesPage>;
+}
+
+/// Account-level operations plugins use.
+#[async_trait]
+pub trait MatrixClient: Send + Sync + Debug {
+    fn user_id(&self) -> Option<OwnedUserId>;
+    fn device_id(&self) -> Option<OwnedDeviceId>;
+    fn room(&self, room_id: &RoomId) -> Option<Arc<dyn MatrixRoom>>;
+    fn joined_rooms(&self) -> Vec<Arc<dyn MatrixRoom>>;
+
+    /// Whether events from `user` are the bot's own: its user, or in
+    /// appservice mode any user in the appservice's namespace.
+    fn is_own_user(&self, user: &UserId) -> bool {
+        self.user_id().is_some_and(|own| own == user)
+    }
+
+    /// The same connection acting as `user`, one of the appservice's
+    /// virtual users.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection may not act as `user`; only appservice mode
+    /// has virtual users.
+    fn acting_as(&self, user: &UserId) -> Result<Arc<dyn MatrixClient>> {
+        bail!("cannot act as {user} outside appservice mode")
+    }
+
+    async fn resolve_alias(&self, alias: &RoomAliasId) -> Result<OwnedRoomId>;
+    async fn download(&self, source: &MediaSource) -> Result<Vec<u8>>;
+    async fn encryption_status(&self) -> EncryptionStatus;
+}
+
+/// [`MatrixClient`] backed by a real `matrix_sdk::Client`.
+#[derive(Debug, Clone)]
+pub struct SdkClient(pub Client);
+
+/// [`MatrixRoom`] backed by a real `matrix_sdk::room::Room`.
+#[derive(Debug, Clone)]
+pub struct SdkRoom(pub Room);
+
+#[async_trait]
+impl MatrixRoom for SdkRoom {
+    fn room_id(&self) -> &RoomId {
+        self.0.room_id()
+    }
+
+    fn aliases(&self) -> Vec<OwnedRoomAliasId> {
+        self.0
+            .canonical_alias()
+            .into_iter()
+            .chain(self.0.alt_aliases())
+            .collect()
+    }
+
+    fn last_prev_batch(&self) -> Option<String> {
+        self.0.last_prev_batch()
+    }
+
+    async fn send(&self, content: RoomMessageEventContent) -> Result<OwnedEventId> {
+        Ok(self.0.send(content).await?.event_id)
+    }
+
+    async fn react(&self, event_id: &EventId, key: &str) -> Result<OwnedEventId> {
+        let content =
+            ReactionEventContent::new(Annotation::new(event_id.to_owned(), key.to_owned()));
+        Ok(self.0.send(content).await?.event_id)
+    }
+
+    async fn send_attachment(
+        &self,
+        body: &str,
+        mime: &Mime,
+        data: Vec<u8>,
+    ) -> Result<OwnedEventId> {
+        let response = self
+            .0
+            .send_attachment(body, mime, data, AttachmentConfig::new())
+            .await?;
+        Ok(response.event_id)
+    }
+
+    async fn typing(&self, typing: bool) -> Result<()> {
+        Ok(self.0.typing_notice(typing).await?)
+    }
+
+    async fn member(&self, user: &UserId) -> Result<Option<MemberInfo>> {
+        Ok(self.0.get_member(user).await?.map(|member| MemberInfo {
+            display_name: member.display_name().map(ToOwned::to_owned),
+            power_level: match member.power_level() {
+                UserPowerLevel::Int(level) => level.into(),
+                UserPowerLevel::Infinite | _ => i64::MAX,
+            },
+        }))
+    }
+
+    async fn is_encrypted(&self) -> bool {
+        self.0
+            .latest_encryption_state()
+            .await
+            .is_ok_and(|state| state.is_encrypted())
+    }
+
+    async fn messages(&self, from: Option<String>, limit: u32) -> Result<MessagesPage> {
+        let mut options = MessagesOptions::backward();
+        options.from = from;
+        options.limit = limit.into();
+        let response = self.0.messages(options).await?;
+        Ok(MessagesPage {
+            events: response
+                .chunk
+                .into_iter()
+                .map(matrix_sdk::deserialized_responses::TimelineEvent::into_raw)
+                .collect(),
+            end: response.end,
+        })
+    }
+}
+
+#[async_trait]
+impl MatrixClient for SdkClient {
+    fn user_id(&self) -> Option<OwnedUserId> {
+        self.0.user_id().map(ToOwned::to_owned)
+    }
+
+    fn device_id(&self) -> Option<OwnedDeviceId> {
+        self.0.device_id().map(ToOwned::to_owned)
+    }
+
+    fn room(&self, room_id: &RoomId) -> Option<Arc<dyn MatrixRoom>> {
+        self.0
+            .get_room(room_id)
+            .map(|room| Arc::new(SdkRoom(room)) as Arc<dyn MatrixRoom>)
+    }
+
+    fn joined_rooms(&self) -> Vec<Arc<dyn MatrixRoom>> {
+        self.0
+            .joined_rooms()
+            .into_iter()
+            .map(|room| Arc::new(SdkRoom(room)) as Arc<dyn MatrixRoom>)
+            .collect()
+    }
+
+    async fn resolve_alias(&self, alias: &RoomAliasId) -> Result<OwnedRoomId> {
+        let response = self
+            .0
+            .resolve_room_alias(alias)
+            .await
+            .with_context(|| format!("resolving {alias}"))?;
+        Ok(response.room_id)
+    }
+
+    async fn download(&self, source: &MediaSource) -> Result<Vec<u8>> {
+        let request = MediaRequestParameters {
+            source: source.clone(),
+            format: MediaFormat::File,
+        };
+        Ok(self.0.media().get_media_content(&request, true).await?)
+    }
+
+    async fn encryption_status(&self) -> EncryptionStatus {
+        let encryption = self.0.encryption();
+        let own_device_verified = match encryption.get_own_device().await {
+            Ok(Some(device)) => Some(device.is_verified()),
+            _ => None,
+        };
+        let cross_signing = encryption.cross_signing_status().await.map(|status| {
+            let missing: Vec<&str> = [
+                (status.has_master, "master"),
+                (status.has_self_signing, "self-signing"),
+                (status.has_user_signing, "user-signing"),
+            ]
+            .into_iter()
+            .filter_map(|(has, key)| (!has).then_some(key))
+            .collect();
+            match missing.len() {
+                0 => "all keys present".to_owned(),
+                3 => "not set up".to_owned(),
+                _ => format!("missing {} key", missing.join(", ")),
+            }
+        });
+        let backup_upload = self.0.user_id().and_then(|user_id| {
+            lock_uploads().get(user_id).map(|state| match state {
+                UploadState::Uploading(counts) => {
+                    format!("{}/{} room keys", counts.backed_up, counts.total)
+                }
+                UploadState::Error => "failed, retrying later".to_owned(),
+                UploadState::Done | UploadState::Idle => "all room keys uploaded".to_owned(),
+            })
+        });
+        EncryptionStatus {
+            own_device_verified,
+            cross_signing,
+            recovery_state: format!("{:?}", encryption.recovery().state()),
+            backup_state: format!("{:?}", encryption.backups().state()),
+            backup_upload,
+        }
+    }
+}
+
+/// The last room key upload progress of each logged-in user. The SDK only
+/// reports it as a stream, see [`track_backup_uploads`].
+static BACKUP_UPLOADS: Mutex<BTreeMap<OwnedUserId, UploadState>> = Mutex::new(BTreeMap::new());
+
+fn lock_uploads() -> MutexGuard<'static, BTreeMap<OwnedUserId, UploadState>> {
+    BACKUP_UPLOADS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Follow `client`'s room key backup uploads so
+/// [`MatrixClient::encryption_status`] can report their progress.
+pub fn track_backup_uploads(client: &Client) {
+    let Some(user_id) = client.user_id().map(ToOwned::to_owned) else {
+        return;
+    };
+    let mut progress = client
+        .encryption()
+        .backups()
+        .wait_for_steady_state()
+        .subscribe_to_progress();
+    tokio::spawn(async move {
+        // Lagging only skips updates; the stream ends with the client.
+        while let Some(update) = progress.next().await {
+            let Ok(state) = update else { continue };
+            // `Idle` follows every other state, which says more.
+            if !matches!(state, UploadState::Idle) {
+                lock_uploads().insert(user_id.clone(), state);
+            }
+        }
+    });
+}
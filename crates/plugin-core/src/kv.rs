@@ -0,0 +1,391 @@
+use core::time::Duration;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, Result};
+use rusqlite::{Connection, OptionalExtension as _, TransactionBehavior, params};
+use serde::{Serialize, de::DeserializeOwned};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS plugin_kv (
+    namespace TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    expires_at INTEGER,
+    PRIMARY KEY (namespace, key)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS plugin_kv_expires_at ON plugin_kv (expires_at)
+    WHERE expires_at IS NOT NULL;
+";
+
+/// Rows that have not expired as of `?now`.
+const LIVE: &str = "(expires_at IS NULL OR expires_at > ?)";
+
+/// Durable key-value storage for plugins, one `SQLite` database shared by all
+/// of them. Plugins get their own namespace through [`PluginContext::kv`]
+/// and store JSON documents under string keys.
+///
+/// [`PluginContext::kv`]: crate::PluginContext::kv
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    reason = "milliseconds since 1970 fit in i64 for the next 290 million years"
+)]
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn expiry(ttl: Duration) -> i64 {
+    now_ms().saturating_add(i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX))
+}
+
+impl KvStore {
+    pub const FILE_NAME: &'static str = "plugin-state.sqlite3";
+
+    /// Open (or create) `plugin-state.sqlite3` inside the bot's store directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database cannot be opened or migrated.
+    pub fn in_dir(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let path = dir.join(Self::FILE_NAME);
+        let conn =
+            Connection::open(&path).with_context(|| format!("opening {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Self::init(conn).with_context(|| format!("initializing {}", path.display()))
+    }
+
+    /// A store that lives only as long as the process, for tests.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `SQLite` cannot allocate the database.
+    pub fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        conn.execute(
+            "DELETE FROM plugin_kv WHERE expires_at <= ?",
+            params![now_ms()],
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Write the WAL back into the database file, so a stopped bot leaves a
+    /// single self-contained file behind.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the checkpoint fails.
+    pub async fn checkpoint(&self) -> Result<()> {
+        self.with_conn(|conn| {
+            conn.pragma_update(None, "wal_checkpoint", "TRUNCATE")?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The store as seen by one plugin.
+    pub(crate) fn namespace(&self, namespace: &str) -> PluginKv {
+        PluginKv {
+            store: self.clone(),
+            namespace: Arc::from(namespace),
+        }
+    }
+
+    /// Run `f` on the connection without blocking the async runtime.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || f(&mut lock(&conn)))
+            .await
+            .context("key-value store task failed")?
+    }
+}
+
+/// One plugin's namespace in the [`KvStore`]. Values are serialized as JSON;
+/// keys with a time to live disappear once it has passed.
+#[derive(Debug, Clone)]
+pub struct PluginKv {
+    store: KvStore,
+    namespace: Arc<str>,
+}
+
+impl PluginKv {
+    /// # Errors
+    ///
+    /// Returns an error if the database fails or the stored value does not
+    /// deserialize as `T`.
+    pub async fn get<T: DeserializeOwned + Send + 'static>(&self, key: &str) -> Result<Option<T>> {
+        let namespace = Arc::clone(&self.namespace);
+        let key = key.to_owned();
+        self.store
+            .with_conn(move |conn| {
+                let raw = read(conn, &namespace, &key)?;
+                raw.map(|(value, _)| decode(&key, &value)).transpose()
+            })
+            .await
+    }
+
+    /// Store `value` under `key` with no expiry, replacing any earlier value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `value` does not serialize or the write fails.
+    pub async fn put<T: Serialize + Sync + ?Sized>(&self, key: &str, value: &T) -> Result<()> {
+        self.write(key, serde_json::to_string(value)?, None).await
+    }
+
+    /// Like [`PluginKv::put`], but the key disappears after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `value` does not serialize or the write fails.
+    pub async fn put_with_ttl<T: Serialize + Sync + ?Sized>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.write(key, serde_json::to_string(value)?, Some(expiry(ttl)))
+            .await
+    }
+
+    async fn write(&self, key: &str, value: String, expires_at: Option<i64>) -> Result<()> {
+        let namespace = Arc::clone(&self.namespace);
+        let key = key.to_owned();
+        self.store
+            .with_conn(move |conn| {
+                upsert(conn, &namespace, &key, &value, expires_at)?;
+                // Writes are rare enough that sweeping here keeps the table
+                // small without a background task.
+                conn.execute(
+                    "DELETE FROM plugin_kv WHERE expires_at <= ?",
+                    params![now_ms()],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Remove `key`; returns whether it existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database fails.
+    pub async fn delete(&self, key: &str) -> Result<bool> {
+        let namespace = Arc::clone(&self.namespace);
+        let key = key.to_owned();
+        self.store
+            .with_conn(move |conn| {
+                let removed = conn.execute(
+                    &format!("DELETE FROM plugin_kv WHERE namespace = ? AND key = ? AND {LIVE}"),
+                    params![namespace, key, now_ms()],
+                )?;
+                Ok(removed > 0)
+            })
+            .await
+    }
+
+    /// Every live key starting with `prefix`, in key order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database fails or a value does not
+    /// deserialize as `T`; use `serde_json::Value` for mixed values.
+    pub async fn list<T: DeserializeOwned + Send + 'static>(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, T)>> {
+        let namespace = Arc::clone(&self.namespace);
+        let prefix = prefix.to_owned();
+        self.store
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT key, value FROM plugin_kv
+                     WHERE namespace = ? AND substr(key, 1, length(?2)) = ?2 AND {LIVE}
+                     ORDER BY key"
+                ))?;
+                let rows = stmt.query_map(params![namespace, prefix, now_ms()], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?;
+                rows.map(|row| {
+                    let (key, value) = row?;
+                    let value = decode(&key, &value)?;
+                    Ok((key, value))
+                })
+                .collect()
+            })
+            .await
+    }
+
+    /// Atomically replace the value of `key` with `f(current)`; `None` in
+    /// either direction means the key is absent. An existing time to live is
+    /// kept. Returns the new value.
+    ///
+    /// `f` runs while the store is locked, so it should be quick.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database fails or the current value does not
+    /// deserialize as `T`.
+    pub async fn update<T, F>(&self, key: &str, f: F) -> Result<Option<T>>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        F: FnOnce(Option<T>) -> Option<T> + Send + 'static,
+    {
+        let namespace = Arc::clone(&self.namespace);
+        let key = key.to_owned();
+        self.store
+            .with_conn(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let (current, expires_at) = match read(&tx, &namespace, &key)? {
+                    Some((value, expires_at)) => (Some(decode(&key, &value)?), expires_at),
+                    None => (None, None),
+                };
+                let new = f(current);
+                match &new {
+                    Some(value) => {
+                        let value = serde_json::to_string(value)?;
+                        upsert(&tx, &namespace, &key, &value, expires_at)?;
+                    }
+                    None => {
+                        tx.execute(
+                            "DELETE FROM plugin_kv WHERE namespace = ? AND key = ?",
+                            params![namespace, key],
+                        )?;
+                    }
+                }
+                tx.commit()?;
+                Ok(new)
+            })
+            .await
+    }
+}
+
+fn read(conn: &Connection, namespace: &str, key: &str) -> Result<Option<(String, Option<i64>)>> {
+    let row = conn
+        .query_row(
+            &format!(
+                "SELECT value, expires_at FROM plugin_kv WHERE namespace = ? AND key = ? AND {LIVE}"
+            ),
+            params![namespace, key, now_ms()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    Ok(row)
+}
+
+fn upsert(
+    conn: &Connection,
+    namespace: &str,
+    key: &str,
+    value: &str,
+    expires_at: Option<i64>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO plugin_kv (namespace, key, value, expires_at) VALUES (?, ?, ?, ?)
+         ON CONFLICT (namespace, key) DO UPDATE
+         SET value = excluded.value, expires_at = excluded.expires_at",
+        params![namespace, key, value, expires_at],
+    )?;
+    Ok(())
+}
+
+fn decode<T: DeserializeOwned>(key: &str, value: &str) -> Result<T> {
+    serde_json::from_str(value).with_context(|| format!("decoding stored value for {key:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn namespaces_are_isolated() {
+        let store = KvStore::in_memory().unwrap();
+        let echo = store.namespace("echo");
+        let relay = store.namespace("relay");
+        echo.put("greeting", "hi").await.unwrap();
+        relay
+            .put("greeting", &json!({ "text": "hello" }))
+            .await
+            .unwrap();
+
+        assert_eq!(echo.get::<String>("greeting").await.unwrap().unwrap(), "hi");
+        assert!(relay.delete("greeting").await.unwrap());
+        assert!(!relay.delete("greeting").await.unwrap());
+        assert_eq!(relay.get::<Value>("greeting").await.unwrap(), None);
+        assert!(echo.get::<String>("greeting").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn lists_by_prefix_and_drops_expired_keys() {
+        let store = KvStore::in_memory().unwrap();
+        let kv = store.namespace("ai");
+        kv.put("user:@a", &1).await.unwrap();
+        kv.put("user:@b", &2).await.unwrap();
+        kv.put("user_x", &3).await.unwrap();
+        kv.put_with_ttl("user:@c", &4, Duration::from_millis(1))
+            .await
+            .unwrap();
+        store.namespace("other").put("user:@d", &5).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let listed: Vec<(String, u32)> = kv.list("user:").await.unwrap();
+        assert_eq!(
+            listed,
+            [("user:@a".to_owned(), 1), ("user:@b".to_owned(), 2)]
+        );
+        assert_eq!(kv.get::<u32>("user:@c").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn concurrent_updates_are_not_lost() {
+        let kv = KvStore::in_memory().unwrap().namespace("counter");
+        let tasks: Vec<_> = (0..20)
+            .map(|_| {
+                let kv = kv.clone();
+                tokio::spawn(async move {
+                    kv.update("hits", |n: Option<u32>| Some(n.unwrap_or(0) + 1))
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(kv.get::<u32>("hits").await.unwrap(), Some(20));
+
+        assert_eq!(
+            kv.update("hits", |_: Option<u32>| None).await.unwrap(),
+            None
+        );
+        assert_eq!(kv.get::<u32>("hits").await.unwrap(), None);
+    }
+}
@@ -1,40 +1,126 @@
-use core::fmt::Debug;
+mod command;
+mod events;
+mod invocations;
+mod io;
+mod kv;
+mod message;
+mod permissions;
+mod ratelimit;
+mod schema;
+mod secrets;
+mod settings;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+pub use command::{
+    Arg, ArgMatches, CommandSpec, Opt, ParseError, ParseErrorKind, Value, ValueKind,
+};
+pub use events::{RoomEvent, RoomEventKind};
+pub use invocations::Invocations;
+pub use io::{
+    EncryptionStatus, MatrixClient, MatrixRoom, MemberInfo, MessagesPage, SdkClient, SdkRoom,
+    track_backup_uploads,
+};
+pub use kv::{KvStore, PluginKv};
+pub use message::{
+    OutgoingMessage, escape_html, react, reply_markdown, send_html, send_markdown, send_notice,
+    send_text,
+};
+pub use permissions::{Denial, DenyReason, PermissionRules, member_power_level};
+pub use ratelimit::{
+    BucketRule, LimitScope, RateLimitRules, RateLimiter, ThrottleNotice, Throttled,
+};
+pub use schema::{ConfigError, ConfigSchema, ConfigType, check_spec};
+pub use secrets::{redact_secrets, register_secret};
+pub use settings::{PersistedOverride, PersistedSettings, SettingsStore};
+
+use core::{fmt::Debug, time::Duration};
 use std::{
     borrow::ToOwned,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     path::PathBuf,
     sync::Arc,
 };
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use async_trait::async_trait;
-use matrix_sdk::{
-    Client,
-    room::Room,
-    ruma::events::room::message::{OriginalSyncRoomMessageEvent, RoomMessageEventContent},
-};
+use matrix_sdk::ruma::{UserId, events::room::message::OriginalSyncRoomMessageEvent};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 #[derive(Clone, Debug)]
 pub struct PluginContext {
-    pub client: Client,
-    pub room: Room,
+    pub client: Arc<dyn MatrixClient>,
+    pub room: Arc<dyn MatrixRoom>,
+    /// The message that invoked the plugin, for replies and reactions.
+    pub event: Option<Arc<OriginalSyncRoomMessageEvent>>,
     pub dev_active: bool,
     pub dev_id: Option<Arc<str>>,
     pub registry: Arc<PluginRegistry>,
     pub history_dir: Arc<PathBuf>,
+    /// Reach it through [`PluginContext::kv`].
+    pub store: KvStore,
+}
+
+impl PluginContext {
+    /// `plugin`'s own namespace in the persistent key-value store.
+    #[must_use]
+    pub fn kv<P: Plugin + ?Sized>(&self, plugin: &P) -> PluginKv {
+        self.store.namespace(plugin.id())
+    }
+
+    /// This context with the client and room acting as `user`, so what is
+    /// sent through it, e.g. an [`OutgoingMessage`], comes from that
+    /// virtual user. Replies still point at the triggering event.
+    ///
+    /// # Errors
+    ///
+    /// Fails outside appservice mode or if `user` is not in the
+    /// appservice's namespace.
+    pub fn acting_as(&self, user: &UserId) -> Result<Self> {
+        let client = self.client.acting_as(user)?;
+        let room = client
+            .room(self.room.room_id())
+            .with_context(|| format!("{user} has no handle on {}", self.room.room_id()))?;
+        Ok(Self {
+            client,
+            room,
+            ..self.clone()
+        })
+    }
+}
+
+/// What lifecycle hooks get instead of a room-bound [`PluginContext`].
+#[derive(Clone, Debug)]
+pub struct LifecycleContext {
+    pub client: Arc<dyn MatrixClient>,
+    pub dev_active: bool,
+    pub registry: Arc<PluginRegistry>,
+    pub history_dir: Arc<PathBuf>,
+    /// Reach it through [`LifecycleContext::kv`].
+    pub store: KvStore,
+}
+
+impl LifecycleContext {
+    /// `plugin`'s own namespace in the persistent key-value store.
+    #[must_use]
+    pub fn kv<P: Plugin + ?Sized>(&self, plugin: &P) -> PluginKv {
+        self.store.namespace(plugin.id())
+    }
 }
 
 #[derive(Debug)]
 pub struct RoomMessageMeta<'a> {
     pub body: Option<&'a str>,
+    /// Plugins this message invoked by command or mention. Invocations run
+    /// concurrently, so these have been started but may not have finished.
     pub triggered_plugins: &'a HashSet<String>,
 }
 
 #[async_trait]
 pub trait Plugin: Send + Sync + Debug {
     fn id(&self) -> &'static str;
+    /// One-line summary shown in the `!help` listing.
     fn help(&self) -> &'static str;
     /// Return this plugin's default specifications to be merged at startup.
     fn spec(&self) -> PluginSpec;
@@ -59,6 +145,70 @@ pub trait Plugin: Send + Sync + Debug {
     ) -> Result<()> {
         Ok(())
     }
+
+    /// Non-message room events this plugin wants in [`Plugin::on_room_event`].
+    /// They are gated like room messages: dev-only plugins only see them in
+    /// dev mode, disabled plugins not at all, and the bot's own events only
+    /// with [`Plugin::wants_own_messages`]. Like room messages they run in
+    /// the background, in order per room, limited by `timeout_secs`.
+    fn room_event_kinds(&self) -> &'static [RoomEventKind] {
+        &[]
+    }
+
+    async fn on_room_event(
+        &self,
+        _ctx: &PluginContext,
+        _event: &RoomEvent<'_>,
+        _spec: &PluginSpec,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Declared command line, shown in detail by `!help <id>`. Plugins that
+    /// parse their arguments with a [`CommandSpec`] should return it here.
+    fn command(&self) -> Option<CommandSpec> {
+        None
+    }
+
+    /// Keys this plugin reads from its config, checked by `--check-config`.
+    /// Keys every plugin accepts, such as `permissions`, need not be listed.
+    /// Without a schema only those shared keys are checked.
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        None
+    }
+
+    /// Called after a config reload swapped in `spec`; drop anything cached
+    /// from the previous spec.
+    async fn on_reload(&self, _spec: &PluginSpec) {}
+
+    /// Called once after the first sync, or when a config reload adds the
+    /// plugin, before any tick. Warm caches or spawn background work here;
+    /// long jobs should not block the caller.
+    async fn on_start(&self, _ctx: &LifecycleContext, _spec: &PluginSpec) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called on Ctrl+C or SIGTERM before the process exits.
+    async fn on_shutdown(&self) {}
+
+    /// How often [`Plugin::on_tick`] runs by default; `None` never ticks.
+    /// Operators can override it with `tick_interval_secs` in the plugin's
+    /// config, where 0 turns ticking off.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Periodic work, called with the current spec every tick interval.
+    async fn on_tick(&self, _ctx: &LifecycleContext, _spec: &PluginSpec) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Re-reads configuration and swaps the result into a running registry.
+#[async_trait]
+pub trait Reloader: Send + Sync + Debug {
+    /// Reload and return a short human-readable summary.
+    async fn reload(&self) -> Result<String>;
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
@@ -82,6 +232,25 @@ pub struct PluginSpec {
     pub config: serde_yaml::Value,
 }
 
+impl PluginSpec {
+    /// Used when the config has no `timeout_secs`.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+    /// How long one invocation may run: `timeout_secs` from the config, where
+    /// 0 means no limit.
+    #[must_use]
+    pub fn invocation_timeout(&self) -> Option<Duration> {
+        let Some(value) = self.config.get("timeout_secs") else {
+            return Some(Self::DEFAULT_TIMEOUT);
+        };
+        match value.as_f64() {
+            Some(secs) if secs > 0.0 => Duration::try_from_secs_f64(secs).ok(),
+            Some(_) => None,
+            None => Some(Self::DEFAULT_TIMEOUT),
+        }
+    }
+}
+
 const fn enabled_true() -> bool {
     true
 }
@@ -92,17 +261,79 @@ pub struct PluginEntry {
     pub plugin: Arc<dyn Plugin + Send + Sync>,
 }
 
+/// Where an enable/disable override applies.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(tag = "scope", content = "target", rename_all = "snake_case")]
+pub enum OverrideScope {
+    Global,
+    Cluster(String),
+    Room(String),
+}
+
+/// Which layer decided a plugin's effective enabled state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnabledSource {
+    Default,
+    Global,
+    Cluster(String),
+    Room,
+}
+
+impl core::fmt::Display for EnabledSource {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Default => f.write_str("default"),
+            Self::Global => f.write_str("global"),
+            Self::Cluster(name) => write!(f, "cluster {name}"),
+            Self::Room => f.write_str("room"),
+        }
+    }
+}
+
+/// The identifiers a room can be referenced by in cluster definitions.
+#[derive(Debug, Clone)]
+pub struct RoomRef {
+    pub id: String,
+    pub aliases: Vec<String>,
+}
+
+impl RoomRef {
+    #[must_use]
+    pub fn from_room(room: &dyn MatrixRoom) -> Self {
+        let aliases = room
+            .aliases()
+            .into_iter()
+            .map(|alias| alias.to_string())
+            .collect();
+        Self {
+            id: room.room_id().to_string(),
+            aliases,
+        }
+    }
+
+    fn matches(&self, room_ref: &str) -> bool {
+        self.id == room_ref || self.aliases.iter().any(|a| a == room_ref)
+    }
+}
+
 #[derive(Default, Debug)]
 struct RegistryInner {
     by_id: HashMap<String, PluginEntry>,
     by_command: HashMap<String, String>,
     by_mention: HashMap<String, String>,
-    overrides: HashMap<String, bool>,
+    overrides: HashMap<String, HashMap<OverrideScope, bool>>,
+    /// Cluster name -> room IDs/aliases, in config order.
+    clusters: Vec<(String, Vec<String>)>,
+    plugin_settings: BTreeMap<String, serde_json::Value>,
+    /// Where overrides and plugin settings are persisted, once attached.
+    store: Option<SettingsStore>,
+    reloader: Option<Arc<dyn Reloader>>,
 }
 
 #[derive(Clone, Default, Debug)]
 pub struct PluginRegistry {
     inner: Arc<RwLock<RegistryInner>>,
+    invocations: Arc<Invocations>,
 }
 
 impl PluginRegistry {
@@ -111,6 +342,12 @@ impl PluginRegistry {
         Self::default()
     }
 
+    /// Plugin work currently in flight, for cancelling it.
+    #[must_use]
+    pub fn invocations(&self) -> &Invocations {
+        &self.invocations
+    }
+
     pub async fn register(
         &self,
         spec: PluginSpec,
@@ -137,6 +374,51 @@ impl PluginRegistry {
         previous
     }
 
+    /// Atomically replace every registered plugin and the known clusters, e.g.
+    /// after a config reload. Overrides and plugin settings are kept.
+    pub async fn replace_plugins(
+        &self,
+        entries: Vec<(PluginSpec, Arc<dyn Plugin + Send + Sync>)>,
+        clusters: Vec<(String, Vec<String>)>,
+    ) {
+        let mut by_id = HashMap::new();
+        let mut by_command = HashMap::new();
+        let mut by_mention = HashMap::new();
+        for (spec, plugin) in entries {
+            for cmd in &spec.triggers.commands {
+                by_command.insert(normalize_cmd(cmd), spec.id.clone());
+            }
+            for mention in &spec.triggers.mentions {
+                by_mention.insert(normalize_mention(mention), spec.id.clone());
+            }
+            by_id.insert(spec.id.clone(), PluginEntry { spec, plugin });
+        }
+        let mut inner = self.inner.write().await;
+        inner.by_id = by_id;
+        inner.by_command = by_command;
+        inner.by_mention = by_mention;
+        inner.clusters = clusters;
+    }
+
+    pub async fn set_reloader(&self, reloader: Arc<dyn Reloader>) {
+        let mut inner = self.inner.write().await;
+        inner.reloader = Some(reloader);
+    }
+
+    /// Ask the attached [`Reloader`] to re-read configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no reloader is attached or the reload failed; the
+    /// previous configuration stays active in that case.
+    pub async fn reload(&self) -> Result<String> {
+        let reloader = self.inner.read().await.reloader.clone();
+        match reloader {
+            Some(reloader) => reloader.reload().await,
+            None => Err(anyhow::anyhow!("config reload is not available")),
+        }
+    }
+
     pub async fn unregister(&self, id: &str) -> Option<PluginEntry> {
         let mut inner = self.inner.write().await;
         let removed = inner.by_id.remove(id);
@@ -176,25 +458,192 @@ impl PluginRegistry {
             .collect()
     }
 
-    pub async fn set_override(&self, id: impl Into<String>, enabled: bool) {
+    /// Load persisted overrides and plugin settings from `store` and write all
+    /// later changes back to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store cannot be read; the store is not attached
+    /// in that case so a corrupt file is never overwritten.
+    pub async fn attach_store(&self, store: SettingsStore) -> Result<()> {
+        let persisted = store.load()?;
         let mut inner = self.inner.write().await;
-        inner.overrides.insert(id.into(), enabled);
+        for o in persisted.overrides {
+            inner
+                .overrides
+                .entry(o.plugin)
+                .or_default()
+                .insert(o.scope, o.enabled);
+        }
+        inner.plugin_settings.extend(persisted.plugin_settings);
+        inner.store = Some(store);
+        drop(inner);
+        Ok(())
     }
 
-    pub async fn clear_override(&self, id: &str) {
+    /// Set an override and persist it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if persisting fails; the override still applies in memory.
+    pub async fn set_override(
+        &self,
+        id: impl Into<String>,
+        scope: OverrideScope,
+        enabled: bool,
+    ) -> Result<()> {
         let mut inner = self.inner.write().await;
-        inner.overrides.remove(id);
+        inner
+            .overrides
+            .entry(id.into())
+            .or_default()
+            .insert(scope, enabled);
+        inner.persist()
+    }
+
+    /// Remove the override for `id` at `scope`, returning whether one existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if persisting the change fails.
+    pub async fn clear_override(&self, id: &str, scope: &OverrideScope) -> Result<bool> {
+        let mut inner = self.inner.write().await;
+        let Some(scopes) = inner.overrides.get_mut(id) else {
+            return Ok(false);
+        };
+        let removed = scopes.remove(scope).is_some();
+        if scopes.is_empty() {
+            inner.overrides.remove(id);
+        }
+        inner.persist()?;
+        drop(inner);
+        Ok(removed)
+    }
+
+    /// Remove every override for `id`, returning how many were cleared.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if persisting the change fails.
+    pub async fn clear_overrides(&self, id: &str) -> Result<usize> {
+        let mut inner = self.inner.write().await;
+        let removed = inner.overrides.remove(id).map_or(0, |scopes| scopes.len());
+        inner.persist()?;
+        drop(inner);
+        Ok(removed)
+    }
+
+    /// Runtime settings a plugin stored through [`Self::set_plugin_setting`].
+    pub async fn plugin_setting(&self, id: &str) -> Option<serde_json::Value> {
+        let inner = self.inner.read().await;
+        inner.plugin_settings.get(id).cloned()
     }
 
+    /// Store (or with `None`, remove) a plugin's runtime settings and persist them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if persisting fails.
+    pub async fn set_plugin_setting(
+        &self,
+        id: impl Into<String>,
+        value: Option<serde_json::Value>,
+    ) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        let id = id.into();
+        match value {
+            Some(value) => inner.plugin_settings.insert(id, value),
+            None => inner.plugin_settings.remove(&id),
+        };
+        inner.persist()
+    }
+
+    /// Replace the known room clusters used to resolve cluster-scoped overrides.
+    pub async fn set_clusters(&self, clusters: Vec<(String, Vec<String>)>) {
+        let mut inner = self.inner.write().await;
+        inner.clusters = clusters;
+    }
+
+    /// Names of the clusters that contain `room`, in config order.
+    pub async fn clusters_for(&self, room: &RoomRef) -> Vec<String> {
+        let inner = self.inner.read().await;
+        inner.clusters_for(room).map(ToOwned::to_owned).collect()
+    }
+
+    /// Whether the plugin is enabled ignoring room and cluster overrides.
     #[must_use]
     pub async fn is_enabled(&self, id: &str) -> bool {
         let inner = self.inner.read().await;
         let default = inner.by_id.get(id).is_some_and(|entry| entry.spec.enabled);
-        inner.overrides.get(id).copied().unwrap_or(default)
+        inner
+            .overrides
+            .get(id)
+            .and_then(|scopes| scopes.get(&OverrideScope::Global))
+            .copied()
+            .unwrap_or(default)
+    }
+
+    #[must_use]
+    pub async fn is_enabled_in(&self, id: &str, room: &RoomRef) -> bool {
+        self.effective_state(id, room).await.0
+    }
+
+    /// Resolve whether `id` is enabled in `room`, most specific override first:
+    /// room, then cluster, then global, then the plugin spec default.
+    pub async fn effective_state(&self, id: &str, room: &RoomRef) -> (bool, EnabledSource) {
+        self.inner.read().await.effective_state(id, room)
     }
 }
 
 impl RegistryInner {
+    fn persist(&self) -> Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let mut overrides: Vec<PersistedOverride> = self
+            .overrides
+            .iter()
+            .flat_map(|(plugin, scopes)| {
+                scopes.iter().map(|(scope, enabled)| PersistedOverride {
+                    plugin: plugin.clone(),
+                    scope: scope.clone(),
+                    enabled: *enabled,
+                })
+            })
+            .collect();
+        overrides.sort_by(|a, b| (&a.plugin, &a.scope).cmp(&(&b.plugin, &b.scope)));
+        store.save(&PersistedSettings {
+            overrides,
+            plugin_settings: self.plugin_settings.clone(),
+        })
+    }
+
+    fn effective_state(&self, id: &str, room: &RoomRef) -> (bool, EnabledSource) {
+        let default = self.by_id.get(id).is_some_and(|entry| entry.spec.enabled);
+        let Some(scopes) = self.overrides.get(id) else {
+            return (default, EnabledSource::Default);
+        };
+        if let Some(enabled) = scopes.get(&OverrideScope::Room(room.id.clone())) {
+            return (*enabled, EnabledSource::Room);
+        }
+        for cluster in self.clusters_for(room) {
+            if let Some(enabled) = scopes.get(&OverrideScope::Cluster(cluster.to_owned())) {
+                return (*enabled, EnabledSource::Cluster(cluster.to_owned()));
+            }
+        }
+        if let Some(enabled) = scopes.get(&OverrideScope::Global) {
+            return (*enabled, EnabledSource::Global);
+        }
+        (default, EnabledSource::Default)
+    }
+
+    fn clusters_for<'a>(&'a self, room: &'a RoomRef) -> impl Iterator<Item = &'a str> {
+        self.clusters
+            .iter()
+            .filter(|(_, rooms)| rooms.iter().any(|r| room.matches(r)))
+            .map(|(name, _)| name.as_str())
+    }
+
     fn remove_triggers_for(&mut self, id: &str) {
         self.by_command.retain(|_, existing| existing != id);
         self.by_mention.retain(|_, existing| existing != id);
@@ -231,33 +680,121 @@ pub fn truncate(s: &str, max: usize) -> String {
     s.chars().take(max).collect()
 }
 
-#[must_use]
-fn decorate_dev(text: &str, dev_active: bool) -> String {
-    if dev_active {
-        format!("=======DEV MODE=======\n{text}")
-    } else {
-        text.to_owned()
-    }
-}
-
-/// Send a plain-text message to the current room.
-///
-/// The message text will be decorated with a development mode banner when
-/// `PluginContext.dev_active` is true.
-///
-/// # Errors
-///
-/// Returns an error if sending the message fails. The underlying error is
-/// propagated from the matrix-sdk send operation.
-pub async fn send_text(ctx: &PluginContext, text: impl Into<String>) -> Result<()> {
-    let text = text.into();
-    let content = RoomMessageEventContent::text_plain(decorate_dev(&text, ctx.dev_active));
-    ctx.room.send(content).await?;
-    Ok(())
-}
-
 #[must_use]
 pub fn sanitize_line(s: &str, max: usize) -> String {
     let compact = s.split_whitespace().collect::<Vec<_>>().join(" ");
     truncate(&compact, max)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Noop;
+
+    #[async_trait]
+    impl Plugin for Noop {
+        fn id(&self) -> &'static str {
+            "noop"
+        }
+        fn help(&self) -> &'static str {
+            ""
+        }
+        fn spec(&self) -> PluginSpec {
+            PluginSpec {
+                id: "noop".to_owned(),
+                enabled: true,
+                dev_only: None,
+                triggers: PluginTriggers::default(),
+                config: serde_yaml::Value::default(),
+            }
+        }
+        async fn run(&self, _ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn room(id: &str) -> RoomRef {
+        RoomRef {
+            id: id.to_owned(),
+            aliases: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn overrides_resolve_most_specific_first() {
+        let registry = PluginRegistry::new();
+        registry.register(Noop.spec(), Arc::new(Noop)).await;
+        registry
+            .set_clusters(vec![(
+                "pair".to_owned(),
+                vec!["!a:x".to_owned(), "!b:x".to_owned()],
+            )])
+            .await;
+        let (a, b, c) = (room("!a:x"), room("!b:x"), room("!c:x"));
+
+        registry
+            .set_override("noop", OverrideScope::Global, false)
+            .await
+            .unwrap();
+        registry
+            .set_override("noop", OverrideScope::Cluster("pair".to_owned()), true)
+            .await
+            .unwrap();
+        registry
+            .set_override("noop", OverrideScope::Room("!a:x".to_owned()), false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            registry.effective_state("noop", &a).await,
+            (false, EnabledSource::Room)
+        );
+        assert_eq!(
+            registry.effective_state("noop", &b).await,
+            (true, EnabledSource::Cluster("pair".to_owned()))
+        );
+        assert_eq!(
+            registry.effective_state("noop", &c).await,
+            (false, EnabledSource::Global)
+        );
+
+        assert!(
+            registry
+                .clear_override("noop", &OverrideScope::Global)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            registry.effective_state("noop", &c).await,
+            (true, EnabledSource::Default)
+        );
+    }
+
+    #[tokio::test]
+    async fn overrides_survive_a_new_registry() {
+        let dir = std::env::temp_dir().join(format!("plugin-core-settings-{}", std::process::id()));
+        let registry = PluginRegistry::new();
+        registry.register(Noop.spec(), Arc::new(Noop)).await;
+        registry
+            .attach_store(SettingsStore::in_dir(&dir))
+            .await
+            .unwrap();
+        registry
+            .set_override("noop", OverrideScope::Room("!a:x".to_owned()), false)
+            .await
+            .unwrap();
+
+        let reloaded = PluginRegistry::new();
+        reloaded.register(Noop.spec(), Arc::new(Noop)).await;
+        reloaded
+            .attach_store(SettingsStore::in_dir(&dir))
+            .await
+            .unwrap();
+        assert!(!reloaded.is_enabled_in("noop", &room("!a:x")).await);
+
+        assert_eq!(reloaded.clear_overrides("noop").await.unwrap(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
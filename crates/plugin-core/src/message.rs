@@ -0,0 +1,340 @@
+use anyhow::{Result, anyhow};
+use matrix_sdk::ruma::{
+    OwnedEventId,
+    events::room::message::{
+        AddMentions, FormattedBody, ForwardThread, OriginalSyncRoomMessageEvent,
+        ReplacementMetadata, ReplyWithinThread, RoomMessageEventContent,
+    },
+};
+
+use crate::PluginContext;
+
+const DEV_BANNER: &str = "=======DEV MODE=======";
+
+/// A message built up by a plugin before sending it to the current room.
+///
+/// ```ignore
+/// let id = OutgoingMessage::markdown("Working on it…").reply().send(ctx).await?;
+/// OutgoingMessage::markdown("**Done.**").replacing(id).send(ctx).await?;
+/// ```
+///
+/// Every message gets the development mode banner when
+/// `PluginContext.dev_active` is true.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct OutgoingMessage {
+    plain: String,
+    html: Option<String>,
+    notice: bool,
+    relation: Relation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Relation {
+    None,
+    Reply,
+    Thread,
+    Replace(OwnedEventId),
+}
+
+impl OutgoingMessage {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            plain: text.into(),
+            html: None,
+            notice: false,
+            relation: Relation::None,
+        }
+    }
+
+    /// Markdown source; the plain body keeps the source as written. Inline
+    /// HTML passes through, so escape untrusted input with [`escape_html`].
+    pub fn markdown(markdown: impl Into<String>) -> Self {
+        let plain = markdown.into();
+        let html = FormattedBody::markdown(&plain).map(|formatted| formatted.body);
+        Self {
+            html,
+            ..Self::text(plain)
+        }
+    }
+
+    /// An HTML body with a plain-text fallback.
+    pub fn html(plain: impl Into<String>, html: impl Into<String>) -> Self {
+        Self {
+            html: Some(html.into()),
+            ..Self::text(plain)
+        }
+    }
+
+    /// Send as `m.notice`, which other bots are expected to ignore.
+    pub const fn notice(mut self) -> Self {
+        self.notice = true;
+        self
+    }
+
+    /// Reply to the event that triggered the plugin. Without one the message
+    /// is sent normally.
+    pub fn reply(mut self) -> Self {
+        self.relation = Relation::Reply;
+        self
+    }
+
+    /// Reply in the triggering event's thread, starting one at that event if
+    /// it is not in a thread yet.
+    pub fn in_thread(mut self) -> Self {
+        self.relation = Relation::Thread;
+        self
+    }
+
+    /// Edit an earlier message of the bot's instead of sending a new one.
+    pub fn replacing(mut self, event_id: OwnedEventId) -> Self {
+        self.relation = Relation::Replace(event_id);
+        self
+    }
+
+    /// Send to the current room and return the new event's ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if sending the message fails.
+    pub async fn send(self, ctx: &PluginContext) -> Result<OwnedEventId> {
+        let content = self.into_content(ctx.dev_active, ctx.event.as_deref());
+        ctx.room.send(content).await
+    }
+
+    fn into_content(
+        self,
+        dev_active: bool,
+        trigger: Option<&OriginalSyncRoomMessageEvent>,
+    ) -> RoomMessageEventContent {
+        let plain = decorate_dev(&self.plain, dev_active);
+        let html = self.html.map(|html| {
+            if dev_active {
+                format!("<p>{DEV_BANNER}</p>{html}")
+            } else {
+                html
+            }
+        });
+        let content = match (html, self.notice) {
+            (Some(html), true) => RoomMessageEventContent::notice_html(plain, html),
+            (Some(html), false) => RoomMessageEventContent::text_html(plain, html),
+            (None, true) => RoomMessageEventContent::notice_plain(plain),
+            (None, false) => RoomMessageEventContent::text_plain(plain),
+        };
+        match (self.relation, trigger) {
+            (Relation::Replace(event_id), _) => {
+                content.make_replacement(ReplacementMetadata::new(event_id, None))
+            }
+            (Relation::Reply, Some(event)) => {
+                content.make_reply_to(event, ForwardThread::Yes, AddMentions::Yes)
+            }
+            (Relation::Thread, Some(event)) => {
+                content.make_for_thread(event, ReplyWithinThread::No, AddMentions::No)
+            }
+            (Relation::None | Relation::Reply | Relation::Thread, _) => content,
+        }
+    }
+}
+
+#[must_use]
+fn decorate_dev(text: &str, dev_active: bool) -> String {
+    if dev_active {
+        format!("{DEV_BANNER}\n{text}")
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Send a plain-text message to the current room.
+///
+/// The message text will be decorated with a development mode banner when
+/// `PluginContext.dev_active` is true.
+///
+/// # Errors
+///
+/// Returns an error if sending the message fails. The underlying error is
+/// propagated from the matrix-sdk send operation.
+pub async fn send_text(ctx: &PluginContext, text: impl Into<String>) -> Result<()> {
+    OutgoingMessage::text(text).send(ctx).await?;
+    Ok(())
+}
+
+/// Send a message with an HTML body and a plain-text fallback.
+///
+/// Both bodies get the development mode banner when `PluginContext.dev_active`
+/// is true.
+///
+/// # Errors
+///
+/// Returns an error if sending the message fails.
+pub async fn send_html(
+    ctx: &PluginContext,
+    plain: impl Into<String>,
+    html: impl Into<String>,
+) -> Result<()> {
+    OutgoingMessage::html(plain, html).send(ctx).await?;
+    Ok(())
+}
+
+/// Send markdown, rendered to HTML, to the current room.
+///
+/// # Errors
+///
+/// Returns an error if sending the message fails.
+pub async fn send_markdown(ctx: &PluginContext, markdown: impl Into<String>) -> Result<()> {
+    OutgoingMessage::markdown(markdown).send(ctx).await?;
+    Ok(())
+}
+
+/// Send a plain-text `m.notice` to the current room.
+///
+/// # Errors
+///
+/// Returns an error if sending the message fails.
+pub async fn send_notice(ctx: &PluginContext, text: impl Into<String>) -> Result<()> {
+    OutgoingMessage::text(text).notice().send(ctx).await?;
+    Ok(())
+}
+
+/// Reply to the triggering event with markdown.
+///
+/// # Errors
+///
+/// Returns an error if sending the message fails.
+pub async fn reply_markdown(ctx: &PluginContext, markdown: impl Into<String>) -> Result<()> {
+    OutgoingMessage::markdown(markdown)
+        .reply()
+        .send(ctx)
+        .await?;
+    Ok(())
+}
+
+/// React to the triggering event with `key`, usually an emoji.
+///
+/// # Errors
+///
+/// Returns an error if there is no triggering event or sending fails.
+pub async fn react(ctx: &PluginContext, key: &str) -> Result<OwnedEventId> {
+    let event = ctx
+        .event
+        .as_ref()
+        .ok_or_else(|| anyhow!("no triggering event to react to"))?;
+    ctx.room.react(&event.event_id, key).await
+}
+
+/// Escape text for inclusion in an HTML message body.
+#[must_use]
+pub fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{
+        event_id,
+        events::room::message::{MessageType, Relation as EventRelation},
+    };
+
+    use super::*;
+
+    fn trigger(content: &serde_json::Value) -> OriginalSyncRoomMessageEvent {
+        serde_json::from_value(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$trigger:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    fn html_of(content: &RoomMessageEventContent) -> Option<&str> {
+        let MessageType::Text(text) = &content.msgtype else {
+            return None;
+        };
+        text.formatted.as_ref().map(|f| f.body.as_str())
+    }
+
+    #[test]
+    fn markdown_renders_with_dev_banner() {
+        let content = OutgoingMessage::markdown("**hi** & bye").into_content(true, None);
+        assert_eq!(content.body(), "=======DEV MODE=======\n**hi** & bye");
+        assert_eq!(
+            html_of(&content),
+            Some("<p>=======DEV MODE=======</p><strong>hi</strong> &amp; bye")
+        );
+
+        let plain = OutgoingMessage::markdown("just text").into_content(false, None);
+        assert_eq!(html_of(&plain), None);
+    }
+
+    #[test]
+    fn relations_target_the_trigger() {
+        let event = trigger(&serde_json::json!({ "msgtype": "m.text", "body": "q" }));
+        let reply = OutgoingMessage::text("a")
+            .reply()
+            .into_content(false, Some(&event));
+        assert!(matches!(
+            reply.relates_to,
+            Some(EventRelation::Reply { in_reply_to }) if in_reply_to.event_id == event.event_id
+        ));
+
+        let threaded = OutgoingMessage::text("a")
+            .in_thread()
+            .into_content(false, Some(&event));
+        assert!(matches!(
+            threaded.relates_to,
+            Some(EventRelation::Thread(thread)) if thread.event_id == event.event_id
+        ));
+
+        // Without a trigger there is nothing to reply to.
+        let standalone = OutgoingMessage::text("a").reply().into_content(false, None);
+        assert!(standalone.relates_to.is_none());
+    }
+
+    #[test]
+    fn thread_replies_stay_in_the_existing_thread() {
+        let event = trigger(&serde_json::json!({
+            "msgtype": "m.text",
+            "body": "q",
+            "m.relates_to": {
+                "rel_type": "m.thread",
+                "event_id": "$root:example.org",
+                "is_falling_back": true,
+                "m.in_reply_to": { "event_id": "$root:example.org" },
+            },
+        }));
+        let content = OutgoingMessage::text("a")
+            .notice()
+            .in_thread()
+            .into_content(false, Some(&event));
+        assert!(matches!(content.msgtype, MessageType::Notice(_)));
+        assert!(matches!(
+            content.relates_to,
+            Some(EventRelation::Thread(thread)) if thread.event_id == "$root:example.org"
+        ));
+    }
+
+    #[test]
+    fn edits_replace_the_given_event() {
+        let original = event_id!("$mine:example.org").to_owned();
+        let content = OutgoingMessage::text("fixed")
+            .replacing(original.clone())
+            .into_content(false, None);
+        assert!(matches!(
+            content.relates_to,
+            Some(EventRelation::Replacement(replacement)) if replacement.event_id == original
+        ));
+    }
+}
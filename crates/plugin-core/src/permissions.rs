@@ -0,0 +1,238 @@
+use core::fmt;
+use std::collections::HashMap;
+
+use anyhow::{Context as _, Result};
+use matrix_sdk::ruma::UserId;
+use serde::{Deserialize, Serialize};
+
+use crate::{MatrixRoom, PluginSpec};
+
+/// Access rules for a plugin or one of its subcommands.
+///
+/// Rules are read from the `permissions` key of a plugin's config, so they
+/// can live either in `config.yaml` or in `plugins/<id>/config.yaml`:
+///
+/// ```yaml
+/// permissions:
+///   allow_servers: ["example.org"]
+///   subcommands:
+///     disable:
+///       min_power_level: 50
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PermissionRules {
+    /// Full user IDs that may always run the command (bypasses `min_power_level`).
+    #[serde(default)]
+    pub allow_users: Vec<String>,
+    /// Full user IDs that may never run the command.
+    #[serde(default)]
+    pub deny_users: Vec<String>,
+    /// Homeserver domains whose users may run the command.
+    #[serde(default)]
+    pub allow_servers: Vec<String>,
+    /// Homeserver domains whose users may never run the command.
+    #[serde(default)]
+    pub deny_servers: Vec<String>,
+    /// Minimum power level the sender needs in the room.
+    #[serde(default)]
+    pub min_power_level: Option<i64>,
+    /// Additional rules for subcommands, keyed by the first argument.
+    #[serde(default)]
+    pub subcommands: HashMap<String, Self>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DenyReason {
+    UserDenied,
+    ServerDenied,
+    NotAllowed,
+    PowerLevel { required: i64, actual: i64 },
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UserDenied => f.write_str("user is on the deny list"),
+            Self::ServerDenied => f.write_str("homeserver is on the deny list"),
+            Self::NotAllowed => f.write_str("user is not on the allow list"),
+            Self::PowerLevel { required, actual } => {
+                write!(f, "requires power level {required} (you have {actual})")
+            }
+        }
+    }
+}
+
+/// A failed permission check, including the subcommand whose rules denied it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denial {
+    pub subcommand: Option<String>,
+    pub reason: DenyReason,
+}
+
+impl PermissionRules {
+    /// Parse the `permissions` key from a plugin spec, if present.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the `permissions` value does not match the expected shape.
+    pub fn from_spec(spec: &PluginSpec) -> Result<Option<Self>> {
+        spec.config
+            .get("permissions")
+            .map(|value| {
+                serde_yaml::from_value(value.clone())
+                    .with_context(|| format!("parsing permissions for plugin {}", spec.id))
+            })
+            .transpose()
+    }
+
+    /// Rules that apply to `subcommand`, matched case-insensitively.
+    #[must_use]
+    pub fn subcommand(&self, subcommand: &str) -> Option<&Self> {
+        self.subcommands
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(subcommand))
+            .map(|(_, rules)| rules)
+    }
+
+    /// Whether evaluating these rules (or those of `subcommand`) needs the
+    /// sender's room power level.
+    #[must_use]
+    pub fn needs_power_level(&self, subcommand: Option<&str>) -> bool {
+        self.min_power_level.is_some()
+            || subcommand
+                .and_then(|sub| self.subcommand(sub))
+                .is_some_and(|rules| rules.min_power_level.is_some())
+    }
+
+    /// Check the plugin-level rules and then the rules of `subcommand`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first [`Denial`] encountered.
+    pub fn check_invocation(
+        &self,
+        sender: &UserId,
+        power_level: i64,
+        subcommand: Option<&str>,
+    ) -> Result<(), Denial> {
+        self.check(sender, power_level).map_err(|reason| Denial {
+            subcommand: None,
+            reason,
+        })?;
+        if let Some(sub) = subcommand
+            && let Some(rules) = self.subcommand(sub)
+        {
+            rules.check(sender, power_level).map_err(|reason| Denial {
+                subcommand: Some(sub.to_lowercase()),
+                reason,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Check a single level of rules, ignoring `subcommands`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`DenyReason`] if the sender is not permitted.
+    pub fn check(&self, sender: &UserId, power_level: i64) -> Result<(), DenyReason> {
+        let user = sender.as_str();
+        let server = sender.server_name().as_str();
+        if self.deny_users.iter().any(|u| u == user) {
+            return Err(DenyReason::UserDenied);
+        }
+        if self
+            .deny_servers
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(server))
+        {
+            return Err(DenyReason::ServerDenied);
+        }
+        let user_allowed = self.allow_users.iter().any(|u| u == user);
+        if user_allowed {
+            return Ok(());
+        }
+        let has_allow_list = !self.allow_users.is_empty() || !self.allow_servers.is_empty();
+        let server_allowed = self
+            .allow_servers
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(server));
+        if has_allow_list && !server_allowed {
+            return Err(DenyReason::NotAllowed);
+        }
+        if let Some(required) = self.min_power_level
+            && power_level < required
+        {
+            return Err(DenyReason::PowerLevel {
+                required,
+                actual: power_level,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Look up `user`'s power level in `room`, treating unknown members as level 0.
+pub async fn member_power_level(room: &dyn MatrixRoom, user: &UserId) -> i64 {
+    match room.member(user).await {
+        Ok(Some(member)) => member.power_level,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::user_id;
+
+    use super::*;
+
+    fn rules(yaml: &str) -> PermissionRules {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn empty_rules_allow_everyone() {
+        let r = PermissionRules::default();
+        assert_eq!(r.check(user_id!("@a:example.org"), 0), Ok(()));
+    }
+
+    #[test]
+    fn deny_beats_allow() {
+        let r = rules("allow_servers: [example.org]\ndeny_users: ['@a:example.org']");
+        assert_eq!(
+            r.check(user_id!("@a:example.org"), 100),
+            Err(DenyReason::UserDenied)
+        );
+        assert_eq!(r.check(user_id!("@b:example.org"), 0), Ok(()));
+        assert_eq!(
+            r.check(user_id!("@c:other.org"), 0),
+            Err(DenyReason::NotAllowed)
+        );
+    }
+
+    #[test]
+    fn allowed_user_bypasses_power_level() {
+        let r = rules(
+            "allow_users: ['@admin:example.org']\nallow_servers: [example.org]\nmin_power_level: 50",
+        );
+        assert_eq!(r.check(user_id!("@admin:example.org"), 0), Ok(()));
+        assert_eq!(
+            r.check(user_id!("@b:example.org"), 10),
+            Err(DenyReason::PowerLevel {
+                required: 50,
+                actual: 10
+            })
+        );
+    }
+
+    #[test]
+    fn subcommand_rules_apply_after_plugin_rules() {
+        let r = rules("subcommands:\n  Disable:\n    min_power_level: 50");
+        let sender = user_id!("@a:example.org");
+        assert!(r.needs_power_level(Some("disable")));
+        assert!(!r.needs_power_level(Some("list")));
+        assert_eq!(r.check_invocation(sender, 0, Some("list")), Ok(()));
+        let denial = r.check_invocation(sender, 0, Some("disable")).unwrap_err();
+        assert_eq!(denial.subcommand.as_deref(), Some("disable"));
+    }
+}
@@ -0,0 +1,322 @@
+use core::{fmt, time::Duration};
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use anyhow::{Context as _, Result, bail};
+use matrix_sdk::ruma::{RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+use crate::PluginSpec;
+
+/// Token-bucket limits for a plugin, read from the `rate_limit` key of its
+/// config:
+///
+/// ```yaml
+/// rate_limit:
+///   per_user: { burst: 3, per_minute: 2 }
+///   per_room: { burst: 10, per_minute: 6 }
+///   exempt_users: ["@admin:example.org"]
+///   notify: react
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RateLimitRules {
+    /// Bucket per (sender, plugin).
+    #[serde(default)]
+    pub per_user: Option<BucketRule>,
+    /// Bucket per (room, plugin), shared by everyone in the room.
+    #[serde(default)]
+    pub per_room: Option<BucketRule>,
+    /// Full user IDs that are never limited.
+    #[serde(default)]
+    pub exempt_users: Vec<String>,
+    #[serde(default)]
+    pub notify: ThrottleNotice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct BucketRule {
+    /// Invocations allowed back to back.
+    pub burst: u32,
+    /// Sustained invocations per minute once the burst is spent.
+    pub per_minute: f64,
+}
+
+/// How a throttled invocation is answered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottleNotice {
+    #[default]
+    Reply,
+    React,
+    Silent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitScope {
+    User,
+    Room,
+}
+
+impl fmt::Display for LimitScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User => f.write_str("for you"),
+            Self::Room => f.write_str("in this room"),
+        }
+    }
+}
+
+/// A rejected invocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Throttled {
+    pub scope: LimitScope,
+    pub retry_after: Duration,
+    /// Whether this is the first rejection since the bucket ran dry; later
+    /// ones should not be answered again.
+    pub first: bool,
+}
+
+impl RateLimitRules {
+    /// Parse the `rate_limit` key from a plugin spec, if present.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the `rate_limit` value does not match the expected
+    /// shape or a bucket refills at no more than 0 per minute.
+    pub fn from_spec(spec: &PluginSpec) -> Result<Option<Self>> {
+        let Some(value) = spec.config.get("rate_limit") else {
+            return Ok(None);
+        };
+        let rules: Self = serde_yaml::from_value(value.clone())
+            .with_context(|| format!("parsing rate_limit for plugin {}", spec.id))?;
+        for (name, bucket) in [("per_user", rules.per_user), ("per_room", rules.per_room)] {
+            if let Some(bucket) = bucket
+                && !(bucket.per_minute.is_finite() && bucket.per_minute > 0.0)
+            {
+                bail!(
+                    "rate_limit.{name}.per_minute for plugin {} must be above 0",
+                    spec.id
+                );
+            }
+        }
+        Ok(Some(rules))
+    }
+
+    fn is_exempt(&self, user: &UserId) -> bool {
+        self.exempt_users.iter().any(|u| u == user.as_str())
+    }
+}
+
+impl BucketRule {
+    fn capacity(self) -> f64 {
+        f64::from(self.burst.max(1))
+    }
+
+    fn per_second(self) -> f64 {
+        self.per_minute.max(0.0) / 60.0
+    }
+}
+
+/// Buckets untouched for this long are full again and can be forgotten.
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(3600);
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    notified: bool,
+}
+
+impl Bucket {
+    fn refill(&mut self, rule: BucketRule, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = rule
+            .capacity()
+            .min(elapsed.mul_add(rule.per_second(), self.tokens));
+        self.updated = now;
+    }
+}
+
+/// In-memory token buckets keyed by scope, plugin and user or room.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(LimitScope, String, String), Bucket>>,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take one token from every bucket that applies, or none if any is empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Throttled`] for the first bucket without a token.
+    pub fn check(
+        &self,
+        plugin: &str,
+        rules: &RateLimitRules,
+        user: &UserId,
+        room: &RoomId,
+    ) -> Result<(), Throttled> {
+        self.check_at(plugin, rules, user, room, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        plugin: &str,
+        rules: &RateLimitRules,
+        user: &UserId,
+        room: &RoomId,
+        now: Instant,
+    ) -> Result<(), Throttled> {
+        if rules.is_exempt(user) {
+            return Ok(());
+        }
+        let applicable: Vec<_> = [
+            (LimitScope::User, user.as_str(), rules.per_user),
+            (LimitScope::Room, room.as_str(), rules.per_room),
+        ]
+        .into_iter()
+        .filter_map(|(scope, subject, rule)| {
+            rule.map(|rule| ((scope, plugin.to_owned(), subject.to_owned()), rule))
+        })
+        .collect();
+        if applicable.is_empty() {
+            return Ok(());
+        }
+
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                now.saturating_duration_since(bucket.updated) < IDLE_BUCKET_TTL
+            });
+        }
+        for (key, rule) in &applicable {
+            let bucket = buckets.entry(key.clone()).or_insert_with(|| Bucket {
+                tokens: rule.capacity(),
+                updated: now,
+                notified: false,
+            });
+            bucket.refill(*rule, now);
+            if bucket.tokens < 1.0 {
+                let first = !bucket.notified;
+                bucket.notified = true;
+                let wait = (1.0 - bucket.tokens) / rule.per_second();
+                return Err(Throttled {
+                    scope: key.0,
+                    retry_after: Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX),
+                    first,
+                });
+            }
+        }
+        for (key, _) in &applicable {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+                bucket.notified = false;
+            }
+        }
+        drop(buckets);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{room_id, user_id};
+
+    use super::*;
+
+    fn rules(yaml: &str) -> RateLimitRules {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn user_bucket_refills_over_time() {
+        let limiter = RateLimiter::new();
+        let r = rules("per_user: { burst: 2, per_minute: 6 }");
+        let (user, room) = (user_id!("@a:example.org"), room_id!("!r:example.org"));
+        let start = Instant::now();
+        assert!(limiter.check_at("ai", &r, user, room, start).is_ok());
+        assert!(limiter.check_at("ai", &r, user, room, start).is_ok());
+        let throttled = limiter.check_at("ai", &r, user, room, start).unwrap_err();
+        assert_eq!(throttled.scope, LimitScope::User);
+        assert_eq!(throttled.retry_after, Duration::from_secs(10));
+        assert!(throttled.first);
+        assert!(
+            !limiter
+                .check_at("ai", &r, user, room, start)
+                .unwrap_err()
+                .first
+        );
+        // Other users and plugins have their own buckets.
+        assert!(
+            limiter
+                .check_at("ai", &r, user_id!("@b:example.org"), room, start)
+                .is_ok()
+        );
+        assert!(limiter.check_at("echo", &r, user, room, start).is_ok());
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.check_at("ai", &r, user, room, later).is_ok());
+    }
+
+    #[test]
+    fn empty_room_bucket_spends_no_user_token() {
+        let limiter = RateLimiter::new();
+        let r =
+            rules("per_user: { burst: 1, per_minute: 1 }\nper_room: { burst: 1, per_minute: 1 }");
+        let (a, b) = (user_id!("@a:example.org"), user_id!("@b:example.org"));
+        let room = room_id!("!r:example.org");
+        let now = Instant::now();
+        assert!(limiter.check_at("ai", &r, a, room, now).is_ok());
+        let err = limiter.check_at("ai", &r, b, room, now).unwrap_err();
+        assert_eq!(err.scope, LimitScope::Room);
+        assert!(
+            limiter
+                .check_at("ai", &r, b, room_id!("!o:example.org"), now)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn from_spec_rejects_buckets_that_never_refill() {
+        let mut spec: PluginSpec = serde_yaml::from_str("id: ai").unwrap();
+        spec.config =
+            serde_yaml::from_str("rate_limit: { per_room: { burst: 1, per_minute: 0 } }").unwrap();
+        let err = RateLimitRules::from_spec(&spec).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "rate_limit.per_room.per_minute for plugin ai must be above 0"
+        );
+
+        spec.config =
+            serde_yaml::from_str("rate_limit: { per_room: { burst: 1, per_minute: 0.5 } }")
+                .unwrap();
+        assert!(RateLimitRules::from_spec(&spec).unwrap().is_some());
+    }
+
+    #[test]
+    fn exempt_users_are_never_limited() {
+        let limiter = RateLimiter::new();
+        let r =
+            rules("per_user: { burst: 1, per_minute: 0 }\nexempt_users: ['@admin:example.org']");
+        let room = room_id!("!r:example.org");
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(
+                limiter
+                    .check_at("ai", &r, user_id!("@admin:example.org"), room, now)
+                    .is_ok()
+            );
+        }
+        let user = user_id!("@a:example.org");
+        assert!(limiter.check_at("ai", &r, user, room, now).is_ok());
+        let err = limiter.check_at("ai", &r, user, room, now).unwrap_err();
+        assert_eq!(err.retry_after, Duration::MAX);
+    }
+}
@@ -0,0 +1,320 @@
+use core::fmt;
+
+use serde_yaml::Value;
+
+use crate::PluginSpec;
+
+/// The keys a plugin reads from its config and what each must hold, so
+/// `--check-config` can catch typos and wrong types before the bot starts:
+///
+/// ```
+/// use plugin_core::{ConfigSchema, ConfigType};
+///
+/// let schema = ConfigSchema::new()
+///     .field("prefix", ConfigType::String)
+///     .field("uppercase", ConfigType::Bool);
+/// let config = serde_yaml::from_str("{ prefix: '> ', upercase: true }").unwrap();
+/// assert_eq!(schema.validate(&config).len(), 1);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSchema {
+    fields: Vec<(&'static str, ConfigType)>,
+    /// Keys that are not declared are allowed.
+    open: bool,
+}
+
+/// What a config value must hold. A null value is always accepted, since
+/// plugins treat it like a missing key.
+#[derive(Debug, Clone)]
+pub enum ConfigType {
+    String,
+    Bool,
+    Integer,
+    /// Integer or float.
+    Number,
+    /// Integer or float above 0.
+    PositiveNumber,
+    /// One of these strings.
+    OneOf(&'static [&'static str]),
+    List(Box<Self>),
+    /// A mapping with arbitrary keys, each holding this type.
+    Map(Box<Self>),
+    Object(ConfigSchema),
+    /// Not checked.
+    Any,
+}
+
+/// A problem found at `path`: mapping keys and sequence indices below the
+/// value that was checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub path: Vec<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.path.join("."), self.message)
+        }
+    }
+}
+
+impl ConfigSchema {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn field(mut self, key: &'static str, ty: ConfigType) -> Self {
+        self.fields.push((key, ty));
+        self
+    }
+
+    /// Check `value`, which should be a mapping of the declared fields.
+    #[must_use]
+    pub fn validate(&self, value: &Value) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        self.check(value, &mut Vec::new(), &mut errors);
+        errors
+    }
+
+    fn check(&self, value: &Value, path: &mut Vec<String>, errors: &mut Vec<ConfigError>) {
+        let Some(map) = value.as_mapping() else {
+            if !value.is_null() {
+                push(errors, path, "expected a mapping".to_owned());
+            }
+            return;
+        };
+        for (key, value) in map {
+            let Some(key) = key.as_str() else {
+                push(errors, path, format!("key {key:?} is not a string"));
+                continue;
+            };
+            path.push(key.to_owned());
+            match self.fields.iter().find(|(name, _)| *name == key) {
+                Some((_, ty)) => ty.check(value, path, errors),
+                None if self.open => {}
+                None => {
+                    let message = self.closest(key).map_or_else(
+                        || format!("unknown key `{key}`"),
+                        |near| format!("unknown key `{key}`; did you mean `{near}`?"),
+                    );
+                    push(errors, path, message);
+                }
+            }
+            path.pop();
+        }
+    }
+
+    /// A declared key that `key` is probably a typo of.
+    fn closest(&self, key: &str) -> Option<&'static str> {
+        self.fields
+            .iter()
+            .map(|(name, _)| (edit_distance(key, name), *name))
+            .filter(|(distance, name)| *distance <= name.len().div_ceil(3))
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, name)| name)
+    }
+}
+
+impl ConfigType {
+    #[must_use]
+    pub fn list(item: Self) -> Self {
+        Self::List(Box::new(item))
+    }
+
+    #[must_use]
+    pub fn map(value: Self) -> Self {
+        Self::Map(Box::new(value))
+    }
+
+    fn check(&self, value: &Value, path: &mut Vec<String>, errors: &mut Vec<ConfigError>) {
+        if value.is_null() {
+            return;
+        }
+        let ok = match self {
+            Self::String => value.is_string(),
+            Self::Bool => value.is_bool(),
+            Self::Integer => value.is_i64() || value.is_u64(),
+            Self::Number => value.is_number(),
+            Self::PositiveNumber => value.as_f64().is_some_and(|n| n > 0.0),
+            Self::OneOf(choices) => value.as_str().is_some_and(|s| choices.contains(&s)),
+            Self::List(item) => {
+                let Some(items) = value.as_sequence() else {
+                    return push(errors, path, format!("expected {}", self.describe()));
+                };
+                for (idx, value) in items.iter().enumerate() {
+                    path.push(idx.to_string());
+                    item.check(value, path, errors);
+                    path.pop();
+                }
+                true
+            }
+            Self::Map(item) => {
+                let Some(map) = value.as_mapping() else {
+                    return push(errors, path, format!("expected {}", self.describe()));
+                };
+                for (key, value) in map {
+                    path.push(
+                        key.as_str()
+                            .map_or_else(|| format!("{key:?}"), ToOwned::to_owned),
+                    );
+                    item.check(value, path, errors);
+                    path.pop();
+                }
+                true
+            }
+            Self::Object(schema) => {
+                schema.check(value, path, errors);
+                true
+            }
+            Self::Any => true,
+        };
+        if !ok {
+            push(errors, path, format!("expected {}", self.describe()));
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::String => "a string".to_owned(),
+            Self::Bool => "true or false".to_owned(),
+            Self::Integer => "an integer".to_owned(),
+            Self::Number => "a number".to_owned(),
+            Self::PositiveNumber => "a number above 0".to_owned(),
+            Self::OneOf(choices) => format!("one of {}", choices.join(", ")),
+            Self::List(_) => "a list".to_owned(),
+            Self::Map(_) | Self::Object(_) => "a mapping".to_owned(),
+            Self::Any => "anything".to_owned(),
+        }
+    }
+}
+
+fn push(errors: &mut Vec<ConfigError>, path: &[String], message: String) {
+    errors.push(ConfigError {
+        path: path.to_vec(),
+        message,
+    });
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(ca != *cb))
+                .min(above + 1)
+                .min(row[j] + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+fn bucket_schema() -> ConfigSchema {
+    ConfigSchema::new()
+        .field("burst", ConfigType::Integer)
+        .field("per_minute", ConfigType::PositiveNumber)
+}
+
+fn permissions_schema(subcommands: bool) -> ConfigSchema {
+    let schema = ConfigSchema::new()
+        .field("allow_users", ConfigType::list(ConfigType::String))
+        .field("deny_users", ConfigType::list(ConfigType::String))
+        .field("allow_servers", ConfigType::list(ConfigType::String))
+        .field("deny_servers", ConfigType::list(ConfigType::String))
+        .field("min_power_level", ConfigType::Integer);
+    if subcommands {
+        schema.field(
+            "subcommands",
+            ConfigType::map(ConfigType::Object(permissions_schema(false))),
+        )
+    } else {
+        // Rules nest no deeper than one subcommand.
+        schema.field("subcommands", ConfigType::Any)
+    }
+}
+
+/// Keys every plugin accepts because the registry reads them itself.
+fn core_schema() -> ConfigSchema {
+    ConfigSchema::new()
+        .field("timeout_secs", ConfigType::Number)
+        .field("tick_interval_secs", ConfigType::Number)
+        .field("permissions", ConfigType::Object(permissions_schema(true)))
+        .field(
+            "rate_limit",
+            ConfigType::Object(
+                ConfigSchema::new()
+                    .field("per_user", ConfigType::Object(bucket_schema()))
+                    .field("per_room", ConfigType::Object(bucket_schema()))
+                    .field("exempt_users", ConfigType::list(ConfigType::String))
+                    .field("notify", ConfigType::OneOf(&["reply", "react", "silent"])),
+            ),
+        )
+}
+
+/// Check a spec's config: the keys every plugin accepts, plus those of
+/// `schema` if the plugin declares one. Without a schema, other keys are
+/// not checked.
+#[must_use]
+pub fn check_spec(spec: &PluginSpec, schema: Option<ConfigSchema>) -> Vec<ConfigError> {
+    let mut full = core_schema();
+    match schema {
+        Some(schema) => full.fields.extend(schema.fields),
+        None => full.open = true,
+    }
+    full.validate(&spec.config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_unknown_keys_and_wrong_types() {
+        let schema = ConfigSchema::new()
+            .field("model", ConfigType::String)
+            .field(
+                "servers",
+                ConfigType::map(ConfigType::Object(
+                    ConfigSchema::new().field("args", ConfigType::list(ConfigType::String)),
+                )),
+            );
+        let mut spec: PluginSpec = serde_yaml::from_str("id: ai").unwrap();
+        spec.config = serde_yaml::from_str(
+            r"
+modle: gpt
+timeout_secs: soon
+rate_limit: { notify: loud, per_user: { burst: 1, per_minute: 0 } }
+servers:
+  time: { args: [run, { x: 1 }] }
+",
+        )
+        .unwrap();
+
+        let errors: Vec<String> = check_spec(&spec, Some(schema))
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "modle: unknown key `modle`; did you mean `model`?",
+                "timeout_secs: expected a number",
+                "rate_limit.notify: expected one of reply, react, silent",
+                "rate_limit.per_user.per_minute: expected a number above 0",
+                "servers.time.args.1: expected a string",
+            ]
+        );
+
+        // Without a schema only the shared keys are checked.
+        assert_eq!(check_spec(&spec, None).len(), 3);
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    borrow::Cow,
+    sync::{PoisonError, RwLock},
+};
+
+/// Values known to be secret, such as those the config pulled in from the
+/// environment or from files. Anything shown to people or written to logs
+/// should pass through [`redact_secrets`].
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Shorter values would match ordinary words and numbers in the text.
+const MIN_SECRET_LEN: usize = 8;
+
+const MASK: &str = "[redacted]";
+
+/// Remember `secret` so [`redact_secrets`] masks it from now on. Values
+/// under eight characters are ignored.
+///
+/// The JSON-escaped form is masked too, since JSON logs escape quotes,
+/// backslashes and control characters before the text is redacted.
+pub fn register_secret(secret: &str) {
+    let secret = secret.trim();
+    if secret.len() < MIN_SECRET_LEN {
+        return;
+    }
+    let escaped = serde_json::to_string(secret)
+        .ok()
+        .map(|quoted| quoted[1..quoted.len() - 1].to_owned());
+    let mut secrets = SECRETS.write().unwrap_or_else(PoisonError::into_inner);
+    for form in core::iter::once(secret.to_owned()).chain(escaped) {
+        if !secrets.contains(&form) {
+            secrets.push(form);
+        }
+    }
+    // Longest first, so a secret containing another is masked whole.
+    secrets.sort_by_key(|known| core::cmp::Reverse(known.len()));
+}
+
+/// `text` with every registered secret replaced by `[redacted]`.
+#[must_use]
+pub fn redact_secrets(text: &str) -> Cow<'_, str> {
+    let secrets = SECRETS.read().unwrap_or_else(PoisonError::into_inner);
+    let mut text = Cow::Borrowed(text);
+    for secret in secrets.iter() {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), MASK));
+        }
+    }
+    drop(secrets);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_registered_secrets() {
+        register_secret("sk-test-0123456789");
+        register_secret("short");
+        assert_eq!(
+            redact_secrets("key=sk-test-0123456789, mode=short"),
+            "key=[redacted], mode=short"
+        );
+        assert!(matches!(redact_secrets("nothing here"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn masks_json_escaped_secrets() {
+        let secret = "pa\"ss\\wo\trd-42";
+        register_secret(secret);
+        let line = serde_json::json!({ "fields": { "message": format!("login with {secret}") } })
+            .to_string();
+        assert_eq!(
+            redact_secrets(&line),
+            r#"{"fields":{"message":"login with [redacted]"}}"#
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::OverrideScope;
+
+/// Runtime registry state that must survive restarts.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct PersistedSettings {
+    #[serde(default)]
+    pub overrides: Vec<PersistedOverride>,
+    /// Free-form runtime settings, keyed by plugin ID.
+    #[serde(default)]
+    pub plugin_settings: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PersistedOverride {
+    pub plugin: String,
+    #[serde(flatten)]
+    pub scope: OverrideScope,
+    pub enabled: bool,
+}
+
+/// A JSON file holding [`PersistedSettings`], replaced atomically on save.
+#[derive(Debug, Clone)]
+pub struct SettingsStore {
+    path: PathBuf,
+}
+
+impl SettingsStore {
+    pub const FILE_NAME: &'static str = "registry.json";
+
+    /// Store settings in `registry.json` inside the bot's store directory.
+    #[must_use]
+    pub fn in_dir(dir: &Path) -> Self {
+        Self {
+            path: dir.join(Self::FILE_NAME),
+        }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the settings file; a missing file yields empty settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file exists but cannot be read or parsed.
+    pub fn load(&self) -> Result<PersistedSettings> {
+        if !self.path.exists() {
+            return Ok(PersistedSettings::default());
+        }
+        let data = fs::read_to_string(&self.path)
+            .with_context(|| format!("reading {}", self.path.display()))?;
+        serde_json::from_str(&data).with_context(|| format!("parsing {}", self.path.display()))
+    }
+
+    /// Write the settings via a temporary file and rename, so a crash never
+    /// leaves a half-written file behind.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if serialising or writing the file fails.
+    pub fn save(&self, settings: &PersistedSettings) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string_pretty(settings)?;
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, data).with_context(|| format!("writing {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("replacing {}", self.path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+//! In-memory [`MatrixClient`] and [`MatrixRoom`] for plugin tests.
+//!
+//! ```
+//! use matrix_sdk::ruma::{room_id, user_id};
+//! use plugin_core::testing::FakeClient;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let client = FakeClient::new(user_id!("@bot:example.org"));
+//! let room = client.add_room(room_id!("!r:example.org"));
+//! let ctx = client.context(&room);
+//! plugin_core::send_text(&ctx, "hi").await.unwrap();
+//! assert_eq!(room.sent_bodies(), ["hi"]);
+//! # }
+//! ```
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use matrix_sdk::ruma::{
+    EventId, OwnedDeviceId, OwnedEventId, OwnedMxcUri, OwnedRoomAliasId, OwnedRoomId, OwnedUserId,
+    RoomAliasId, RoomId, UserId, device_id,
+    events::{
+        AnySyncTimelineEvent,
+        room::{
+            MediaSource,
+            message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        },
+    },
+    serde::Raw,
+};
+use mime::Mime;
+
+use crate::{
+    EncryptionStatus, KvStore, MatrixClient, MatrixRoom, MemberInfo, MessagesPage, PluginContext,
+    PluginRegistry,
+};
+
+/// Something a plugin sent through a [`FakeRoom`].
+#[derive(Debug, Clone)]
+pub enum Sent {
+    Message(Box<RoomMessageEventContent>),
+    Reaction {
+        event_id: OwnedEventId,
+        key: String,
+    },
+    Attachment {
+        body: String,
+        mime: String,
+        data: Vec<u8>,
+    },
+}
+
+impl Sent {
+    /// The plain-text body of a message, or `None` for other kinds.
+    #[must_use]
+    pub fn body(&self) -> Option<&str> {
+        match self {
+            Self::Message(content) => Some(content.body()),
+            Self::Reaction { .. } | Self::Attachment { .. } => None,
+        }
+    }
+
+    /// The HTML body of a formatted message.
+    #[must_use]
+    pub fn html(&self) -> Option<&str> {
+        let Self::Message(content) = self else {
+            return None;
+        };
+        match &content.msgtype {
+            MessageType::Text(text) => text.formatted.as_ref().map(|f| f.body.as_str()),
+            MessageType::Notice(notice) => notice.formatted.as_ref().map(|f| f.body.as_str()),
+            MessageType::Emote(emote) => emote.formatted.as_ref().map(|f| f.body.as_str()),
+            MessageType::Audio(_)
+            | MessageType::File(_)
+            | MessageType::Image(_)
+            | MessageType::Location(_)
+            | MessageType::ServerNotice(_)
+            | MessageType::Video(_)
+            | MessageType::VerificationRequest(_)
+            | _ => None,
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+static EVENT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn next_event_id() -> OwnedEventId {
+    let n = EVENT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    EventId::parse(format!("$fake{n}:example.org")).unwrap_or_else(|_| unreachable!())
+}
+
+/// An in-memory room that records everything sent to it.
+#[derive(Debug)]
+pub struct FakeRoom {
+    room_id: OwnedRoomId,
+    aliases: Mutex<Vec<OwnedRoomAliasId>>,
+    members: Mutex<HashMap<OwnedUserId, MemberInfo>>,
+    encrypted: AtomicBool,
+    sent: Mutex<Vec<Sent>>,
+    typing: Mutex<Vec<bool>>,
+    /// Pages returned by successive `messages` calls.
+    pages: Mutex<Vec<MessagesPage>>,
+}
+
+impl FakeRoom {
+    #[must_use]
+    pub fn new(room_id: &RoomId) -> Self {
+        Self {
+            room_id: room_id.to_owned(),
+            aliases: Mutex::default(),
+            members: Mutex::default(),
+            encrypted: AtomicBool::new(false),
+            sent: Mutex::default(),
+            typing: Mutex::default(),
+            pages: Mutex::default(),
+        }
+    }
+
+    pub fn add_alias(&self, alias: &RoomAliasId) {
+        lock(&self.aliases).push(alias.to_owned());
+    }
+
+    pub fn add_member(&self, user: &UserId, display_name: Option<&str>, power_level: i64) {
+        lock(&self.members).insert(
+            user.to_owned(),
+            MemberInfo {
+                display_name: display_name.map(ToOwned::to_owned),
+                power_level,
+            },
+        );
+    }
+
+    pub fn set_encrypted(&self, encrypted: bool) {
+        self.encrypted.store(encrypted, Ordering::Relaxed);
+    }
+
+    /// Queue a page for the next `messages` call.
+    pub fn push_page(&self, page: MessagesPage) {
+        lock(&self.pages).push(page);
+    }
+
+    #[must_use]
+    pub fn sent(&self) -> Vec<Sent> {
+        lock(&self.sent).clone()
+    }
+
+    /// Bodies of every message sent so far.
+    #[must_use]
+    pub fn sent_bodies(&self) -> Vec<String> {
+        lock(&self.sent)
+            .iter()
+            .filter_map(Sent::body)
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    /// Typing notifications, in order.
+    #[must_use]
+    pub fn typing_calls(&self) -> Vec<bool> {
+        lock(&self.typing).clone()
+    }
+
+    fn record(&self, sent: Sent) -> OwnedEventId {
+        lock(&self.sent).push(sent);
+        next_event_id()
+    }
+}
+
+#[async_trait]
+impl MatrixRoom for FakeRoom {
+    fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    fn aliases(&self) -> Vec<OwnedRoomAliasId> {
+        lock(&self.aliases).clone()
+    }
+
+    fn last_prev_batch(&self) -> Option<String> {
+        None
+    }
+
+    async fn send(&self, content: RoomMessageEventContent) -> Result<OwnedEventId> {
+        Ok(self.record(Sent::Message(Box::new(content))))
+    }
+
+    async fn react(&self, event_id: &EventId, key: &str) -> Result<OwnedEventId> {
+        Ok(self.record(Sent::Reaction {
+            event_id: event_id.to_owned(),
+            key: key.to_owned(),
+        }))
+    }
+
+    async fn send_attachment(
+        &self,
+        body: &str,
+        mime: &Mime,
+        data: Vec<u8>,
+    ) -> Result<OwnedEventId> {
+        Ok(self.record(Sent::Attachment {
+            body: body.to_owned(),
+            mime: mime.to_string(),
+            data,
+        }))
+    }
+
+    async fn typing(&self, typing: bool) -> Result<()> {
+        lock(&self.typing).push(typing);
+        Ok(())
+    }
+
+    async fn member(&self, user: &UserId) -> Result<Option<MemberInfo>> {
+        Ok(lock(&self.members).get(user).cloned())
+    }
+
+    async fn is_encrypted(&self) -> bool {
+        self.encrypted.load(Ordering::Relaxed)
+    }
+
+    async fn messages(&self, _from: Option<String>, _limit: u32) -> Result<MessagesPage> {
+        let mut pages = lock(&self.pages);
+        if pages.is_empty() {
+            return Ok(MessagesPage::default());
+        }
+        Ok(pages.remove(0))
+    }
+}
+
+/// An in-memory client owning a set of [`FakeRoom`]s.
+#[derive(Debug)]
+pub struct FakeClient {
+    user_id: OwnedUserId,
+    rooms: Mutex<Vec<Arc<FakeRoom>>>,
+    aliases: Mutex<HashMap<OwnedRoomAliasId, OwnedRoomId>>,
+    media: Mutex<HashMap<OwnedMxcUri, Vec<u8>>>,
+    encryption: Mutex<EncryptionStatus>,
+    /// Shared by every context made from this client.
+    store: KvStore,
+}
+
+impl FakeClient {
+    /// # Panics
+    ///
+    /// Panics if the in-memory key-value store cannot be created.
+    #[must_use]
+    pub fn new(user_id: &UserId) -> Arc<Self> {
+        Arc::new(Self {
+            user_id: user_id.to_owned(),
+            rooms: Mutex::default(),
+            aliases: Mutex::default(),
+            media: Mutex::default(),
+            encryption: Mutex::default(),
+            store: KvStore::in_memory().unwrap_or_else(|e| panic!("in-memory store: {e}")),
+        })
+    }
+
+    /// The key-value store behind this client's contexts.
+    #[must_use]
+    pub fn store(&self) -> KvStore {
+        self.store.clone()
+    }
+
+    /// Join a new, empty room.
+    pub fn add_room(&self, room_id: &RoomId) -> Arc<FakeRoom> {
+        let room = Arc::new(FakeRoom::new(room_id));
+        lock(&self.rooms).push(Arc::clone(&room));
+        room
+    }
+
+    pub fn add_alias(&self, alias: &RoomAliasId, room_id: &RoomId) {
+        lock(&self.aliases).insert(alias.to_owned(), room_id.to_owned());
+    }
+
+    /// Make `data` downloadable from `mxc://`-URI `uri`.
+    pub fn add_media(&self, uri: OwnedMxcUri, data: Vec<u8>) {
+        lock(&self.media).insert(uri, data);
+    }
+
+    pub fn set_encryption_status(&self, status: EncryptionStatus) {
+        *lock(&self.encryption) = status;
+    }
+
+    /// A plugin context for `room` with an empty registry and a fresh
+    /// history directory under the system temp dir.
+    #[must_use]
+    pub fn context(self: &Arc<Self>, room: &Arc<FakeRoom>) -> PluginContext {
+        static CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+        let n = CONTEXTS.fetch_add(1, Ordering::Relaxed);
+        let history_dir: PathBuf =
+            std::env::temp_dir().join(format!("plugin-core-fake-{}-{n}", std::process::id()));
+        PluginContext {
+            client: Arc::clone(self) as Arc<dyn MatrixClient>,
+            room: Arc::clone(room) as Arc<dyn MatrixRoom>,
+            event: None,
+            dev_active: false,
+            dev_id: None,
+            registry: Arc::new(PluginRegistry::new()),
+            history_dir: Arc::new(history_dir),
+            store: self.store.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl MatrixClient for FakeClient {
+    fn user_id(&self) -> Option<OwnedUserId> {
+        Some(self.user_id.clone())
+    }
+
+    fn device_id(&self) -> Option<OwnedDeviceId> {
+        Some(device_id!("FAKEDEVICE").to_owned())
+    }
+
+    fn room(&self, room_id: &RoomId) -> Option<Arc<dyn MatrixRoom>> {
+        lock(&self.rooms)
+            .iter()
+            .find(|room| room.room_id == room_id)
+            .map(|room| Arc::clone(room) as Arc<dyn MatrixRoom>)
+    }
+
+    fn joined_rooms(&self) -> Vec<Arc<dyn MatrixRoom>> {
+        lock(&self.rooms)
+            .iter()
+            .map(|room| Arc::clone(room) as Arc<dyn MatrixRoom>)
+            .collect()
+    }
+
+    async fn resolve_alias(&self, alias: &RoomAliasId) -> Result<OwnedRoomId> {
+        lock(&self.aliases)
+            .get(alias)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown alias {alias}"))
+    }
+
+    async fn download(&self, source: &MediaSource) -> Result<Vec<u8>> {
+        let MediaSource::Plain(uri) = source else {
+            return Err(anyhow!("encrypted media is not supported by the fake"));
+        };
+        lock(&self.media)
+            .get(uri)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown media {uri}"))
+    }
+
+    async fn encryption_status(&self) -> EncryptionStatus {
+        lock(&self.encryption).clone()
+    }
+}
+
+/// Build a room message event as the sync loop would deliver it.
+///
+/// # Panics
+///
+/// Panics if `content` does not serialize into a valid event.
+#[must_use]
+pub fn message_event(
+    sender: &UserId,
+    content: &RoomMessageEventContent,
+) -> OriginalSyncRoomMessageEvent {
+    let json = serde_json::json!({
+        "type": "m.room.message",
+        "event_id": next_event_id(),
+        "sender": sender,
+        "origin_server_ts": 1_700_000_000_000_u64,
+        "content": content,
+    });
+    serde_json::from_value(json).expect("valid m.room.message event")
+}
+
+/// Shorthand for a plain-text [`message_event`].
+#[must_use]
+pub fn text_event(sender: &UserId, body: &str) -> OriginalSyncRoomMessageEvent {
+    message_event(sender, &RoomMessageEventContent::text_plain(body))
+}
+
+/// Serialize an event for a [`MessagesPage`].
+///
+/// # Panics
+///
+/// Panics if the event cannot be serialized.
+#[must_use]
+pub fn raw_timeline_event(event: &OriginalSyncRoomMessageEvent) -> Raw<AnySyncTimelineEvent> {
+    let json = serde_json::json!({
+        "type": "m.room.message",
+        "event_id": event.event_id,
+        "sender": event.sender,
+        "origin_server_ts": event.origin_server_ts,
+        "content": event.content,
+    });
+    Raw::new(&json)
+        .expect("serializable event")
+        .cast_unchecked()
+}
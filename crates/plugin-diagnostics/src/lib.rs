@@ -1,9 +1,7 @@
-use std::string::ToString;
-
 use anyhow::Result;
 use async_trait::async_trait;
 
-use plugin_core::{Plugin, PluginContext, PluginSpec, PluginTriggers, send_text};
+use plugin_core::{ConfigSchema, Plugin, PluginContext, PluginSpec, PluginTriggers, send_text};
 
 #[derive(Debug)]
 pub struct DiagnosticsPlugin;
@@ -31,34 +29,36 @@ impl Plugin for DiagTool {
             config: serde_yaml::Value::default(),
         }
     }
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(ConfigSchema::new())
+    }
     async fn run(&self, ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
         let user_id = ctx
             .client
             .user_id()
-            .map_or_else(|| "<unknown>".to_owned(), ToString::to_string);
+            .map_or_else(|| "<unknown>".to_owned(), |id| id.to_string());
         let device_id = ctx
             .client
             .device_id()
-            .map_or_else(|| "<unknown>".to_owned(), ToString::to_string);
-        let is_encrypted = ctx
-            .room
-            .latest_encryption_state()
-            .await
-            .map(|s| s.is_encrypted())
-            .unwrap_or(false);
-        let bot_verified = if let Ok(Some(dev)) = ctx.client.encryption().get_own_device().await {
-            Some(dev.is_verified())
-        } else {
-            None
-        };
-        let backup_state = format!("{:?}", ctx.client.encryption().backups().state());
+            .map_or_else(|| "<unknown>".to_owned(), |id| id.to_string());
+        let is_encrypted = ctx.room.is_encrypted().await;
+        let encryption = ctx.client.encryption_status().await;
+        let bot_verified = encryption.own_device_verified;
+        let backup_state = encryption.backup_state;
         let mut lines = vec![
             format!("diag for {}", ctx.room.room_id()),
             format!("user: {}", user_id),
             format!("device: {}", device_id),
             format!("room_encrypted: {}", is_encrypted),
+            format!("recovery_state: {}", encryption.recovery_state),
             format!("backup_state: {}", backup_state),
         ];
+        if let Some(upload) = encryption.backup_upload {
+            lines.push(format!("backup_upload: {upload}"));
+        }
+        if let Some(cross_signing) = encryption.cross_signing {
+            lines.push(format!("cross_signing: {cross_signing}"));
+        }
         if let Some(v) = bot_verified {
             lines.push(format!("bot_verified: {v}"));
         }
@@ -2,7 +2,10 @@ use anyhow::Result;
 use async_trait::async_trait;
 use serde::Deserialize;
 
-use plugin_core::{Plugin, PluginContext, PluginSpec, PluginTriggers, send_text};
+use plugin_core::{
+    Arg, CommandSpec, ConfigSchema, ConfigType, Plugin, PluginContext, PluginSpec, PluginTriggers,
+    send_text,
+};
 
 #[derive(Debug)]
 pub struct EchoPlugin;
@@ -24,7 +27,7 @@ impl Plugin for EchoTool {
         "echo"
     }
     fn help(&self) -> &'static str {
-        "Echo text back. Config: prefix, uppercase"
+        "Echo text back: !echo <text>. Config: prefix, uppercase"
     }
     fn spec(&self) -> PluginSpec {
         PluginSpec {
@@ -38,9 +41,22 @@ impl Plugin for EchoTool {
             config: serde_yaml::Value::default(),
         }
     }
+    fn command(&self) -> Option<CommandSpec> {
+        Some(command())
+    }
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(
+            ConfigSchema::new()
+                .field("prefix", ConfigType::String)
+                .field("uppercase", ConfigType::Bool),
+        )
+    }
     async fn run(&self, ctx: &PluginContext, args: &str, spec: &PluginSpec) -> Result<()> {
+        let Some(m) = command().parse_or_reply(ctx, args).await? else {
+            return Ok(());
+        };
         let cfg: EchoConfig = serde_yaml::from_value(spec.config.clone()).unwrap_or_default();
-        let mut out = args.trim().to_owned();
+        let mut out = m.get_str("text").unwrap_or_default().to_owned();
         if cfg.uppercase {
             out = out.to_uppercase();
         }
@@ -53,3 +69,28 @@ impl Plugin for EchoTool {
         send_text(ctx, out).await
     }
 }
+
+fn command() -> CommandSpec {
+    CommandSpec::new("!echo")
+        .about("Echo text back")
+        .arg(Arg::rest("text").help("text to repeat, verbatim"))
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{room_id, user_id};
+    use plugin_core::testing::FakeClient;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn applies_prefix_and_uppercase() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        let mut spec = EchoTool.spec();
+        spec.config = serde_yaml::from_str("{ prefix: '> ', uppercase: true }").unwrap();
+        EchoTool.run(&ctx, "hello  there", &spec).await.unwrap();
+        assert_eq!(room.sent_bodies(), ["> HELLO  THERE"]);
+    }
+}
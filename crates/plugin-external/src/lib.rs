@@ -0,0 +1,550 @@
+//! Plugins that run as a separate process and talk to the bot over stdio.
+//!
+//! The bot starts the configured command and exchanges newline-delimited
+//! JSON-RPC 2.0 messages on its stdin and stdout; stderr is logged. Any
+//! language that can read and write lines of JSON can implement a plugin.
+//!
+//! Requests from the bot:
+//!
+//! - `initialize` `{protocol_version, plugin_id}` is sent once at startup and
+//!   answered with the plugin's manifest: `{help, triggers: {commands,
+//!   mentions}, handles_room_messages, wants_own_messages, dev_only}`, all
+//!   optional.
+//! - `run` `{call, args, room, event, config, dev_active}` when one of the
+//!   triggers is used.
+//! - `on_room_message` `{call, room, event, config, triggered_plugins}` for
+//!   every room message, if the manifest set `handles_room_messages`.
+//!
+//! `room` is `{room_id, aliases}` and `event` is `{event_id, sender, body,
+//! origin_server_ts}`. The bot also sends a `shutdown` notification before
+//! it exits.
+//!
+//! Until it answers `run` or `on_room_message`, the plugin may call back
+//! into the bot, passing the `call` it was given:
+//!
+//! - `send_message` `{call, body, html?, markdown?, notice?, reply?,
+//!   thread?, replaces?}` sends to the room the call came from and returns
+//!   `{event_id}`. `markdown` renders `body` as markdown; `reply` and `thread`
+//!   refer to the triggering event; `replaces` edits an earlier message.
+//! - `react` `{call, key, event_id?}` reacts to `event_id`, or to the
+//!   triggering event, and returns `{event_id}`.
+
+mod rpc;
+
+use core::time::Duration;
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use async_trait::async_trait;
+use matrix_sdk::ruma::{OwnedEventId, events::room::message::OriginalSyncRoomMessageEvent};
+use plugin_core::{
+    OutgoingMessage, Plugin, PluginContext, PluginSpec, PluginTriggers, RoomMessageMeta, react,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncRead, AsyncWrite, BufReader},
+    process::{Child, Command},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use crate::rpc::{CALL_FAILED, Connection, INVALID_PARAMS, METHOD_NOT_FOUND, Request, RpcError};
+
+/// Bumped when a change to the protocol would break existing plugins.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a plugin has to exit after the `shutdown` notification.
+const EXIT_GRACE: Duration = Duration::from_secs(2);
+
+/// One entry of `external_plugins` in `config.yaml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExternalPluginConfig {
+    /// Plugin ID, used like a built-in plugin's for triggers, overrides and
+    /// `plugins/<id>/config.yaml`.
+    pub id: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Extra environment variables for the process.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// How long `run` and `on_room_message` may take; 60 seconds by default.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+/// What the plugin reports about itself in answer to `initialize`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct Manifest {
+    help: String,
+    triggers: PluginTriggers,
+    handles_room_messages: bool,
+    wants_own_messages: bool,
+    dev_only: bool,
+}
+
+/// Contexts of the `run` and `on_room_message` calls in flight, by request ID.
+type Calls = Arc<Mutex<HashMap<u64, PluginContext>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A plugin implemented by an external process.
+#[derive(Debug)]
+pub struct ExternalPlugin {
+    id: &'static str,
+    help: &'static str,
+    manifest: Manifest,
+    timeout: Duration,
+    conn: Arc<Connection>,
+    calls: Calls,
+    child: tokio::sync::Mutex<Option<Child>>,
+}
+
+impl ExternalPlugin {
+    /// Start the plugin's process and ask it for its manifest.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the process cannot be started or does not answer
+    /// `initialize` with a valid manifest.
+    pub async fn spawn(config: &ExternalPluginConfig) -> Result<Self> {
+        // Plugins are started once per account at startup, so leaking the ID to
+        // satisfy `Plugin::id` costs a few bytes at most.
+        let id: &'static str = Box::leak(config.id.clone().into_boxed_str());
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("starting external plugin {id} ({})", config.command))?;
+        let stdin = child.stdin.take().context("plugin stdin unavailable")?;
+        let stdout = child.stdout.take().context("plugin stdout unavailable")?;
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    info!(plugin = id, "{line}");
+                }
+            });
+        }
+        let timeout = config
+            .timeout_secs
+            .map_or(DEFAULT_TIMEOUT, Duration::from_secs);
+        Self::connect(id, timeout, stdout, stdin, Some(child))
+            .await
+            .with_context(|| format!("initializing external plugin {id}"))
+    }
+
+    async fn connect<R, W>(
+        id: &'static str,
+        timeout: Duration,
+        reader: R,
+        writer: W,
+        child: Option<Child>,
+    ) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (conn, requests) = Connection::start(id, reader, writer);
+        let calls = Calls::default();
+        tokio::spawn(serve_callbacks(
+            Arc::clone(&conn),
+            Arc::clone(&calls),
+            requests,
+        ));
+        let params = json!({ "protocol_version": PROTOCOL_VERSION, "plugin_id": id });
+        let manifest = conn
+            .request(
+                conn.next_id(),
+                "initialize",
+                Some(params),
+                INITIALIZE_TIMEOUT,
+            )
+            .await?;
+        let manifest: Manifest =
+            serde_json::from_value(manifest).context("invalid initialize result")?;
+        let help = Box::leak(manifest.help.clone().into_boxed_str());
+        Ok(Self {
+            id,
+            help,
+            manifest,
+            timeout,
+            conn,
+            calls,
+            child: tokio::sync::Mutex::new(child),
+        })
+    }
+
+    /// Send `method` and wait for the answer, serving callbacks that name
+    /// this call with `ctx` meanwhile.
+    async fn call(&self, ctx: &PluginContext, method: &str, mut params: Value) -> Result<()> {
+        let id = self.conn.next_id();
+        let _call = CallGuard::register(&self.calls, id, ctx.clone());
+        params["call"] = id.into();
+        self.conn
+            .request(id, method, Some(params), self.timeout)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Keeps a call's context reachable for callbacks until the call ends or is
+/// dropped.
+struct CallGuard<'a> {
+    calls: &'a Calls,
+    id: u64,
+}
+
+impl<'a> CallGuard<'a> {
+    fn register(calls: &'a Calls, id: u64, ctx: PluginContext) -> Self {
+        lock(calls).insert(id, ctx);
+        Self { calls, id }
+    }
+}
+
+impl Drop for CallGuard<'_> {
+    fn drop(&mut self) {
+        lock(self.calls).remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl Plugin for ExternalPlugin {
+    fn id(&self) -> &'static str {
+        self.id
+    }
+    fn help(&self) -> &'static str {
+        self.help
+    }
+    fn spec(&self) -> PluginSpec {
+        PluginSpec {
+            id: self.id.to_owned(),
+            enabled: true,
+            dev_only: None,
+            triggers: self.manifest.triggers.clone(),
+            config: serde_yaml::Value::default(),
+        }
+    }
+    fn dev_only(&self) -> bool {
+        self.manifest.dev_only
+    }
+    fn handles_room_messages(&self) -> bool {
+        self.manifest.handles_room_messages
+    }
+    fn wants_own_messages(&self) -> bool {
+        self.manifest.wants_own_messages
+    }
+    async fn run(&self, ctx: &PluginContext, args: &str, spec: &PluginSpec) -> Result<()> {
+        let params = json!({
+            "args": args,
+            "room": room_json(ctx),
+            "event": ctx.event.as_deref().map(event_json),
+            "config": config_json(spec),
+            "dev_active": ctx.dev_active,
+        });
+        self.call(ctx, "run", params).await
+    }
+    async fn on_room_message(
+        &self,
+        ctx: &PluginContext,
+        event: &OriginalSyncRoomMessageEvent,
+        spec: &PluginSpec,
+        meta: &RoomMessageMeta<'_>,
+    ) -> Result<()> {
+        if !self.manifest.handles_room_messages {
+            return Ok(());
+        }
+        let params = json!({
+            "room": room_json(ctx),
+            "event": event_json(event),
+            "config": config_json(spec),
+            "triggered_plugins": meta.triggered_plugins,
+        });
+        self.call(ctx, "on_room_message", params).await
+    }
+    async fn on_shutdown(&self) {
+        let _ = self.conn.notify("shutdown", None).await;
+        let Some(mut child) = self.child.lock().await.take() else {
+            return;
+        };
+        if tokio::time::timeout(EXIT_GRACE, child.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                plugin = self.id,
+                "Plugin did not exit after shutdown; killing it"
+            );
+            let _ = child.kill().await;
+        }
+    }
+}
+
+fn room_json(ctx: &PluginContext) -> Value {
+    json!({
+        "room_id": ctx.room.room_id(),
+        "aliases": ctx.room.aliases(),
+    })
+}
+
+fn event_json(event: &OriginalSyncRoomMessageEvent) -> Value {
+    json!({
+        "event_id": event.event_id,
+        "sender": event.sender,
+        "body": event.content.body(),
+        "origin_server_ts": event.origin_server_ts,
+    })
+}
+
+fn config_json(spec: &PluginSpec) -> Value {
+    serde_json::to_value(&spec.config).unwrap_or_else(|e| {
+        warn!(plugin = %spec.id, error = %e, "Plugin config is not representable as JSON");
+        Value::Null
+    })
+}
+
+/// Answer the plugin's callbacks, each in its own task so a slow send does
+/// not hold up the others.
+async fn serve_callbacks(
+    conn: Arc<Connection>,
+    calls: Calls,
+    mut requests: mpsc::Receiver<Request>,
+) {
+    while let Some(request) = requests.recv().await {
+        let conn = Arc::clone(&conn);
+        let calls = Arc::clone(&calls);
+        tokio::spawn(async move {
+            let result = handle_callback(&calls, &request.method, request.params).await;
+            conn.respond(request.id, result).await;
+        });
+    }
+}
+
+#[allow(clippy::struct_excessive_bools, reason = "independent wire flags")]
+#[derive(Debug, Deserialize)]
+struct SendMessageParams {
+    call: u64,
+    body: String,
+    #[serde(default)]
+    html: Option<String>,
+    #[serde(default)]
+    markdown: bool,
+    #[serde(default)]
+    notice: bool,
+    #[serde(default)]
+    reply: bool,
+    #[serde(default)]
+    thread: bool,
+    #[serde(default)]
+    replaces: Option<OwnedEventId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReactParams {
+    call: u64,
+    key: String,
+    #[serde(default)]
+    event_id: Option<OwnedEventId>,
+}
+
+async fn handle_callback(
+    calls: &Calls,
+    method: &str,
+    params: Option<Value>,
+) -> Result<Value, RpcError> {
+    let params = params.unwrap_or(Value::Null);
+    let event_id = match method {
+        "send_message" => {
+            let p: SendMessageParams = parse_params(params)?;
+            let ctx = call_context(calls, p.call)?;
+            let mut message = match (p.html, p.markdown) {
+                (Some(html), _) => OutgoingMessage::html(p.body, html),
+                (None, true) => OutgoingMessage::markdown(p.body),
+                (None, false) => OutgoingMessage::text(p.body),
+            };
+            if p.notice {
+                message = message.notice();
+            }
+            if let Some(original) = p.replaces {
+                message = message.replacing(original);
+            } else if p.thread {
+                message = message.in_thread();
+            } else if p.reply {
+                message = message.reply();
+            }
+            message.send(&ctx).await
+        }
+        "react" => {
+            let p: ReactParams = parse_params(params)?;
+            let ctx = call_context(calls, p.call)?;
+            match p.event_id {
+                Some(event_id) => ctx.room.react(&event_id, &p.key).await,
+                None => react(&ctx, &p.key).await,
+            }
+        }
+        _ => {
+            return Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("unknown method: {method}"),
+                data: None,
+            });
+        }
+    };
+    event_id
+        .map(|event_id| json!({ "event_id": event_id }))
+        .map_err(|e| RpcError {
+            code: CALL_FAILED,
+            message: format!("{e:#}"),
+            data: None,
+        })
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| invalid_params(&e))
+}
+
+fn call_context(calls: &Calls, call: u64) -> Result<PluginContext, RpcError> {
+    lock(calls)
+        .get(&call)
+        .cloned()
+        .ok_or_else(|| invalid_params(&anyhow!("call {call} is not in progress")))
+}
+
+fn invalid_params(e: &dyn core::fmt::Display) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: e.to_string(),
+        data: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{room_id, user_id};
+    use plugin_core::testing::{FakeClient, text_event};
+    use tokio::io::{AsyncWriteExt as _, DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    struct FakeProcess {
+        lines: tokio::io::Lines<BufReader<ReadHalf<DuplexStream>>>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl FakeProcess {
+        async fn recv(&mut self) -> Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        async fn send(&mut self, message: Value) {
+            let mut line = message.to_string();
+            line.push('\n');
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+        }
+    }
+
+    /// A weather plugin: answers `!weather <city>` with a markdown reply
+    /// sent through the `send_message` callback.
+    async fn weather_plugin(stream: DuplexStream) {
+        let (reader, writer) = tokio::io::split(stream);
+        let mut process = FakeProcess {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        };
+        loop {
+            let request = process.recv().await;
+            let id = request["id"].clone();
+            match request["method"].as_str() {
+                Some("initialize") => {
+                    process
+                        .send(json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "result": {
+                                "help": "Weather forecasts: !weather <city>",
+                                "triggers": { "commands": ["!weather"] },
+                            },
+                        }))
+                        .await;
+                }
+                Some("run") if request["params"]["args"] == "nowhere" => {
+                    process
+                        .send(json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "error": { "code": 1, "message": "unknown city" },
+                        }))
+                        .await;
+                }
+                Some("run") => {
+                    let city = request["params"]["args"].as_str().unwrap().to_owned();
+                    process
+                        .send(json!({
+                            "jsonrpc": "2.0",
+                            "id": "send-1",
+                            "method": "send_message",
+                            "params": {
+                                "call": request["params"]["call"],
+                                "body": format!("**{city}:** sunny"),
+                                "markdown": true,
+                                "reply": true,
+                            },
+                        }))
+                        .await;
+                    let sent = process.recv().await;
+                    assert_eq!(sent["id"], "send-1");
+                    assert!(sent["result"]["event_id"].is_string(), "{sent}");
+                    process
+                        .send(json!({ "jsonrpc": "2.0", "id": id, "result": null }))
+                        .await;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_and_sends_through_callbacks() {
+        let (bot_side, plugin_side) = tokio::io::duplex(4096);
+        tokio::spawn(weather_plugin(plugin_side));
+        let (reader, writer) = tokio::io::split(bot_side);
+        let plugin = ExternalPlugin::connect("weather", DEFAULT_TIMEOUT, reader, writer, None)
+            .await
+            .unwrap();
+        let spec = plugin.spec();
+        assert_eq!(spec.triggers.commands, ["!weather"]);
+        assert_eq!(plugin.help(), "Weather forecasts: !weather <city>");
+
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let mut ctx = client.context(&room);
+        ctx.event = Some(Arc::new(text_event(
+            user_id!("@alice:example.org"),
+            "!weather Paris",
+        )));
+        plugin.run(&ctx, "Paris", &spec).await.unwrap();
+        let sent = room.sent();
+        assert_eq!(sent[0].body(), Some("**Paris:** sunny"));
+        assert_eq!(sent[0].html(), Some("<strong>Paris:</strong> sunny"));
+
+        let err = plugin.run(&ctx, "nowhere", &spec).await.unwrap_err();
+        assert!(err.to_string().contains("unknown city"), "{err}");
+        assert_eq!(room.sent().len(), 1);
+        assert!(lock(&plugin.calls).is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+//! Newline-delimited JSON-RPC 2.0 over a pair of byte streams.
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _, BufReader},
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, warn};
+
+/// JSON-RPC error codes used in replies to the plugin.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The request was understood but carrying it out failed.
+pub const CALL_FAILED: i64 = -32000;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Message {
+    Request(Request),
+    Response(Response),
+    Notification(Notification),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    /// Plugins may use strings or numbers; replies echo it back unchanged.
+    pub id: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Response {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    pub id: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notification {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+type Pending = HashMap<u64, oneshot::Sender<Result<Value>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// One side of a JSON-RPC connection: sends requests and matches responses
+/// to them. Requests from the other side are handed to the caller of
+/// [`Connection::start`].
+#[derive(Debug)]
+pub struct Connection {
+    label: &'static str,
+    tx: mpsc::Sender<Message>,
+    pending: Mutex<Pending>,
+    next_id: AtomicU64,
+}
+
+impl Connection {
+    /// Spawn the reader and writer tasks. Incoming requests arrive on the
+    /// returned channel, which closes when the other side hangs up.
+    pub fn start<R, W>(
+        label: &'static str,
+        reader: R,
+        writer: W,
+    ) -> (Arc<Self>, mpsc::Receiver<Request>)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<Message>(32);
+        let (requests_tx, requests_rx) = mpsc::channel::<Request>(32);
+        let conn = Arc::new(Self {
+            label,
+            tx,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        });
+        tokio::spawn(write_messages(label, writer, rx));
+        tokio::spawn(read_messages(Arc::clone(&conn), reader, requests_tx));
+        (conn, requests_rx)
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Send a request with an ID from [`Connection::next_id`] and wait up to
+    /// `timeout` for its result.
+    pub async fn request(
+        &self,
+        id: u64,
+        method: &str,
+        params: Option<Value>,
+        timeout: Duration,
+    ) -> Result<Value> {
+        let (tx, rx) = oneshot::channel();
+        lock(&self.pending).insert(id, tx);
+        let request = Message::Request(Request {
+            jsonrpc: "2.0".to_owned(),
+            method: method.to_owned(),
+            params,
+            id: id.into(),
+        });
+        if self.tx.send(request).await.is_err() {
+            lock(&self.pending).remove(&id);
+            return Err(anyhow!("connection to {} is closed", self.label));
+        }
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(anyhow!("{} exited before answering {method}", self.label)),
+            Err(_) => {
+                lock(&self.pending).remove(&id);
+                Err(anyhow!(
+                    "{} did not answer {method} within {}s",
+                    self.label,
+                    timeout.as_secs_f64()
+                ))
+            }
+        }
+    }
+
+    pub async fn notify(&self, method: &str, params: Option<Value>) -> Result<()> {
+        let notification = Message::Notification(Notification {
+            jsonrpc: "2.0".to_owned(),
+            method: method.to_owned(),
+            params,
+        });
+        self.tx
+            .send(notification)
+            .await
+            .with_context(|| format!("connection to {} is closed", self.label))
+    }
+
+    /// Answer a request from the other side.
+    pub async fn respond(&self, id: Value, result: Result<Value, RpcError>) {
+        let (result, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(error) => (None, Some(error)),
+        };
+        let response = Message::Response(Response {
+            jsonrpc: "2.0".to_owned(),
+            result,
+            error,
+            id,
+        });
+        if self.tx.send(response).await.is_err() {
+            debug!(
+                plugin = self.label,
+                "Dropping response; connection is closed"
+            );
+        }
+    }
+
+    fn resolve(&self, response: Response) {
+        let Some(id) = response.id.as_u64() else {
+            warn!(plugin = self.label, id = %response.id, "Response with an unknown ID");
+            return;
+        };
+        let Some(sender) = lock(&self.pending).remove(&id) else {
+            debug!(
+                plugin = self.label,
+                id, "Response to a request that timed out"
+            );
+            return;
+        };
+        let result = match response.error {
+            Some(err) => Err(anyhow!("{} (code {})", err.message, err.code)),
+            None => Ok(response.result.unwrap_or(Value::Null)),
+        };
+        let _ = sender.send(result);
+    }
+}
+
+async fn write_messages<W>(label: &'static str, mut writer: W, mut rx: mpsc::Receiver<Message>)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(message) = rx.recv().await {
+        let mut line = match serde_json::to_vec(&message) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(plugin = label, error = %e, "Failed to encode message");
+                continue;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = async {
+            writer.write_all(&line).await?;
+            writer.flush().await
+        }
+        .await
+        {
+            warn!(plugin = label, error = %e, "Failed to write to plugin");
+            break;
+        }
+    }
+}
+
+async fn read_messages<R>(conn: Arc<Connection>, reader: R, requests: mpsc::Sender<Request>)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                warn!(plugin = conn.label, error = %e, "Failed to read from plugin");
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Message>(&line) {
+            Ok(Message::Response(response)) => conn.resolve(response),
+            Ok(Message::Request(request)) => {
+                if requests.send(request).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Message::Notification(notification)) => {
+                debug!(plugin = conn.label, method = %notification.method, "Ignoring notification");
+            }
+            Err(e) => {
+                warn!(plugin = conn.label, error = %e, %line, "Unparseable message from plugin");
+            }
+        }
+    }
+    // Fail everything still waiting instead of letting it run into the timeout.
+    lock(&conn.pending).clear();
+    debug!(plugin = conn.label, "Plugin closed its output");
+}
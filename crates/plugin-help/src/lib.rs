@@ -0,0 +1,332 @@
+use core::fmt::Write as _;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use plugin_core::{
+    Arg, CommandSpec, ConfigSchema, Plugin, PluginContext, PluginEntry, PluginSpec, PluginTriggers,
+    RoomRef, escape_html, send_html, send_text,
+};
+
+#[derive(Debug)]
+pub struct HelpPlugin;
+
+#[derive(Debug)]
+pub struct HelpTool;
+
+#[async_trait]
+impl Plugin for HelpTool {
+    fn id(&self) -> &'static str {
+        "help"
+    }
+    fn help(&self) -> &'static str {
+        "List available plugins, or show one plugin's usage: !help [id]"
+    }
+    fn spec(&self) -> PluginSpec {
+        PluginSpec {
+            id: "help".to_owned(),
+            enabled: true,
+            dev_only: None,
+            triggers: PluginTriggers {
+                commands: vec!["!help".to_owned()],
+                mentions: vec![],
+            },
+            config: serde_yaml::Value::default(),
+        }
+    }
+    fn command(&self) -> Option<CommandSpec> {
+        Some(command())
+    }
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(ConfigSchema::new())
+    }
+    async fn run(&self, ctx: &PluginContext, args: &str, _spec: &PluginSpec) -> Result<()> {
+        let Some(m) = command().parse_or_reply(ctx, args).await? else {
+            return Ok(());
+        };
+        let entries = visible_entries(ctx).await;
+        let dev_prefix = dev_prefix(ctx);
+        let Some(name) = m.get_str("plugin") else {
+            let (plain, html) = listing(&entries, dev_prefix);
+            return send_html(ctx, plain, html).await;
+        };
+        let wanted = name.trim_start_matches(['!', '@']);
+        let found = entries.iter().find(|(id, entry)| {
+            id.eq_ignore_ascii_case(wanted)
+                || entry
+                    .spec
+                    .triggers
+                    .commands
+                    .iter()
+                    .chain(&entry.spec.triggers.mentions)
+                    .any(|t| t.get(1..).is_some_and(|t| t.eq_ignore_ascii_case(wanted)))
+        });
+        match found {
+            Some((id, entry)) => {
+                let (plain, html) = detail_page(id, entry, dev_prefix);
+                send_html(ctx, plain, html).await
+            }
+            None => send_text(ctx, format!("no such plugin here: {name}")).await,
+        }
+    }
+}
+
+fn command() -> CommandSpec {
+    CommandSpec::new("!help")
+        .about("List available plugins or show how to use one")
+        .arg(Arg::new("plugin").help("plugin ID or trigger, e.g. tools or !tools"))
+}
+
+/// Plugins a user in this room can actually reach: enabled here and, unless
+/// this instance runs in dev mode, not dev-only.
+async fn visible_entries(ctx: &PluginContext) -> Vec<(String, PluginEntry)> {
+    let room = RoomRef::from_room(ctx.room.as_ref());
+    let mut visible = Vec::new();
+    for (id, entry) in ctx.registry.entries().await {
+        let dev_only = entry
+            .spec
+            .dev_only
+            .unwrap_or_else(|| entry.plugin.dev_only());
+        if dev_only && !ctx.dev_active {
+            continue;
+        }
+        if ctx.registry.is_enabled_in(&id, &room).await {
+            visible.push((id, entry));
+        }
+    }
+    visible.sort_by(|(a, _), (b, _)| a.cmp(b));
+    visible
+}
+
+/// Dev instances only answer `!<dev_id>.<command>` and `@<dev_id>.<name>`.
+fn dev_prefix(ctx: &PluginContext) -> Option<&str> {
+    ctx.dev_id.as_deref().filter(|_| ctx.dev_active)
+}
+
+/// Triggers as users have to type them on this instance.
+fn display_triggers(triggers: &PluginTriggers, dev_prefix: Option<&str>) -> Vec<String> {
+    triggers
+        .commands
+        .iter()
+        .chain(&triggers.mentions)
+        .map(|trigger| match dev_prefix {
+            Some(dev) if trigger.len() > 1 => {
+                let (sigil, name) = trigger.split_at(1);
+                format!("{sigil}{dev}.{name}")
+            }
+            _ => trigger.clone(),
+        })
+        .collect()
+}
+
+fn listing(entries: &[(String, PluginEntry)], dev_prefix: Option<&str>) -> (String, String) {
+    let help_cmd = dev_prefix.map_or_else(|| "!help".to_owned(), |dev| format!("!{dev}.help"));
+    let mut plain = format!("Available plugins ({help_cmd} <id> for details):");
+    let mut html = format!(
+        "<p><b>Available plugins</b> (<code>{}</code> for details)</p><ul>",
+        escape_html(&format!("{help_cmd} <id>"))
+    );
+    for (id, entry) in entries {
+        let triggers = display_triggers(&entry.spec.triggers, dev_prefix);
+        let summary = entry.plugin.help();
+        if triggers.is_empty() {
+            write!(plain, "\n- {id} — {summary}").ok();
+            write!(
+                html,
+                "<li><b>{}</b> — {}</li>",
+                escape_html(id),
+                escape_html(summary)
+            )
+            .ok();
+        } else {
+            write!(plain, "\n- {id} ({}) — {summary}", triggers.join(", ")).ok();
+            write!(
+                html,
+                "<li><b>{}</b> ({}) — {}</li>",
+                escape_html(id),
+                code_list(&triggers),
+                escape_html(summary)
+            )
+            .ok();
+        }
+    }
+    html.push_str("</ul>");
+    (plain, html)
+}
+
+fn detail_page(id: &str, entry: &PluginEntry, dev_prefix: Option<&str>) -> (String, String) {
+    let triggers = display_triggers(&entry.spec.triggers, dev_prefix);
+    let summary = entry.plugin.help();
+    let mut plain = format!("{id}: {summary}");
+    let mut html = format!(
+        "<h4>{}</h4><p>{}</p>",
+        escape_html(id),
+        escape_html(summary)
+    );
+    if triggers.is_empty() {
+        let note = if entry.plugin.handles_room_messages() {
+            "Runs on room messages; it has no commands."
+        } else {
+            "It has no commands."
+        };
+        write!(plain, "\n{note}").ok();
+        write!(html, "<p>{note}</p>").ok();
+    } else {
+        write!(plain, "\nTriggers: {}", triggers.join(", ")).ok();
+        write!(html, "<p>Triggers: {}</p>", code_list(&triggers)).ok();
+    }
+    if let Some(command) = entry.plugin.command() {
+        write!(plain, "\n{}", command.help()).ok();
+        html.push_str(&command.help_html());
+    }
+    (plain, html)
+}
+
+fn code_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|item| format!("<code>{}</code>", escape_html(item)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use matrix_sdk::ruma::{room_id, user_id};
+    use plugin_core::{OverrideScope, testing::FakeClient};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Stub {
+        id: &'static str,
+        dev_only: bool,
+    }
+
+    #[async_trait]
+    impl Plugin for Stub {
+        fn id(&self) -> &'static str {
+            self.id
+        }
+        fn help(&self) -> &'static str {
+            "Does <nothing>"
+        }
+        fn spec(&self) -> PluginSpec {
+            PluginSpec {
+                id: self.id.to_owned(),
+                enabled: true,
+                dev_only: None,
+                triggers: PluginTriggers {
+                    commands: vec![format!("!{}", self.id)],
+                    mentions: vec![],
+                },
+                config: serde_yaml::Value::default(),
+            }
+        }
+        fn dev_only(&self) -> bool {
+            self.dev_only
+        }
+        async fn run(&self, _ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_reachable_plugins_and_shows_command_help() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        ctx.registry
+            .register(HelpTool.spec(), Arc::new(HelpTool))
+            .await;
+        for (id, dev_only) in [("ping", false), ("quiet", false), ("debug", true)] {
+            let stub = Stub { id, dev_only };
+            ctx.registry.register(stub.spec(), Arc::new(stub)).await;
+        }
+        ctx.registry
+            .set_override(
+                "quiet",
+                OverrideScope::Room("!r:example.org".to_owned()),
+                false,
+            )
+            .await
+            .unwrap();
+
+        HelpTool.run(&ctx, "", &HelpTool.spec()).await.unwrap();
+        HelpTool.run(&ctx, "!help", &HelpTool.spec()).await.unwrap();
+        HelpTool.run(&ctx, "quiet", &HelpTool.spec()).await.unwrap();
+
+        let sent = room.sent();
+        assert_eq!(
+            sent[0].body(),
+            Some(
+                "Available plugins (!help <id> for details):\n\
+                 - help (!help) — List available plugins, or show one plugin's usage: !help [id]\n\
+                 - ping (!ping) — Does <nothing>"
+            )
+        );
+        assert_eq!(
+            sent[0].html(),
+            Some(
+                "<p><b>Available plugins</b> (<code>!help &lt;id&gt;</code> for details)</p><ul>\
+                 <li><b>help</b> (<code>!help</code>) — List available plugins, or show one plugin&#39;s usage: !help [id]</li>\
+                 <li><b>ping</b> (<code>!ping</code>) — Does &lt;nothing&gt;</li></ul>"
+            )
+        );
+        assert_eq!(
+            sent[1].body(),
+            Some(
+                format!(
+                    "help: List available plugins, or show one plugin's usage: !help [id]\n\
+                     Triggers: !help\n{}",
+                    command().help()
+                )
+                .as_str()
+            )
+        );
+        assert_eq!(
+            sent[1].html(),
+            Some(
+                format!(
+                    "<h4>help</h4><p>List available plugins, or show one plugin&#39;s usage: !help [id]</p>\
+                     <p>Triggers: <code>!help</code></p>{}",
+                    command().help_html()
+                )
+                .as_str()
+            )
+        );
+        assert!(
+            sent[1]
+                .html()
+                .unwrap()
+                .contains("<code>!help [plugin]</code>")
+        );
+        assert_eq!(sent[2].body(), Some("no such plugin here: quiet"));
+
+        let dev = PluginContext {
+            dev_active: true,
+            dev_id: Some(Arc::from("alice")),
+            ..ctx
+        };
+        HelpTool.run(&dev, "", &HelpTool.spec()).await.unwrap();
+        let listing = room.sent_bodies().pop().unwrap();
+        assert!(listing.contains("Available plugins (!alice.help <id> for details):"));
+        assert!(listing.contains("\n- debug (!alice.debug) — Does <nothing>"));
+        assert!(!listing.contains("quiet"));
+    }
+
+    #[test]
+    fn dev_instances_show_prefixed_triggers() {
+        let triggers = PluginTriggers {
+            commands: vec!["!ai".to_owned()],
+            mentions: vec!["@ai".to_owned()],
+        };
+        assert_eq!(display_triggers(&triggers, None), ["!ai", "@ai"]);
+        assert_eq!(
+            display_triggers(&triggers, Some("alice")),
+            ["!alice.ai", "@alice.ai"]
+        );
+    }
+}
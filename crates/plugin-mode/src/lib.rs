@@ -1,7 +1,7 @@
 use anyhow::Result;
 use async_trait::async_trait;
 
-use plugin_core::{Plugin, PluginContext, PluginSpec, PluginTriggers, send_text};
+use plugin_core::{ConfigSchema, Plugin, PluginContext, PluginSpec, PluginTriggers, send_text};
 
 #[derive(Debug)]
 pub struct ModePlugin;
@@ -17,7 +17,7 @@ impl Plugin for ModeTool {
     fn help(&self) -> &'static str {
         "Show current mode (dev/prod) and how to target it."
     }
-       fn spec(&self) -> PluginSpec {
+    fn spec(&self) -> PluginSpec {
         PluginSpec {
             id: "mode".into(),
             enabled: true,
@@ -29,6 +29,9 @@ impl Plugin for ModeTool {
             config: serde_yaml::Value::default(),
         }
     }
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(ConfigSchema::new())
+    }
     async fn run(&self, ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
         let mode = if ctx.dev_active { "dev" } else { "prod" };
         let mut lines = vec![format!("mode: {}", mode)];
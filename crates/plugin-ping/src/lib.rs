@@ -1,7 +1,7 @@
 use anyhow::Result;
 
 use async_trait::async_trait;
-use plugin_core::{Plugin, PluginContext, PluginSpec, PluginTriggers, send_text};
+use plugin_core::{ConfigSchema, Plugin, PluginContext, PluginSpec, PluginTriggers, send_text};
 
 #[derive(Debug)]
 pub struct PingPlugin;
@@ -30,6 +30,9 @@ impl Plugin for Ping {
         }
     }
 
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(ConfigSchema::new())
+    }
     async fn run(&self, ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
         send_text(ctx, "Pong! 🏓".to_owned()).await
     }
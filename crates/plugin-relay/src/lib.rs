@@ -3,25 +3,23 @@ mod relay_config;
 pub use relay_config::{RelayCluster, RelayConfig};
 
 use core::fmt::Write as _;
-use std::{borrow::ToOwned, collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 
-use anyhow::{Context as _, Result, anyhow};
+use anyhow::{Context as _, Result};
 use async_trait::async_trait;
-use matrix_sdk::{
-    Client,
-    attachment::AttachmentConfig,
-    room::Room,
-    ruma::{
-        OwnedRoomId, RoomAliasId, RoomId,
-        events::room::message::{
-            AudioMessageEventContent, FileMessageEventContent, ImageMessageEventContent,
-            MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
-            VideoMessageEventContent,
-        },
+use matrix_sdk::ruma::{
+    OwnedEventId, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId,
+    events::room::message::{
+        AudioMessageEventContent, FileMessageEventContent, ImageMessageEventContent, MessageType,
+        OriginalSyncRoomMessageEvent, RoomMessageEventContent, VideoMessageEventContent,
     },
 };
 use mime::Mime;
-use plugin_core::{Plugin, PluginContext, PluginSpec, PluginTriggers, RoomMessageMeta, truncate};
+use plugin_core::{
+    ConfigSchema, ConfigType, MatrixClient, MatrixRoom, Plugin, PluginContext, PluginSpec,
+    PluginTriggers, RoomMessageMeta, escape_html, truncate,
+};
+use serde::Serialize;
 use tokio::sync::RwLock;
 use tracing::{info, warn};
 
@@ -33,12 +31,21 @@ pub struct Relay {
     plan: RwLock<Option<Arc<RelayPlan>>>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 struct RelayOptions {
+    cluster: Arc<str>,
     reupload_media: bool,
     caption_media: bool,
 }
 
+/// Where messages from one room are relayed to, as resolved from the clusters.
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayRoute {
+    pub cluster: String,
+    pub from: OwnedRoomId,
+    pub to: Vec<OwnedRoomId>,
+}
+
 #[derive(Debug, Clone)]
 struct RelayPlan {
     map: HashMap<OwnedRoomId, Vec<OwnedRoomId>>,
@@ -59,11 +66,24 @@ impl Plugin for Relay {
         true
     }
 
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        let cluster = ConfigSchema::new()
+            .field("name", ConfigType::String)
+            .field("rooms", ConfigType::list(ConfigType::String))
+            .field("reupload_media", ConfigType::Bool)
+            .field("caption_media", ConfigType::Bool);
+        Some(
+            ConfigSchema::new()
+                .field("clusters", ConfigType::list(ConfigType::Object(cluster)))
+                .field("reupload_media", ConfigType::Bool)
+                .field("caption_media", ConfigType::Bool),
+        )
+    }
     async fn run(&self, _ctx: &PluginContext, _args: &str, _spec: &PluginSpec) -> Result<()> {
         Ok(())
     }
 
-       fn spec(&self) -> PluginSpec {
+    fn spec(&self) -> PluginSpec {
         PluginSpec {
             id: "relay".to_owned(),
             enabled: true,
@@ -81,13 +101,13 @@ impl Plugin for Relay {
         _meta: &RoomMessageMeta<'_>,
     ) -> Result<()> {
         info!(room_id = %ctx.room.room_id(), sender = %event.sender, "Relay: on_room_message called");
-        
+
         if ctx.dev_active {
             info!(room_id = %ctx.room.room_id(), "Dev mode active: relay disabled");
             return Ok(());
         }
 
-        let Some(plan) = self.ensure_plan(&ctx.client, spec).await? else {
+        let Some(plan) = self.ensure_plan(ctx.client.as_ref(), spec).await? else {
             info!(room_id = %ctx.room.room_id(), "Relay: no plan loaded (config empty?)");
             return Ok(());
         };
@@ -97,27 +117,48 @@ impl Plugin for Relay {
             info!(room_id = %source_id, "Relay: room not in mapping");
             return Ok(());
         };
-        let opts = plan.opts.get(&source_id).copied().unwrap_or(RelayOptions {
-            reupload_media: true,
-            caption_media: true,
-        });
-
-        let display_name = resolve_display_name(&ctx.room, &event.sender).await;
-        let display_name_bold = to_bold(&display_name);
-        let formatted_text = format_text_message(&event.content.msgtype, &display_name_bold);
+        let opts = plan
+            .opts
+            .get(&source_id)
+            .cloned()
+            .unwrap_or_else(|| RelayOptions {
+                cluster: Arc::from("unknown"),
+                reupload_media: true,
+                caption_media: true,
+            });
+
+        let display_name = resolve_display_name(ctx.room.as_ref(), &event.sender).await;
+        let formatted_text = format_text_message(&event.content.msgtype, &display_name);
 
         for target_id in targets {
             if target_id == source_id {
                 continue;
             }
-            if let Some(room_handle) = ctx.client.get_room(&target_id) {
-                let send_res = if let Some(text) = formatted_text.as_ref() {
-                    let content = RoomMessageEventContent::text_plain(text.clone());
+            if let Some(room_handle) = ctx.client.room(&target_id) {
+                let send_res = if let Some((plain, html)) = formatted_text.as_ref() {
+                    let content = RoomMessageEventContent::text_html(plain.clone(), html.clone());
                     room_handle.send(content).await
                 } else {
-                    forward_media(&ctx.client, &room_handle, event, opts.reupload_media).await
+                    forward_media(
+                        ctx.client.as_ref(),
+                        room_handle.as_ref(),
+                        event,
+                        opts.reupload_media,
+                    )
+                    .await
                 };
 
+                let outcome = if send_res.is_ok() {
+                    "forwarded"
+                } else {
+                    "failed"
+                };
+                metrics::counter!(
+                    "matrix_bot_relay_messages_total",
+                    "cluster" => opts.cluster.to_string(),
+                    "outcome" => outcome,
+                )
+                .increment(1);
                 match send_res {
                     Ok(_) => {
                         info!(from = %source_id, to = %target_id, sender = %event.sender, "Relayed message");
@@ -125,10 +166,11 @@ impl Plugin for Relay {
                             && opts.caption_media
                             && let Some(kind) = media_kind(&event.content.msgtype)
                         {
-                            let caption = format!("{display_name_bold}: sent a {kind}");
-                            let _ = room_handle
-                                .send(RoomMessageEventContent::text_plain(caption))
-                                .await;
+                            let caption = RoomMessageEventContent::text_html(
+                                format!("{display_name}: sent a {kind}"),
+                                format!("<b>{}</b>: sent a {kind}", escape_html(&display_name)),
+                            );
+                            let _ = room_handle.send(caption).await;
                         }
                     }
                     Err(e) => warn!(
@@ -139,18 +181,66 @@ impl Plugin for Relay {
                     ),
                 }
             } else {
+                metrics::counter!(
+                    "matrix_bot_relay_messages_total",
+                    "cluster" => opts.cluster.to_string(),
+                    "outcome" => "failed",
+                )
+                .increment(1);
                 warn!(from = %source_id, to = %target_id, "No handle for target room; skipping relay");
             }
         }
 
         Ok(())
     }
+
+    async fn on_reload(&self, _spec: &PluginSpec) {
+        // Clusters may have changed; the plan is rebuilt from the new spec on
+        // the next message.
+        self.invalidate().await;
+        info!("Relay: plan invalidated by config reload");
+    }
 }
 
 impl Relay {
+    /// The relay plan for `spec`, resolving aliases first if it is not
+    /// cached. `None` if no clusters are configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the relay config cannot be parsed.
+    pub async fn routes(
+        &self,
+        client: &dyn MatrixClient,
+        spec: &PluginSpec,
+    ) -> Result<Option<Vec<RelayRoute>>> {
+        let Some(plan) = self.ensure_plan(client, spec).await? else {
+            return Ok(None);
+        };
+        let mut routes: Vec<_> = plan
+            .map
+            .iter()
+            .map(|(from, to)| RelayRoute {
+                cluster: plan
+                    .opts
+                    .get(from)
+                    .map_or_else(String::new, |o| o.cluster.to_string()),
+                from: from.clone(),
+                to: to.clone(),
+            })
+            .collect();
+        routes.sort_by(|a, b| (&a.cluster, &a.from).cmp(&(&b.cluster, &b.from)));
+        Ok(Some(routes))
+    }
+
+    /// Forget the cached plan, so aliases are resolved again on next use.
+    pub async fn invalidate(&self) {
+        *self.plan.write().await = None;
+    }
+
     async fn ensure_plan(
         &self,
-        client: &Client,
+        client: &dyn MatrixClient,
         spec: &PluginSpec,
     ) -> Result<Option<Arc<RelayPlan>>> {
         let value = self.plan.read().await.clone();
@@ -180,11 +270,15 @@ impl Relay {
     }
 }
 
-async fn resolve_relay_map(client: &Client, cfg: &RelayConfig) -> Result<RelayPlan> {
+async fn resolve_relay_map(client: &dyn MatrixClient, cfg: &RelayConfig) -> Result<RelayPlan> {
     let mut map: HashMap<OwnedRoomId, Vec<OwnedRoomId>> = HashMap::new();
     let mut opts: HashMap<OwnedRoomId, RelayOptions> = HashMap::new();
 
-    for cluster in &cfg.clusters {
+    for (idx, cluster) in cfg.clusters.iter().enumerate() {
+        let label: Arc<str> = cluster
+            .name
+            .clone()
+            .map_or_else(|| format!("cluster-{}", idx + 1).into(), Into::into);
         let mut resolved: Vec<OwnedRoomId> = Vec::new();
         for room_ref in &cluster.rooms {
             if let Ok(id) = RoomId::parse(room_ref) {
@@ -193,9 +287,9 @@ async fn resolve_relay_map(client: &Client, cfg: &RelayConfig) -> Result<RelayPl
             }
             if room_ref.starts_with('#') {
                 if let Ok(alias) = RoomAliasId::parse(room_ref) {
-                    match client.resolve_room_alias(&alias).await {
-                        Ok(resp) => {
-                            resolved.push(resp.room_id.clone());
+                    match client.resolve_alias(&alias).await {
+                        Ok(room_id) => {
+                            resolved.push(room_id);
                         }
                         Err(e) => {
                             warn!(alias = %room_ref, error = %e, "Failed to resolve room alias; skipping");
@@ -229,6 +323,7 @@ async fn resolve_relay_map(client: &Client, cfg: &RelayConfig) -> Result<RelayPl
             opts.insert(
                 r.clone(),
                 RelayOptions {
+                    cluster: Arc::clone(&label),
                     reupload_media: reupload,
                     caption_media: caption,
                 },
@@ -253,28 +348,30 @@ async fn resolve_relay_map(client: &Client, cfg: &RelayConfig) -> Result<RelayPl
     Ok(RelayPlan { map, opts })
 }
 
-async fn resolve_display_name(room: &Room, sender: &matrix_sdk::ruma::OwnedUserId) -> String {
-    match room.get_member(sender).await {
+async fn resolve_display_name(room: &dyn MatrixRoom, sender: &OwnedUserId) -> String {
+    match room.member(sender).await {
         Ok(Some(member)) => member
-            .display_name()
-            .map_or_else(|| sender.localpart().to_owned(), ToOwned::to_owned),
+            .display_name
+            .unwrap_or_else(|| sender.localpart().to_owned()),
         _ => sender.localpart().to_owned(),
     }
 }
 
-fn format_text_message(msg: &MessageType, display_name_bold: &str) -> Option<String> {
+/// Plain and HTML bodies for a relayed text message, with the sender's name
+/// in bold and any reply fallback turned into a short quote.
+fn format_text_message(msg: &MessageType, display_name: &str) -> Option<(String, String)> {
     match msg {
         MessageType::Text(t) => {
             let (quoted, main) = split_reply_fallback(&t.body);
-            Some(format_output(quoted, display_name_bold, main.trim(), ""))
+            Some(format_output(quoted, display_name, main.trim(), ""))
         }
         MessageType::Notice(n) => {
             let (quoted, main) = split_reply_fallback(&n.body);
-            Some(format_output(quoted, display_name_bold, main.trim(), ""))
+            Some(format_output(quoted, display_name, main.trim(), ""))
         }
         MessageType::Emote(e) => {
             let (quoted, main) = split_reply_fallback(&e.body);
-            Some(format_output(quoted, display_name_bold, main.trim(), "* "))
+            Some(format_output(quoted, display_name, main.trim(), "* "))
         }
         MessageType::Audio(_)
         | MessageType::File(_)
@@ -289,28 +386,38 @@ fn format_text_message(msg: &MessageType, display_name_bold: &str) -> Option<Str
 
 fn format_output(
     quoted: Option<String>,
-    display_name_bold: &str,
+    display_name: &str,
     main: &str,
     prefix: &str,
-) -> String {
-    let mut out = String::new();
+) -> (String, String) {
+    let mut plain = String::new();
+    let mut html = String::new();
     if let Some(q) = quoted {
         let snippet = truncate(q.as_str(), 300);
-        _ = writeln!(&mut out, "↪ {snippet}");
+        _ = writeln!(&mut plain, "↪ {snippet}");
+        _ = write!(
+            &mut html,
+            "<blockquote>{}</blockquote>",
+            escape_html(&snippet)
+        );
     }
-    out.push_str(display_name_bold);
-    out.push_str(": ");
-    out.push_str(prefix);
-    out.push_str(main);
-    out
+    _ = write!(&mut plain, "{display_name}: {prefix}{main}");
+    _ = write!(
+        &mut html,
+        "<b>{}</b>: {}{}",
+        escape_html(display_name),
+        escape_html(prefix),
+        escape_html(main).replace('\n', "<br>")
+    );
+    (plain, html)
 }
 
 async fn forward_media(
-    client: &Client,
-    room: &Room,
+    client: &dyn MatrixClient,
+    room: &dyn MatrixRoom,
     event: &OriginalSyncRoomMessageEvent,
     reupload: bool,
-) -> matrix_sdk::Result<matrix_sdk::ruma::api::client::message::send_message_event::v3::Response> {
+) -> Result<OwnedEventId> {
     let msg = &event.content.msgtype;
     match msg {
         MessageType::Image(img) => {
@@ -392,74 +499,64 @@ const fn media_kind(msg: &MessageType) -> Option<&'static str> {
 }
 
 async fn reupload_image(
-    client: &Client,
+    client: &dyn MatrixClient,
     img: &ImageMessageEventContent,
 ) -> Result<(String, Mime, Vec<u8>)> {
     let body = img.body.clone();
     let mime = parse_mime(img.info.as_ref().and_then(|i| i.mimetype.as_deref()));
-    let data_opt = client
-        .media()
-        .get_file(&img.clone(), true)
+    let data = client
+        .download(&img.source)
         .await
         .context("downloading image")?;
-    let data = data_opt.ok_or_else(|| anyhow!("image bytes missing"))?;
     Ok((body, mime, data))
 }
 
 async fn reupload_file(
-    client: &Client,
+    client: &dyn MatrixClient,
     file: &FileMessageEventContent,
 ) -> Result<(String, Mime, Vec<u8>)> {
     let body = file.body.clone();
     let mime = parse_mime(file.info.as_ref().and_then(|i| i.mimetype.as_deref()));
-    let data_opt = client
-        .media()
-        .get_file(&file.clone(), true)
+    let data = client
+        .download(&file.source)
         .await
         .context("downloading file")?;
-    let data = data_opt.ok_or_else(|| anyhow!("file bytes missing"))?;
     Ok((body, mime, data))
 }
 
 async fn reupload_audio(
-    client: &Client,
+    client: &dyn MatrixClient,
     audio: &AudioMessageEventContent,
 ) -> Result<(String, Mime, Vec<u8>)> {
     let body = audio.body.clone();
     let mime = parse_mime(audio.info.as_ref().and_then(|i| i.mimetype.as_deref()));
-    let data_opt = client
-        .media()
-        .get_file(&audio.clone(), true)
+    let data = client
+        .download(&audio.source)
         .await
         .context("downloading audio")?;
-    let data = data_opt.ok_or_else(|| anyhow!("audio bytes missing"))?;
     Ok((body, mime, data))
 }
 
 async fn reupload_video(
-    client: &Client,
+    client: &dyn MatrixClient,
     video: &VideoMessageEventContent,
 ) -> Result<(String, Mime, Vec<u8>)> {
     let body = video.body.clone();
     let mime = parse_mime(video.info.as_ref().and_then(|i| i.mimetype.as_deref()));
-    let data_opt = client
-        .media()
-        .get_file(&video.clone(), true)
+    let data = client
+        .download(&video.source)
         .await
         .context("downloading video")?;
-    let data = data_opt.ok_or_else(|| anyhow!("video bytes missing"))?;
     Ok((body, mime, data))
 }
 
 async fn send_attachment(
-    room: &Room,
+    room: &dyn MatrixRoom,
     body: &str,
     mime: &Mime,
     data: Vec<u8>,
-) -> matrix_sdk::Result<matrix_sdk::ruma::api::client::message::send_message_event::v3::Response> {
-    let config = AttachmentConfig::new();
-    room.send_attachment(body, &mime.clone(), data, config)
-        .await
+) -> Result<OwnedEventId> {
+    room.send_attachment(body, mime, data).await
 }
 
 fn parse_mime(opt: Option<&str>) -> Mime {
@@ -467,17 +564,6 @@ fn parse_mime(opt: Option<&str>) -> Mime {
         .unwrap_or(mime::APPLICATION_OCTET_STREAM)
 }
 
-fn to_bold(s: &str) -> String {
-    s.chars()
-        .map(|c| match c {
-            'A'..='Z' => char::from_u32('𝐀' as u32 + (c as u32 - 'A' as u32)).unwrap_or(c),
-            'a'..='z' => char::from_u32('𝐚' as u32 + (c as u32 - 'a' as u32)).unwrap_or(c),
-            '0'..='9' => char::from_u32('𝟎' as u32 + (c as u32 - '0' as u32)).unwrap_or(c),
-            _ => c,
-        })
-        .collect()
-}
-
 fn split_reply_fallback(body: &str) -> (Option<String>, String) {
     if let Some(sep_idx) = body.find("\n\n") {
         let (quoted_block, rest) = body.split_at(sep_idx);
@@ -501,3 +587,80 @@ fn split_reply_fallback(body: &str) -> (Option<String>, String) {
     }
     (None, body.to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use matrix_sdk::ruma::{mxc_uri, room_alias_id, room_id, user_id};
+    use plugin_core::testing::{FakeClient, Sent, message_event, text_event};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn relays_text_to_peers_resolved_by_alias() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let source = client.add_room(room_id!("!a:example.org"));
+        let peer = client.add_room(room_id!("!b:example.org"));
+        client.add_alias(room_alias_id!("#b:example.org"), peer.room_id());
+        let alice = user_id!("@alice:example.org");
+        source.add_member(alice, Some("Alice"), 0);
+
+        let relay = Relay::default();
+        let mut spec = relay.spec();
+        spec.config =
+            serde_yaml::from_str("clusters: [{ rooms: ['!a:example.org', '#b:example.org'] }]")
+                .unwrap();
+        let triggered = HashSet::new();
+        let meta = RoomMessageMeta {
+            body: Some("hi all"),
+            triggered_plugins: &triggered,
+        };
+        let ctx = client.context(&source);
+        relay
+            .on_room_message(&ctx, &text_event(alice, "hi all"), &spec, &meta)
+            .await
+            .unwrap();
+
+        assert!(source.sent().is_empty());
+        let sent = peer.sent();
+        assert_eq!(sent[0].body(), Some("Alice: hi all"));
+        assert_eq!(sent[0].html(), Some("<b>Alice</b>: hi all"));
+    }
+
+    #[tokio::test]
+    async fn reuploads_images() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let source = client.add_room(room_id!("!a:example.org"));
+        let peer = client.add_room(room_id!("!b:example.org"));
+        let uri = mxc_uri!("mxc://example.org/cat").to_owned();
+        client.add_media(uri.clone(), b"meow".to_vec());
+
+        let relay = Relay::default();
+        let mut spec = relay.spec();
+        spec.config =
+            serde_yaml::from_str("clusters: [{ rooms: ['!a:example.org', '!b:example.org'] }]")
+                .unwrap();
+        let content = RoomMessageEventContent::new(MessageType::Image(
+            ImageMessageEventContent::plain("cat.png".to_owned(), uri),
+        ));
+        let event = message_event(user_id!("@bob:example.org"), &content);
+        let triggered = HashSet::new();
+        let meta = RoomMessageMeta {
+            body: None,
+            triggered_plugins: &triggered,
+        };
+        relay
+            .on_room_message(&client.context(&source), &event, &spec, &meta)
+            .await
+            .unwrap();
+
+        let sent = peer.sent();
+        assert!(
+            matches!(&sent[0], Sent::Attachment { body, data, .. }
+                if body == "cat.png" && data == b"meow"),
+            "{sent:?}"
+        );
+        assert_eq!(sent[1].html(), Some("<b>bob</b>: sent a image"));
+    }
+}
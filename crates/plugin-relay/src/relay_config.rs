@@ -12,6 +12,9 @@ pub struct RelayConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct RelayCluster {
+    /// Label for metrics; unnamed clusters are `cluster-<n>`, counting from 1.
+    #[serde(default)]
+    pub name: Option<String>,
     #[serde(default)]
     pub rooms: Vec<String>,
     #[serde(default)]
@@ -1,11 +1,29 @@
 use anyhow::Result;
 use async_trait::async_trait;
 
-use plugin_core::{Plugin, PluginContext, PluginRegistry, PluginSpec, PluginTriggers, send_text};
+use plugin_core::{
+    Arg, ArgMatches, CommandSpec, ConfigSchema, Opt, OverrideScope, Plugin, PluginContext,
+    PluginRegistry, PluginSpec, PluginTriggers, RoomRef, send_text,
+};
 
 #[derive(Debug)]
 pub struct ToolsManagerPlugin;
 
+/// Toggling plugins affects everyone, so it needs moderator rights unless the
+/// operator configures `permissions` differently.
+const DEFAULT_CONFIG: &str = "
+permissions:
+  subcommands:
+    enable:
+      min_power_level: 50
+    disable:
+      min_power_level: 50
+    reset:
+      min_power_level: 50
+    reload:
+      min_power_level: 50
+";
+
 #[derive(Debug)]
 pub struct ToolsManager;
 
@@ -15,9 +33,9 @@ impl Plugin for ToolsManager {
         "tools"
     }
     fn help(&self) -> &'static str {
-        "Manage plugins: !tools list | enable <id> | disable <id>"
+        "Manage plugins: !tools list | enable <id> [--room|--cluster|--global] | disable <id> [...] | reset <id> [...|--all] | reload"
     }
-       fn spec(&self) -> PluginSpec {
+    fn spec(&self) -> PluginSpec {
         PluginSpec {
             id: "tools".to_owned(),
             enabled: true,
@@ -26,17 +44,77 @@ impl Plugin for ToolsManager {
                 commands: vec!["!tools".to_owned(), "!plugins".to_owned()],
                 mentions: vec![],
             },
-            config: serde_yaml::Value::default(),
+            config: serde_yaml::from_str(DEFAULT_CONFIG).unwrap_or_default(),
         }
     }
+    fn command(&self) -> Option<CommandSpec> {
+        Some(command())
+    }
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(ConfigSchema::new())
+    }
     async fn run(&self, ctx: &PluginContext, args: &str, _spec: &PluginSpec) -> Result<()> {
+        let Some(m) = command().parse_or_reply(ctx, args).await? else {
+            return Ok(());
+        };
         let registry: &PluginRegistry = &ctx.registry;
-        let mut parts = args.split_whitespace();
-        match parts.next() {
-            Some("list") | None => {
+        let room = RoomRef::from_room(ctx.room.as_ref());
+        let id = m.get_str("id").unwrap_or_default();
+        match m.subcommand() {
+            Some(action @ ("enable" | "disable")) => {
+                let enabled = action == "enable";
+                if registry.entry(id).await.is_none() {
+                    return send_text(ctx, format!("unknown plugin: {id}")).await;
+                }
+                let scope = match resolve_scope(registry, &room, &m).await {
+                    Ok(scope) => scope,
+                    Err(msg) => return send_text(ctx, msg).await,
+                };
+                let label = describe_scope(&scope);
+                if let Err(e) = registry.set_override(id, scope, enabled).await {
+                    return send_text(
+                        ctx,
+                        format!("{action}d plugin: {id} ({label}), but saving failed: {e}"),
+                    )
+                    .await;
+                }
+                send_text(ctx, format!("{action}d plugin: {id} ({label})")).await
+            }
+            Some("reload") => match registry.reload().await {
+                Ok(summary) => send_text(ctx, summary).await,
+                Err(e) => send_text(ctx, format!("reload failed: {e:#}")).await,
+            },
+            Some("reset") => {
+                let result = if m.flag("all") {
+                    registry
+                        .clear_overrides(id)
+                        .await
+                        .map(|n| format!("cleared {n} override(s) for {id}"))
+                } else {
+                    let scope = match resolve_scope(registry, &room, &m).await {
+                        Ok(scope) => scope,
+                        Err(msg) => return send_text(ctx, msg).await,
+                    };
+                    let label = describe_scope(&scope);
+                    registry.clear_override(id, &scope).await.map(|removed| {
+                        if removed {
+                            format!("reset plugin: {id} ({label})")
+                        } else {
+                            format!("no override for {id} ({label})")
+                        }
+                    })
+                };
+                match result {
+                    Ok(msg) => send_text(ctx, msg).await,
+                    Err(e) => send_text(ctx, format!("failed to reset {id}: {e}")).await,
+                }
+            }
+            _ => {
+                let mut entries = registry.entries().await;
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
                 let mut rows = vec!["plugins:".to_owned()];
-                for (id, entry) in registry.entries().await {
-                    let enabled = registry.is_enabled(&id).await;
+                for (id, entry) in entries {
+                    let (enabled, source) = registry.effective_state(&id, &room).await;
                     #[allow(clippy::or_fun_call, reason = "const fn")]
                     let dev_only = entry.spec.dev_only.unwrap_or(entry.plugin.dev_only());
                     let triggers = format!(
@@ -45,34 +123,97 @@ impl Plugin for ToolsManager {
                         entry.spec.triggers.mentions.join(", ")
                     );
                     rows.push(format!(
-                        "- {id}: enabled={enabled} dev_only={dev_only} {triggers}",
+                        "- {id}: enabled={enabled} ({source}) dev_only={dev_only} {triggers}",
                     ));
                 }
                 send_text(ctx, rows.join("\n")).await
             }
-            Some("enable") => {
-                if let Some(id) = parts.next() {
-                    registry.set_override(id, true).await;
-                    send_text(ctx, format!("enabled plugin: {id}")).await
-                } else {
-                    send_text(ctx, "Usage: !tools enable <id> (alias: !plugins)").await
-                }
-            }
-            Some("disable") => {
-                if let Some(id) = parts.next() {
-                    registry.set_override(id, false).await;
-                    send_text(ctx, format!("disabled plugin: {id}")).await
-                } else {
-                    send_text(ctx, "Usage: !tools disable <id> (alias: !plugins)").await
-                }
-            }
-            _ => {
-                send_text(
-                    ctx,
-                    "Usage: !tools [list|enable <id>|disable <id>] (alias: !plugins)",
-                )
-                .await
-            }
         }
     }
 }
+
+fn command() -> CommandSpec {
+    let plugin_id = || Arg::new("id").required().help("plugin ID, see !tools list");
+    let scoped = |name: &str, about: &str| {
+        CommandSpec::new(name)
+            .about(about)
+            .arg(plugin_id())
+            .opt(Opt::flag("room").help("only this room (default)"))
+            .opt(Opt::flag("cluster").help("every room in this room's cluster"))
+            .opt(Opt::flag("global").help("every room"))
+    };
+    CommandSpec::new("!tools")
+        .about("Manage plugins (alias: !plugins)")
+        .default_subcommand("list")
+        .subcommand(CommandSpec::new("list").about("Show plugins and where they are enabled"))
+        .subcommand(scoped("enable", "Enable a plugin"))
+        .subcommand(scoped("disable", "Disable a plugin"))
+        .subcommand(
+            scoped("reset", "Remove an override")
+                .opt(Opt::flag("all").help("remove every override for the plugin")),
+        )
+        .subcommand(CommandSpec::new("reload").about("Re-read the config files"))
+}
+
+/// Map the scope flags to an override scope. Without a flag the current room is used.
+async fn resolve_scope(
+    registry: &PluginRegistry,
+    room: &RoomRef,
+    m: &ArgMatches,
+) -> Result<OverrideScope, String> {
+    let flags = ["room", "cluster", "global", "all"];
+    match flags.iter().filter(|flag| m.flag(flag)).collect::<Vec<_>>()[..] {
+        [] | [&"room"] => Ok(OverrideScope::Room(room.id.clone())),
+        [&"global"] => Ok(OverrideScope::Global),
+        [&"cluster"] => registry
+            .clusters_for(room)
+            .await
+            .into_iter()
+            .next()
+            .map(OverrideScope::Cluster)
+            .ok_or_else(|| "this room is not part of any cluster".to_owned()),
+        _ => Err("use only one of --room, --cluster, --global or --all".to_owned()),
+    }
+}
+
+fn describe_scope(scope: &OverrideScope) -> String {
+    match scope {
+        OverrideScope::Global => "global".to_owned(),
+        OverrideScope::Cluster(name) => format!("cluster {name}"),
+        OverrideScope::Room(_) => "this room".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use matrix_sdk::ruma::{room_id, user_id};
+    use plugin_core::testing::FakeClient;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn disable_shows_up_in_list() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let room = client.add_room(room_id!("!r:example.org"));
+        let ctx = client.context(&room);
+        ctx.registry
+            .register(ToolsManager.spec(), Arc::new(ToolsManager))
+            .await;
+        let spec = ToolsManager.spec();
+
+        ToolsManager
+            .run(&ctx, "disable tools", &spec)
+            .await
+            .unwrap();
+        ToolsManager.run(&ctx, "disable nope", &spec).await.unwrap();
+        ToolsManager.run(&ctx, "", &spec).await.unwrap();
+        let sent = room.sent_bodies();
+        assert_eq!(sent[0], "disabled plugin: tools (this room)");
+        assert_eq!(sent[1], "unknown plugin: nope");
+        assert!(sent[2].contains("- tools: enabled=false"), "{}", sent[2]);
+        let room_ref = RoomRef::from_room(room.as_ref());
+        assert!(!ctx.registry.is_enabled_in("tools", &room_ref).await);
+    }
+}
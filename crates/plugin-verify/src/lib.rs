@@ -0,0 +1,484 @@
+//! `!verify`: operator-confirmed SAS verification.
+//!
+//! When the bot runs with `--verify-mode operator`, it posts each
+//! verification's emojis to the configured admin room, or to the room it
+//! shares with the requesting user, and waits for `!verify confirm <flow>`
+//! or `!verify cancel <flow>` before answering the other device:
+//!
+//! ```yaml
+//! plugins:
+//!   - id: verify
+//!     admin_room: "#bot-admin:example.org"
+//!     operators: ["@alice:example.org"]
+//!     confirm_timeout_secs: 300
+//! ```
+//!
+//! Answers only count in the room the emojis went to, and only from the
+//! listed operators; the requesting user cannot confirm their own flow.
+//! Operator mode refuses to start without operators.
+
+use core::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use async_trait::async_trait;
+use matrix_sdk::ruma::{
+    OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId,
+    events::room::message::RoomMessageEventContent,
+};
+use plugin_core::{
+    Arg, CommandSpec, ConfigSchema, ConfigType, MatrixClient, MatrixRoom, Plugin, PluginContext,
+    PluginSpec, PluginTriggers, send_text,
+};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tracing::info;
+
+const DEFAULT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What an operator decided about a verification flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Confirm,
+    Cancel,
+    /// Nobody answered within `confirm_timeout_secs`.
+    TimedOut,
+}
+
+/// A verification flow whose short authentication string awaits a decision.
+#[derive(Debug, Clone)]
+pub struct SasPrompt {
+    pub flow_id: String,
+    pub user: OwnedUserId,
+    pub device: OwnedDeviceId,
+    /// Symbol and name of each emoji, in order.
+    pub emojis: Vec<(String, String)>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct VerifyConfig {
+    #[serde(default)]
+    admin_room: Option<String>,
+    #[serde(default)]
+    operators: Vec<String>,
+    #[serde(default)]
+    confirm_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    user: OwnedUserId,
+    /// Where the emojis were posted.
+    room: OwnedRoomId,
+    operators: Vec<String>,
+    decide: oneshot::Sender<Decision>,
+}
+
+/// The flows waiting for `!verify`, shared by the plugin and the bot's
+/// verification handlers.
+#[derive(Debug, Default)]
+pub struct Verifier {
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+/// Removes a flow from the pending ones when its prompt is dropped, e.g.
+/// because the other device cancelled.
+struct PendingGuard<'a> {
+    verifier: &'a Verifier,
+    flow_id: &'a str,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.verifier.lock().remove(self.flow_id);
+    }
+}
+
+impl Verifier {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Pending>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Post `prompt` to `room`, see [`room_to_ask`], and wait for an answer
+    /// or the timeout.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the config is invalid or posting to `room` fails.
+    pub async fn ask(
+        &self,
+        spec: &PluginSpec,
+        prompt: &SasPrompt,
+        room: Arc<dyn MatrixRoom>,
+    ) -> Result<Decision> {
+        let config = operator_config(spec)?;
+        let timeout = config
+            .confirm_timeout_secs
+            .map_or(DEFAULT_CONFIRM_TIMEOUT, Duration::from_secs);
+
+        let (decide, decision) = oneshot::channel();
+        self.lock().insert(
+            prompt.flow_id.clone(),
+            Pending {
+                user: prompt.user.clone(),
+                room: room.room_id().to_owned(),
+                operators: config.operators,
+                decide,
+            },
+        );
+        let _guard = PendingGuard {
+            verifier: self,
+            flow_id: &prompt.flow_id,
+        };
+        room.send(RoomMessageEventContent::text_plain(prompt_text(
+            prompt, timeout,
+        )))
+        .await
+        .context("posting the verification emojis")?;
+        info!(user = %prompt.user, flow = %prompt.flow_id, room = %room.room_id(), "Waiting for !verify");
+
+        let decision = match tokio::time::timeout(timeout, decision).await {
+            Ok(Ok(decision)) => decision,
+            Ok(Err(_)) => Decision::Cancel,
+            Err(_) => {
+                room.send(RoomMessageEventContent::text_plain(format!(
+                    "verification {} timed out and was cancelled",
+                    prompt.flow_id
+                )))
+                .await?;
+                Decision::TimedOut
+            }
+        };
+        Ok(decision)
+    }
+
+    /// Decide the flow that `flow` names, for `sender` writing in `room`.
+    fn answer(
+        &self,
+        flow: &str,
+        sender: &str,
+        room: &RoomId,
+        decision: Decision,
+    ) -> Result<String, String> {
+        let mut pending = self.lock();
+        let flow_id = find_flow(&pending, flow)?;
+        let entry = &pending[&flow_id];
+        if entry.room != room {
+            return Err(format!(
+                "answer {flow_id} in the room that shows its emojis"
+            ));
+        }
+        if !entry.operators.iter().any(|op| op == sender) {
+            return Err(format!("you may not answer verification {flow_id}"));
+        }
+        let entry = pending.remove(&flow_id).expect("found above");
+        drop(pending);
+        info!(target: "audit", user = %entry.user, flow = %flow_id, by = %sender, ?decision, "Verification answered");
+        // The waiting side may just have timed out; then this is moot.
+        let _ = entry.decide.send(decision);
+        Ok(match decision {
+            Decision::Confirm => format!("confirmed {flow_id}; waiting for the other device"),
+            Decision::Cancel | Decision::TimedOut => format!("cancelled {flow_id}"),
+        })
+    }
+
+    /// Pending flows that `room` can answer, one line each.
+    fn list(&self, room: &RoomId) -> Vec<String> {
+        let pending = self.lock();
+        let mut lines: Vec<String> = pending
+            .iter()
+            .filter(|(_, entry)| entry.room == room)
+            .map(|(flow_id, entry)| format!("- {flow_id}: {}", entry.user))
+            .collect();
+        drop(pending);
+        lines.sort();
+        lines
+    }
+}
+
+/// Check that `spec` can back `--verify-mode operator`: it parses and lists
+/// at least one operator.
+///
+/// # Errors
+///
+/// Fails if the config is invalid or `operators` is empty.
+pub fn check_operator_mode(spec: &PluginSpec) -> Result<()> {
+    operator_config(spec).map(drop)
+}
+
+fn operator_config(spec: &PluginSpec) -> Result<VerifyConfig> {
+    let config: VerifyConfig = if spec.config.is_null() {
+        VerifyConfig::default()
+    } else {
+        serde_yaml::from_value(spec.config.clone()).context("parsing the verify config")?
+    };
+    if config.operators.is_empty() {
+        bail!("verify: operator mode needs at least one entry in `operators`");
+    }
+    Ok(config)
+}
+
+async fn admin_room_of(client: &dyn MatrixClient, room: &str) -> Result<Arc<dyn MatrixRoom>> {
+    let room_id = if room.starts_with('#') {
+        let alias = RoomAliasId::parse(room).with_context(|| format!("invalid alias {room}"))?;
+        client.resolve_alias(&alias).await?
+    } else {
+        RoomId::parse(room).with_context(|| format!("invalid room ID {room}"))?
+    };
+    client
+        .room(&room_id)
+        .ok_or_else(|| anyhow!("verify: the bot is not in the admin room {room}"))
+}
+
+/// Where to ask about `prompt`: the admin room from `spec`, or `user_room`
+/// when none is configured.
+///
+/// # Errors
+///
+/// Fails if the config is invalid or there is no room to ask in.
+pub async fn room_to_ask(
+    client: &dyn MatrixClient,
+    spec: &PluginSpec,
+    prompt: &SasPrompt,
+    user_room: Option<Arc<dyn MatrixRoom>>,
+) -> Result<Arc<dyn MatrixRoom>> {
+    match operator_config(spec)?.admin_room {
+        Some(admin_room) => admin_room_of(client, &admin_room).await,
+        None => user_room.ok_or_else(|| {
+            anyhow!(
+                "verify: no room shared with {} to ask in; set admin_room",
+                prompt.user
+            )
+        }),
+    }
+}
+
+/// The flow ID that `flow` is, or starts uniquely.
+fn find_flow(pending: &HashMap<String, Pending>, flow: &str) -> Result<String, String> {
+    if pending.contains_key(flow) {
+        return Ok(flow.to_owned());
+    }
+    let mut matches = pending.keys().filter(|id| id.starts_with(flow));
+    match (matches.next(), matches.next()) {
+        (Some(id), None) if !flow.is_empty() => Ok(id.clone()),
+        (Some(_), Some(_)) => Err(format!("{flow} matches several verifications")),
+        _ => Err(format!("no verification {flow} is waiting")),
+    }
+}
+
+fn prompt_text(prompt: &SasPrompt, timeout: Duration) -> String {
+    let symbols: Vec<&str> = prompt.emojis.iter().map(|(s, _)| s.as_str()).collect();
+    let names: Vec<&str> = prompt.emojis.iter().map(|(_, n)| n.as_str()).collect();
+    format!(
+        "{} wants to verify the bot from device {} (flow {}). Do these match what that device shows?\n\
+         {}\n{}\n\
+         Answer with !verify confirm {2} or !verify cancel {2} within {} minutes.",
+        prompt.user,
+        prompt.device,
+        prompt.flow_id,
+        symbols.join(" "),
+        names.join(", "),
+        timeout.as_secs().div_ceil(60),
+    )
+}
+
+#[async_trait]
+impl Plugin for Verifier {
+    fn id(&self) -> &'static str {
+        "verify"
+    }
+    fn help(&self) -> &'static str {
+        "Answer SAS verifications: !verify [list] | confirm <flow> | cancel <flow>"
+    }
+    fn spec(&self) -> PluginSpec {
+        PluginSpec {
+            id: "verify".to_owned(),
+            enabled: true,
+            dev_only: None,
+            triggers: PluginTriggers {
+                commands: vec!["!verify".to_owned()],
+                mentions: vec![],
+            },
+            config: serde_yaml::Value::default(),
+        }
+    }
+    fn command(&self) -> Option<CommandSpec> {
+        Some(command())
+    }
+    fn config_schema(&self) -> Option<ConfigSchema> {
+        Some(
+            ConfigSchema::new()
+                .field("admin_room", ConfigType::String)
+                .field("operators", ConfigType::list(ConfigType::String))
+                .field("confirm_timeout_secs", ConfigType::Integer),
+        )
+    }
+    async fn run(&self, ctx: &PluginContext, args: &str, _spec: &PluginSpec) -> Result<()> {
+        let Some(m) = command().parse_or_reply(ctx, args).await? else {
+            return Ok(());
+        };
+        let room = ctx.room.room_id();
+        let decision = match m.subcommand() {
+            Some("confirm") => Decision::Confirm,
+            Some("cancel") => Decision::Cancel,
+            _ => {
+                let lines = self.list(room);
+                if lines.is_empty() {
+                    return send_text(ctx, "no verifications are waiting here").await;
+                }
+                return send_text(ctx, format!("waiting:\n{}", lines.join("\n"))).await;
+            }
+        };
+        let sender = ctx
+            .event
+            .as_ref()
+            .map(|ev| ev.sender.to_string())
+            .unwrap_or_default();
+        let flow = m.get_str("flow").unwrap_or_default();
+        let reply = self
+            .answer(flow, &sender, room, decision)
+            .unwrap_or_else(|e| e);
+        send_text(ctx, reply).await
+    }
+}
+
+fn command() -> CommandSpec {
+    let flow = || {
+        Arg::new("flow")
+            .required()
+            .help("flow ID from the emoji message, or its start")
+    };
+    CommandSpec::new("!verify")
+        .about("Answer SAS verifications the bot is waiting on")
+        .default_subcommand("list")
+        .subcommand(CommandSpec::new("list").about("Show the flows waiting here"))
+        .subcommand(
+            CommandSpec::new("confirm")
+                .about("The emojis match; confirm the verification")
+                .arg(flow()),
+        )
+        .subcommand(
+            CommandSpec::new("cancel")
+                .about("The emojis differ; cancel the verification")
+                .arg(flow()),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{device_id, room_id, user_id};
+    use plugin_core::testing::{FakeClient, text_event};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn operators_answer_in_the_admin_room() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let admin = client.add_room(room_id!("!admin:example.org"));
+        let other = client.add_room(room_id!("!other:example.org"));
+        let verifier = Arc::new(Verifier::default());
+        let mut spec = verifier.spec();
+        spec.config = serde_yaml::from_str(
+            "{ admin_room: '!admin:example.org', operators: ['@op:example.org'] }",
+        )
+        .unwrap();
+        let prompt = SasPrompt {
+            flow_id: "abcdef123".to_owned(),
+            user: user_id!("@bob:example.org").to_owned(),
+            device: device_id!("BOBPHONE").to_owned(),
+            emojis: vec![("🐶".to_owned(), "Dog".to_owned())],
+        };
+
+        let asking = {
+            let (verifier, client, spec, prompt) = (
+                Arc::clone(&verifier),
+                Arc::clone(&client),
+                spec.clone(),
+                prompt.clone(),
+            );
+            tokio::spawn(async move {
+                let room = room_to_ask(client.as_ref(), &spec, &prompt, None).await?;
+                verifier.ask(&spec, &prompt, room).await
+            })
+        };
+        while admin.sent_bodies().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert!(admin.sent_bodies()[0].contains("🐶\nDog\n"));
+
+        let say = |room: &Arc<plugin_core::testing::FakeRoom>, sender, body: &str| {
+            let mut ctx = client.context(room);
+            ctx.event = Some(Arc::new(text_event(sender, body)));
+            let args = body.trim_start_matches("!verify").trim().to_owned();
+            let (verifier, spec) = (Arc::clone(&verifier), spec.clone());
+            async move { verifier.run(&ctx, &args, &spec).await.unwrap() }
+        };
+        say(&other, user_id!("@op:example.org"), "!verify confirm abc").await;
+        say(&admin, user_id!("@bob:example.org"), "!verify confirm abc").await;
+        say(&admin, user_id!("@op:example.org"), "!verify").await;
+        say(&admin, user_id!("@op:example.org"), "!verify confirm abc").await;
+        assert_eq!(asking.await.unwrap().unwrap(), Decision::Confirm);
+        assert_eq!(
+            other.sent_bodies(),
+            ["answer abcdef123 in the room that shows its emojis"]
+        );
+        assert_eq!(
+            admin.sent_bodies()[1..],
+            [
+                "you may not answer verification abcdef123",
+                "waiting:\n- abcdef123: @bob:example.org",
+                "confirmed abcdef123; waiting for the other device",
+            ]
+        );
+        assert!(verifier.list(admin.room_id()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn requesting_user_cannot_confirm_their_own_flow() {
+        let client = FakeClient::new(user_id!("@bot:example.org"));
+        let dm = client.add_room(room_id!("!dm:example.org"));
+        let verifier = Arc::new(Verifier::default());
+        let mut spec = verifier.spec();
+        spec.config = serde_yaml::from_str("{ operators: ['@op:example.org'] }").unwrap();
+        let prompt = SasPrompt {
+            flow_id: "flow1".to_owned(),
+            user: user_id!("@bob:example.org").to_owned(),
+            device: device_id!("BOBPHONE").to_owned(),
+            emojis: vec![("🐶".to_owned(), "Dog".to_owned())],
+        };
+
+        let user_room = Arc::clone(&dm) as Arc<dyn MatrixRoom>;
+        let room = room_to_ask(client.as_ref(), &spec, &prompt, Some(user_room))
+            .await
+            .unwrap();
+        assert_eq!(room.room_id(), dm.room_id());
+        let asking = {
+            let (verifier, spec, prompt) = (Arc::clone(&verifier), spec.clone(), prompt.clone());
+            tokio::spawn(async move { verifier.ask(&spec, &prompt, room).await })
+        };
+        while dm.sent_bodies().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            verifier.answer("flow1", "@bob:example.org", dm.room_id(), Decision::Confirm),
+            Err("you may not answer verification flow1".to_owned())
+        );
+        verifier
+            .answer("flow1", "@op:example.org", dm.room_id(), Decision::Cancel)
+            .unwrap();
+        assert_eq!(asking.await.unwrap().unwrap(), Decision::Cancel);
+    }
+
+    #[test]
+    fn operator_mode_needs_operators() {
+        let mut spec = Verifier::default().spec();
+        assert!(check_operator_mode(&spec).is_err());
+        spec.config = serde_yaml::from_str("{ admin_room: '!admin:example.org' }").unwrap();
+        assert!(check_operator_mode(&spec).is_err());
+        spec.config = serde_yaml::from_str("{ operators: ['@op:example.org'] }").unwrap();
+        assert!(check_operator_mode(&spec).is_ok());
+    }
+}